@@ -0,0 +1,125 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! Throughput of TCP relay `Server` relaying `Data` packets between linked
+clients from several threads at once.
+
+Run with `cargo bench --bench tcp_server`.
+*/
+
+extern crate futures;
+extern crate tox;
+
+use tox::toxcore::crypto_core::*;
+use tox::toxcore::tcp::packet::*;
+use tox::toxcore::tcp::server::{Client, Server, Limits, RateLimit};
+
+use futures::{Future, Stream};
+use futures::sync::mpsc;
+
+use std::net::{IpAddr, SocketAddr};
+use std::thread;
+use std::time::Instant;
+
+/// Number of linked pairs of clients used by each thread
+const PAIRS_PER_THREAD: usize = 16;
+
+/// Number of `Data` packets sent by each thread
+const PACKETS_PER_THREAD: usize = 100_000;
+
+/// Size of data in each `Data` packet
+const DATA_SIZE: usize = 1024;
+
+/// Connect a new client to the server, returns its PK and receiver of
+/// packets sent to it
+fn add_client(server: &Server) -> (PublicKey, mpsc::UnboundedReceiver<Packet>) {
+    let (pk, _) = gen_keypair();
+    let (tx, rx) = mpsc::unbounded();
+    // every client has its own TCP connection
+    let addr = SocketAddr::new(IpAddr::V4(random_u32().into()), random_u32() as u16);
+    server.insert(Client::new(tx, &pk, addr));
+    (pk, rx)
+}
+
+/// Connect two clients and link them to each other, returns PK of the first
+/// client and receiver of the second one
+fn add_linked_pair(server: &Server) -> (PublicKey, mpsc::UnboundedReceiver<Packet>) {
+    let (pk_a, rx_a) = add_client(server);
+    let (pk_b, rx_b) = add_client(server);
+    server.handle_packet(&pk_a, Packet::RouteRequest(RouteRequest { pk: pk_b }))
+        .wait().unwrap();
+    server.handle_packet(&pk_b, Packet::RouteRequest(RouteRequest { pk: pk_a }))
+        .wait().unwrap();
+    // nothing is sent to the first client anymore
+    drop(rx_a);
+    // skip RouteResponse and ConnectNotification
+    let (_, rx_b) = rx_b.into_future().wait().map_err(|_| ()).unwrap();
+    let (_, rx_b) = rx_b.into_future().wait().map_err(|_| ()).unwrap();
+    (pk_a, rx_b)
+}
+
+/// Relay `PACKETS_PER_THREAD` packets on each of `threads_count` threads,
+/// returns number of relayed packets per second
+fn run(threads_count: usize) -> f64 {
+    let mut server = Server::new();
+    // relaying is measured, not limiting
+    server.set_limits(Limits {
+        data_bytes: RateLimit { rate: u32::max_value(), burst: u32::max_value() },
+        .. Limits::default()
+    });
+    let pairs = (0..threads_count)
+        .map(|_| (0..PAIRS_PER_THREAD).map(|_| add_linked_pair(&server)).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+
+    let start = Instant::now();
+    let threads = pairs.into_iter()
+        .map(|pairs| {
+            let server = server.clone();
+            thread::spawn(move || {
+                let mut pairs = pairs.into_iter()
+                    .map(|(pk_a, rx_b)| (pk_a, rx_b.wait()))
+                    .collect::<Vec<_>>();
+                for i in 0..PACKETS_PER_THREAD {
+                    let &mut (ref pk_a, ref mut rx_b) = &mut pairs[i % PAIRS_PER_THREAD];
+                    server.handle_packet(pk_a, Packet::Data(Data {
+                        connection_id: 16,
+                        data: vec![42; DATA_SIZE],
+                    })).wait().unwrap();
+                    rx_b.next().unwrap().unwrap();
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    let elapsed = start.elapsed();
+    let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+    (threads_count * PACKETS_PER_THREAD) as f64 / secs
+}
+
+fn main() {
+    let single = run(1);
+    println!("threads: 1, packets/s: {:.0}", single);
+    for &threads_count in &[2, 4, 8] {
+        let throughput = run(threads_count);
+        println!("threads: {}, packets/s: {:.0}, speedup: {:.2}",
+            threads_count, throughput, throughput / single);
+    }
+}
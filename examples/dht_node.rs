@@ -25,6 +25,10 @@
 //
 // to get some meaningful info, run it with `RUST_LOG` env variable
 // set, e.g. `RUST_LOG="tox=debug,dht_node=debug"`
+//
+// path to a file with nodes to bootstrap from can be supplied as the first
+// argument, e.g. the JSON list from https://nodes.tox.chat/json or a plain
+// text list with "IP port PK" lines
 
 extern crate futures;
 extern crate env_logger;
@@ -42,14 +46,16 @@ use rustc_serialize::hex::FromHex;
 use tokio_core::reactor::Core;
 use tokio_timer::*;
 
+use std::env;
 use std::net::SocketAddr;
 use std::time::Duration;
 use std::cell::RefCell;
 use std::sync::Arc;
 
+use tox::toxcore::bootstrap::*;
 use tox::toxcore::crypto_core::*;
-use tox::toxcore::dht::*;
 use tox::toxcore::dht_node::*;
+use tox::toxcore::lan_discovery::*;
 use tox::toxcore::network::*;
 use tox::toxcore::timeout::*;
 
@@ -64,47 +70,58 @@ fn main() {
     let socket = bind_udp("::".parse().unwrap(), PORT_MIN..PORT_MAX, &handle)
         .unwrap();
 
-    // get PK bytes of some "random" bootstrap node (Impyy's)
-    let bootstrap_pk_bytes = FromHex::from_hex(
-        "6FC41E2BD381D37E9748FC0E0328CE086AF9598BECC8FEB7DDF2E440475F300E")
-        .unwrap();
-    // create PK from bytes
-    let bootstrap_pk = PublicKey::from_slice(&bootstrap_pk_bytes).unwrap();
-
-                 //"51.15.37.145:33445".parse().unwrap()
-    let saddr: SocketAddr = "51.15.37.145:33445".parse().unwrap();
-    let bootstrap_pn = PackedNode::new(true, saddr, &bootstrap_pk);
-    let (sink, stream) = socket.framed(ToxCodec).split();
+    let bootstrap_nodes = match env::args().nth(1) {
+        Some(path) => load_nodes_file(&path)
+            .expect("Failed to load nodes to bootstrap from"),
+        None => {
+            // get PK bytes of some "random" bootstrap node (Impyy's)
+            let bootstrap_pk_bytes = FromHex::from_hex(
+                "6FC41E2BD381D37E9748FC0E0328CE086AF9598BECC8FEB7DDF2E440475F300E")
+                .unwrap();
+            // create PK from bytes
+            let bootstrap_pk = PublicKey::from_slice(&bootstrap_pk_bytes).unwrap();
+
+            let saddr: SocketAddr = "51.15.37.145:33445".parse().unwrap();
+            vec![BootstrapNode {
+                pk: bootstrap_pk,
+                addrs: vec![saddr],
+                tcp_ports: Vec::new(),
+            }]
+        },
+    };
+    let codec = ToxCodec::new(&socket);
+    let (sink, stream) = socket.framed(codec).split();
 
     let timer = Timer::default();
     // GetNodes timeout
     let getn_time = timer.interval(Duration::from_secs(20));
     let eject_nodes = timer.interval(Duration::from_secs(1));
+    let lan_discovery_time = timer.interval(Duration::from_secs(LAN_DISCOVERY_INTERVAL));
 
     let send_tx = send_packets(sink);
 
     // bootstrap
-    // TODO: add method to DhtNode for adding timeouts ?
-    //let req_some_nodes = send_tx.clone()
-    //    .send(node.borrow_mut().request_nodes(&bootstrap_pn).map)
-    //    .then(|_| Ok(()));
-    //handle.spawn(req_some_nodes);
-
-    // node will automatically "bootstrap", but bootstrapping is not
-    // reliable, restarting a few times might be necessary to join the
-    // network (bootstrapping from multiple nodes would help with that)
-    assert!(node.borrow_mut().try_add(&bootstrap_pn));
+    // bootstrapping is not reliable with a single node, restarting a few
+    // times might be necessary to join the network – bootstrapping from
+    // multiple nodes helps with that
+    info!("Bootstrapping from {} nodes", bootstrap_nodes.len());
+    for request in node.borrow_mut().bootstrap(&bootstrap_nodes) {
+        let request_it = send_tx.clone()
+            .send(request)
+            .then(|_| Ok(()));
+        handle.spawn(request_it);
+    }
 
     
     //// handle incoming stuff
 
     let deal_with_it = receive_packets(stream).for_each(|(addr, packet)| {
         debug!("Handling packet from {:?}", addr);
-        let resp = node.borrow_mut().handle_packet(&packet);
+        let resp = node.borrow_mut().handle_packet(addr, &packet);
 
         if let Some(r) = resp {
             let tx = send_tx.clone();
-            handle.spawn(tx.send((addr, r)).then(|_| Ok(())));
+            handle.spawn(tx.send(r).then(|_| Ok(())));
         }
 
         Ok(())
@@ -126,12 +143,41 @@ fn main() {
     }).for_each(|_| Ok(()));
 
 
+    let hc = core.handle();
     let eject = eject_nodes.then(|_| {
         //debug!("Checking for timed out nodes");
         node.borrow_mut().remove_timed_out(RESPONSE_CHECK);
+        // check whether received nodes are online before adding them
+        for ping in node.borrow_mut().ping_to_close_nodes() {
+            let ping_it = send_tx.clone()
+                .send(ping)
+                .then(|_| Ok(()));
+            hc.spawn(ping_it);
+        }
+        // check whether nodes from the Close List are still online
+        for ping in node.borrow_mut().ping_close_list() {
+            let ping_it = send_tx.clone()
+                .send(ping)
+                .then(|_| Ok(()));
+            hc.spawn(ping_it);
+        }
         Ok(())
     }).for_each(|_| Ok(()));
 
-    let joined = getn_f.join3(deal_with_it, eject);
+    // broadcast LAN discovery packets, socket is bound to IPv6 address
+    let lan_discovery_sender = node.borrow().lan_discovery_sender(true);
+    let hc = core.handle();
+    let lan_discovery = lan_discovery_time.then(|_| {
+        debug!("Sending LAN discovery packets");
+        for packet in lan_discovery_sender.packets() {
+            let send_it = send_tx.clone()
+                .send(packet)
+                .then(|_| Ok(()));
+            hc.spawn(send_it);
+        }
+        Ok::<(), ()>(())
+    }).for_each(|_| Ok(()));
+
+    let joined = getn_f.join4(deal_with_it, eject, lan_discovery);
     drop(core.run(joined));
 }
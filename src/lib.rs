@@ -25,8 +25,11 @@ Repo: https://github.com/zetok/tox
 C API: https://github.com/ze-tox/tox-capi
 
 Current API allows one to e.g. find info about DHT nodes from bootstrap
-nodes by sending [`GetNodes`](./toxcore/dht/struct.GetNodes.html) or request
-[`Ping`](./toxcore/dht/struct.Ping.html) response.
+nodes by sending [`GetNodes`](./toxcore/dht_new/packet/struct.GetNodes.html)
+or request [`PingResponse`](./toxcore/dht_new/packet/struct.PingResponse.html).
+
+The legacy `toxcore::dht` module is deprecated in favour of `toxcore::dht_new`
+and is only available with the `legacy_dht` feature.
 
 To request a ping response:
 
@@ -40,9 +43,9 @@ extern crate rustc_serialize;
 use rustc_serialize::hex::FromHex;
 
 extern crate tox;
-use tox::toxcore::binary_io::*;
 use tox::toxcore::crypto_core::*;
-use tox::toxcore::dht::*;
+use tox::toxcore::dht_new::binary_io::*;
+use tox::toxcore::dht_new::packet::*;
 use tox::toxcore::network::*;
 use tox::toxcore::packet_kind::PacketKind;
 
@@ -65,10 +68,13 @@ fn main() {
     let nonce = gen_nonce();
 
     // now create Ping request
-    let ping = &PingReq::new();
+    let ping = PingRequest::new();
 
     // with Ping packet create DhtPacket, and serialize it to bytes
-    let dhtpacket = DhtPacket::new(&precomp, &pk, &nonce, ping).to_bytes();
+    let mut dhtpacket = [0; MAX_UDP_PACKET_SIZE];
+    let (_, size) = DhtPacket::new(&precomp, &pk, &nonce, &ping)
+        .to_bytes((&mut dhtpacket, 0))
+        .expect("Failed to serialize DhtPacket!");
 
     // and since packet is ready, prepare the network part;
     // bind to given address and port in given range
@@ -77,7 +83,7 @@ fn main() {
         .expect("Failed to bind to socket!");
 
     // send DhtPacket via socket to the node (Imppy's)
-    let sent_bytes = socket.send_dgram(&dhtpacket,
+    let sent_bytes = socket.send_dgram(&dhtpacket[..size],
                  "51.15.37.145:33445".parse().unwrap());
     let (socket, _) = reactor.run(sent_bytes)
         .expect("Failed to send bytes!");
@@ -94,14 +100,14 @@ fn main() {
 
     // try to deserialize received bytes as `DhtPacket`
     let recv_packet = match DhtPacket::from_bytes(&buf[..n_bytes]) {
-        Some(p) => p,
+        IResult::Done(_, p) => p,
         // if parsing fails ↓
-        None => panic!("Received packet could not have been parsed!\n{:?}",
+        _ => panic!("Received packet could not have been parsed!\n{:?}",
                         &buf[..n_bytes]),
     };
 
     // decrypt payload of the received packet
-    let payload: PingResp = recv_packet.get_payload(&sk)
+    let payload: PingResponse = recv_packet.get_payload(&sk)
         .expect("Failed to decrypt payload!");
 
     assert_eq!(PacketKind::PingResp, payload.kind());
@@ -130,6 +136,9 @@ extern crate byteorder;
 extern crate futures;
 #[macro_use]
 extern crate log;
+extern crate linked_hash_map;
+extern crate net2;
+extern crate rustc_serialize;
 #[macro_use]
 extern crate nom;
 #[macro_use]
@@ -143,6 +152,7 @@ extern crate quickcheck;
 extern crate tokio_core;
 extern crate tokio_proto;
 extern crate tokio_io;
+extern crate tokio_timer;
 
 
 // TODO: refactor macros
@@ -150,7 +160,6 @@ extern crate tokio_io;
 #[macro_use]
 pub mod toxcore_tests {
     extern crate rand;
-    extern crate rustc_serialize;
 
     // Helper macros for testing, no tests
     #[warn(missing_docs)]
@@ -160,6 +169,8 @@ pub mod toxcore_tests {
     // tests
     mod binary_io_tests;
     mod crypto_core_tests;
+    #[cfg(feature = "legacy_dht")]
+    #[allow(deprecated)]
     mod dht_tests;
     mod network_tests;
     mod packet_kind_tests;
@@ -175,12 +186,23 @@ pub mod toxcore_tests {
 pub mod toxcore {
     #[macro_use]
     pub mod binary_io;
+    pub mod bootstrap;
     pub mod crypto_core;
+    /// Legacy DHT implementation, superseded by [`dht_new`](./dht_new/index.html).
+    #[cfg(feature = "legacy_dht")]
+    #[deprecated(note = "use `toxcore::dht_new` instead")]
     pub mod dht;
+    pub mod dht_friend;
+    pub mod dht_lookup;
     pub mod dht_node;
     pub mod hole_punching;
+    pub mod lan_discovery;
+    pub mod net_crypto;
     pub mod network;
+    pub mod onion;
     pub mod packet_kind;
+    pub mod precomputed_cache;
+    pub mod public_addr;
     pub mod state_format;
     pub mod timeout;
     pub mod toxid;
@@ -0,0 +1,437 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*!
+Loading of nodes to bootstrap from.
+
+Two formats of nodes lists are supported:
+
+- JSON format of the [community nodes list](https://nodes.tox.chat/json),
+  where `nodes` array contains objects with `ipv4`, `ipv6`, `port`,
+  `public_key`, `tcp_ports` and `status_udp` fields. Nodes which
+  `status_udp` is `false` are skipped.
+- plain text format, where each line contains `IP port PK` separated by
+  whitespace. Empty lines and lines starting with `#` are ignored.
+
+Entries that have invalid IP addresses, ports or `PublicKey`s are skipped.
+Host names are not resolved, so entries that have them instead of IP
+addresses are skipped as well.
+
+Loaded nodes should be passed to [`DhtNode::bootstrap()`].
+
+[`DhtNode::bootstrap()`]: ../dht_node/struct.DhtNode.html#method.bootstrap
+*/
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+
+use rustc_serialize::hex::FromHex;
+use rustc_serialize::json::{Json, JsonEvent, Parser, ParserError};
+
+use toxcore::crypto_core::*;
+use toxcore::dht_new::packed_node::PackedNode;
+
+/// Error when loading a nodes list.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NodesListError {
+    /// Failed to read the file with nodes list.
+    Io(io::ErrorKind),
+    /// Nodes list in JSON format is malformed at given line and column.
+    Json(usize, usize),
+    /// Nodes list in JSON format is nested deeper than `MAX_JSON_DEPTH`.
+    JsonTooDeep,
+    /// Nodes list in JSON format doesn't have the `nodes` array.
+    NoNodes,
+}
+
+impl From<io::Error> for NodesListError {
+    fn from(err: io::Error) -> NodesListError {
+        NodesListError::Io(err.kind())
+    }
+}
+
+impl From<ParserError> for NodesListError {
+    fn from(err: ParserError) -> NodesListError {
+        match err {
+            ParserError::SyntaxError(_, line, col) => NodesListError::Json(line, col),
+            ParserError::IoError(err) => NodesListError::Io(err.kind()),
+        }
+    }
+}
+
+/// Node to bootstrap from.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BootstrapNode {
+    /// DHT `PublicKey` of the node.
+    pub pk: PublicKey,
+    /// UDP `IP:port`s of the node, at most one per IP family.
+    pub addrs: Vec<SocketAddr>,
+    /// TCP relay ports of the node.
+    pub tcp_ports: Vec<u16>,
+}
+
+impl BootstrapNode {
+    /// Get a [`PackedNode`](../dht_new/packed_node/struct.PackedNode.html) for every
+    /// UDP `IP:port` of the node.
+    pub fn packed_nodes(&self) -> Vec<PackedNode> {
+        self.addrs.iter()
+            .map(|&addr| PackedNode::new(true, addr, &self.pk))
+            .collect()
+    }
+
+    /// Create `BootstrapNode` from an entry of JSON nodes list.
+    fn from_json(node: &Json) -> Option<BootstrapNode> {
+        if node.find("status_udp").and_then(Json::as_boolean) == Some(false) {
+            debug!(target: "Bootstrap", "Skipping node that is offline: {:?}", node);
+            return None
+        }
+
+        let pk = match node.find("public_key").and_then(Json::as_string).and_then(pk_from_hex) {
+            Some(pk) => pk,
+            None => return None,
+        };
+        let port = match node.find("port").and_then(as_port) {
+            Some(port) => port,
+            _ => return None,
+        };
+
+        let ipv4 = node.find("ipv4").and_then(Json::as_string)
+            .and_then(|ip| ip.parse::<Ipv4Addr>().ok())
+            .map(IpAddr::V4);
+        let ipv6 = node.find("ipv6").and_then(Json::as_string)
+            .and_then(|ip| ip.parse::<Ipv6Addr>().ok())
+            .map(IpAddr::V6);
+        let addrs = ipv4.into_iter().chain(ipv6)
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect::<Vec<_>>();
+        if addrs.is_empty() {
+            return None
+        }
+
+        let tcp_ports = node.find("tcp_ports").and_then(Json::as_array)
+            .map(|ports| ports.iter()
+                .filter_map(as_port)
+                .collect::<Vec<_>>())
+            .unwrap_or_default();
+
+        Some(BootstrapNode {
+            pk: pk,
+            addrs: addrs,
+            tcp_ports: tcp_ports,
+        })
+    }
+
+    /// Create `BootstrapNode` from a line of plain text nodes list.
+    fn from_line(line: &str) -> Option<BootstrapNode> {
+        let parts = line.split_whitespace().collect::<Vec<_>>();
+        if parts.len() != 3 {
+            return None
+        }
+
+        let ip = match parts[0].trim_matches(|c| c == '[' || c == ']').parse::<IpAddr>() {
+            Ok(ip) => ip,
+            Err(_) => return None,
+        };
+        let port = match parts[1].parse::<u16>() {
+            Ok(port) if port != 0 => port,
+            _ => return None,
+        };
+        let pk = match pk_from_hex(parts[2]) {
+            Some(pk) => pk,
+            None => return None,
+        };
+
+        Some(BootstrapNode {
+            pk: pk,
+            addrs: vec![SocketAddr::new(ip, port)],
+            tcp_ports: Vec::new(),
+        })
+    }
+}
+
+/// Parse `PublicKey` from its hex representation.
+fn pk_from_hex(hex: &str) -> Option<PublicKey> {
+    hex.from_hex().ok()
+        .and_then(|bytes| PublicKey::from_slice(&bytes))
+}
+
+/// Get non-zero port from JSON number.
+fn as_port(json: &Json) -> Option<u16> {
+    match json.as_u64() {
+        Some(port) if port != 0 && port <= u64::from(u16::max_value()) => Some(port as u16),
+        _ => None,
+    }
+}
+
+/// Maximum nesting depth of JSON arrays and objects. Nodes lists need only
+/// a few levels, deeper input is rejected so it can't overflow the stack.
+const MAX_JSON_DEPTH: usize = 32;
+
+/// Parse JSON, rejecting input nested deeper than `MAX_JSON_DEPTH`.
+fn parse_json(input: &str) -> Result<Json, NodesListError> {
+    // building `Json` is recursive, so the depth is checked with the
+    // streaming parser first
+    let mut depth = 0;
+    for event in Parser::new(input.chars()) {
+        match event {
+            JsonEvent::ArrayStart | JsonEvent::ObjectStart => {
+                depth += 1;
+                if depth > MAX_JSON_DEPTH {
+                    return Err(NodesListError::JsonTooDeep)
+                }
+            },
+            JsonEvent::ArrayEnd | JsonEvent::ObjectEnd => depth -= 1,
+            JsonEvent::Error(err) => return Err(err.into()),
+            _ => {},
+        }
+    }
+    Ok(Json::from_str(input)?)
+}
+
+/**
+Parse nodes list in the JSON format.
+
+Returns an error if JSON is malformed or doesn't contain the `nodes`
+array. Invalid entries of the array are skipped.
+*/
+pub fn parse_nodes_json(input: &str) -> Result<Vec<BootstrapNode>, NodesListError> {
+    let json = parse_json(input)?;
+    let nodes = match json.find("nodes").and_then(Json::as_array) {
+        Some(nodes) => nodes,
+        None => return Err(NodesListError::NoNodes),
+    };
+    Ok(nodes.iter()
+        .filter_map(|node| {
+            let result = BootstrapNode::from_json(node);
+            if result.is_none() {
+                warn!(target: "Bootstrap", "Skipping invalid node: {:?}", node);
+            }
+            result
+        })
+        .collect())
+}
+
+/**
+Parse nodes list in the plain text format.
+
+Invalid lines are skipped.
+*/
+pub fn parse_nodes_text(input: &str) -> Vec<BootstrapNode> {
+    input.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let result = BootstrapNode::from_line(line);
+            if result.is_none() {
+                warn!(target: "Bootstrap", "Skipping invalid line: {}", line);
+            }
+            result
+        })
+        .collect()
+}
+
+/**
+Parse nodes list in any of supported formats.
+
+Input that starts with `{` is treated as JSON, otherwise as plain text.
+*/
+// `trim_start` is not available in Rust 1.21
+#[allow(deprecated)]
+pub fn parse_nodes(input: &str) -> Result<Vec<BootstrapNode>, NodesListError> {
+    if input.trim_left().starts_with('{') {
+        parse_nodes_json(input)
+    } else {
+        Ok(parse_nodes_text(input))
+    }
+}
+
+/// Load nodes list in any of supported formats from the file.
+pub fn load_nodes_file<P: AsRef<Path>>(path: P) -> Result<Vec<BootstrapNode>, NodesListError> {
+    let mut contents = String::new();
+    File::open(path)?.read_to_string(&mut contents)?;
+    parse_nodes(&contents)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PK_HEX: &str = "6FC41E2BD381D37E9748FC0E0328CE086AF9598BECC8FEB7DDF2E440475F300E";
+
+    fn pk() -> PublicKey {
+        pk_from_hex(PK_HEX).unwrap()
+    }
+
+    #[test]
+    fn pk_from_hex_test() {
+        let pk = pk();
+        assert_eq!(0x6F, pk.0[0]);
+        assert_eq!(0x0E, pk.0[PUBLICKEYBYTES - 1]);
+        assert_eq!(Some(pk), pk_from_hex(&PK_HEX.to_lowercase()));
+        assert_eq!(None, pk_from_hex(&PK_HEX[1..]));
+        assert_eq!(None, pk_from_hex(&PK_HEX.replace("6F", "6X")));
+    }
+
+    #[test]
+    fn parse_json_test() {
+        let json = parse_json(r#" { "a": [1, true, null], "b": "x\"A\n" } "#).unwrap();
+        assert_eq!(Some("x\"A\n"), json.find("b").and_then(Json::as_string));
+        assert_eq!(3, json.find("a").and_then(Json::as_array).unwrap().len());
+
+        assert_eq!(Err(NodesListError::Json(1, 1)), parse_json(""));
+        assert_eq!(Err(NodesListError::Json(1, 6)), parse_json("[1, 2"));
+        assert_eq!(Err(NodesListError::Json(1, 2)), parse_json("{a: 1}"));
+        assert!(parse_json("{} {}").is_err());
+    }
+
+    #[test]
+    fn parse_json_depth_test() {
+        let nested = |depth: usize| "[".repeat(depth) + &"]".repeat(depth);
+        assert!(parse_json(&nested(MAX_JSON_DEPTH)).is_ok());
+        assert_eq!(Err(NodesListError::JsonTooDeep), parse_json(&nested(MAX_JSON_DEPTH + 1)));
+        // deep nesting fails without overflowing the stack
+        let deep = "{\"a\":".repeat(100_000);
+        assert_eq!(Err(NodesListError::JsonTooDeep), parse_json(&deep));
+    }
+
+    #[test]
+    fn parse_nodes_json_test() {
+        let input = format!(r#"{{
+            "last_scan": 1516397428,
+            "nodes": [
+                {{
+                    "ipv4": "51.15.37.145",
+                    "ipv6": "2001:bc8:4400:2100::1c:50f",
+                    "port": 33445,
+                    "tcp_ports": [33445, 3389],
+                    "public_key": "{pk}",
+                    "maintainer": "Impyy",
+                    "status_udp": true,
+                    "status_tcp": true
+                }},
+                {{
+                    "ipv4": "1.2.3.4",
+                    "ipv6": "-",
+                    "port": 33445,
+                    "tcp_ports": [],
+                    "public_key": "{pk}",
+                    "status_udp": false
+                }},
+                {{
+                    "ipv4": "node.tox.example",
+                    "ipv6": "-",
+                    "port": 33445,
+                    "public_key": "{pk}"
+                }},
+                {{
+                    "ipv4": "5.6.7.8",
+                    "ipv6": "-",
+                    "port": 70000,
+                    "public_key": "{pk}"
+                }},
+                {{
+                    "ipv4": "5.6.7.8",
+                    "ipv6": "-",
+                    "port": 33445,
+                    "public_key": "invalid"
+                }},
+                {{
+                    "ipv4": "5.6.7.8",
+                    "ipv6": "-",
+                    "port": 443,
+                    "public_key": "{pk}"
+                }}
+            ]
+        }}"#, pk = PK_HEX);
+
+        let nodes = parse_nodes_json(&input).unwrap();
+        assert_eq!(vec![
+            BootstrapNode {
+                pk: pk(),
+                addrs: vec![
+                    "51.15.37.145:33445".parse().unwrap(),
+                    "[2001:bc8:4400:2100::1c:50f]:33445".parse().unwrap(),
+                ],
+                tcp_ports: vec![33445, 3389],
+            },
+            BootstrapNode {
+                pk: pk(),
+                addrs: vec!["5.6.7.8:443".parse().unwrap()],
+                tcp_ports: Vec::new(),
+            },
+        ], nodes);
+
+        assert_eq!(Err(NodesListError::NoNodes), parse_nodes_json("{}"));
+        assert_eq!(Err(NodesListError::NoNodes), parse_nodes_json(r#"{"nodes": 1}"#));
+    }
+
+    #[test]
+    fn parse_nodes_text_test() {
+        let input = format!("
+            # comment
+            51.15.37.145 33445 {pk}
+
+            2001:bc8:4400:2100::1c:50f 33445 {pk}
+            [::1] 1 {pk}
+            1.2.3.4 0 {pk}
+            1.2.3.4 33445
+            1.2.3.4 33445 {pk} extra
+            node.tox.example 33445 {pk}
+        ", pk = PK_HEX);
+
+        let nodes = parse_nodes_text(&input);
+        let addrs = nodes.iter().map(|node| node.addrs.clone()).collect::<Vec<_>>();
+        assert_eq!(vec![
+            vec!["51.15.37.145:33445".parse::<SocketAddr>().unwrap()],
+            vec!["[2001:bc8:4400:2100::1c:50f]:33445".parse::<SocketAddr>().unwrap()],
+            vec!["[::1]:1".parse::<SocketAddr>().unwrap()],
+        ], addrs);
+        assert!(nodes.iter().all(|node| node.pk == pk()));
+    }
+
+    #[test]
+    fn parse_nodes_test() {
+        let json = format!(r#"{{"nodes": [{{"ipv4": "1.2.3.4", "port": 1, "public_key": "{}"}}]}}"#, PK_HEX);
+        let text = format!("1.2.3.4 1 {}", PK_HEX);
+        assert_eq!(parse_nodes(&json), Ok(parse_nodes_text(&text)));
+        assert_eq!(1, parse_nodes(&text).unwrap().len());
+    }
+
+    #[test]
+    fn packed_nodes_test() {
+        let node = BootstrapNode {
+            pk: pk(),
+            addrs: vec!["1.2.3.4:1".parse().unwrap(), "[::1]:2".parse().unwrap()],
+            tcp_ports: Vec::new(),
+        };
+        assert_eq!(vec![
+            PackedNode::new(true, "1.2.3.4:1".parse().unwrap(), &pk()),
+            PackedNode::new(true, "[::1]:2".parse().unwrap(), &pk()),
+        ], node.packed_nodes());
+    }
+
+    #[test]
+    fn load_nodes_file_test() {
+        assert_eq!(Err(NodesListError::Io(io::ErrorKind::NotFound)),
+            load_nodes_file("/nonexistent/nodes.json"));
+    }
+}
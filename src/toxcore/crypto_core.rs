@@ -113,6 +113,19 @@ pub fn encrypt_precompute(their_public_key: &PublicKey,
 //pub use sodiumoxide::crypto::box_::precompute as encrypt_precompute;
 
 
+/** Returns a new random symmetric key.
+
+    The key is used to encrypt data that only its owner has to be able to
+    decrypt later, e.g. return paths of onion packets.
+*/
+#[inline]
+pub fn new_symmetric_key() -> PrecomputedKey {
+    let mut key = [0; PRECOMPUTEDKEYBYTES];
+    randombytes_into(&mut key);
+    PrecomputedKey(key)
+}
+
+
 /** Returns encrypted data from `plain`, with length of `plain + 16` due to
     padding.
 
@@ -50,6 +50,7 @@ use std::ops::Deref;
 
 use toxcore::binary_io::*;
 use toxcore::crypto_core::*;
+use toxcore::network::normalize_addr;
 use toxcore::packet_kind::PacketKind;
 
 
@@ -278,12 +279,16 @@ impl PackedNode {
     `udp` - whether UDP or TCP should be used. UDP is used for DHT nodes,
     whereas TCP is used for TCP relays. When `true`, UDP is used, otherwise
     TCP is used.
+
+    IPv4-mapped IPv6 address is converted to IPv4 address.
     */
     pub fn new(udp: bool, saddr: SocketAddr, pk: &PublicKey) -> Self {
         debug!(target: "PackedNode", "Creating new PackedNode.");
         trace!(target: "PackedNode", "With args: udp: {}, saddr: {:?}, PK: {:?}",
                udp, &saddr, pk);
 
+        let saddr = normalize_addr(saddr);
+
         let v4: bool = match saddr {
             SocketAddr::V4(_) => true,
             SocketAddr::V6(_) => false,
@@ -378,17 +383,14 @@ named_args!(as_ipv4_packed_node(iptype: IpType) <PackedNode>, do_parse!(
     })
 ));
 
-// Parse bytes as an IPv6 PackedNode.
+// Parse bytes as an IPv6 PackedNode. IPv4-mapped address is converted to
+// IPv4 address.
 named_args!(as_ipv6_packed_node(iptype: IpType) <PackedNode>, do_parse!(
     addr: call!(Ipv6Addr::parse_bytes) >>
     port: be_u16 >>
     saddr: value!(SocketAddrV6::new(addr, port, 0, 0)) >>
     pk: call!(PublicKey::parse_bytes) >>
-    (PackedNode {
-        ip_type: iptype,
-        saddr: SocketAddr::V6(saddr),
-        pk: pk
-    })
+    (PackedNode::new(iptype == IpType::U6, SocketAddr::V6(saddr), &pk))
 ));
 
 /** Deserialize bytes into `PackedNode`. Returns `None` if deseralizing
@@ -0,0 +1,331 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*!
+Search of friends in DHT.
+
+Each friend has its own close list with nodes that are closest to the
+friend's DHT `PublicKey`. Every [`FRIEND_GETN_INTERVAL`] seconds
+[`GetNodes`] requests for the friend's `PublicKey` are sent to the nodes
+from this list. When one of them returns a node with the friend's
+`PublicKey`, the friend's `IP:port` becomes known.
+
+When enough nodes reported the friend's `IP:port`, holes in NATs are
+punched to reach the friend directly, see
+[`hole_punching`](../hole_punching/index.html).
+
+https://zetok.github.io/tox-spec/#dht-operation
+
+[`FRIEND_GETN_INTERVAL`]: ./constant.FRIEND_GETN_INTERVAL.html
+[`GetNodes`]: ../dht_new/packet/struct.GetNodes.html
+*/
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use toxcore::crypto_core::*;
+use toxcore::dht_new::kbucket::*;
+use toxcore::dht_new::packed_node::PackedNode;
+use toxcore::dht_new::packet::{GetNodes, NatPingRequest, NatPingResponse, SendNodes};
+use toxcore::hole_punching::{HolePunching, MAX_FRIEND_CLIENTS};
+use toxcore::timeout::*;
+
+/// Interval in seconds between `GetNodes` requests for a friend.
+pub const FRIEND_GETN_INTERVAL: u64 = 20;
+
+/**
+Friend which `IP:port` is searched in DHT.
+
+It doesn't send anything itself, [`GetNodes`] requests created by
+[`requests()`](#method.requests) should be sent to the nodes and
+[`SendNodes`] responses should be passed to
+[`handle_send_nodes()`](#method.handle_send_nodes).
+
+[`GetNodes`]: ../dht_new/packet/struct.GetNodes.html
+[`SendNodes`]: ../dht_new/packet/struct.SendNodes.html
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DhtFriend {
+    /// DHT `PublicKey` of the friend
+    pk: PublicKey,
+    /// Close List of the friend (contains nodes close to the friend's PK)
+    close_nodes: Bucket,
+    /// timeouts of `GetNodes` requests sent for the friend
+    getn_timeout: TimeoutQueue,
+    /// time when `GetNodes` requests were sent last time
+    last_getn_time: Option<Instant>,
+    /// `IP:port`s of the friend reported by nodes from the Close List,
+    /// together with `PublicKey`s of reporting nodes
+    reports: VecDeque<(PublicKey, SocketAddr)>,
+    /// state of hole punching with the friend
+    hole_punch: HolePunching,
+}
+
+impl DhtFriend {
+    /// Create new `DhtFriend` with friend's DHT `PublicKey`.
+    pub fn new(pk: &PublicKey) -> DhtFriend {
+        DhtFriend {
+            pk: *pk,
+            close_nodes: Bucket::new(Some(MAX_FRIEND_CLIENTS as u8)),
+            getn_timeout: Default::default(),
+            last_getn_time: None,
+            reports: VecDeque::new(),
+            hole_punch: HolePunching::new(),
+        }
+    }
+
+    /// DHT `PublicKey` of the friend.
+    pub fn pk(&self) -> &PublicKey {
+        &self.pk
+    }
+
+    /// Close List of the friend.
+    pub fn close_nodes(&self) -> &Bucket {
+        &self.close_nodes
+    }
+
+    /**
+    The latest `IP:port` of the friend that was reported by a node from
+    the friend's Close List.
+
+    Returns `None` if the friend wasn't found yet.
+    */
+    pub fn addr(&self) -> Option<SocketAddr> {
+        self.reports.back().map(|&(_, addr)| addr)
+    }
+
+    /**
+    `IP:port`s of the friend that were reported by different nodes, can be
+    used for hole punching.
+    */
+    pub fn reported_addrs(&self) -> Vec<SocketAddr> {
+        self.reports.iter().map(|&(_, addr)| addr).collect()
+    }
+
+    /**
+    `IP:port` from which the friend responded to us directly, e.g. after
+    holes in NATs were punched.
+
+    Returns `None` if the friend didn't respond directly yet.
+    */
+    pub fn direct_addr(&self) -> Option<SocketAddr> {
+        self.hole_punch.direct_addr()
+    }
+
+    /**
+    Nodes from the friend's Close List that reported its `IP:port`. DHT
+    requests to the friend should be sent through them.
+    */
+    pub fn reporting_nodes(&self) -> Vec<PackedNode> {
+        self.close_nodes.iter()
+            .filter(|node| self.reports.iter().any(|&(ref pk, _)| pk == node.pk()))
+            .cloned()
+            .collect()
+    }
+
+    /**
+    Create [`NatPingRequest`] that should be sent to the friend through
+    [`reporting_nodes()`](#method.reporting_nodes) to start hole punching.
+
+    Returns `None` if hole punching isn't needed or possible now.
+
+    [`NatPingRequest`]: ../dht_new/packet/struct.NatPingRequest.html
+    */
+    pub fn nat_ping_request(&mut self) -> Option<NatPingRequest> {
+        let reports = self.reported_addrs();
+        self.hole_punch.nat_ping_request(&reports)
+    }
+
+    /**
+    Handle [`NatPingRequest`] received from the friend.
+
+    Returns [`NatPingResponse`] that should be sent back to the friend.
+
+    [`NatPingRequest`]: ../dht_new/packet/struct.NatPingRequest.html
+    [`NatPingResponse`]: ../dht_new/packet/struct.NatPingResponse.html
+    */
+    pub fn handle_nat_ping_request(&mut self, request: &NatPingRequest) -> NatPingResponse {
+        self.hole_punch.handle_nat_ping_request(request)
+    }
+
+    /**
+    Handle [`NatPingResponse`] received from the friend.
+
+    Returns `false` if it's not a response to our request.
+
+    [`NatPingResponse`]: ../dht_new/packet/struct.NatPingResponse.html
+    */
+    pub fn handle_nat_ping_response(&mut self, response: &NatPingResponse) -> bool {
+        self.hole_punch.handle_nat_ping_response(response)
+    }
+
+    /**
+    Get addresses of the friend that DHT ping requests should be sent to
+    in order to punch holes in NATs.
+    */
+    pub fn punching_addrs(&mut self) -> Vec<SocketAddr> {
+        let reports = self.reported_addrs();
+        self.hole_punch.punching_addrs(&reports)
+    }
+
+    /**
+    Handle response of the friend to DHT ping request received directly
+    from `addr`.
+    */
+    pub fn handle_ping_response(&mut self, addr: SocketAddr) {
+        self.hole_punch.handle_friend_response(addr)
+    }
+
+    /**
+    Try to add node to the friend's Close List. The friend itself is never
+    added.
+
+    Returns `true` if node was added.
+    */
+    pub fn try_add(&mut self, node: &PackedNode) -> bool {
+        *node.pk() != self.pk && self.close_nodes.try_add(&self.pk, node)
+    }
+
+    /**
+    Create [`GetNodes`] requests for the friend's `PublicKey` to nodes from
+    the friend's Close List. If the Close List is empty, requests are sent
+    to nodes from `kbucket` that are closest to the friend.
+
+    Requests are created only once in
+    [`FRIEND_GETN_INTERVAL`](./constant.FRIEND_GETN_INTERVAL.html)
+    seconds. Nodes that didn't respond to previous requests are removed
+    from the Close List.
+
+    [`GetNodes`]: ../dht_new/packet/struct.GetNodes.html
+    */
+    pub fn requests(&mut self, kbucket: &Kbucket) -> Vec<(PackedNode, GetNodes)> {
+        let interval = Duration::from_secs(FRIEND_GETN_INTERVAL);
+        if self.last_getn_time.map_or(false, |time| time.elapsed() < interval) {
+            return Vec::new()
+        }
+        self.last_getn_time = Some(Instant::now());
+
+        for pk in self.getn_timeout.get_timed_out(PING_TIMEOUT) {
+            debug!("Removing timed out node from friend's Close List");
+            self.close_nodes.remove(&self.pk, &pk);
+        }
+
+        let nodes = if self.close_nodes.is_empty() {
+            kbucket.get_closest(&self.pk)
+        } else {
+            self.close_nodes.iter().cloned().collect()
+        };
+        nodes.into_iter()
+            .map(|node| {
+                let request = GetNodes::new(&self.pk);
+                self.getn_timeout.add(node.pk(), request.id);
+                (node, request)
+            })
+            .collect()
+    }
+
+    /**
+    Handle [`SendNodes`] response from node with `sender_pk`. Received
+    nodes are added to the friend's Close List, and if one of them is the
+    friend, its `IP:port` is recorded.
+
+    Returns `false` if it's not a response to the request for this friend.
+
+    [`SendNodes`]: ../dht_new/packet/struct.SendNodes.html
+    */
+    pub fn handle_send_nodes(&mut self, sender_pk: &PublicKey, response: &SendNodes) -> bool {
+        if !self.getn_timeout.remove(response.id) {
+            return false
+        }
+        for node in &response.nodes {
+            if *node.pk() == self.pk {
+                debug!("Found friend {:?} at {}", self.pk, node.socket_addr());
+                self.reports.retain(|&(ref pk, _)| pk != sender_pk);
+                if self.reports.len() == MAX_FRIEND_CLIENTS {
+                    self.reports.pop_front();
+                }
+                self.reports.push_back((*sender_pk, node.socket_addr()));
+            } else {
+                self.try_add(node);
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(pk: &PublicKey, port: u16) -> PackedNode {
+        PackedNode::new(true, SocketAddr::new("1.2.3.4".parse().unwrap(), port), pk)
+    }
+
+    #[test]
+    fn dht_friend_try_add() {
+        let (pk, _) = gen_keypair();
+        let mut friend = DhtFriend::new(&pk);
+        assert!(!friend.try_add(&node(&pk, 1)));
+        assert!(friend.try_add(&node(&gen_keypair().0, 1)));
+        assert_eq!(1, friend.close_nodes().iter().count());
+    }
+
+    #[test]
+    fn dht_friend_requests_from_kbucket() {
+        let (pk, _) = gen_keypair();
+        let mut friend = DhtFriend::new(&pk);
+        let mut kbucket = Kbucket::new(KBUCKET_BUCKETS, &gen_keypair().0);
+        assert!(kbucket.try_add(&node(&gen_keypair().0, 1)));
+
+        let requests = friend.requests(&kbucket);
+        assert_eq!(1, requests.len());
+        assert_eq!(pk, requests[0].1.pk);
+        // next requests are sent only after the interval
+        assert!(friend.requests(&kbucket).is_empty());
+    }
+
+    #[test]
+    fn dht_friend_handle_send_nodes() {
+        let (pk, _) = gen_keypair();
+        let mut friend = DhtFriend::new(&pk);
+        let close_node = node(&gen_keypair().0, 1);
+        assert!(friend.try_add(&close_node));
+        let requests = friend.requests(&Kbucket::new(KBUCKET_BUCKETS, &gen_keypair().0));
+        assert_eq!(1, requests.len());
+        let (_, request) = requests[0];
+
+        let other_node = node(&gen_keypair().0, 2);
+        let response = SendNodes {
+            nodes: vec![node(&pk, 3), other_node],
+            id: request.id.wrapping_add(1),
+        };
+        assert!(!friend.handle_send_nodes(close_node.pk(), &response));
+        assert_eq!(None, friend.addr());
+
+        let response = SendNodes { id: request.id, .. response };
+        assert!(friend.handle_send_nodes(close_node.pk(), &response));
+        assert_eq!(Some(node(&pk, 3).socket_addr()), friend.addr());
+        assert_eq!(vec![node(&pk, 3).socket_addr()], friend.reported_addrs());
+        assert_eq!(vec![close_node], friend.reporting_nodes());
+        assert!(friend.close_nodes().contains(other_node.pk()));
+        // response can be handled only once
+        assert!(!friend.handle_send_nodes(close_node.pk(), &response));
+    }
+}
@@ -0,0 +1,426 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*!
+Iterative lookup of nodes that are closest to a given `PublicKey`.
+
+Lookup starts with nodes from own Close List. [`GetNodes`] requests are
+sent to [`LOOKUP_PARALLELISM`] closest nodes that weren't queried yet, and
+nodes from their [`SendNodes`] responses become new candidates. Lookup is
+finished when [`LOOKUP_RESULT_SIZE`] closest known nodes have responded,
+nodes that don't respond within [`LOOKUP_REQUEST_TIMEOUT`] seconds are
+skipped.
+
+https://zetok.github.io/tox-spec/#dht-operation
+
+[`GetNodes`]: ../dht_new/packet/struct.GetNodes.html
+[`LOOKUP_PARALLELISM`]: ./constant.LOOKUP_PARALLELISM.html
+[`LOOKUP_REQUEST_TIMEOUT`]: ./constant.LOOKUP_REQUEST_TIMEOUT.html
+[`LOOKUP_RESULT_SIZE`]: ./constant.LOOKUP_RESULT_SIZE.html
+[`SendNodes`]: ../dht_new/packet/struct.SendNodes.html
+*/
+
+use futures::{Future, Poll};
+use futures::sync::oneshot;
+
+use std::cmp::Ordering;
+use std::time::{Duration, Instant};
+
+use toxcore::crypto_core::*;
+use toxcore::dht_new::kbucket::{Distance, BUCKET_DEFAULT_SIZE};
+use toxcore::dht_new::packed_node::PackedNode;
+use toxcore::dht_new::packet::{GetNodes, SendNodes};
+use toxcore::timeout::PING_TIMEOUT;
+
+/// Maximum number of `GetNodes` requests of one lookup that wait for response.
+pub const LOOKUP_PARALLELISM: usize = 3;
+
+/// Number of closest nodes that lookup results in.
+pub const LOOKUP_RESULT_SIZE: usize = BUCKET_DEFAULT_SIZE;
+
+/// Number of seconds after which node that didn't respond is skipped.
+pub const LOOKUP_REQUEST_TIMEOUT: u64 = PING_TIMEOUT;
+
+/// Maximum number of candidates that are kept by lookup.
+const LOOKUP_MAX_CANDIDATES: usize = LOOKUP_RESULT_SIZE * 4;
+
+/// State of the node that takes part in lookup.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum CandidateState {
+    /// `GetNodes` request wasn't sent to the node yet
+    NotQueried,
+    /// `GetNodes` request with `id` was sent at `time`
+    Queried {
+        id: u64,
+        time: Instant,
+    },
+    /// Node responded to our request
+    Responded,
+    /// Node didn't respond in time
+    Failed,
+}
+
+/// Node that takes part in lookup.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct Candidate {
+    node: PackedNode,
+    state: CandidateState,
+}
+
+/**
+State of iterative lookup of nodes closest to `target` `PublicKey`.
+
+It doesn't send anything itself, [`GetNodes`] requests created by
+[`next_requests()`](#method.next_requests) should be sent to the nodes and
+[`SendNodes`] responses should be passed to
+[`handle_response()`](#method.handle_response).
+
+[`GetNodes`]: ../dht_new/packet/struct.GetNodes.html
+[`SendNodes`]: ../dht_new/packet/struct.SendNodes.html
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Lookup {
+    /// Own DHT `PublicKey` that shouldn't be added to candidates
+    own_pk: PublicKey,
+    /// `PublicKey` which closest nodes are looked for
+    target: PublicKey,
+    /// Candidates sorted by distance to `target`
+    candidates: Vec<Candidate>,
+}
+
+impl Lookup {
+    /// Create new `Lookup` of nodes closest to `target`.
+    pub fn new(own_pk: &PublicKey, target: &PublicKey) -> Lookup {
+        Lookup {
+            own_pk: *own_pk,
+            target: *target,
+            candidates: Vec::new(),
+        }
+    }
+
+    /// `PublicKey` which closest nodes are looked for.
+    pub fn target(&self) -> &PublicKey {
+        &self.target
+    }
+
+    /**
+    Add node to candidates of lookup.
+
+    Returns `false` if node is already known, is own node or is too far
+    from target compared to known candidates.
+    */
+    pub fn add_node(&mut self, node: &PackedNode) -> bool {
+        if *node.pk() == self.own_pk {
+            return false
+        }
+        let index = {
+            let target = &self.target;
+            match self.candidates.binary_search_by(|candidate|
+                cmp_distance(target, &candidate.node, node)
+            ) {
+                Ok(_) => return false,
+                Err(index) => index,
+            }
+        };
+        if index >= LOOKUP_MAX_CANDIDATES {
+            return false
+        }
+        self.candidates.insert(index, Candidate {
+            node: *node,
+            state: CandidateState::NotQueried,
+        });
+        // responses from removed nodes will be ignored
+        self.candidates.truncate(LOOKUP_MAX_CANDIDATES);
+        true
+    }
+
+    /**
+    Create [`GetNodes`] requests for the closest nodes that weren't queried
+    yet, so that at most [`LOOKUP_PARALLELISM`] requests wait for response.
+
+    Only nodes that may get in the result are queried.
+
+    [`GetNodes`]: ../dht_new/packet/struct.GetNodes.html
+    [`LOOKUP_PARALLELISM`]: ./constant.LOOKUP_PARALLELISM.html
+    */
+    pub fn next_requests(&mut self) -> Vec<(PackedNode, GetNodes)> {
+        let mut in_flight = self.candidates.iter()
+            .filter(|candidate| match candidate.state {
+                CandidateState::Queried { .. } => true,
+                _ => false,
+            })
+            .count();
+        let mut requests = Vec::new();
+        let target = self.target;
+        for candidate in self.candidates.iter_mut()
+            .filter(|candidate| candidate.state != CandidateState::Failed)
+            .take(LOOKUP_RESULT_SIZE)
+        {
+            if in_flight >= LOOKUP_PARALLELISM {
+                break
+            }
+            if candidate.state == CandidateState::NotQueried {
+                let request = GetNodes::new(&target);
+                candidate.state = CandidateState::Queried {
+                    id: request.id,
+                    time: Instant::now(),
+                };
+                requests.push((candidate.node, request));
+                in_flight += 1;
+            }
+        }
+        requests
+    }
+
+    /**
+    Check whether `SendNodes` with `id` from node with `pk` is a response
+    to the request of this lookup.
+    */
+    pub fn is_response(&self, pk: &PublicKey, id: u64) -> bool {
+        self.candidates.iter().any(|candidate|
+            candidate.node.pk() == pk && match candidate.state {
+                CandidateState::Queried { id: request_id, .. } => request_id == id,
+                _ => false,
+            }
+        )
+    }
+
+    /**
+    Handle [`SendNodes`] response from node with `pk`. Received nodes are
+    added to candidates.
+
+    Returns `false` if it's not a response to the request of this lookup.
+
+    [`SendNodes`]: ../dht_new/packet/struct.SendNodes.html
+    */
+    pub fn handle_response(&mut self, pk: &PublicKey, response: &SendNodes) -> bool {
+        if !self.is_response(pk, response.id) {
+            return false
+        }
+        if let Some(candidate) = self.candidates.iter_mut()
+            .find(|candidate| candidate.node.pk() == pk) {
+            candidate.state = CandidateState::Responded;
+        }
+        for node in &response.nodes {
+            self.add_node(node);
+        }
+        true
+    }
+
+    /**
+    Skip nodes that didn't respond within
+    [`LOOKUP_REQUEST_TIMEOUT`](./constant.LOOKUP_REQUEST_TIMEOUT.html)
+    seconds.
+    */
+    pub fn check_timeouts(&mut self) {
+        self.check_timeouts_at(Instant::now())
+    }
+
+    /// Skip nodes that didn't respond within `LOOKUP_REQUEST_TIMEOUT` seconds
+    /// before `now`.
+    fn check_timeouts_at(&mut self, now: Instant) {
+        let timeout = Duration::from_secs(LOOKUP_REQUEST_TIMEOUT);
+        for candidate in &mut self.candidates {
+            if let CandidateState::Queried { time, .. } = candidate.state {
+                if now.duration_since(time) >= timeout {
+                    trace!("Lookup request to {:?} timed out", candidate.node.pk());
+                    candidate.state = CandidateState::Failed;
+                }
+            }
+        }
+    }
+
+    /**
+    Check whether lookup is finished, i.e. all closest nodes that may get
+    in the result responded.
+    */
+    pub fn is_finished(&self) -> bool {
+        self.candidates.iter()
+            .filter(|candidate| candidate.state != CandidateState::Failed)
+            .take(LOOKUP_RESULT_SIZE)
+            .all(|candidate| candidate.state == CandidateState::Responded)
+    }
+
+    /**
+    Up to [`LOOKUP_RESULT_SIZE`](./constant.LOOKUP_RESULT_SIZE.html) nodes
+    closest to target that responded, sorted by distance to target.
+    */
+    pub fn result(&self) -> Vec<PackedNode> {
+        self.candidates.iter()
+            .filter(|candidate| candidate.state == CandidateState::Responded)
+            .take(LOOKUP_RESULT_SIZE)
+            .map(|candidate| candidate.node)
+            .collect()
+    }
+}
+
+/**
+Future that resolves to the nodes closest to the target of lookup once
+lookup is finished.
+
+Fails if `DhtNode` that performs lookup is dropped before lookup is
+finished.
+*/
+#[derive(Debug)]
+pub struct LookupFuture {
+    rx: oneshot::Receiver<Vec<PackedNode>>,
+}
+
+impl LookupFuture {
+    /**
+    Create new `LookupFuture` and `Sender` that should be used to complete
+    it with the result of lookup.
+    */
+    pub fn new() -> (oneshot::Sender<Vec<PackedNode>>, LookupFuture) {
+        let (tx, rx) = oneshot::channel();
+        (tx, LookupFuture { rx: rx })
+    }
+}
+
+impl Future for LookupFuture {
+    type Item = Vec<PackedNode>;
+    type Error = oneshot::Canceled;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.rx.poll()
+    }
+}
+
+/// Compare distance of nodes to `target`.
+fn cmp_distance(target: &PublicKey, node1: &PackedNode, node2: &PackedNode) -> Ordering {
+    target.distance(node1.pk(), node2.pk())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::SocketAddr;
+
+    fn node(pk_byte: u8, port: u16) -> PackedNode {
+        let addr: SocketAddr = format!("1.2.3.4:{}", port).parse().unwrap();
+        PackedNode::new(true, addr, &PublicKey([pk_byte; PUBLICKEYBYTES]))
+    }
+
+    fn lookup() -> Lookup {
+        Lookup::new(&PublicKey([0xff; PUBLICKEYBYTES]), &PublicKey([0; PUBLICKEYBYTES]))
+    }
+
+    #[test]
+    fn lookup_add_node_sorted() {
+        let mut lookup = lookup();
+        assert!(lookup.add_node(&node(3, 3)));
+        assert!(lookup.add_node(&node(1, 1)));
+        assert!(lookup.add_node(&node(2, 2)));
+        assert!(!lookup.add_node(&node(2, 2)));
+        // own node is never added
+        assert!(!lookup.add_node(&node(0xff, 4)));
+        let pks = lookup.candidates.iter().map(|c| *c.node.pk()).collect::<Vec<_>>();
+        assert_eq!(vec![PublicKey([1; PUBLICKEYBYTES]), PublicKey([2; PUBLICKEYBYTES]),
+            PublicKey([3; PUBLICKEYBYTES])], pks);
+    }
+
+    #[test]
+    fn lookup_add_node_max_candidates() {
+        let mut lookup = lookup();
+        for i in 0 .. LOOKUP_MAX_CANDIDATES as u8 {
+            assert!(lookup.add_node(&node(i + 2, 1)));
+        }
+        assert!(!lookup.add_node(&node(200, 1)));
+        assert!(lookup.add_node(&node(1, 1)));
+        assert_eq!(LOOKUP_MAX_CANDIDATES, lookup.candidates.len());
+    }
+
+    #[test]
+    fn lookup_next_requests_parallelism() {
+        let mut lookup = lookup();
+        for i in 1 .. 10 {
+            lookup.add_node(&node(i, 1));
+        }
+        let requests = lookup.next_requests();
+        assert_eq!(LOOKUP_PARALLELISM, requests.len());
+        for (i, &(ref node, ref request)) in requests.iter().enumerate() {
+            assert_eq!(PublicKey([i as u8 + 1; PUBLICKEYBYTES]), *node.pk());
+            assert_eq!(PublicKey([0; PUBLICKEYBYTES]), request.pk);
+        }
+        assert!(lookup.next_requests().is_empty());
+        assert!(!lookup.is_finished());
+    }
+
+    #[test]
+    fn lookup_converges() {
+        let mut lookup = lookup();
+        lookup.add_node(&node(100, 1));
+        let requests = lookup.next_requests();
+        assert_eq!(1, requests.len());
+        let (ref queried, ref request) = requests[0];
+
+        // unknown id is ignored
+        let wrong = SendNodes { nodes: vec![node(1, 1)], id: request.id.wrapping_add(1) };
+        assert!(!lookup.handle_response(queried.pk(), &wrong));
+
+        let response = SendNodes { nodes: vec![node(1, 1), node(2, 2)], id: request.id };
+        assert!(lookup.handle_response(queried.pk(), &response));
+        // duplicate response is ignored
+        assert!(!lookup.handle_response(queried.pk(), &response));
+        assert!(!lookup.is_finished());
+
+        let requests = lookup.next_requests();
+        assert_eq!(2, requests.len());
+        for &(ref queried, ref request) in &requests {
+            let response = SendNodes { nodes: vec![node(100, 1)], id: request.id };
+            assert!(lookup.handle_response(queried.pk(), &response));
+        }
+        assert!(lookup.is_finished());
+        assert!(lookup.next_requests().is_empty());
+        assert_eq!(vec![node(1, 1), node(2, 2), node(100, 1)], lookup.result());
+    }
+
+    #[test]
+    fn lookup_skips_timed_out() {
+        let mut lookup = lookup();
+        lookup.add_node(&node(1, 1));
+        lookup.add_node(&node(2, 2));
+        assert_eq!(2, lookup.next_requests().len());
+        lookup.check_timeouts();
+        assert!(!lookup.is_finished());
+
+        // check timeouts as if a long time passed since the requests
+        let now = Instant::now() + Duration::from_secs(LOOKUP_REQUEST_TIMEOUT + 1);
+        lookup.check_timeouts_at(now);
+        assert!(lookup.is_finished());
+        assert!(lookup.result().is_empty());
+    }
+
+    #[test]
+    fn lookup_empty_is_finished() {
+        assert!(lookup().is_finished());
+    }
+
+    #[test]
+    fn lookup_future_resolves() {
+        let (tx, future) = LookupFuture::new();
+        tx.send(vec![node(1, 1)]).unwrap();
+        assert_eq!(vec![node(1, 1)], future.wait().unwrap());
+
+        let (tx, future) = LookupFuture::new();
+        drop(tx);
+        assert!(future.wait().is_err());
+    }
+}
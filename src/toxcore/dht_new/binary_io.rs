@@ -24,6 +24,8 @@
 
 // FIXME: merge with toxcore/binary_io.rs
 
+use sodiumoxide::crypto::hash::{sha256, sha512};
+
 use toxcore::crypto_core::*;
 pub use nom::IResult;
 pub use cookie_factory::GenError;
@@ -47,3 +49,11 @@ impl FromBytes for PublicKey {
 impl FromBytes for Nonce {
     named!(from_bytes<Nonce>, map_opt!(take!(NONCEBYTES), Nonce::from_slice));
 }
+
+impl FromBytes for sha256::Digest {
+    named!(from_bytes<sha256::Digest>, map_opt!(take!(sha256::DIGESTBYTES), sha256::Digest::from_slice));
+}
+
+impl FromBytes for sha512::Digest {
+    named!(from_bytes<sha512::Digest>, map_opt!(take!(sha512::DIGESTBYTES), sha512::Digest::from_slice));
+}
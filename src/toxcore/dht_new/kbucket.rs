@@ -41,7 +41,9 @@ PK; and additionally used to store nodes closest to friends.
 */
 use toxcore::crypto_core::*;
 use toxcore::dht_new::packed_node::PackedNode;
+use toxcore::timeout::{NodeState, BAD_TIME, RESPONSE_CHECK, UNRESPONSIVE_TIME};
 use std::cmp::{Ord, Ordering};
+use std::time::{Duration, Instant};
 
 /** Calculate the [`k-bucket`](./struct.Kbucket.html) index of a PK compared
 to "own" PK.
@@ -90,6 +92,60 @@ impl Distance for PublicKey {
     }
 }
 
+/**
+[`PackedNode`](./struct.PackedNode.html) stored in a
+[`Bucket`](./struct.Bucket.html) together with the time of the last
+response received from it and the last ping sent to it.
+
+Node's [`NodeState`] is derived from the time of the last response.
+
+[`NodeState`]: ../../timeout/enum.NodeState.html
+*/
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct BucketNode {
+    /// The node itself.
+    node: PackedNode,
+    /// When the last response from the node was received.
+    last_resp_time: Instant,
+    /// When the last ping was sent to the node.
+    last_ping_time: Instant,
+}
+
+impl BucketNode {
+    /// Create a new `BucketNode` that has responded at `now`.
+    fn new(node: &PackedNode, now: Instant) -> Self {
+        BucketNode {
+            node: *node,
+            last_resp_time: now,
+            last_ping_time: now,
+        }
+    }
+
+    /// Get the PK of the node.
+    fn pk(&self) -> &PublicKey {
+        self.node.pk()
+    }
+
+    /// Get the [`NodeState`](../../timeout/enum.NodeState.html) at `now`
+    /// according to the time elapsed since the last response.
+    fn state(&self, now: Instant) -> NodeState {
+        let elapsed = now.duration_since(self.last_resp_time);
+        if elapsed > Duration::from_secs(UNRESPONSIVE_TIME) {
+            NodeState::Unresponsive
+        } else if elapsed > Duration::from_secs(BAD_TIME) {
+            NodeState::Bad
+        } else {
+            NodeState::Good
+        }
+    }
+
+    /// Check whether the node wasn't pinged for at least
+    /// [`RESPONSE_CHECK`](../../timeout/constant.RESPONSE_CHECK.html) at `now`.
+    fn is_ping_due(&self, now: Instant) -> bool {
+        now.duration_since(self.last_ping_time) >= Duration::from_secs(RESPONSE_CHECK)
+    }
+}
+
 /**
 Structure for holding nodes.
 
@@ -98,7 +154,8 @@ is supplied), number of nodes defaults to [`BUCKET_DEFAULT_SIZE`]
 (./constant.BUCKET_DEFAULT_SIZE.html).
 
 Nodes stored in `Bucket` are in [`PackedNode`](./struct.PackedNode.html)
-format.
+format, along with the time of the last response and the last ping, which
+determine their [`NodeState`](../../timeout/enum.NodeState.html).
 
 Used in [`Kbucket`](./struct.Kbucket.html) for storing nodes close to given
 PK; and additionally used to store nodes closest to friends.
@@ -110,7 +167,7 @@ pub struct Bucket {
     /// Amount of nodes it can hold.
     capacity: u8,
     /// Nodes that bucket has, sorted by distance to PK.
-    nodes: Vec<PackedNode>
+    nodes: Vec<BucketNode>
 }
 
 /// Default number of nodes that bucket can hold.
@@ -158,14 +215,18 @@ impl Bucket {
     /**
     Try to add [`PackedNode`] to the bucket.
 
+    Adding a node counts as a response received from it.
+
     - If the [`PackedNode`] with given `PublicKey` is already in the `Bucket`,
       the [`PackedNode`] is updated (since its `SocketAddr` can differ).
     - If bucket is not full, node is appended.
-    - If bucket is full, node's closeness is compared to nodes already
-      in bucket, and if it's closer than some node, it prepends that
-      node, and last node is removed from the list.
-    - If the node being added is farther away than the nodes in the bucket,
-      it isn't added and `false` is returned.
+    - If bucket is full and has `Bad` or `Unresponsive` nodes, the farthest
+      of them is removed to make space for the new node.
+    - If bucket is full of `Good` nodes, node's closeness is compared to
+      nodes already in bucket, and if it's closer than some node, it
+      prepends that node, and last node is removed from the list.
+    - If the node being added is farther away than the `Good` nodes in the
+      bucket, it isn't added and `false` is returned.
 
     Note that you must pass the same `base_pk` each call or the internal
     state will be undefined.
@@ -176,17 +237,32 @@ impl Bucket {
     */
     pub fn try_add(&mut self, base_pk: &PublicKey, new_node: &PackedNode)
         -> bool
+    {
+        self.try_add_at(base_pk, new_node, Instant::now())
+    }
+
+    /// Same as [`try_add`](#method.try_add) but with the time of the response
+    /// passed explicitly.
+    fn try_add_at(&mut self, base_pk: &PublicKey, new_node: &PackedNode,
+        now: Instant) -> bool
     {
         debug!(target: "Bucket", "Trying to add PackedNode.");
         trace!(target: "Bucket", "With bucket: {:?}; PK: {:?} and new node: {:?}",
             self, base_pk, new_node);
 
+        if self.is_full() && !self.contains(new_node.pk()) &&
+            self.remove_not_good(now) {
+            debug!(target: "Bucket",
+                "Not responding node removed to make space for a new node.");
+        }
+
         match self.nodes.binary_search_by(|n| base_pk.distance(n.pk(), new_node.pk())) {
             Ok(index) => {
                 debug!(target: "Bucket",
                     "Updated: the node was already in the bucket.");
-                self.nodes.remove(index);
-                self.nodes.insert(index, *new_node);
+                let node = &mut self.nodes[index];
+                node.node = *new_node;
+                node.last_resp_time = now;
                 true
             },
             Err(index) if index == self.nodes.len() => {
@@ -200,7 +276,7 @@ impl Bucket {
                     // there's still free space in the bucket for a node
                     debug!(target: "Bucket",
                         "Node inserted at the end of the bucket.");
-                    self.nodes.push(*new_node);
+                    self.nodes.push(BucketNode::new(new_node, now));
                     true
                 }
             },
@@ -212,7 +288,7 @@ impl Bucket {
                     self.nodes.pop();
                 }
                 debug!(target: "Bucket", "Node inserted inside the bucket.");
-                self.nodes.insert(index, *new_node);
+                self.nodes.insert(index, BucketNode::new(new_node, now));
                 true
             },
         }
@@ -239,11 +315,102 @@ impl Bucket {
         }
     }
 
+    /** Remove the farthest `Bad` or `Unresponsive` node from the `Bucket`.
+
+    `Unresponsive` nodes are removed before `Bad` ones.
+
+    Returns `true` if a node was removed, `false` if all nodes are `Good`.
+    */
+    fn remove_not_good(&mut self, now: Instant) -> bool {
+        let index = self.nodes.iter()
+            .rposition(|n| n.state(now) == NodeState::Unresponsive)
+            .or_else(|| self.nodes.iter()
+                .rposition(|n| n.state(now) == NodeState::Bad));
+        match index {
+            Some(index) => {
+                trace!(target: "Bucket", "Removing not good node: {:?}",
+                    self.nodes[index]);
+                self.nodes.remove(index);
+                true
+            },
+            None => false,
+        }
+    }
+
     /// Check if node with given PK is in the `Bucket`.
     pub fn contains(&self, pk: &PublicKey) -> bool {
         self.nodes.iter().any(|n| n.pk() == pk)
     }
 
+    /** Get the [`NodeState`] of the node with given PK.
+
+    Returns `None` if there's no such node in the `Bucket`.
+
+    [`NodeState`]: ../../timeout/enum.NodeState.html
+    */
+    pub fn node_state(&self, pk: &PublicKey) -> Option<NodeState> {
+        self.node_state_at(pk, Instant::now())
+    }
+
+    /// Get the `NodeState` of the node with given PK at `now`.
+    fn node_state_at(&self, pk: &PublicKey, now: Instant) -> Option<NodeState> {
+        self.nodes.iter()
+            .find(|n| n.pk() == pk)
+            .map(|n| n.state(now))
+    }
+
+    /** Record that a response was received from the node with given PK.
+
+    Returns `false` if there's no such node in the `Bucket`.
+    */
+    pub fn record_response(&mut self, pk: &PublicKey) -> bool {
+        self.record_response_at(pk, Instant::now())
+    }
+
+    /// Record that a response was received from the node with given PK at
+    /// `now`.
+    fn record_response_at(&mut self, pk: &PublicKey, now: Instant) -> bool {
+        match self.nodes.iter_mut().find(|n| n.pk() == pk) {
+            Some(node) => {
+                node.last_resp_time = now;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /** Get nodes that should be pinged to check whether they are still
+    online, and mark them as pinged.
+
+    A node is pinged once per [`RESPONSE_CHECK`] seconds while it is `Good`
+    or `Bad`. `Unresponsive` nodes are pinged only if `all_unresponsive` is
+    `true`, i.e. when there's no other way to reconnect to the network.
+
+    [`RESPONSE_CHECK`]: ../../timeout/constant.RESPONSE_CHECK.html
+    */
+    pub fn nodes_to_ping(&mut self, all_unresponsive: bool) -> Vec<PackedNode> {
+        self.nodes_to_ping_at(all_unresponsive, Instant::now())
+    }
+
+    /// Get nodes that should be pinged at `now` and mark them as pinged.
+    fn nodes_to_ping_at(&mut self, all_unresponsive: bool, now: Instant)
+        -> Vec<PackedNode>
+    {
+        self.nodes.iter_mut()
+            .filter(|n| n.is_ping_due(now) &&
+                (all_unresponsive || n.state(now) != NodeState::Unresponsive))
+            .map(|n| {
+                n.last_ping_time = now;
+                n.node
+            })
+            .collect()
+    }
+
+    /// Check if all nodes in the `Bucket` are `Unresponsive` at `now`.
+    fn is_all_unresponsive(&self, now: Instant) -> bool {
+        self.nodes.iter().all(|n| n.state(now) == NodeState::Unresponsive)
+    }
+
     /// Get the capacity of the Bucket.
     pub fn capacity(&self) -> usize {
         self.capacity as usize
@@ -266,13 +433,32 @@ impl Bucket {
     pub fn is_full(&self) -> bool {
         self.nodes.len() == self.capacity()
     }
+
+    /// Returns an iterator over contained [`PackedNode`]
+    /// (../packed_node/struct.PackedNode.html)s.
+    pub fn iter(&self) -> BucketIter {
+        BucketIter { iter: self.nodes.iter() }
+    }
+}
+
+/// Iterator over `Bucket`.
+pub struct BucketIter<'a> {
+    iter: ::std::slice::Iter<'a, BucketNode>,
+}
+
+impl<'a> Iterator for BucketIter<'a> {
+    type Item = &'a PackedNode;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next().map(|node| &node.node)
+    }
 }
 
 /**
 Equivalent to calling [`Bucket::new()`] with `None`:
 
 ```
-# use tox::toxcore::dht::Bucket;
+# use tox::toxcore::dht_new::kbucket::Bucket;
 assert_eq!(Bucket::new(None), Bucket::default());
 ```
 
@@ -418,11 +604,11 @@ impl Kbucket {
         let mut bucket = Bucket::new(Some(4));
         for buc in &*self.buckets {
             for node in &*buc.nodes {
-                bucket.try_add(pk, node);
+                bucket.try_add(pk, &node.node);
             }
         }
         trace!("Returning nodes: {:?}", &bucket.nodes);
-        bucket.nodes
+        bucket.nodes.into_iter().map(|n| n.node).collect()
     }
 
     /**
@@ -456,6 +642,53 @@ impl Kbucket {
         }
     }
 
+    /** Get the [`NodeState`] of the node with given PK.
+
+    Returns `None` if there's no such node in the `Kbucket`.
+
+    [`NodeState`]: ../../timeout/enum.NodeState.html
+    */
+    pub fn node_state(&self, pk: &PublicKey) -> Option<NodeState> {
+        match self.bucket_index(pk) {
+            Some(i) => self.buckets[i].node_state(pk),
+            None => None,
+        }
+    }
+
+    /** Record that a response was received from the node with given PK,
+    so that it stays `Good`.
+
+    Returns `false` if there's no such node in the `Kbucket`.
+    */
+    pub fn record_response(&mut self, pk: &PublicKey) -> bool {
+        match self.bucket_index(pk) {
+            Some(i) => self.buckets[i].record_response(pk),
+            None => false,
+        }
+    }
+
+    /** Get nodes that should be pinged to check whether they are still
+    online, and mark them as pinged.
+
+    Should be called periodically. `Unresponsive` nodes are pinged only
+    when all nodes in the `Kbucket` are `Unresponsive`, hoping that the
+    network will become available again.
+    */
+    pub fn nodes_to_ping(&mut self) -> Vec<PackedNode> {
+        self.nodes_to_ping_at(Instant::now())
+    }
+
+    /// Get nodes that should be pinged at `now` and mark them as pinged.
+    fn nodes_to_ping_at(&mut self, now: Instant) -> Vec<PackedNode> {
+        let all_unresponsive = self.buckets.iter()
+            .all(|bucket| bucket.is_all_unresponsive(now));
+        let mut nodes = Vec::new();
+        for bucket in &mut self.buckets {
+            nodes.extend(bucket.nodes_to_ping_at(all_unresponsive, now));
+        }
+        nodes
+    }
+
     /** Check if `Kbucket` is empty.
 
     Returns `true` if all `buckets` are empty, `false`
@@ -491,7 +724,7 @@ impl<'a> Iterator for KbucketIter<'a> {
             match self.buckets[self.pos_b].nodes.iter().nth(self.pos_pn) {
                 Some(s) => {
                     self.pos_pn += 1;
-                    Some(s)
+                    Some(&s.node)
                 },
                 None => {
                     self.pos_b += 1;
@@ -701,6 +934,133 @@ mod test {
         quickcheck(with_pns as fn(Vec<PackedNode>, u64, u64, u64, u64) -> TestResult);
     }
 
+    /// Get a `PackedNode` with PK made of given byte.
+    fn node_with_pk_byte(byte: u8) -> PackedNode {
+        let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 33445));
+        PackedNode::new(true, addr, &PublicKey([byte; PUBLICKEYBYTES]))
+    }
+
+    // Bucket::try_add() with not responding nodes
+
+    #[test]
+    fn dht_bucket_try_add_replaces_bad_node_test() {
+        let base_pk = PublicKey([0; PUBLICKEYBYTES]);
+        let close = node_with_pk_byte(1);
+        let far = node_with_pk_byte(2);
+        let now = Instant::now();
+
+        let mut bucket = Bucket::new(Some(1));
+        assert!(bucket.try_add_at(&base_pk, &close, now));
+        // farther node can't replace a good one
+        assert!(!bucket.try_add_at(&base_pk, &far, now));
+
+        let now = now + Duration::from_secs(BAD_TIME + 1);
+        assert!(bucket.try_add_at(&base_pk, &far, now));
+        assert!(bucket.contains(far.pk()));
+        assert!(!bucket.contains(close.pk()));
+    }
+
+    #[test]
+    fn dht_bucket_try_add_prefers_unresponsive_for_removal_test() {
+        let base_pk = PublicKey([0; PUBLICKEYBYTES]);
+        let bad = node_with_pk_byte(1);
+        let unresponsive = node_with_pk_byte(2);
+        let new = node_with_pk_byte(3);
+        let now = Instant::now();
+
+        let mut bucket = Bucket::new(Some(2));
+        assert!(bucket.try_add_at(&base_pk, &unresponsive, now));
+        let now = now + Duration::from_secs(UNRESPONSIVE_TIME - BAD_TIME);
+        assert!(bucket.try_add_at(&base_pk, &bad, now));
+
+        let now = now + Duration::from_secs(BAD_TIME + 1);
+        assert_eq!(Some(NodeState::Bad), bucket.node_state_at(bad.pk(), now));
+        assert_eq!(Some(NodeState::Unresponsive),
+            bucket.node_state_at(unresponsive.pk(), now));
+        assert!(bucket.try_add_at(&base_pk, &new, now));
+        assert!(bucket.contains(bad.pk()));
+        assert!(!bucket.contains(unresponsive.pk()));
+        assert!(bucket.contains(new.pk()));
+    }
+
+    #[test]
+    fn dht_bucket_try_add_updates_resp_time_test() {
+        let base_pk = PublicKey([0; PUBLICKEYBYTES]);
+        let node = node_with_pk_byte(1);
+        let now = Instant::now();
+
+        let mut bucket = Bucket::new(None);
+        assert!(bucket.try_add_at(&base_pk, &node, now));
+        let now = now + Duration::from_secs(BAD_TIME + 1);
+        assert_eq!(Some(NodeState::Bad), bucket.node_state_at(node.pk(), now));
+
+        assert!(bucket.try_add_at(&base_pk, &node, now));
+        assert_eq!(Some(NodeState::Good), bucket.node_state_at(node.pk(), now));
+    }
+
+    // Bucket::node_state()
+
+    #[test]
+    fn dht_bucket_node_state_test() {
+        let base_pk = PublicKey([0; PUBLICKEYBYTES]);
+        let node = node_with_pk_byte(1);
+        let now = Instant::now();
+
+        let mut bucket = Bucket::new(None);
+        assert_eq!(None, bucket.node_state(node.pk()));
+
+        assert!(bucket.try_add_at(&base_pk, &node, now));
+        assert_eq!(Some(NodeState::Good), bucket.node_state_at(node.pk(), now));
+
+        let bad_time = now + Duration::from_secs(BAD_TIME + 1);
+        assert_eq!(Some(NodeState::Bad), bucket.node_state_at(node.pk(), bad_time));
+
+        let unresponsive_time = now + Duration::from_secs(UNRESPONSIVE_TIME + 1);
+        assert_eq!(Some(NodeState::Unresponsive),
+            bucket.node_state_at(node.pk(), unresponsive_time));
+    }
+
+    // Bucket::record_response()
+
+    #[test]
+    fn dht_bucket_record_response_test() {
+        let base_pk = PublicKey([0; PUBLICKEYBYTES]);
+        let node = node_with_pk_byte(1);
+        let now = Instant::now();
+
+        let mut bucket = Bucket::new(None);
+        assert!(!bucket.record_response_at(node.pk(), now));
+
+        assert!(bucket.try_add_at(&base_pk, &node, now));
+        let now = now + Duration::from_secs(BAD_TIME + 1);
+        assert_eq!(Some(NodeState::Bad), bucket.node_state_at(node.pk(), now));
+
+        assert!(bucket.record_response_at(node.pk(), now));
+        assert_eq!(Some(NodeState::Good), bucket.node_state_at(node.pk(), now));
+    }
+
+    // Bucket::nodes_to_ping()
+
+    #[test]
+    fn dht_bucket_nodes_to_ping_test() {
+        let base_pk = PublicKey([0; PUBLICKEYBYTES]);
+        let node = node_with_pk_byte(1);
+        let now = Instant::now();
+
+        let mut bucket = Bucket::new(None);
+        assert!(bucket.try_add_at(&base_pk, &node, now));
+        // was just added
+        assert!(bucket.nodes_to_ping_at(false, now).is_empty());
+
+        let now = now + Duration::from_secs(RESPONSE_CHECK);
+        assert_eq!(vec![node], bucket.nodes_to_ping_at(false, now));
+        // was just pinged
+        assert!(bucket.nodes_to_ping_at(false, now).is_empty());
+
+        let now = now + Duration::from_secs(UNRESPONSIVE_TIME);
+        assert!(bucket.nodes_to_ping_at(false, now).is_empty());
+        assert_eq!(vec![node], bucket.nodes_to_ping_at(true, now));
+    }
 
     // Kbucket::
 
@@ -973,6 +1333,55 @@ mod test {
         }
     }
 
+    // Kbucket::record_response()
+
+    #[test]
+    fn kbucket_record_response_test() {
+        let pk = PublicKey([0; PUBLICKEYBYTES]);
+        let node = node_with_pk_byte(1);
+
+        let mut kbucket = Kbucket::new(KBUCKET_BUCKETS, &pk);
+        assert!(!kbucket.record_response(node.pk()));
+        assert!(kbucket.try_add(&node));
+        assert!(kbucket.record_response(node.pk()));
+        assert_eq!(Some(NodeState::Good), kbucket.node_state(node.pk()));
+    }
+
+    // Kbucket::nodes_to_ping()
+
+    #[test]
+    fn kbucket_nodes_to_ping_test() {
+        let pk = PublicKey([0; PUBLICKEYBYTES]);
+        let good = node_with_pk_byte(1);
+        let unresponsive = node_with_pk_byte(2);
+        let now = Instant::now();
+
+        let mut kbucket = Kbucket::new(KBUCKET_BUCKETS, &pk);
+        assert!(kbucket.try_add(&good));
+        assert!(kbucket.try_add(&unresponsive));
+        for &(b, n) in &[kbucket.find(good.pk()).unwrap(),
+                         kbucket.find(unresponsive.pk()).unwrap()] {
+            kbucket.buckets[b].nodes[n].last_resp_time = now;
+            kbucket.buckets[b].nodes[n].last_ping_time = now;
+        }
+        assert!(kbucket.nodes_to_ping_at(now).is_empty());
+
+        // good node keeps responding
+        let now = now + Duration::from_secs(UNRESPONSIVE_TIME + 1);
+        let (b, _) = kbucket.find(good.pk()).unwrap();
+        assert!(kbucket.buckets[b].record_response_at(good.pk(), now));
+
+        // unresponsive node isn't pinged while there are other nodes
+        assert_eq!(vec![good], kbucket.nodes_to_ping_at(now));
+
+        // all nodes are unresponsive now, so they are all pinged
+        let now = now + Duration::from_secs(UNRESPONSIVE_TIME + 1);
+        let to_ping = kbucket.nodes_to_ping_at(now);
+        assert_eq!(2, to_ping.len());
+        assert!(to_ping.contains(&good));
+        assert!(to_ping.contains(&unresponsive));
+    }
+
    // Kbucket::can_add()
 
     quickcheck! {
@@ -1026,7 +1435,7 @@ mod test {
             let mut expect = Vec::new();
             for bucket in &kbucket.buckets {
                 for node in bucket.nodes.iter() {
-                    expect.push(*node);
+                    expect.push(node.node);
                 }
             }
 
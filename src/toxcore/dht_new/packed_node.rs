@@ -35,6 +35,7 @@ use std::net::{
 
 use toxcore::dht_new::binary_io::*;
 use toxcore::crypto_core::*;
+use toxcore::network::normalize_addr;
 
 /** `PackedNode` format is a way to store the node info in a small yet easy to
 parse format.
@@ -100,7 +101,7 @@ impl FromBytes for PackedNode {
             10 => map!(Ipv6Addr::from_bytes, IpAddr::V6)
         ) >>
         port: be_u16 >>
-        saddr: value!(SocketAddr::new(addr, port)) >>
+        saddr: value!(normalize_addr(SocketAddr::new(addr, port))) >>
         pk: call!(PublicKey::from_bytes) >>
         (PackedNode { saddr: saddr, pk: pk })
     ));
@@ -112,6 +113,8 @@ impl PackedNode {
     `udp` - whether UDP or TCP should be used. UDP is used for DHT nodes,
     whereas TCP is used for TCP relays. When `true`, UDP is used, otherwise
     TCP is used.
+
+    IPv4-mapped IPv6 address is converted to IPv4 address.
     */
     pub fn new(udp: bool, saddr: SocketAddr, pk: &PublicKey) -> Self {
         debug!(target: "PackedNode", "Creating new PackedNode.");
@@ -119,7 +122,7 @@ impl PackedNode {
             udp, &saddr, pk);
 
         PackedNode {
-            saddr: saddr,
+            saddr: normalize_addr(saddr),
             pk: *pk,
         }
     }
@@ -190,4 +193,40 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn packed_node_new_ipv4_mapped_test() {
+        let pk = PublicKey([0; PUBLICKEYBYTES]);
+        let node = PackedNode::new(true, "[::ffff:1.2.3.4]:5".parse().unwrap(), &pk);
+        assert_eq!("1.2.3.4:5".parse::<SocketAddr>().unwrap(), node.socket_addr());
+        assert_eq!(2, node.ip_type());
+    }
+
+    #[test]
+    fn packed_node_from_bytes_ipv4_mapped_test() {
+        let pk = PublicKey([1; PUBLICKEYBYTES]);
+        let mut bytes = vec![10];
+        bytes.extend_from_slice(&Ipv4Addr::new(1, 2, 3, 4).to_ipv6_mapped().octets());
+        bytes.extend_from_slice(&[0, 5]);
+        bytes.extend_from_slice(&pk.0);
+
+        let (_, node) = PackedNode::from_bytes(&bytes).unwrap();
+        assert_eq!("1.2.3.4:5".parse::<SocketAddr>().unwrap(), node.socket_addr());
+        assert_eq!(pk, node.pk);
+    }
+
+    #[test]
+    fn packed_node_ipv6_bytes_test() {
+        let pk = PublicKey([1; PUBLICKEYBYTES]);
+        let node = PackedNode::new(true, "[2001:db8::1]:33445".parse().unwrap(), &pk);
+        let mut bytes = vec![10,
+            0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01,
+            0x82, 0xa5];
+        bytes.extend_from_slice(&pk.0);
+
+        let mut buf = [0; 51];
+        let (_, size) = node.to_bytes((&mut buf, 0)).unwrap();
+        assert_eq!(&bytes[..], &buf[..size]);
+        assert_eq!(node, PackedNode::from_bytes(&bytes).unwrap().1);
+    }
 }
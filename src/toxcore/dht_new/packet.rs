@@ -26,7 +26,7 @@
     ------------------------+--------------------------------------------
     PingRequest             +    ----------------------
     PingResponse            +    | this 4 first class packet is also
-    GetNodes                + <--| grouped as DhtPacket
+    GetNodes                + <--| grouped as DhtPacketPayload
     SendNodes               +    ----------------------
     ------------------------+--------------------------------------------
     DhtRequest              +    NatPingRequest and NatPingResponse
@@ -37,8 +37,9 @@
     * takes care of the serializing and de-serializing DHT packets
 */
 
-use nom::{le_u8, le_u16, be_u64};
+use nom::{le_u8, be_u16, be_u64, rest};
 
+use std::fmt::Debug;
 use std::net::{
     IpAddr,
     Ipv4Addr,
@@ -47,19 +48,20 @@ use std::net::{
 
 use toxcore::dht_new::binary_io::*;
 use toxcore::crypto_core::*;
+use toxcore::dht_new::kbucket::Kbucket;
 use toxcore::dht_new::packed_node::PackedNode;
+use toxcore::packet_kind::PacketKind;
 
 /// Length in bytes of [`PingRequest`](./struct.PingRequest.html) and
 /// [`PingResponse`](./struct.PingResponse.html) when serialized into bytes.
 pub const PING_SIZE: usize = 9;
 
-/** Standard DHT packet that encapsulates in the payload
-[`DhtPacketT`](./trait.DhtPacketT.html).
+/** Decrypted payload of [`DhtPacket`](./struct.DhtPacket.html).
 
 https://zetok.github.io/tox-spec/#dht-packet
 */
 #[derive(Clone, Debug, Eq, PartialEq)]
-pub enum DhtPacket {
+pub enum DhtPacketPayload {
     /// [`PingRequest`](./struct.PingRequest.html) structure.
     PingRequest(PingRequest),
     /// [`PingResponse`](./struct.PingResponse.html) structure.
@@ -70,26 +72,190 @@ pub enum DhtPacket {
     SendNodes(SendNodes),
 }
 
-impl ToBytes for DhtPacket {
+impl ToBytes for DhtPacketPayload {
     fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
         match *self {
-            DhtPacket::PingRequest(ref p) => p.to_bytes(buf),
-            DhtPacket::PingResponse(ref p) => p.to_bytes(buf),
-            DhtPacket::GetNodes(ref p) => p.to_bytes(buf),
-            DhtPacket::SendNodes(ref p) => p.to_bytes(buf),
+            DhtPacketPayload::PingRequest(ref p) => p.to_bytes(buf),
+            DhtPacketPayload::PingResponse(ref p) => p.to_bytes(buf),
+            DhtPacketPayload::GetNodes(ref p) => p.to_bytes(buf),
+            DhtPacketPayload::SendNodes(ref p) => p.to_bytes(buf),
+        }
+    }
+}
+
+impl FromBytes for DhtPacketPayload {
+    named!(from_bytes<DhtPacketPayload>, alt!(
+        map!(PingRequest::from_bytes, DhtPacketPayload::PingRequest) |
+        map!(PingResponse::from_bytes, DhtPacketPayload::PingResponse) |
+        map!(GetNodes::from_bytes, DhtPacketPayload::GetNodes) |
+        map!(SendNodes::from_bytes, DhtPacketPayload::SendNodes)
+    ));
+}
+
+/// Trait for types of DHT packets that can be put in [`DhtPacket`]
+/// (./struct.DhtPacket.html).
+pub trait DhtPacketT: ToBytes + FromBytes + Debug {
+    /// Provide packet type number.
+    ///
+    /// To use for serialization: `.kind() as u8`.
+    fn kind(&self) -> PacketKind;
+}
+
+/** Standard DHT packet that encapsulates in the encrypted payload
+[`DhtPacketT`](./trait.DhtPacketT.html).
+
+Length      | Contents
+----------- | --------
+`1`         | `uint8_t` [`PacketKind`](../../packet_kind/enum.PacketKind.html)
+`32`        | Sender DHT Public Key
+`24`        | Random nonce
+variable    | Encrypted payload
+
+`PacketKind` values for `DhtPacket` can be only `<= 4`.
+
+https://zetok.github.io/tox-spec/#dht-packet
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DhtPacket {
+    packet_kind: PacketKind,
+    /// Public key of sender.
+    pub sender_pk: PublicKey,
+    nonce: Nonce,
+    payload: Vec<u8>,
+}
+
+impl DhtPacket {
+    /// Create new `DhtPacket` with `dp` encrypted by `symmetric_key`.
+    pub fn new<P>(symmetric_key: &PrecomputedKey,
+               own_public_key: &PublicKey,
+               nonce: &Nonce,
+               dp: &P) -> Self
+        where P: DhtPacketT
+    {
+        debug!(target: "DhtPacket", "Creating new DhtPacket.");
+        trace!(target: "DhtPacket", "With args: symmetric_key: <secret>,
+        own_public_key: {:?}, nonce: {:?}, packet: {:?}",
+        own_public_key, nonce, &dp);
+
+        let mut buf = [0; 512];
+        // DHT payloads are never bigger than `SendNodes` with 4 IPv6 nodes
+        let (_, size) = dp.to_bytes((&mut buf, 0))
+            .expect("DHT packet payload should fit in the buffer");
+        let payload = seal_precomputed(&buf[..size], nonce, symmetric_key);
+
+        DhtPacket {
+            packet_kind: dp.kind(),
+            sender_pk: *own_public_key,
+            nonce: *nonce,
+            payload: payload,
+        }
+    }
+
+    /** Get [`PacketKind`](../../packet_kind/enum.PacketKind.html) that
+    `DhtPacket`'s payload is supposed to contain.
+    */
+    pub fn kind(&self) -> PacketKind {
+        self.packet_kind
+    }
+
+    /**
+    Decrypt payload and try to parse it as packet type.
+
+    To get info about it's packet type use
+    [`.kind()`](./struct.DhtPacket.html#method.kind) method.
+
+    Returns `None` in case of faliure:
+
+    - fails to decrypt
+    - fails to parse as given packet type
+    */
+    pub fn get_payload<P>(&self, own_secret_key: &SecretKey) -> Option<P>
+        where P: DhtPacketT
+    {
+        debug!(target: "DhtPacket", "Getting packet data from DhtPacket.");
+        trace!(target: "DhtPacket", "With DhtPacket: {:?}", self);
+        match open(&self.payload, &self.nonce, &self.sender_pk, own_secret_key) {
+            Ok(decrypted) => self.parse_payload(&decrypted),
+            Err(_) => {
+                debug!("Decrypting DhtPacket failed!");
+                None
+            },
+        }
+    }
+
+    /**
+    Decrypt payload with precomputed `symmetric_key` and try to parse it
+    as packet type.
+
+    Same as [`.get_payload()`](./struct.DhtPacket.html#method.get_payload)
+    but doesn't compute the shared key.
+    */
+    pub fn get_payload_precomputed<P>(&self, symmetric_key: &PrecomputedKey) -> Option<P>
+        where P: DhtPacketT
+    {
+        debug!(target: "DhtPacket", "Getting packet data from DhtPacket.");
+        trace!(target: "DhtPacket", "With DhtPacket: {:?}", self);
+        match decrypt_data_symmetric(symmetric_key, &self.nonce, &self.payload) {
+            Ok(decrypted) => self.parse_payload(&decrypted),
+            Err(_) => {
+                debug!("Decrypting DhtPacket failed!");
+                None
+            },
+        }
+    }
+
+    /// Parse decrypted payload as packet type, checking that it matches
+    /// the kind of `DhtPacket`.
+    fn parse_payload<P>(&self, decrypted: &[u8]) -> Option<P>
+        where P: DhtPacketT
+    {
+        trace!("Decrypted bytes: {:?}", decrypted);
+        match P::from_bytes(decrypted) {
+            IResult::Done(_, payload) => if payload.kind() == self.packet_kind {
+                Some(payload)
+            } else {
+                debug!("DhtPacket kind doesn't match its payload");
+                None
+            },
+            _ => {
+                debug!("Can't parse DhtPacket payload");
+                None
+            },
         }
     }
 }
 
 impl FromBytes for DhtPacket {
-    named!(from_bytes<DhtPacket>, alt!(
-        map!(PingRequest::from_bytes, DhtPacket::PingRequest) |
-        map!(PingResponse::from_bytes, DhtPacket::PingResponse) |
-        map!(GetNodes::from_bytes, DhtPacket::GetNodes) |
-        map!(SendNodes::from_bytes, DhtPacket::SendNodes)
+    named!(from_bytes<DhtPacket>, do_parse!(
+        packet_kind: switch!(le_u8,
+            0 => value!(PacketKind::PingReq) |
+            1 => value!(PacketKind::PingResp) |
+            2 => value!(PacketKind::GetN) |
+            4 => value!(PacketKind::SendN)
+        ) >>
+        sender_pk: call!(PublicKey::from_bytes) >>
+        nonce: call!(Nonce::from_bytes) >>
+        payload: rest >>
+        (DhtPacket {
+            packet_kind: packet_kind,
+            sender_pk: sender_pk,
+            nonce: nonce,
+            payload: payload.to_vec(),
+        })
     ));
 }
 
+impl ToBytes for DhtPacket {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(self.packet_kind as u8) >>
+            gen_slice!(self.sender_pk.as_ref()) >>
+            gen_slice!(self.nonce.as_ref()) >>
+            gen_slice!(self.payload.as_slice())
+        )
+    }
+}
+
 /**
 Used to request/respond to ping. Used in an encrypted form.
 
@@ -140,6 +306,25 @@ impl ToBytes for PingRequest {
     }
 }
 
+impl PingRequest {
+    /// Create new ping request with a randomly generated `request id`.
+    pub fn new() -> Self {
+        trace!("Creating new Ping.");
+        PingRequest { id: random_u64() }
+    }
+
+    /// An ID of the request / response.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl DhtPacketT for PingRequest {
+    fn kind(&self) -> PacketKind {
+        PacketKind::PingReq
+    }
+}
+
 /**
 Used to request/respond to ping. Used in an encrypted form.
 
@@ -190,6 +375,25 @@ impl ToBytes for PingResponse {
     }
 }
 
+impl PingResponse {
+    /// An ID of the request / response.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl From<PingRequest> for PingResponse {
+    fn from(p: PingRequest) -> Self {
+        PingResponse { id: p.id }
+    }
+}
+
+impl DhtPacketT for PingResponse {
+    fn kind(&self) -> PacketKind {
+        PacketKind::PingResp
+    }
+}
+
 // Ip Type
 // Value | Type
 // ----- | ----
@@ -229,7 +433,7 @@ impl ToBytes for Ipv4Addr {
 
 // TODO: move it somewhere else
 impl FromBytes for Ipv6Addr {
-    named!(from_bytes<Ipv6Addr>, map!(count!(le_u16, 8), 
+    named!(from_bytes<Ipv6Addr>, map!(count!(be_u16, 8), 
         |v| Ipv6Addr::new(v[0], v[1], v[2], v[3], v[4], v[5], v[6], v[7])
     ));
 }
@@ -238,14 +442,14 @@ impl ToBytes for Ipv6Addr {
     fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
         let s = self.segments();
         do_gen!(buf,
-            gen_le_u16!(s[0]) >>
-            gen_le_u16!(s[1]) >>
-            gen_le_u16!(s[2]) >>
-            gen_le_u16!(s[3]) >>
-            gen_le_u16!(s[4]) >>
-            gen_le_u16!(s[5]) >>
-            gen_le_u16!(s[6]) >>
-            gen_le_u16!(s[7]) 
+            gen_be_u16!(s[0]) >>
+            gen_be_u16!(s[1]) >>
+            gen_be_u16!(s[2]) >>
+            gen_be_u16!(s[3]) >>
+            gen_be_u16!(s[4]) >>
+            gen_be_u16!(s[5]) >>
+            gen_be_u16!(s[6]) >>
+            gen_be_u16!(s[7]) 
         )
     }
 }
@@ -287,6 +491,30 @@ impl FromBytes for GetNodes {
     ));
 }
 
+impl GetNodes {
+    /// Create new `GetNodes` with given PK.
+    pub fn new(their_public_key: &PublicKey) -> Self {
+        trace!(target: "GetNodes", "Creating new GetNodes request.");
+        GetNodes { pk: *their_public_key, id: random_u64() }
+    }
+
+    /**
+    Create response to `self` request with nodes provided from the `Kbucket`.
+
+    Fails (returns `None`) if `Kbucket` is empty.
+    */
+    pub fn response(&self, kbucket: &Kbucket) -> Option<SendNodes> {
+        let nodes = kbucket.get_closest(&self.pk);
+        SendNodes::with_nodes(self, nodes)
+    }
+}
+
+impl DhtPacketT for GetNodes {
+    fn kind(&self) -> PacketKind {
+        PacketKind::GetN
+    }
+}
+
 /** Response to [`GetNodes`](./struct.GetNodes.html) request, containing up to
 `4` nodes closest to the requested node.
 
@@ -340,12 +568,147 @@ impl FromBytes for SendNodes {
     ));
 }
 
-/** DHT Request packet.
+impl SendNodes {
+    /**
+    Create new `SendNodes`. Returns `None` if 0 or more than 4 nodes are
+    supplied.
+
+    Created as a response to `GetNodes` request.
+    */
+    pub fn with_nodes(request: &GetNodes, nodes: Vec<PackedNode>) -> Option<Self> {
+        debug!(target: "SendNodes", "Creating SendNodes from GetNodes.");
+        trace!(target: "SendNodes", "With GetNodes: {:?}", request);
+        trace!("With nodes: {:?}", &nodes);
+
+        if nodes.is_empty() || nodes.len() > 4 {
+            warn!(target: "SendNodes", "Wrong number of nodes supplied!");
+            return None
+        }
+
+        Some(SendNodes { nodes: nodes, id: request.id })
+    }
+}
+
+impl DhtPacketT for SendNodes {
+    fn kind(&self) -> PacketKind {
+        PacketKind::SendN
+    }
+}
+
+/** DHT Request packet. Used to send packets to a friend through nodes that
+are close to it: nodes that have the receiver in their Close List forward
+the packet to it unchanged.
+
+Length      | Contents
+----------- | --------
+`1`         | `0x20`
+`32`        | Receiver DHT Public Key
+`32`        | Sender DHT Public Key
+`24`        | Random nonce
+variable    | Encrypted payload
 
 https://zetok.github.io/tox-spec/#dht-request-packets
 */
 #[derive(Clone, Debug, Eq, PartialEq)]
-pub enum DhtRequest {
+pub struct DhtRequest {
+    /// Public key of receiver.
+    pub rpk: PublicKey,
+    /// Public key of sender.
+    pub spk: PublicKey,
+    nonce: Nonce,
+    payload: Vec<u8>,
+}
+
+impl DhtRequest {
+    /// Create new `DhtRequest` to `rpk` with `dp` encrypted by
+    /// `symmetric_key`.
+    pub fn new(symmetric_key: &PrecomputedKey,
+               rpk: &PublicKey,
+               spk: &PublicKey,
+               nonce: &Nonce,
+               dp: &DhtRequestPayload) -> Self
+    {
+        debug!(target: "DhtRequest", "Creating new DhtRequest.");
+        trace!(target: "DhtRequest", "With args: symmetric_key: <secret>,
+        rpk: {:?}, spk: {:?}, nonce: {:?}, payload: {:?}",
+        rpk, spk, nonce, &dp);
+
+        let mut buf = [0; 512];
+        let (_, size) = dp.to_bytes((&mut buf, 0))
+            .expect("DHT request payload should fit in the buffer");
+        let payload = seal_precomputed(&buf[..size], nonce, symmetric_key);
+
+        DhtRequest {
+            rpk: *rpk,
+            spk: *spk,
+            nonce: *nonce,
+            payload: payload,
+        }
+    }
+
+    /**
+    Decrypt payload with precomputed `symmetric_key` and try to parse it
+    as [`DhtRequestPayload`](./enum.DhtRequestPayload.html).
+
+    Returns `None` in case of faliure:
+
+    - fails to decrypt
+    - fails to parse
+    */
+    pub fn get_payload(&self, symmetric_key: &PrecomputedKey) -> Option<DhtRequestPayload> {
+        debug!(target: "DhtRequest", "Getting packet data from DhtRequest.");
+        trace!(target: "DhtRequest", "With DhtRequest: {:?}", self);
+        let decrypted = match decrypt_data_symmetric(symmetric_key, &self.nonce, &self.payload) {
+            Ok(decrypted) => decrypted,
+            Err(_) => {
+                debug!("Decrypting DhtRequest failed!");
+                return None
+            },
+        };
+        match DhtRequestPayload::from_bytes(&decrypted) {
+            IResult::Done(_, payload) => Some(payload),
+            _ => {
+                debug!("Can't parse DhtRequest payload");
+                None
+            },
+        }
+    }
+}
+
+impl FromBytes for DhtRequest {
+    named!(from_bytes<DhtRequest>, do_parse!(
+        tag!(&[0x20][..]) >>
+        rpk: call!(PublicKey::from_bytes) >>
+        spk: call!(PublicKey::from_bytes) >>
+        nonce: call!(Nonce::from_bytes) >>
+        payload: rest >>
+        (DhtRequest {
+            rpk: rpk,
+            spk: spk,
+            nonce: nonce,
+            payload: payload.to_vec(),
+        })
+    ));
+}
+
+impl ToBytes for DhtRequest {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(0x20) >>
+            gen_slice!(self.rpk.as_ref()) >>
+            gen_slice!(self.spk.as_ref()) >>
+            gen_slice!(self.nonce.as_ref()) >>
+            gen_slice!(self.payload.as_slice())
+        )
+    }
+}
+
+/** Decrypted payload of [`DhtRequest`](./struct.DhtRequest.html).
+
+https://zetok.github.io/tox-spec/#dht-request-packets
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DhtRequestPayload {
     /// [`NatPingRequest`](./struct.NatPingRequest.html) structure.
     NatPingRequest(NatPingRequest),
     /// [`NatPingResponse`](./struct.NatPingResponse.html) structure.
@@ -368,19 +731,19 @@ pub struct NatPingResponse {
     pub id: u64,
 }
 
-impl ToBytes for DhtRequest {
+impl ToBytes for DhtRequestPayload {
     fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
         match *self {
-            DhtRequest::NatPingRequest(ref p) => p.to_bytes(buf),
-            DhtRequest::NatPingResponse(ref p) => p.to_bytes(buf),
+            DhtRequestPayload::NatPingRequest(ref p) => p.to_bytes(buf),
+            DhtRequestPayload::NatPingResponse(ref p) => p.to_bytes(buf),
         }
     }
 }
 
-impl FromBytes for DhtRequest {
-    named!(from_bytes<DhtRequest>, alt!(
-        map!(NatPingRequest::from_bytes, DhtRequest::NatPingRequest) |
-        map!(NatPingResponse::from_bytes, DhtRequest::NatPingResponse)
+impl FromBytes for DhtRequestPayload {
+    named!(from_bytes<DhtRequestPayload>, alt!(
+        map!(NatPingRequest::from_bytes, DhtRequestPayload::NatPingRequest) |
+        map!(NatPingResponse::from_bytes, DhtRequestPayload::NatPingResponse)
     ));
 }
 
@@ -422,10 +785,72 @@ impl ToBytes for NatPingResponse {
     }
 }
 
+impl NatPingRequest {
+    /// Create new ping request with a randomly generated `request id`.
+    pub fn new() -> Self {
+        trace!("Creating new Ping.");
+        NatPingRequest { id: random_u64() }
+    }
+
+    /// An ID of the request / response.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl NatPingResponse {
+    /// An ID of the request / response.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl From<NatPingRequest> for NatPingResponse {
+    fn from(p: NatPingRequest) -> Self {
+        NatPingResponse { id: p.id }
+    }
+}
+
+/** LAN discovery packet. It's broadcasted periodically to the local network
+so that nodes on the same LAN could find each other without bootstrap
+nodes.
+
+https://zetok.github.io/tox-spec/#lan-discovery
+
+Serialized form:
+
+Length | Content
+------ | ------
+`1`    | `0x21`
+`32`   | DHT Public Key of the sender
+*/
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct LanDiscovery {
+    /// DHT `PublicKey` of the sender
+    pub pk: PublicKey,
+}
+
+impl FromBytes for LanDiscovery {
+    named!(from_bytes<LanDiscovery>, do_parse!(
+        tag!(&[0x21][..]) >>
+        pk: call!(PublicKey::from_bytes) >>
+        (LanDiscovery { pk: pk })
+    ));
+}
+
+impl ToBytes for LanDiscovery {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(0x21) >>
+            gen_slice!(self.pk.as_ref())
+        )
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
-    use std::fmt::Debug;
+    use toxcore::dht_new::kbucket::KBUCKET_BUCKETS;
     use byteorder::{ByteOrder, BigEndian, WriteBytesExt};
 
     use quickcheck::{Arbitrary, Gen, quickcheck};
@@ -452,32 +877,6 @@ mod test {
         }
     }
 
-    impl PingRequest {
-        /// Create new ping request with a randomly generated `request id`.
-        pub fn new() -> Self {
-            trace!("Creating new Ping.");
-            PingRequest { id: random_u64() }
-        }
-
-        /// An ID of the request / response.
-        pub fn id(&self) -> u64 {
-            self.id
-        }
-    }
-
-    impl PingResponse {
-        /// An ID of the request / response.
-        pub fn id(&self) -> u64 {
-            self.id
-        }
-    }
-
-    impl From<PingRequest> for PingResponse {
-        fn from(p: PingRequest) -> Self {
-            PingResponse { id: p.id }
-        }
-    }
-
     // PingRequest::
     impl Arbitrary for NatPingRequest {
         fn arbitrary<G: Gen>(_g: &mut G) -> Self {
@@ -492,42 +891,6 @@ mod test {
         }
     }
 
-    impl NatPingRequest {
-        /// Create new ping request with a randomly generated `request id`.
-        pub fn new() -> Self {
-            trace!("Creating new Ping.");
-            NatPingRequest { id: random_u64() }
-        }
-
-        /// An ID of the request / response.
-        pub fn id(&self) -> u64 {
-            self.id
-        }
-    }
-
-    impl NatPingResponse {
-        /// An ID of the request / response.
-        pub fn id(&self) -> u64 {
-            self.id
-        }
-    }
-
-    impl From<NatPingRequest> for NatPingResponse {
-        fn from(p: NatPingRequest) -> Self {
-            NatPingResponse { id: p.id }
-        }
-    }
-
-    /// Trait for types of DHT packets that can be put in [`DhtPacket`]
-    /// (./struct.DhtPacket.html).
-    pub trait DhtPacketT: ToBytes + FromBytes + Eq + PartialEq + Debug {
-        /// Provide packet type number.
-        ///
-        /// To use for serialization: `.kind() as u8`.
-        fn kind(&self) -> PacketKind;
-
-    }
-
     macro_rules! tests_for_pings {
         ($($p:ident $b_t:ident $f_t:ident)+) => ($(
 
@@ -582,11 +945,11 @@ mod test {
         }
     }
 
-    impl Arbitrary for DhtPacket {
+    impl Arbitrary for DhtPacketPayload {
         fn arbitrary<G: Gen>(g: &mut G) -> Self {
             let mut a: [u8; PUBLICKEYBYTES] = [0; PUBLICKEYBYTES];
             g.fill_bytes(&mut a);
-            DhtPacket::GetNodes(GetNodes { pk: PublicKey(a), id: g.gen() })
+            DhtPacketPayload::GetNodes(GetNodes { pk: PublicKey(a), id: g.gen() })
         }
     }
 
@@ -624,30 +987,30 @@ mod test {
         quickcheck(with_bytes as fn(Vec<u8>));
     }
 
-    // DhtPacket::GetNodes::to_bytes()
+    // DhtPacketPayload::GetNodes::to_bytes()
     #[test]
     fn dht_packet_get_nodes_to_bytes_test() {
-        fn with_gn(gn: DhtPacket) {
+        fn with_gn(gn: DhtPacketPayload) {
             let mut _buf = [0;1024];
             let g_bytes = gn.to_bytes((&mut _buf, 0)).ok().unwrap().0;
-            if let DhtPacket::GetNodes(gp) = gn {
+            if let DhtPacketPayload::GetNodes(gp) = gn {
                 let PublicKey(pk_bytes) = gp.pk;
                 assert_eq!(&pk_bytes, &g_bytes[..PUBLICKEYBYTES]);
                 assert_eq!(gp.id, BigEndian::read_u64(&g_bytes[PUBLICKEYBYTES..]));
             }
         }
-        quickcheck(with_gn as fn(DhtPacket));
+        quickcheck(with_gn as fn(DhtPacketPayload));
     }
 
-    // DhtPacket::GetNodes::from_bytes()
+    // DhtPacketPayload::GetNodes::from_bytes()
     #[test]
     fn dht_packet_get_nodes_from_bytes_test() {
         fn with_bytes(bytes: Vec<u8>) {
             if bytes.len() < GET_NODES_SIZE {
                 assert!(!GetNodes::from_bytes(&bytes).is_done());
             } else {
-                let gn = DhtPacket::from_bytes(&bytes).unwrap().1;
-                if let DhtPacket::GetNodes(gp) = gn {
+                let gn = DhtPacketPayload::from_bytes(&bytes).unwrap().1;
+                if let DhtPacketPayload::GetNodes(gp) = gn {
                     // ping_id as bytes should match "original" bytes
                     assert_eq!(BigEndian::read_u64(&bytes[PUBLICKEYBYTES..GET_NODES_SIZE]), gp.id);
 
@@ -668,27 +1031,6 @@ mod test {
         }
     }
 
-    impl SendNodes {
-        /**
-        Create new `SendNodes`. Returns `None` if 0 or more than 4 nodes are
-        supplied.
-
-        Created as a response to `GetNodes` request.
-        */
-        pub fn with_nodes(request: &GetNodes, nodes: Vec<PackedNode>) -> Option<Self> {
-            debug!(target: "SendNodes", "Creating SendNodes from GetNodes.");
-            trace!(target: "SendNodes", "With GetNodes: {:?}", request);
-            trace!("With nodes: {:?}", &nodes);
-
-            if nodes.is_empty() || nodes.len() > 4 {
-                warn!(target: "SendNodes", "Wrong number of nodes supplied!");
-                return None
-            }
-
-            Some(SendNodes { nodes: nodes, id: request.id })
-        }
-    }
-
     // SendNodes::to_bytes()
     #[test]
     fn packet_send_nodes_to_bytes_test() {
@@ -810,4 +1152,154 @@ mod test {
             packet_nat_ping_resp_to_bytes_test
             packet_nat_ping_resp_from_bytes_test
     );
+    // LanDiscovery::to_bytes()
+    #[test]
+    fn packet_lan_discovery_to_bytes_test() {
+        fn with_pk(a: Vec<u8>) {
+            if a.len() < PUBLICKEYBYTES { return }
+            let pk = PublicKey::from_slice(&a[..PUBLICKEYBYTES]).unwrap();
+            let mut _buf = [0; 1024];
+            let (bytes, size) = LanDiscovery { pk: pk }.to_bytes((&mut _buf, 0)).ok().unwrap();
+            assert_eq!(1 + PUBLICKEYBYTES, size);
+            assert_eq!(0x21, bytes[0]);
+            assert_eq!(&a[..PUBLICKEYBYTES], &bytes[1..size]);
+        }
+        quickcheck(with_pk as fn(Vec<u8>));
+    }
+
+    // LanDiscovery::from_bytes()
+    #[test]
+    fn packet_lan_discovery_from_bytes_test() {
+        fn with_bytes(bytes: Vec<u8>) {
+            if bytes.len() < 1 + PUBLICKEYBYTES || bytes[0] != 0x21 {
+                assert!(!LanDiscovery::from_bytes(&bytes).is_done());
+            } else {
+                let (_, packet) = LanDiscovery::from_bytes(&bytes).unwrap();
+                let PublicKey(ref pk) = packet.pk;
+                assert_eq!(pk, &bytes[1..1 + PUBLICKEYBYTES]);
+            }
+        }
+        quickcheck(with_bytes as fn(Vec<u8>));
+
+        // just in case
+        let mut bytes = vec![0x21];
+        bytes.extend_from_slice(gen_keypair().0.as_ref());
+        with_bytes(bytes);
+    }
+
+    // DhtPacket::
+    impl Arbitrary for DhtPacket {
+        fn arbitrary<G: Gen>(g: &mut G) -> Self {
+            let (pk, sk) = gen_keypair();  // "sender" keypair
+            let (r_pk, _) = gen_keypair();  // receiver PK
+            let precomputed = precompute(&r_pk, &sk);
+            let nonce = gen_nonce();
+
+            match g.gen_range(0, 4) {
+                0 => DhtPacket::new(&precomputed, &pk, &nonce, &PingRequest::arbitrary(g)),
+                1 => DhtPacket::new(&precomputed, &pk, &nonce, &PingResponse::arbitrary(g)),
+                2 => DhtPacket::new(&precomputed, &pk, &nonce, &GetNodes::arbitrary(g)),
+                _ => DhtPacket::new(&precomputed, &pk, &nonce, &SendNodes::arbitrary(g)),
+            }
+        }
+    }
+
+    // DhtPacket::to_bytes() / DhtPacket::from_bytes()
+    #[test]
+    fn dht_packet_encode_decode_test() {
+        fn with_dp(dp: DhtPacket) {
+            let mut buf = [0; 1024];
+            let (_, size) = dp.to_bytes((&mut buf, 0)).unwrap();
+            assert_eq!(dp.kind() as u8, buf[0]);
+            assert_eq!(dp.sender_pk.as_ref(), &buf[1..1 + PUBLICKEYBYTES]);
+
+            let (rest, parsed) = DhtPacket::from_bytes(&buf[..size]).unwrap();
+            assert!(rest.is_empty());
+            assert_eq!(dp, parsed);
+        }
+        quickcheck(with_dp as fn(DhtPacket));
+    }
+
+    // DhtPacket::from_bytes()
+    #[test]
+    fn dht_packet_from_bytes_invalid_kind_test() {
+        let mut bytes = vec![3];
+        bytes.extend_from_slice(gen_keypair().0.as_ref());
+        bytes.extend_from_slice(gen_nonce().as_ref());
+        bytes.extend_from_slice(&[0; 32]);
+        assert!(!DhtPacket::from_bytes(&bytes).is_done());
+    }
+
+    // DhtPacket::get_payload()
+    #[test]
+    fn dht_packet_get_payload_test() {
+        let (alice_pk, alice_sk) = gen_keypair();
+        let (bob_pk, bob_sk) = gen_keypair();
+        let (_, eve_sk) = gen_keypair();
+        let precomputed = precompute(&bob_pk, &alice_sk);
+
+        let request = GetNodes::new(&alice_pk);
+        let dp = DhtPacket::new(&precomputed, &alice_pk, &gen_nonce(), &request);
+        assert_eq!(::toxcore::packet_kind::PacketKind::GetN, dp.kind());
+
+        assert_eq!(Some(request), dp.get_payload::<GetNodes>(&bob_sk));
+        assert_eq!(Some(request), dp.get_payload_precomputed::<GetNodes>(&precomputed));
+        assert_eq!(None, dp.get_payload::<GetNodes>(&eve_sk));
+        // payload has to match the kind of the packet
+        assert_eq!(None, dp.get_payload::<SendNodes>(&bob_sk));
+        assert_eq!(None, dp.get_payload::<PingRequest>(&bob_sk));
+    }
+
+    // DhtRequest::get_payload()
+    #[test]
+    fn dht_request_get_payload_test() {
+        let (alice_pk, alice_sk) = gen_keypair();
+        let (bob_pk, bob_sk) = gen_keypair();
+        let (_, eve_sk) = gen_keypair();
+        let precomputed = precompute(&bob_pk, &alice_sk);
+
+        let payload = DhtRequestPayload::NatPingRequest(NatPingRequest::new());
+        let dr = DhtRequest::new(&precomputed, &bob_pk, &alice_pk, &gen_nonce(), &payload);
+        assert_eq!(bob_pk, dr.rpk);
+        assert_eq!(alice_pk, dr.spk);
+
+        assert_eq!(Some(payload.clone()), dr.get_payload(&precompute(&alice_pk, &bob_sk)));
+        assert_eq!(None, dr.get_payload(&precompute(&alice_pk, &eve_sk)));
+    }
+
+    // DhtRequest::to_bytes()
+    // DhtRequest::from_bytes()
+    #[test]
+    fn dht_request_encode_decode_test() {
+        let (alice_pk, alice_sk) = gen_keypair();
+        let (bob_pk, _) = gen_keypair();
+        let precomputed = precompute(&bob_pk, &alice_sk);
+        let payload = DhtRequestPayload::NatPingResponse(NatPingRequest::new().into());
+        let dr = DhtRequest::new(&precomputed, &bob_pk, &alice_pk, &gen_nonce(), &payload);
+
+        let mut buf = [0; 1024];
+        let (_, size) = dr.to_bytes((&mut buf, 0)).unwrap();
+        assert_eq!(0x20, buf[0]);
+        assert_eq!(bob_pk.as_ref(), &buf[1..1 + PUBLICKEYBYTES]);
+        assert_eq!(alice_pk.as_ref(), &buf[1 + PUBLICKEYBYTES..1 + 2 * PUBLICKEYBYTES]);
+
+        let (rest, parsed) = DhtRequest::from_bytes(&buf[..size]).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(dr, parsed);
+    }
+
+    // GetNodes::response()
+    #[test]
+    fn get_nodes_response_test() {
+        let (pk, _) = gen_keypair();
+        let mut kbucket = Kbucket::new(KBUCKET_BUCKETS, &pk);
+        let request = GetNodes::new(&pk);
+        assert_eq!(None, request.response(&kbucket));
+
+        let node = PackedNode::new(true, "1.2.3.4:5".parse().unwrap(), &gen_keypair().0);
+        assert!(kbucket.try_add(&node));
+        let response = request.response(&kbucket).unwrap();
+        assert_eq!(request.id, response.id);
+        assert_eq!(vec![node], response.nodes);
+    }
 }
@@ -21,7 +21,7 @@
 /*!
 Functionality needed to work as a DHT node.
 
-Made on top of `dht` and `network` modules.
+Made on top of `dht_new` and `network` modules.
 */
 // TODO: expand doc
 
@@ -29,20 +29,39 @@ Made on top of `dht` and `network` modules.
 use futures::*;
 use futures::sink;
 use futures::stream::*;
-use futures::sync::mpsc;
-use tokio_core::net::{UdpCodec, UdpFramed};
+use futures::sync::{mpsc, oneshot};
+use tokio_core::net::{UdpCodec, UdpFramed, UdpSocket};
 use tokio_core::reactor::Core;
 use tokio_proto::multiplex::RequestId;
 
-use std::collections::VecDeque;
+use std::cell::{Ref, RefCell};
+use std::collections::{HashMap, VecDeque};
 use std::io::{self, ErrorKind};
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::thread;
+use std::time::{Duration, Instant};
 
-use toxcore::binary_io::{FromBytes, ToBytes};
+use toxcore::binary_io::{FromBytes, ParseResult, ToBytes};
+use toxcore::bootstrap::BootstrapNode;
 use toxcore::crypto_core::*;
-use toxcore::dht::*;
+use toxcore::dht_friend::*;
+use toxcore::dht_lookup::*;
+use toxcore::dht_new::binary_io::{
+    FromBytes as OnionFromBytes,
+    ToBytes as OnionToBytes,
+};
+use toxcore::dht_new::kbucket::*;
+use toxcore::dht_new::packed_node::PackedNode;
+use toxcore::dht_new::packet::*;
+use toxcore::lan_discovery::*;
+use toxcore::network::{normalize_addr, MAX_UDP_PACKET_SIZE};
+use toxcore::onion::ONION_KEY_REFRESH_INTERVAL;
+use toxcore::onion::onion_announce::OnionAnnounce;
+use toxcore::onion::packet::*;
 use toxcore::packet_kind::PacketKind;
+use toxcore::precomputed_cache::*;
+use toxcore::public_addr::*;
+use toxcore::tcp::packet::OnionDataRequest as TcpOnionRequest;
 use toxcore::timeout::*;
 
 
@@ -61,13 +80,151 @@ pub type ToxSplitStream = SplitStream<UdpFramed<ToxCodec>>;
 // TODO: rename
 pub type SendSink = sink::Send<SplitSink<UdpFramed<ToxCodec>>>;
 
+/**
+Maximum number of nodes that are checked for being online before adding
+to the Close List at the same time.
+*/
+pub const MAX_TO_PING: usize = 32;
+
 /// Type representing Tox UDP packets.
-// TODO: change DhtPacket to and enum with all possible packets
-pub type ToxUdpPacket = (SocketAddr, DhtPacket);
+pub type ToxUdpPacket = (SocketAddr, UdpPacket);
 
 /// Type representing received Tox UDP packets.
-// TODO: change DhtPacket to and enum with all possible packets
-pub type ToxRecvUdpPacket = (SocketAddr, Option<DhtPacket>);
+pub type ToxRecvUdpPacket = (SocketAddr, Option<UdpPacket>);
+
+/**
+Enum with all UDP packets that `DhtNode` can send or receive.
+
+[`DhtPacket`]: ../dht_new/packet/struct.DhtPacket.html
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum UdpPacket {
+    /// [`DhtPacket`](../dht_new/packet/struct.DhtPacket.html) structure.
+    Dht(DhtPacket),
+    /// [`DhtRequest`](../dht_new/packet/struct.DhtRequest.html) structure.
+    DhtRequest(DhtRequest),
+    /// [`OnionRequest0`](../onion/packet/struct.OnionRequest0.html) structure.
+    OnionRequest0(OnionRequest0),
+    /// [`OnionRequest1`](../onion/packet/struct.OnionRequest1.html) structure.
+    OnionRequest1(OnionRequest1),
+    /// [`OnionRequest2`](../onion/packet/struct.OnionRequest2.html) structure.
+    OnionRequest2(OnionRequest2),
+    /// [`OnionAnnounceRequest`](../onion/packet/struct.OnionAnnounceRequest.html) structure.
+    OnionAnnounceRequest(OnionAnnounceRequest),
+    /// [`OnionDataRequest`](../onion/packet/struct.OnionDataRequest.html) structure.
+    OnionDataRequest(OnionDataRequest),
+    /// [`OnionResponse3`](../onion/packet/struct.OnionResponse3.html) structure.
+    OnionResponse3(OnionResponse3),
+    /// [`OnionResponse2`](../onion/packet/struct.OnionResponse2.html) structure.
+    OnionResponse2(OnionResponse2),
+    /// [`OnionResponse1`](../onion/packet/struct.OnionResponse1.html) structure.
+    OnionResponse1(OnionResponse1),
+    /// [`OnionAnnounceResponse`](../onion/packet/struct.OnionAnnounceResponse.html) structure.
+    OnionAnnounceResponse(OnionAnnounceResponse),
+    /// [`OnionDataResponse`](../onion/packet/struct.OnionDataResponse.html) structure.
+    OnionDataResponse(OnionDataResponse),
+    /// [`LanDiscovery`](../dht_new/packet/struct.LanDiscovery.html) structure.
+    LanDiscovery(LanDiscovery),
+}
+
+impl From<DhtPacket> for UdpPacket {
+    fn from(packet: DhtPacket) -> UdpPacket {
+        UdpPacket::Dht(packet)
+    }
+}
+
+impl From<DhtRequest> for UdpPacket {
+    fn from(packet: DhtRequest) -> UdpPacket {
+        UdpPacket::DhtRequest(packet)
+    }
+}
+
+impl From<InnerOnionResponse> for UdpPacket {
+    fn from(packet: InnerOnionResponse) -> UdpPacket {
+        match packet {
+            InnerOnionResponse::OnionAnnounceResponse(p) =>
+                UdpPacket::OnionAnnounceResponse(p),
+            InnerOnionResponse::OnionDataResponse(p) =>
+                UdpPacket::OnionDataResponse(p),
+        }
+    }
+}
+
+/// Serialize packet that implements new `ToBytes` into bytes.
+fn packet_to_bytes<P: OnionToBytes>(packet: &P) -> Vec<u8> {
+    let mut buf = [0; MAX_UDP_PACKET_SIZE];
+    // packets that are sent by `DhtNode` are never bigger than the ones
+    // it receives
+    let (_, size) = packet.to_bytes((&mut buf, 0))
+        .expect("Packet should fit in MAX_UDP_PACKET_SIZE");
+    buf[..size].to_vec()
+}
+
+impl ToBytes for UdpPacket {
+    fn to_bytes(&self) -> Vec<u8> {
+        match *self {
+            UdpPacket::Dht(ref p) => packet_to_bytes(p),
+            UdpPacket::DhtRequest(ref p) => packet_to_bytes(p),
+            UdpPacket::OnionRequest0(ref p) => packet_to_bytes(p),
+            UdpPacket::OnionRequest1(ref p) => packet_to_bytes(p),
+            UdpPacket::OnionRequest2(ref p) => packet_to_bytes(p),
+            UdpPacket::OnionAnnounceRequest(ref p) => packet_to_bytes(p),
+            UdpPacket::OnionDataRequest(ref p) => packet_to_bytes(p),
+            UdpPacket::OnionResponse3(ref p) => packet_to_bytes(p),
+            UdpPacket::OnionResponse2(ref p) => packet_to_bytes(p),
+            UdpPacket::OnionResponse1(ref p) => packet_to_bytes(p),
+            UdpPacket::OnionAnnounceResponse(ref p) => packet_to_bytes(p),
+            UdpPacket::OnionDataResponse(ref p) => packet_to_bytes(p),
+            UdpPacket::LanDiscovery(ref p) => packet_to_bytes(p),
+        }
+    }
+}
+
+impl FromBytes for UdpPacket {
+    fn parse_bytes(bytes: &[u8]) -> ParseResult<Self> {
+        alt!(bytes,
+            map!(complete!(DhtPacket::from_bytes), UdpPacket::Dht) |
+            map!(complete!(DhtRequest::from_bytes), UdpPacket::DhtRequest) |
+            map!(complete!(OnionRequest0::from_bytes), UdpPacket::OnionRequest0) |
+            map!(complete!(OnionRequest1::from_bytes), UdpPacket::OnionRequest1) |
+            map!(complete!(OnionRequest2::from_bytes), UdpPacket::OnionRequest2) |
+            map!(complete!(OnionResponse3::from_bytes), UdpPacket::OnionResponse3) |
+            map!(complete!(OnionResponse2::from_bytes), UdpPacket::OnionResponse2) |
+            map!(complete!(OnionResponse1::from_bytes), UdpPacket::OnionResponse1) |
+            map!(complete!(OnionAnnounceResponse::from_bytes), UdpPacket::OnionAnnounceResponse) |
+            map!(complete!(OnionDataResponse::from_bytes), UdpPacket::OnionDataResponse) |
+            map!(complete!(LanDiscovery::from_bytes), UdpPacket::LanDiscovery) |
+            // these two start with the inner request and have to be checked
+            // after all packets that start with their own tag
+            map!(complete!(OnionAnnounceRequest::from_bytes), UdpPacket::OnionAnnounceRequest) |
+            map!(complete!(OnionDataRequest::from_bytes), UdpPacket::OnionDataRequest)
+        )
+    }
+}
+
+/// Senders that deliver results and events of [`DhtNode`](./struct.DhtNode.html).
+#[derive(Default)]
+struct Senders {
+    /// senders that complete futures of lookups by IDs of lookups
+    lookups: HashMap<u64, oneshot::Sender<Vec<PackedNode>>>,
+    /// senders of own public `IP:port` change events
+    public_addr: Vec<mpsc::UnboundedSender<PublicAddrChange>>,
+    /// sink for onion responses that should be relayed to clients of our
+    /// TCP relay
+    tcp_onion: Option<mpsc::UnboundedSender<(InnerOnionResponse, SocketAddr)>>,
+}
+
+/**
+Senders can't be cloned, so a clone of [`DhtNode`](./struct.DhtNode.html)
+gets its own empty senders. Results and events of the clone are not
+delivered to subscribers of the original node, and its lookups are dropped
+as canceled.
+*/
+impl Clone for Senders {
+    fn clone(&self) -> Senders {
+        Senders::default()
+    }
+}
 
 /**
 Own DHT node data.
@@ -76,52 +233,96 @@ Contains:
 
 - DHT public key
 - DHT secret key
+- cache of keys precomputed with DHT peers ([`PrecomputedCache`])
 - Close List ([`Kbucket`] with nodes close to own DHT public key)
-- ping timeout lists ([`TimeoutQueue`])
+- friends that are searched in DHT ([`DhtFriend`])
+- IDs and timeouts of sent requests that wait for response ([`TimeoutQueue`])
+- own public `IP:port` reported by other nodes ([`PublicAddr`])
 
 # Adding node to Close List
 
-Before a [`PackedNode`] is added to the Close List, it needs to be
-checked whether:
+Before a [`PackedNode`] received in [`SendNodes`] is added to the Close
+List, it needs to be checked whether:
 
-- it can be added to [`Kbucket`] \(using [`Kbucket::can_add()`])
+- it's not in the [`Kbucket`] already
 - [`PackedNode`] is actually online
 
 Once the first check passes node is added to the temporary list, and
-a [`GetNodes`] request is sent to it in order to check whether it's
-online. If the node responds correctly within [`PING_TIMEOUT`], it's
-removed from temporary list and added to the Close List.
-
-[`GetNodes`]: ../dht/struct.GetNodes.html
-[`Kbucket`]: ../dht/struct.Kbucket.html
-[`Kbucket::can_add()`]: ../dht/struct.Kbucket.html#method.can_add
-[`PackedNode`]: ../dht/struct.PackedNode.html
+a [`PingRequest`] request is sent to it in order to check whether it's
+online. If the node responds with [`PingResponse`] with the same ID within
+[`PING_TIMEOUT`], it's removed from temporary list and added to the Close
+List. This way the Close List can't be filled with fake or dead nodes.
+
+[`DhtFriend`]: ../dht_friend/struct.DhtFriend.html
+[`Kbucket`]: ../dht_new/kbucket/struct.Kbucket.html
+[`PackedNode`]: ../dht_new/packed_node/struct.PackedNode.html
 [`PING_TIMEOUT`]: ../timeout/constant.PING_TIMEOUT.html
+[`PingRequest`]: ../dht_new/packet/struct.PingRequest.html
+[`PingResponse`]: ../dht_new/packet/struct.PingResponse.html
+[`PrecomputedCache`]: ../precomputed_cache/struct.PrecomputedCache.html
+[`PublicAddr`]: ../public_addr/struct.PublicAddr.html
+[`SendNodes`]: ../dht_new/packet/struct.SendNodes.html
 [`TimeoutQueue`]: ../timeout/struct.TimeoutQueue.html
 */
-#[derive(Clone, Eq, PartialEq)]
+#[derive(Clone)]
 pub struct DhtNode {
     dht_secret_key: Box<SecretKey>,
     dht_public_key: Box<PublicKey>,
+    /// keys precomputed with DHT peers; `RefCell` allows to use the cache
+    /// when creating packets through `&self`
+    precomputed_cache: RefCell<PrecomputedCache>,
     /// Close List (contains nodes close to own DHT PK)
     kbucket: Box<Kbucket>,
+    /// IDs and timeouts of sent `GetNodes` requests
     getn_timeout: TimeoutQueue,
-    /// timeouts for requests that check whether a node is online before
-    /// adding it to the Close List
-    // TODO: rename
-    to_close_tout: TimeoutQueue,
+    /// IDs and timeouts of sent ping requests
+    ping_timeout: TimeoutQueue,
     /// list of nodes that are checked for being online before adding
     /// to the Close List
     // TODO: rename
     to_close_nodes: VecDeque<PackedNode>,
-    // TODO: add a "verify" TimeoutQueue to check if nodes are online
-    //       before adding them to the kbucket
+    /// list of nodes that should be pinged to check whether they are
+    /// online before adding to the Close List
+    to_ping_nodes: VecDeque<PackedNode>,
+    /// symmetric key used to encrypt return addresses of relayed onion
+    /// packets
+    onion_symmetric_key: PrecomputedKey,
+    /// time when `onion_symmetric_key` was generated
+    onion_symmetric_key_time: Instant,
+    /// nodes announced through the onion
+    onion_announce: OnionAnnounce,
+    /// lookups that are in progress with their IDs
+    lookups: Vec<(u64, Lookup)>,
+    /// friends which `IP:port`s are searched in DHT
+    friends: Vec<DhtFriend>,
+    /// own public `IP:port`s reported by nodes in `SendNodes` responses
+    public_addr: PublicAddr,
+    /// senders of lookup results and other events
+    senders: Senders,
+}
 
-    // TODO: track sent ping request IDs
-    // TODO: have a table with precomputed keys for all known nodes?
-    //       (use lru-cache for storing last used 1024?)
+/// Cache of precomputed keys and senders are not compared: they don't
+/// affect the state of the node.
+impl PartialEq for DhtNode {
+    fn eq(&self, other: &DhtNode) -> bool {
+        self.dht_secret_key == other.dht_secret_key &&
+            self.dht_public_key == other.dht_public_key &&
+            self.kbucket == other.kbucket &&
+            self.getn_timeout == other.getn_timeout &&
+            self.ping_timeout == other.ping_timeout &&
+            self.to_close_nodes == other.to_close_nodes &&
+            self.to_ping_nodes == other.to_ping_nodes &&
+            self.onion_symmetric_key == other.onion_symmetric_key &&
+            self.onion_symmetric_key_time == other.onion_symmetric_key_time &&
+            self.onion_announce == other.onion_announce &&
+            self.lookups == other.lookups &&
+            self.friends == other.friends &&
+            self.public_addr == other.public_addr
+    }
 }
 
+impl Eq for DhtNode {}
+
 
 impl DhtNode {
     /**
@@ -142,17 +343,27 @@ impl DhtNode {
 
         debug!("Created new DhtNode instance");
         Ok(DhtNode {
+            precomputed_cache: RefCell::new(
+                PrecomputedCache::new(&sk, PRECOMPUTED_CACHE_SIZE)),
             dht_secret_key: Box::new(sk),
             dht_public_key: Box::new(pk),
             kbucket: Box::new(kbucket),
             getn_timeout: Default::default(),
-            to_close_tout: Default::default(),
+            ping_timeout: Default::default(),
             to_close_nodes: Default::default(),
+            to_ping_nodes: Default::default(),
+            onion_symmetric_key: new_symmetric_key(),
+            onion_symmetric_key_time: Instant::now(),
+            onion_announce: OnionAnnounce::new(pk),
+            lookups: Vec::new(),
+            friends: Vec::new(),
+            public_addr: PublicAddr::new(),
+            senders: Senders::default(),
         })
     }
 
 
-    /** Try to add nodes to [Kbucket](../dht/struct.Kbucket.html).
+    /** Try to add nodes to [Kbucket](../dht_new/kbucket/struct.Kbucket.html).
 
     Wrapper around Kbucket's method.
     */
@@ -175,30 +386,67 @@ impl DhtNode {
     }
 
     /**
-    Remove nodes that have crossed `secs` timeout threshold.
+    Get `PrecomputedKey` for DHT peer with `pk` from the cache.
+    */
+    fn precomputed_key(&self, pk: &PublicKey) -> PrecomputedKey {
+        self.precomputed_cache.borrow_mut().get(pk)
+    }
+
+    /**
+    IDs of sent ping requests. Can be used to get the number of received
+    unsolicited ping responses.
+    */
+    pub fn ping_requests(&self) -> &TimeoutQueue {
+        &self.ping_timeout
+    }
+
+    /**
+    IDs of sent [`GetNodes`] requests. Can be used to get the number of
+    received unsolicited [`SendNodes`] responses.
+
+    [`GetNodes`]: ../dht_new/packet/struct.GetNodes.html
+    [`SendNodes`]: ../dht_new/packet/struct.SendNodes.html
+    */
+    pub fn getn_requests(&self) -> &TimeoutQueue {
+        &self.getn_timeout
+    }
+
+    /**
+    Cache of keys precomputed with DHT peers. Can be used to get its hit
+    and miss counters.
+    */
+    pub fn precomputed_cache(&self) -> Ref<PrecomputedCache> {
+        self.precomputed_cache.borrow()
+    }
+
+    /**
+    Forget `GetNodes` requests that have crossed `secs` timeout threshold,
+    so that late responses to them are not accepted.
+
+    Nodes that didn't respond stay in the Close List: they become `Bad` and
+    get replaced according to their [`NodeState`].
+
+    [`NodeState`]: ../timeout/enum.NodeState.html
     */
-    // TODO: test
     // TODO: add fn for ping/getn req timeouts with hardcoded consts?
     pub fn remove_timed_out(&mut self, secs: u64) {
         for pk in self.getn_timeout.get_timed_out(secs) {
-            debug!("Removing timed out node");
-            self.kbucket.remove(&pk);
+            debug!("GetNodes request to {:?} timed out", pk);
         }
     }
 
     /**
     Create a [`DhtPacket`] to peer with `peer_pk` `PublicKey` containing
-    a [`PingReq`] request.
+    a [`PingRequest`] request, together with ID of the request.
 
-    [`DhtPacket`]: ../dht/struct.DhtPacket.html
-    [`PingReq`]: ../dht/struct.PingReq.html
+    [`DhtPacket`]: ../dht_new/packet/struct.DhtPacket.html
+    [`PingRequest`]: ../dht_new/packet/struct.PingRequest.html
     */
-    fn create_ping_req(&self, peer_pk: &PublicKey) -> DhtPacket {
-        let ping = PingReq::new();
-        // TODO: precompute shared key to calculate it 1 time
-        let shared_secret = &encrypt_precompute(peer_pk, self.sk());
+    fn create_ping_req(&self, peer_pk: &PublicKey) -> (RequestId, DhtPacket) {
+        let ping = PingRequest::new();
+        let shared_secret = &self.precomputed_key(peer_pk);
         let nonce = &gen_nonce();
-        DhtPacket::new(shared_secret, self.pk(), nonce, &ping)
+        (ping.id(), DhtPacket::new(shared_secret, self.pk(), nonce, &ping))
     }
 
     /**
@@ -210,35 +458,42 @@ impl DhtNode {
     [`send_packets()`]: ./fn.send_packets.html
     [`ToxUdpPacket`]: ./type.ToxUdpPacket.html
     */
-    // TODO: track requests
-    pub fn request_ping(&self, peer: &PackedNode) -> ToxUdpPacket {
-        let request = self.create_ping_req(peer.pk());
-        (peer.socket_addr(), request)
+    pub fn request_ping(&mut self, peer: &PackedNode) -> ToxUdpPacket {
+        let (id, request) = self.create_ping_req(peer.pk());
+        self.ping_timeout.add(peer.pk(), id);
+        (peer.socket_addr(), request.into())
     }
 
     /**
     Create a [`DhtPacket`] in response to [`DhtPacket`] containing
-    [`PingReq`] packet.
+    [`PingRequest`] packet.
 
-    Returns `None` if [`DhtPacket`] is not a [`PingReq`].
+    Returns `None` if [`DhtPacket`] is not a [`PingRequest`].
 
-    [`DhtPacket`]: ../dht/struct.DhtPacket.html
-    [`PingReq`]: ../dht/struct.PingReq.html
+    [`DhtPacket`]: ../dht_new/packet/struct.DhtPacket.html
+    [`PingRequest`]: ../dht_new/packet/struct.PingRequest.html
     */
     fn create_ping_resp(&self, request: &DhtPacket)
         -> Option<DhtPacket>
     {
-        // TODO: precompute shared key to calculate it 1 time
-        let precomp = encrypt_precompute(&request.sender_pk, self.sk());
-        request.ping_resp(self.sk(), &precomp, self.pk())
+        if request.kind() != PacketKind::PingReq {
+            return None
+        }
+        let precomp = self.precomputed_key(&request.sender_pk);
+        let ping = match request.get_payload_precomputed::<PingRequest>(&precomp) {
+            Some(p) => p,
+            None => return None,
+        };
+        let nonce = &gen_nonce();
+        Some(DhtPacket::new(&precomp, self.pk(), nonce, &PingResponse::from(ping)))
     }
 
     /**
     Create a future sending [`DhtPacket`] that encapsulates
     [ping response] to supplied ping request.
 
-    [`DhtPacket`]: ../dht/struct.DhtPacket.html
-    [ping response]: ../dht/struct.PingResp.html
+    [`DhtPacket`]: ../dht_new/packet/struct.DhtPacket.html
+    [ping response]: ../dht_new/packet/struct.PingResponse.html
     */
     // TODO: change to return Option<ToxUdpPakcet>
     pub fn respond_ping(&self,
@@ -248,7 +503,7 @@ impl DhtNode {
         -> Option<SendSink>
     {
         self.create_ping_resp(request)
-            .map(|p| sink.send((peer_addr, p)))
+            .map(|p| sink.send((peer_addr, p.into())))
     }
 
     /**
@@ -257,16 +512,27 @@ impl DhtNode {
 
     `RequestId` is to be used for tracking node timeouts.
 
-    [`DhtPacket`]: ../dht/struct.DhtPacket.html
-    [`GetNodes`]: ../dht/struct.GetNodes.html
+    [`DhtPacket`]: ../dht_new/packet/struct.DhtPacket.html
+    [`GetNodes`]: ../dht_new/packet/struct.GetNodes.html
     */
     pub fn create_getn(&self, peer_pk: &PublicKey)
         -> (RequestId, DhtPacket) {
         // request for nodes that are close to our own DHT PK
-        let req = &GetNodes::new(self.pk());
-        let shared_secret = &encrypt_precompute(peer_pk, self.sk());
+        let req = GetNodes::new(self.pk());
+        (req.id, self.create_getn_packet(peer_pk, &req))
+    }
+
+    /**
+    Create a [`DhtPacket`] to peer's `PublicKey` containing supplied
+    [`GetNodes`] request.
+
+    [`DhtPacket`]: ../dht_new/packet/struct.DhtPacket.html
+    [`GetNodes`]: ../dht_new/packet/struct.GetNodes.html
+    */
+    fn create_getn_packet(&self, peer_pk: &PublicKey, request: &GetNodes) -> DhtPacket {
+        let shared_secret = &self.precomputed_key(peer_pk);
         let nonce = &gen_nonce();
-        (req.id, DhtPacket::new(shared_secret, self.pk(), nonce, req))
+        DhtPacket::new(shared_secret, self.pk(), nonce, request)
     }
 
     /**
@@ -284,7 +550,20 @@ impl DhtNode {
         -> (RequestId, ToxUdpPacket)
     {
         let (id, request) = self.create_getn(peer.pk());
-        (id, (peer.socket_addr(), request))
+        self.getn_timeout.add(peer.pk(), id);
+        (id, (peer.socket_addr(), request.into()))
+    }
+
+    /**
+    Create a [`ToxUdpPacket`] with supplied [`GetNodes`] request to a peer.
+    ID of the request is remembered so that the response is accepted.
+
+    [`GetNodes`]: ../dht_new/packet/struct.GetNodes.html
+    [`ToxUdpPacket`]: ./type.ToxUdpPacket.html
+    */
+    fn request_getn(&mut self, peer: &PackedNode, request: &GetNodes) -> ToxUdpPacket {
+        self.getn_timeout.add(peer.pk(), request.id);
+        (peer.socket_addr(), self.create_getn_packet(peer.pk(), request).into())
     }
 
     /**
@@ -307,117 +586,959 @@ impl DhtNode {
             .cloned()
             .collect::<Vec<PackedNode>>()
             .iter()
-            .map(|pn| {
-                let (id, packet) = self.request_nodes(pn);
-                // add to timeout queue
-                self.getn_timeout.add(pn.pk(), id);
-                packet
+            .map(|pn| self.request_nodes(pn).1)
+            .collect()
+    }
+
+
+    /**
+    Bootstrap from supplied nodes.
+
+    Every `IP:port` of the nodes is added to the Close List, and
+    [`ToxUdpPacket`]s with requests for nodes close to own PK are created
+    for them. Requests are added to response timeout queue.
+
+    [`ToxUdpPacket`]s are to be passed to `Sender` created by
+    [`send_packets()`].
+
+    [`send_packets()`]: ./fn.send_packets.html
+    [`ToxUdpPacket`]: ./type.ToxUdpPacket.html
+    */
+    pub fn bootstrap(&mut self, nodes: &[BootstrapNode]) -> Vec<ToxUdpPacket> {
+        let mut packets = Vec::new();
+        for node in nodes.iter().flat_map(BootstrapNode::packed_nodes) {
+            if !self.try_add(&node) {
+                debug!("Bootstrap node wasn't added to the Close List: {:?}", node);
+            }
+            let (_, packet) = self.request_nodes(&node);
+            packets.push(packet);
+        }
+        packets
+    }
+
+    /**
+    Create a [`DhtPacket`]  to peer with `peer_pk` `PublicKey`
+    containing [`SendNodes`] response.
+
+    Returns `None` if own `Kbucket` is empty or supplied `DhtPacket`
+    doesn't contain [`GetNodes`] request.
+
+    [`DhtPacket`]: ../dht_new/packet/struct.DhtPacket.html
+    [`GetNodes`]: ../dht_new/packet/struct.GetNodes.html
+    [`SendNodes`]: ../dht_new/packet/struct.SendNodes.html
+    */
+    fn create_sendn(&self, request: &DhtPacket)
+        -> Option<DhtPacket>
+    {
+        let shared_secret = &self.precomputed_key(&request.sender_pk);
+        let getn = match request.get_payload_precomputed::<GetNodes>(shared_secret) {
+            Some(g) => g,
+            None => return None,
+        };
+        let sendn = match getn.response(&*self.kbucket) {
+            Some(s) => s,
+            None => return None,
+        };
+        let nonce = &gen_nonce();
+        Some(DhtPacket::new(shared_secret, self.pk(), nonce, &sendn))
+    }
+
+    /**
+    Add friend with DHT `PublicKey` `pk` which `IP:port` should be searched
+    in DHT. Friend's Close List is filled with known nodes close to it.
+
+    Returns `false` if the friend was already added.
+    */
+    pub fn add_friend(&mut self, pk: &PublicKey) -> bool {
+        if self.friends.iter().any(|friend| friend.pk() == pk) {
+            return false
+        }
+        let mut friend = DhtFriend::new(pk);
+        for node in self.kbucket.iter() {
+            friend.try_add(node);
+        }
+        self.friends.push(friend);
+        true
+    }
+
+    /**
+    Stop searching friend with DHT `PublicKey` `pk`.
+
+    Returns `false` if there was no such friend.
+    */
+    pub fn remove_friend(&mut self, pk: &PublicKey) -> bool {
+        match self.friends.iter().position(|friend| friend.pk() == pk) {
+            Some(index) => {
+                self.friends.remove(index);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Get friend with DHT `PublicKey` `pk`.
+    pub fn friend(&self, pk: &PublicKey) -> Option<&DhtFriend> {
+        self.friends.iter().find(|friend| friend.pk() == pk)
+    }
+
+    /**
+    Get `IP:port` of friend with DHT `PublicKey` `pk` that was returned by
+    one of the nodes close to the friend.
+
+    Returns `None` if there is no such friend or it wasn't found yet.
+    */
+    pub fn friend_addr(&self, pk: &PublicKey) -> Option<SocketAddr> {
+        self.friend(pk).and_then(|friend| friend.addr())
+    }
+
+    /**
+    Create [`ToxUdpPacket`]s with [`GetNodes`] requests for friends'
+    `PublicKey`s to nodes close to them.
+
+    Should be called frequently, e.g. every second; requests for each
+    friend are created once in
+    [`FRIEND_GETN_INTERVAL`](../dht_friend/constant.FRIEND_GETN_INTERVAL.html)
+    seconds.
+
+    [`GetNodes`]: ../dht_new/packet/struct.GetNodes.html
+    [`ToxUdpPacket`]: ./type.ToxUdpPacket.html
+    */
+    pub fn request_nodes_friends(&mut self) -> Vec<ToxUdpPacket> {
+        let mut requests = Vec::new();
+        for friend in &mut self.friends {
+            requests.extend(friend.requests(&*self.kbucket));
+        }
+        requests.iter()
+            .map(|&(ref node, ref request)| self.request_getn(node, request))
+            .collect()
+    }
+
+    /**
+    Create [`ToxUdpPacket`]s that punch holes in NATs of friends that
+    can't be reached directly.
+
+    [`NatPingRequest`]s are sent inside of [`DhtRequest`]s through nodes
+    that reported friends' `IP:port`s. After a friend responds to it,
+    [`PingRequest`]s are sent to `IP:port`s that are likely to be opened by
+    the friend's NAT. The friend becomes reachable directly when it
+    responds to one of them.
+
+    Should be called frequently, e.g. every second; packets for each
+    friend are created once in
+    [`PUNCH_INTERVAL`](../hole_punching/constant.PUNCH_INTERVAL.html)
+    seconds.
+
+    [`DhtRequest`]: ../dht_new/packet/struct.DhtRequest.html
+    [`NatPingRequest`]: ../dht_new/packet/struct.NatPingRequest.html
+    [`PingRequest`]: ../dht_new/packet/struct.PingRequest.html
+    [`ToxUdpPacket`]: ./type.ToxUdpPacket.html
+    */
+    pub fn punch_holes_friends(&mut self) -> Vec<ToxUdpPacket> {
+        let mut nat_pings = Vec::new();
+        let mut punching = Vec::new();
+        for friend in &mut self.friends {
+            if let Some(request) = friend.nat_ping_request() {
+                for node in friend.reporting_nodes() {
+                    nat_pings.push((*friend.pk(), node.socket_addr(), request));
+                }
+            }
+            for addr in friend.punching_addrs() {
+                punching.push(PackedNode::new(true, addr, friend.pk()));
+            }
+        }
+
+        let mut packets = nat_pings.into_iter()
+            .map(|(pk, addr, request)| {
+                let payload = DhtRequestPayload::NatPingRequest(request);
+                (addr, self.create_dht_request(&pk, &payload).into())
+            })
+            .collect::<Vec<ToxUdpPacket>>();
+        packets.extend(punching.iter().map(|node| self.request_ping(node)));
+        packets
+    }
+
+    /**
+    Start iterative lookup of nodes closest to `target` `PublicKey`.
+
+    Returns [`LookupFuture`] that resolves to the closest nodes that
+    responded, and [`ToxUdpPacket`]s with the first [`GetNodes`] requests
+    to nodes from the Close List. Following requests are created by
+    [`lookup_requests()`](#method.lookup_requests).
+
+    [`GetNodes`]: ../dht_new/packet/struct.GetNodes.html
+    [`LookupFuture`]: ../dht_lookup/struct.LookupFuture.html
+    [`ToxUdpPacket`]: ./type.ToxUdpPacket.html
+    */
+    pub fn lookup(&mut self, target: &PublicKey) -> (LookupFuture, Vec<ToxUdpPacket>) {
+        let mut lookup = Lookup::new(self.pk(), target);
+        for node in self.kbucket.iter() {
+            lookup.add_node(node);
+        }
+        let packets = self.lookup_packets(&mut lookup);
+        let (tx, future) = LookupFuture::new();
+        let id = random_u64();
+        self.senders.lookups.insert(id, tx);
+        self.lookups.push((id, lookup));
+        self.complete_lookups();
+        (future, packets)
+    }
+
+    /**
+    Create [`ToxUdpPacket`]s with [`GetNodes`] requests of all lookups
+    that are in progress. Requests that weren't answered in time are
+    skipped, and finished lookups resolve their futures.
+
+    Should be called frequently, e.g. every second, for lookups to make
+    progress.
+
+    [`GetNodes`]: ../dht_new/packet/struct.GetNodes.html
+    [`ToxUdpPacket`]: ./type.ToxUdpPacket.html
+    */
+    pub fn lookup_requests(&mut self) -> Vec<ToxUdpPacket> {
+        let mut lookups = ::std::mem::replace(&mut self.lookups, Vec::new());
+        let mut packets = Vec::new();
+        for &mut (_, ref mut lookup) in &mut lookups {
+            lookup.check_timeouts();
+            packets.extend(self.lookup_packets(lookup));
+        }
+        self.lookups = lookups;
+        self.complete_lookups();
+        packets
+    }
+
+    /// Create [`ToxUdpPacket`]s with the next requests of `lookup`.
+    fn lookup_packets(&mut self, lookup: &mut Lookup) -> Vec<ToxUdpPacket> {
+        lookup.next_requests().iter()
+            .map(|&(ref node, ref request)| self.request_getn(node, request))
+            .collect()
+    }
+
+    /**
+    Resolve futures of finished lookups with their results and remove
+    them, as well as lookups which futures were dropped.
+    */
+    fn complete_lookups(&mut self) {
+        let senders = &mut self.senders;
+        let mut i = 0;
+        while i < self.lookups.len() {
+            let (id, ref lookup) = self.lookups[i];
+            let is_canceled = senders.lookups.get(&id).map_or(true, |tx| tx.is_canceled());
+            if lookup.is_finished() || is_canceled {
+                let (id, lookup) = self.lookups.swap_remove(i);
+                debug!("Lookup of {:?} is finished", lookup.target());
+                if let Some(tx) = senders.lookups.remove(&id) {
+                    // it's fine if nobody waits for the result anymore
+                    drop(tx.send(lookup.result()));
+                }
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    /**
+    Send nodes in response to [`GetNodes`] request contained in
+    [`DhtPacket`].
+
+    Can fail (return `None`) if Kbucket is empty or `DhtPacket` doesn't
+    contain `GetNodes` request.
+
+    [`DhtPacket`]: ../dht_new/packet/struct.DhtPacket.html
+    [`GetNodes`]: ../dht_new/packet/struct.GetNodes.html
+    */
+    pub fn send_nodes(&self,
+                      sink: ToxSplitSink,
+                      peer_addr: SocketAddr,
+                      request: &DhtPacket)
+        -> Option<SendSink>
+    {
+        self.create_sendn(request)
+            .map(|sn| sink.send((peer_addr, sn.into())))
+    }
+
+    /**
+    Handle [`DhtPacket`] that claims to contain [`SendNodes`] packet.
+
+    Packet is dropped if:
+
+    - it doesn't contain [`SendNodes`]
+    - it's not a response to a [`GetNodes`] request (invalid ID) or
+      the request expired
+
+    [`DhtPacket`]: ../dht_new/packet/struct.DhtPacket.html
+    [`GetNodes`]: ../dht_new/packet/struct.GetNodes.html
+    [`SendNodes`]: ../dht_new/packet/struct.SendNodes.html
+    */
+    fn handle_packet_sendn(&mut self, packet: &DhtPacket) {
+        let shared_secret = self.precomputed_key(&packet.sender_pk);
+        match packet.get_payload_precomputed::<SendNodes>(&shared_secret) {
+            Some(sn) => {
+                if !self.getn_timeout.check(&packet.sender_pk, sn.id, PING_TIMEOUT) {
+                    debug!("Received SendN is not a response to our request");
+                    return
+                }
+
+                self.kbucket.record_response(&packet.sender_pk);
+                self.handle_public_addr_reports(&packet.sender_pk, &sn);
+                // received SendNodes packet is a response to our request
+                trace!("Adding nodes from SendNodes to the list of nodes to ping");
+                for node in &sn.nodes {
+                    self.add_to_ping(node);
+                }
+                if self.friends.iter_mut().any(|friend|
+                    friend.handle_send_nodes(&packet.sender_pk, &sn)) {
+                    debug!("Received SendN is a response to friend search request");
+                } else if self.lookups.iter_mut().any(|&mut (_, ref mut lookup)|
+                    lookup.handle_response(&packet.sender_pk, &sn)) {
+                    debug!("Received SendN is a response to lookup request");
+                    self.complete_lookups();
+                } else {
+                    debug!("Received SendN is a valid response");
+                }
+            },
+            None =>
+                debug!("Wrong DhtPacket; should have contained SendNodes"),
+        }
+    }
+
+    /**
+    Collect own `IP:port`s reported in [`SendNodes`] by node with
+    `sender_pk` and notify subscribers when own public `IP:port` changes.
+
+    [`SendNodes`]: ../dht_new/packet/struct.SendNodes.html
+    */
+    fn handle_public_addr_reports(&mut self, sender_pk: &PublicKey, sn: &SendNodes) {
+        let own_pk = *self.pk();
+        for node in sn.nodes.iter().filter(|n| *n.pk() == own_pk) {
+            if let Some(change) = self.public_addr.add_report(sender_pk, node.socket_addr()) {
+                self.senders.public_addr
+                    .retain(|tx| tx.unbounded_send(change).is_ok());
+            }
+        }
+    }
+
+    /// Get own public IPv4 `IP:port` reported by other nodes.
+    pub fn public_addr_v4(&self) -> Option<SocketAddr> {
+        self.public_addr.addr_v4()
+    }
+
+    /// Get own public IPv6 `IP:port` reported by other nodes.
+    pub fn public_addr_v6(&self) -> Option<SocketAddr> {
+        self.public_addr.addr_v6()
+    }
+
+    /**
+    Subscribe to changes of own public `IP:port`.
+
+    Returned stream yields [`PublicAddrChange`] each time the `IP:port` of
+    either IP family changes. Subscription is cancelled when the stream is
+    dropped.
+
+    [`PublicAddrChange`]: ../public_addr/struct.PublicAddrChange.html
+    */
+    pub fn public_addr_changes(&mut self) -> mpsc::UnboundedReceiver<PublicAddrChange> {
+        let (tx, rx) = mpsc::unbounded();
+        self.senders.public_addr.push(tx);
+        rx
+    }
+
+    /**
+    Add node to the list of nodes that should be pinged before adding to
+    the Close List.
+
+    Node isn't added if it's already in the Close List, is being checked
+    already or if there are already [`MAX_TO_PING`] nodes that are checked.
+
+    [`MAX_TO_PING`]: ./constant.MAX_TO_PING.html
+    */
+    fn add_to_ping(&mut self, node: &PackedNode) {
+        if node.pk() == self.pk() || self.kbucket.contains(node.pk()) ||
+            self.to_ping_nodes.iter().chain(self.to_close_nodes.iter())
+                .any(|n| n.pk() == node.pk()) {
+            return
+        }
+        if self.to_ping_nodes.len() + self.to_close_nodes.len() >= MAX_TO_PING {
+            trace!("Too many nodes are checked already, dropping {:?}", node);
+            return
+        }
+        self.to_ping_nodes.push_back(*node);
+    }
+
+    /**
+    Create [`ToxUdpPacket`]s with [`PingRequest`] requests to nodes that should
+    be checked for being online before adding to the Close List. Nodes that
+    didn't respond within [`PING_TIMEOUT`] are dropped.
+
+    Should be called frequently, e.g. every second.
+
+    [`PING_TIMEOUT`]: ../timeout/constant.PING_TIMEOUT.html
+    [`PingRequest`]: ../dht_new/packet/struct.PingRequest.html
+    [`ToxUdpPacket`]: ./type.ToxUdpPacket.html
+    */
+    pub fn ping_to_close_nodes(&mut self) -> Vec<ToxUdpPacket> {
+        for pk in self.ping_timeout.get_timed_out(PING_TIMEOUT) {
+            debug!("Node didn't respond to ping, not adding it to the Close List");
+            self.to_close_nodes.retain(|node| *node.pk() != pk);
+        }
+        let nodes = self.to_ping_nodes.drain(..).collect::<Vec<PackedNode>>();
+        nodes.into_iter()
+            .map(|node| {
+                self.to_close_nodes.push_back(node);
+                self.request_ping(&node)
             })
             .collect()
     }
 
-
     /**
-    Create a [`DhtPacket`]  to peer with `peer_pk` `PublicKey`
-    containing [`SendNodes`] response.
+    Create [`ToxUdpPacket`]s with [`PingRequest`] requests to nodes from the
+    Close List that weren't pinged for [`RESPONSE_CHECK`] seconds, so that
+    nodes that stopped responding become `Bad` and get replaced.
+
+    Should be called frequently, e.g. every second.
+
+    [`PingRequest`]: ../dht_new/packet/struct.PingRequest.html
+    [`RESPONSE_CHECK`]: ../timeout/constant.RESPONSE_CHECK.html
+    [`ToxUdpPacket`]: ./type.ToxUdpPacket.html
+    */
+    pub fn ping_close_list(&mut self) -> Vec<ToxUdpPacket> {
+        self.kbucket.nodes_to_ping().iter()
+            .map(|node| self.request_ping(node))
+            .collect()
+    }
+
+    /**
+    Handle [`DhtPacket`] that claims to contain [`PingResponse`] packet.
+
+    If the node is in the Close List, its last response time is updated.
+    If it's a response to the ping request that checks whether the node is
+    online, the node is added to the Close List and to Close Lists of
+    friends. If it's a friend, the friend is reachable directly at `addr`.
+
+    [`DhtPacket`]: ../dht_new/packet/struct.DhtPacket.html
+    [`PingResponse`]: ../dht_new/packet/struct.PingResponse.html
+    */
+    fn handle_packet_ping_resp(&mut self, addr: SocketAddr, packet: &DhtPacket) {
+        let shared_secret = self.precomputed_key(&packet.sender_pk);
+        let resp = match packet.get_payload_precomputed::<PingResponse>(&shared_secret) {
+            Some(resp) => resp,
+            None => {
+                debug!("Wrong DhtPacket; should have contained PingResponse");
+                return
+            },
+        };
+        if !self.ping_timeout.check(&packet.sender_pk, resp.id(), PING_TIMEOUT) {
+            debug!("Received PingResponse is not a response to our request");
+            return
+        }
+        if self.kbucket.record_response(&packet.sender_pk) {
+            trace!("Node from the Close List responded to ping");
+        }
+        if let Some(friend) = self.friends.iter_mut().find(|friend| *friend.pk() == packet.sender_pk) {
+            friend.handle_ping_response(addr);
+        }
+        let index = match self.to_close_nodes.iter()
+            .position(|node| *node.pk() == packet.sender_pk) {
+            Some(index) => index,
+            None => return,
+        };
+        if let Some(node) = self.to_close_nodes.remove(index) {
+            debug!("Node is online, adding it to the Close List");
+            self.try_add(&node);
+            for friend in &mut self.friends {
+                friend.try_add(&node);
+            }
+        }
+    }
+
+    /**
+    Function to handle incoming [`DhtPacket`]s. If there is a response
+    packet, `Some(DhtPacket)` is returned.
+
+    [`DhtPacket`]: ../dht_new/packet/struct.DhtPacket.html
+    */
+    fn handle_dht_packet(&mut self, addr: SocketAddr, packet: &DhtPacket)
+        -> Option<DhtPacket>
+    {
+        match packet.kind() {
+            PacketKind::PingReq => {
+                debug!("Received ping request");
+                self.create_ping_resp(packet)
+            },
+            PacketKind::GetN => {
+                debug!("Received GetN request");
+                self.create_sendn(packet)
+            },
+            PacketKind::SendN => {
+                debug!("Received SendN packet");
+                self.handle_packet_sendn(packet);
+                None
+            },
+            PacketKind::PingResp => {
+                debug!("Received ping response");
+                self.handle_packet_ping_resp(addr, packet);
+                None
+            },
+            p => {
+                debug!("Received unhandled DhtPacket kind: {:?}", p);
+                None
+            },
+        }
+    }
+
+    /**
+    Create [`DhtRequest`] with `payload` to node with `rpk` `PublicKey`.
+
+    [`DhtRequest`]: ../dht_new/packet/struct.DhtRequest.html
+    */
+    fn create_dht_request(&self, rpk: &PublicKey, payload: &DhtRequestPayload) -> DhtRequest {
+        let shared_secret = &self.precomputed_key(rpk);
+        let nonce = &gen_nonce();
+        DhtRequest::new(shared_secret, rpk, self.pk(), nonce, payload)
+    }
+
+    /**
+    Handle [`DhtRequest`] received from `addr`.
+
+    Requests to other nodes are forwarded to them if they are in the Close
+    List. Requests to us are accepted only from friends:
+    [`NatPingRequest`] is answered with [`NatPingResponse`] sent back
+    through the node it came from, and [`NatPingResponse`] makes hole
+    punching with the friend start.
+
+    [`DhtRequest`]: ../dht_new/packet/struct.DhtRequest.html
+    [`NatPingRequest`]: ../dht_new/packet/struct.NatPingRequest.html
+    [`NatPingResponse`]: ../dht_new/packet/struct.NatPingResponse.html
+    */
+    fn handle_dht_request(&mut self, addr: SocketAddr, packet: &DhtRequest)
+        -> Option<ToxUdpPacket>
+    {
+        if packet.rpk != *self.pk() {
+            return match self.kbucket.iter().find(|node| *node.pk() == packet.rpk) {
+                Some(node) => Some((node.socket_addr(), packet.clone().into())),
+                None => {
+                    debug!("Receiver of DhtRequest is not in the Close List");
+                    None
+                },
+            }
+        }
+
+        let payload = match packet.get_payload(&self.precomputed_key(&packet.spk)) {
+            Some(payload) => payload,
+            None => {
+                debug!("Failed to decrypt DhtRequest payload");
+                return None
+            },
+        };
+        let response = {
+            let friend = match self.friends.iter_mut().find(|friend| *friend.pk() == packet.spk) {
+                Some(friend) => friend,
+                None => {
+                    debug!("Received DhtRequest not from a friend");
+                    return None
+                },
+            };
+            match payload {
+                DhtRequestPayload::NatPingRequest(ref request) => {
+                    debug!("Received NatPingRequest");
+                    friend.handle_nat_ping_request(request)
+                },
+                DhtRequestPayload::NatPingResponse(ref response) => {
+                    debug!("Received NatPingResponse");
+                    friend.handle_nat_ping_response(response);
+                    return None
+                },
+            }
+        };
+        let payload = DhtRequestPayload::NatPingResponse(response);
+        Some((addr, self.create_dht_request(&packet.spk, &payload).into()))
+    }
+
+    /**
+    Get symmetric key for encrypting and decrypting [`OnionReturn`]s.
+
+    The key is replaced with a new one every
+    [`ONION_KEY_REFRESH_INTERVAL`] seconds, after which responses to
+    onion requests relayed with the old key are dropped.
+
+    [`OnionReturn`]: ../onion/packet/struct.OnionReturn.html
+    [`ONION_KEY_REFRESH_INTERVAL`]: ../onion/constant.ONION_KEY_REFRESH_INTERVAL.html
+    */
+    fn onion_symmetric_key(&mut self) -> &PrecomputedKey {
+        let refresh_interval = Duration::from_secs(ONION_KEY_REFRESH_INTERVAL);
+        if self.onion_symmetric_key_time.elapsed() >= refresh_interval {
+            debug!("Refreshing onion symmetric key");
+            self.onion_symmetric_key = new_symmetric_key();
+            self.onion_symmetric_key_time = Instant::now();
+        }
+        &self.onion_symmetric_key
+    }
+
+    /**
+    Handle [`OnionRequest0`] received from `addr` by forwarding it as
+    [`OnionRequest1`] to the next node of the onion path.
+
+    [`OnionRequest0`]: ../onion/packet/struct.OnionRequest0.html
+    [`OnionRequest1`]: ../onion/packet/struct.OnionRequest1.html
+    */
+    fn handle_onion_request_0(&mut self, addr: SocketAddr, packet: &OnionRequest0)
+        -> Option<ToxUdpPacket>
+    {
+        let payload = match packet.get_payload(self.sk()) {
+            Some(payload) => payload,
+            None => {
+                debug!("Failed to decrypt OnionRequest0 payload");
+                return None
+            },
+        };
+        let onion_return = OnionReturn::new(self.onion_symmetric_key(), &addr, None);
+        let next_packet = OnionRequest1 {
+            nonce: packet.nonce,
+            temporary_pk: payload.temporary_pk,
+            payload: payload.inner,
+            onion_return: onion_return,
+        };
+        Some((payload.ip_port, UdpPacket::OnionRequest1(next_packet)))
+    }
+
+    /**
+    Handle [`OnionRequest1`] received from `addr` by forwarding it as
+    [`OnionRequest2`] to the next node of the onion path.
+
+    [`OnionRequest1`]: ../onion/packet/struct.OnionRequest1.html
+    [`OnionRequest2`]: ../onion/packet/struct.OnionRequest2.html
+    */
+    fn handle_onion_request_1(&mut self, addr: SocketAddr, packet: &OnionRequest1)
+        -> Option<ToxUdpPacket>
+    {
+        let payload = match packet.get_payload(self.sk()) {
+            Some(payload) => payload,
+            None => {
+                debug!("Failed to decrypt OnionRequest1 payload");
+                return None
+            },
+        };
+        let onion_return = OnionReturn::new(self.onion_symmetric_key(), &addr,
+                                            Some(&packet.onion_return));
+        let next_packet = OnionRequest2 {
+            nonce: packet.nonce,
+            temporary_pk: payload.temporary_pk,
+            payload: payload.inner,
+            onion_return: onion_return,
+        };
+        Some((payload.ip_port, UdpPacket::OnionRequest2(next_packet)))
+    }
+
+    /**
+    Handle [`OnionRequest2`] received from `addr` by forwarding the inner
+    request to its destination.
+
+    [`OnionRequest2`]: ../onion/packet/struct.OnionRequest2.html
+    */
+    fn handle_onion_request_2(&mut self, addr: SocketAddr, packet: &OnionRequest2)
+        -> Option<ToxUdpPacket>
+    {
+        let payload = match packet.get_payload(self.sk()) {
+            Some(payload) => payload,
+            None => {
+                debug!("Failed to decrypt OnionRequest2 payload");
+                return None
+            },
+        };
+        let onion_return = OnionReturn::new(self.onion_symmetric_key(), &addr,
+                                            Some(&packet.onion_return));
+        let next_packet = match payload.inner {
+            InnerOnionRequest::InnerOnionAnnounceRequest(inner) =>
+                UdpPacket::OnionAnnounceRequest(OnionAnnounceRequest {
+                    inner: inner,
+                    onion_return: onion_return,
+                }),
+            InnerOnionRequest::InnerOnionDataRequest(inner) =>
+                UdpPacket::OnionDataRequest(OnionDataRequest {
+                    inner: inner,
+                    onion_return: onion_return,
+                }),
+        };
+        Some((payload.ip_port, next_packet))
+    }
+
+    /**
+    Handle [`OnionResponse3`] by sending it back as [`OnionResponse2`] to
+    the address stored in its [`OnionReturn`].
+
+    [`OnionResponse3`]: ../onion/packet/struct.OnionResponse3.html
+    [`OnionResponse2`]: ../onion/packet/struct.OnionResponse2.html
+    [`OnionReturn`]: ../onion/packet/struct.OnionReturn.html
+    */
+    fn handle_onion_response_3(&mut self, packet: &OnionResponse3)
+        -> Option<ToxUdpPacket>
+    {
+        match packet.onion_return.get_payload(self.onion_symmetric_key()) {
+            Some((ip_port, Some(onion_return))) => {
+                let next_packet = OnionResponse2 {
+                    onion_return: onion_return,
+                    payload: packet.payload.clone(),
+                };
+                Some((ip_port, UdpPacket::OnionResponse2(next_packet)))
+            },
+            _ => {
+                debug!("Invalid OnionReturn in OnionResponse3");
+                None
+            },
+        }
+    }
+
+    /**
+    Handle [`OnionResponse2`] by sending it back as [`OnionResponse1`] to
+    the address stored in its [`OnionReturn`].
+
+    [`OnionResponse2`]: ../onion/packet/struct.OnionResponse2.html
+    [`OnionResponse1`]: ../onion/packet/struct.OnionResponse1.html
+    [`OnionReturn`]: ../onion/packet/struct.OnionReturn.html
+    */
+    fn handle_onion_response_2(&mut self, packet: &OnionResponse2)
+        -> Option<ToxUdpPacket>
+    {
+        match packet.onion_return.get_payload(self.onion_symmetric_key()) {
+            Some((ip_port, Some(onion_return))) => {
+                let next_packet = OnionResponse1 {
+                    onion_return: onion_return,
+                    payload: packet.payload.clone(),
+                };
+                Some((ip_port, UdpPacket::OnionResponse1(next_packet)))
+            },
+            _ => {
+                debug!("Invalid OnionReturn in OnionResponse2");
+                None
+            },
+        }
+    }
+
+    /**
+    Handle [`OnionResponse1`] by sending the response it carries to the
+    owner of the onion path, whose address is stored in its
+    [`OnionReturn`]. Responses for clients of our TCP relay are passed to
+    the sink set by [`set_tcp_onion_sink`].
+
+    [`OnionResponse1`]: ../onion/packet/struct.OnionResponse1.html
+    [`OnionReturn`]: ../onion/packet/struct.OnionReturn.html
+    [`set_tcp_onion_sink`]: #method.set_tcp_onion_sink
+    */
+    fn handle_onion_response_1(&mut self, packet: &OnionResponse1)
+        -> Option<ToxUdpPacket>
+    {
+        let udp_return = packet.onion_return.get_payload(self.onion_symmetric_key());
+        if let Some((ip_port, None)) = udp_return {
+            return Some((ip_port, packet.payload.clone().into()))
+        }
+        let tcp_return = packet.onion_return.get_tcp_payload(self.onion_symmetric_key());
+        match tcp_return {
+            Some(ip_port) => {
+                match self.senders.tcp_onion {
+                    Some(ref tx) => {
+                        if tx.unbounded_send((packet.payload.clone(), ip_port)).is_err() {
+                            debug!("TCP relay is gone, dropping OnionResponse1");
+                        }
+                    },
+                    None => debug!("Received OnionResponse1 for TCP client without TCP relay"),
+                }
+                None
+            },
+            None => {
+                debug!("Invalid OnionReturn in OnionResponse1");
+                None
+            },
+        }
+    }
+
+    /**
+    Set sink for responses to onion requests that were received from
+    clients of our TCP relay with [`handle_tcp_onion_request`]. Responses
+    are sent along with the address of the client's TCP connection.
+
+    [`handle_tcp_onion_request`]: #method.handle_tcp_onion_request
+    */
+    pub fn set_tcp_onion_sink(&mut self, tx: mpsc::UnboundedSender<(InnerOnionResponse, SocketAddr)>) {
+        self.senders.tcp_onion = Some(tx);
+    }
+
+    /**
+    Handle [`OnionDataRequest`] received by our TCP relay from the client
+    with TCP connection address `addr` by forwarding it as [`OnionRequest1`]
+    to the next node of the onion path. Response to it will be sent to the
+    sink set by [`set_tcp_onion_sink`].
+
+    [`OnionDataRequest`]: ../tcp/packet/struct.OnionDataRequest.html
+    [`OnionRequest1`]: ../onion/packet/struct.OnionRequest1.html
+    [`set_tcp_onion_sink`]: #method.set_tcp_onion_sink
+    */
+    pub fn handle_tcp_onion_request(&mut self, addr: SocketAddr, packet: &TcpOnionRequest)
+        -> ToxUdpPacket
+    {
+        let onion_return = OnionReturn::new_tcp(self.onion_symmetric_key(), &addr);
+        let next_packet = OnionRequest1 {
+            nonce: packet.nonce,
+            temporary_pk: packet.temporary_pk,
+            payload: packet.payload.clone(),
+            onion_return: onion_return,
+        };
+        (packet.ip_port, UdpPacket::OnionRequest1(next_packet))
+    }
+
+    /**
+    Handle [`OnionAnnounceRequest`] received from `addr` by announcing
+    the sender or searching for the requested node in the announce list.
 
-    Returns `None` if own `Kbucket` is empty or supplied `DhtPacket`
-    doesn't contain [`GetNodes`] request.
+    Response contains up to 4 nodes from the Close List that are closest
+    to the searched `PublicKey` and is sent back through the onion path as
+    [`OnionResponse3`].
 
-    [`DhtPacket`]: ../dht/struct.DhtPacket.html
-    [`GetNodes`]: ../dht/struct.GetNodes.html
-    [`SendNodes`]: ../dht/struct.SendNodes.html
+    [`OnionAnnounceRequest`]: ../onion/packet/struct.OnionAnnounceRequest.html
+    [`OnionResponse3`]: ../onion/packet/struct.OnionResponse3.html
     */
-    fn create_sendn(&self, request: &DhtPacket)
-        -> Option<DhtPacket>
+    fn handle_onion_announce_request(&mut self, addr: SocketAddr, packet: &OnionAnnounceRequest)
+        -> Option<ToxUdpPacket>
     {
-        // TODO: precompute shared key to calculate it 1 time
-        let getn = match request.get_payload::<GetNodes>(self.sk()) {
-            Some(g) => g,
-            None => return None,
+        // computed once for both decryption of the request and encryption
+        // of the response; announce keys are not cached so that they don't
+        // evict keys of DHT nodes
+        let shared_secret = encrypt_precompute(&packet.inner.pk, self.sk());
+        let payload = match packet.inner.get_payload(&shared_secret) {
+            Some(payload) => payload,
+            None => {
+                debug!("Failed to decrypt OnionAnnounceRequest payload");
+                return None
+            },
         };
-        let sendn = match getn.response(&*self.kbucket) {
-            Some(s) => s,
-            None => return None,
+
+        let (announce_status, ping_id_or_pk) =
+            self.onion_announce.handle_onion_announce_request(
+                &payload, packet.inner.pk, packet.onion_return.clone(), addr);
+
+        let response_payload = OnionAnnounceResponsePayload {
+            announce_status: announce_status,
+            ping_id_or_pk: ping_id_or_pk,
+            nodes: self.kbucket.get_closest(&payload.search_pk),
         };
-        let shared_secret = &encrypt_precompute(&request.sender_pk, self.sk());
-        let nonce = &gen_nonce();
-        Some(DhtPacket::new(shared_secret, self.pk(), nonce, &sendn))
+        let response = OnionAnnounceResponse::new(&shared_secret,
+            payload.sendback_data, &response_payload);
+
+        let response_packet = OnionResponse3 {
+            onion_return: packet.onion_return.clone(),
+            payload: InnerOnionResponse::OnionAnnounceResponse(response),
+        };
+        Some((addr, UdpPacket::OnionResponse3(response_packet)))
     }
 
     /**
-    Send nodes in response to [`GetNodes`] request contained in
-    [`DhtPacket`].
-
-    Can fail (return `None`) if Kbucket is empty or `DhtPacket` doesn't
-    contain `GetNodes` request.
+    Create [`LanDiscoverySender`] that creates [`LanDiscovery`] packets
+    with own DHT `PublicKey`. `ipv6` should be `true` if packets will be
+    sent from IPv6 socket.
 
-    [`DhtPacket`]: ../dht/struct.DhtPacket.html
-    [`GetNodes`]: ../dht/struct.GetNodes.html
+    [`LanDiscovery`]: ../dht_new/packet/struct.LanDiscovery.html
+    [`LanDiscoverySender`]: ../lan_discovery/struct.LanDiscoverySender.html
     */
-    pub fn send_nodes(&self,
-                      sink: ToxSplitSink,
-                      peer_addr: SocketAddr,
-                      request: &DhtPacket)
-        -> Option<SendSink>
-    {
-        self.create_sendn(request)
-            .map(|sn| sink.send((peer_addr, sn)))
+    pub fn lan_discovery_sender(&self, ipv6: bool) -> LanDiscoverySender {
+        LanDiscoverySender::new(*self.pk(), ipv6)
     }
 
     /**
-    Handle [`DhtPacket`] that claims to contain [`SendNodes`] packet.
+    Handle [`LanDiscovery`] packet received from `addr`.
 
-    Packet is dropped if:
+    If the packet came from the local network and wasn't sent by us, the
+    sender is checked the same way as nodes from [`SendNodes`]: it's pinged
+    right away and added to the Close List after it responds.
 
-    - it doesn't contain [`SendNodes`]
-    - it's not a response to a [`GetNodes`] request (invalid ID)
+    [`SendNodes`]: ../dht_new/packet/struct.SendNodes.html
 
-    [`DhtPacket`]: ../dht/struct.DhtPacket.html
-    [`GetNodes`]: ../dht/struct.GetNodes.html
-    [`SendNodes`]: ../dht/struct.SendNodes.html
+    [`LanDiscovery`]: ../dht_new/packet/struct.LanDiscovery.html
     */
-    fn handle_packet_sendn(&mut self, packet: &DhtPacket) {
-        match packet.get_payload::<SendNodes>(self.sk()) {
-            Some(sn) => {
-                if self.getn_timeout.remove(sn.id) {
-                    debug!("Received SendN is a valid response");
-                    // received SendNodes packet is a response to our request
-                    trace!("Adding nodes from SendNodes to DhtNode's Kbucket");
-                    for node in &sn.nodes {
-                        self.try_add(node);
-                    }
-                }
-            },
-            None =>
-                debug!("Wrong DhtPacket; should have contained SendNodes"),
+    fn handle_lan_discovery(&mut self, addr: SocketAddr, packet: &LanDiscovery)
+        -> Option<ToxUdpPacket>
+    {
+        if !is_lan_ip(&addr.ip()) {
+            debug!("Received LanDiscovery from non-LAN address {}", addr);
+            return None
         }
+        if packet.pk == *self.pk() {
+            trace!("Received own LanDiscovery");
+            return None
+        }
+        self.add_to_ping(&PackedNode::new(true, addr, &packet.pk));
+        // don't wait for `ping_to_close_nodes()` to ping the sender
+        let index = match self.to_ping_nodes.iter().position(|node| node.pk() == &packet.pk) {
+            Some(index) => index,
+            None => return None,
+        };
+        let node = match self.to_ping_nodes.remove(index) {
+            Some(node) => node,
+            None => return None,
+        };
+        self.to_close_nodes.push_back(node);
+        Some(self.request_ping(&node))
     }
 
     /**
-    Function to handle incoming packets. If there is a response packet,
-    `Some(DhtPacket)` is returned.
+    Function to handle incoming packets. If there is a packet that has to
+    be sent in response, `Some(ToxUdpPacket)` is returned.
+
+    Onion packets are relayed to the next node of the onion path or back
+    to the previous one. Onion announce requests are answered using own
+    announce list, and onion data requests are forwarded to the announced
+    destination node. LAN discovery packets are answered with ping
+    requests. DHT requests are forwarded to nodes from the Close List or,
+    if they are sent to us by friends, used for hole punching.
     */
-    pub fn handle_packet(&mut self, packet: &DhtPacket)
-        -> Option<DhtPacket>
+    pub fn handle_packet(&mut self, addr: SocketAddr, packet: &UdpPacket)
+        -> Option<ToxUdpPacket>
     {
-        match packet.kind() {
-            PacketKind::PingReq => {
-                debug!("Received ping request");
-                self.create_ping_resp(packet)
+        match *packet {
+            UdpPacket::Dht(ref packet) =>
+                self.handle_dht_packet(addr, packet)
+                    .map(|resp| (addr, resp.into())),
+            UdpPacket::DhtRequest(ref packet) => {
+                debug!("Received DhtRequest");
+                self.handle_dht_request(addr, packet)
             },
-            PacketKind::GetN => {
-                debug!("Received GetN request");
-                self.create_sendn(packet)
+            UdpPacket::OnionRequest0(ref packet) => {
+                debug!("Received OnionRequest0");
+                self.handle_onion_request_0(addr, packet)
             },
-            PacketKind::SendN => {
-                debug!("Received SendN packet");
-                self.handle_packet_sendn(packet);
-                None
+            UdpPacket::OnionRequest1(ref packet) => {
+                debug!("Received OnionRequest1");
+                self.handle_onion_request_1(addr, packet)
             },
-            // TODO: handle other kinds of packets
-            p => {
-                debug!("Received unhandled packet kind: {:?}", p);
+            UdpPacket::OnionRequest2(ref packet) => {
+                debug!("Received OnionRequest2");
+                self.handle_onion_request_2(addr, packet)
+            },
+            UdpPacket::OnionAnnounceRequest(ref packet) => {
+                debug!("Received OnionAnnounceRequest");
+                self.handle_onion_announce_request(addr, packet)
+            },
+            UdpPacket::OnionDataRequest(ref packet) => {
+                debug!("Received OnionDataRequest");
+                self.onion_announce.handle_data_request(packet)
+                    .map(|(addr, response)|
+                        (addr, UdpPacket::OnionResponse3(response)))
+            },
+            UdpPacket::OnionResponse3(ref packet) => {
+                debug!("Received OnionResponse3");
+                self.handle_onion_response_3(packet)
+            },
+            UdpPacket::OnionResponse2(ref packet) => {
+                debug!("Received OnionResponse2");
+                self.handle_onion_response_2(packet)
+            },
+            UdpPacket::OnionResponse1(ref packet) => {
+                debug!("Received OnionResponse1");
+                self.handle_onion_response_1(packet)
+            },
+            UdpPacket::LanDiscovery(ref packet) => {
+                debug!("Received LanDiscovery");
+                self.handle_lan_discovery(addr, packet)
+            },
+            ref p => {
+                debug!("Received unhandled packet: {:?}", p);
                 None
             },
         }
@@ -428,18 +1549,35 @@ impl DhtNode {
 /// Struct to use for {de-,}serializing Tox UDP packets.
 // TODO: move elsewhere(?)
 // TODO: rename? or implement UdpCodec for something else (enum)
-pub struct ToxCodec;
+pub struct ToxCodec {
+    /// Whether the socket is IPv6 one, IPv4 addresses have to be mapped to
+    /// IPv6 to send packets via it.
+    ipv6: bool,
+}
+
+impl ToxCodec {
+    /** Create codec for `socket`. If the socket is bound to IPv6 address
+    IPv4 destinations are sent to as IPv4-mapped IPv6 addresses.
+    */
+    pub fn new(socket: &UdpSocket) -> ToxCodec {
+        let ipv6 = socket.local_addr()
+            .map(|addr| addr.is_ipv6())
+            .unwrap_or(false);
+        ToxCodec { ipv6: ipv6 }
+    }
+}
 
 impl UdpCodec for ToxCodec {
-    // TODO: make `In`/`Out` support more than just DhtPacket
-    //       (by using enum or Trait: FromBytes + ToBytes ?)
     type In = ToxRecvUdpPacket;
     type Out = ToxUdpPacket;
 
     fn decode(&mut self, src: &SocketAddr, buf: &[u8]) -> io::Result<Self::In>
     {
-        match DhtPacket::from_bytes(buf) {
-            Some(dp) => Ok((*src, Some(dp))),
+        // dual-stack socket receives packets from IPv4 peers with
+        // IPv4-mapped addresses
+        let src = normalize_addr(*src);
+        match UdpPacket::from_bytes(buf) {
+            Some(packet) => Ok((src, Some(packet))),
             None => {
                 match PacketKind::from_bytes(buf) {
                     Some(p) => {
@@ -452,14 +1590,19 @@ impl UdpCodec for ToxCodec {
                             src, buf);
                     },
                 }
-                Ok((*src, None))
+                Ok((src, None))
             }
         }
     }
 
-    fn encode(&mut self, (addr, dp): Self::Out, into: &mut Vec<u8>) -> SocketAddr {
-        into.extend(dp.to_bytes());
-        addr
+    fn encode(&mut self, (addr, packet): Self::Out, into: &mut Vec<u8>) -> SocketAddr {
+        into.extend(packet.to_bytes());
+        match addr {
+            // dual-stack socket can't send to IPv4 address directly
+            SocketAddr::V4(v4) if self.ipv6 =>
+                SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port()),
+            _ => addr,
+        }
     }
 }
 
@@ -531,9 +1674,12 @@ mod test {
     use std::net::SocketAddr;
     use std::time::Duration;
 
-    use toxcore::binary_io::*;
+    use toxcore::bootstrap::BootstrapNode;
     use toxcore::crypto_core::*;
-    use toxcore::dht::*;
+    use toxcore::dht_new::binary_io::FromBytes as OnionFromBytes;
+    use toxcore::dht_new::kbucket::*;
+    use toxcore::dht_new::packed_node::PackedNode;
+    use toxcore::dht_new::packet::*;
     use toxcore::network::*;
     use toxcore::dht_node::*;
     use toxcore::packet_kind::PacketKind;
@@ -619,6 +1765,16 @@ mod test {
         )+)
     }
 
+    /// Get `DhtPacket` from `UdpPacket`, panic if it's something else.
+    macro_rules! unpack_dht_packet {
+        ($packet:expr) => (
+            match $packet {
+                UdpPacket::Dht(packet) => packet,
+                packet => panic!("Expected DhtPacket, got {:?}", packet),
+            }
+        )
+    }
+
     // DhtNode::
 
     // DhtNode::new()
@@ -628,6 +1784,29 @@ mod test {
         let _ = DhtNode::new().unwrap();
     }
 
+    // DhtNode::clone()
+
+    #[test]
+    fn dht_node_clone_test() {
+        let mut alice = DhtNode::new().unwrap();
+        let mut bob = alice.clone();
+        assert!(alice == bob);
+        assert!(alice != DhtNode::new().unwrap());
+
+        // clones don't share senders
+        let (tx, _rx) = mpsc::unbounded();
+        alice.set_tcp_onion_sink(tx);
+        assert!(bob.senders.tcp_onion.is_none());
+
+        // using the cache doesn't make nodes different
+        let _ = alice.precomputed_key(&gen_keypair().0);
+        assert!(alice == bob);
+
+        let bob_node = PackedNode::new(true, "2.2.2.2:2".parse().unwrap(), &gen_keypair().0);
+        assert!(bob.try_add(&bob_node));
+        assert!(alice != bob);
+    }
+
     // DhtNode::try_add()
 
     #[test]
@@ -638,7 +1817,7 @@ mod test {
 
             for pn in &pns {
                 assert_eq!(dhtn.try_add(pn), kbuc.try_add(pn));
-                assert_eq!(kbuc, *dhtn.kbucket);
+                assert!(kbuc.iter().eq(dhtn.kbucket.iter()));
             }
         }
         quickcheck(with_nodes as fn(Vec<PackedNode>));
@@ -661,6 +1840,40 @@ mod test {
         assert_eq!(&*dn.dht_secret_key, dn.sk());
     }
 
+    // DhtNode::precomputed_key()
+
+    #[test]
+    fn dht_node_precomputed_key_test() {
+        let alice = DhtNode::new().unwrap();
+        let (bob_pk, _) = gen_keypair();
+        let key = alice.precomputed_key(&bob_pk);
+        assert_eq!(encrypt_precompute(&bob_pk, alice.sk()), key);
+        assert_eq!(key, alice.precomputed_key(&bob_pk));
+        assert_eq!(1, alice.precomputed_cache().hits());
+        assert_eq!(1, alice.precomputed_cache().misses());
+
+        // cache is used when packets are created
+        alice.create_ping_req(&bob_pk);
+        assert_eq!(2, alice.precomputed_cache().hits());
+        assert_eq!(1, alice.precomputed_cache().misses());
+    }
+
+    // DhtNode::remove_timed_out()
+
+    #[test]
+    fn dht_node_remove_timed_out_keeps_node() {
+        let mut alice = DhtNode::new().unwrap();
+        let (bob_pk, _) = gen_keypair();
+        let bob = PackedNode::new(true, "1.2.3.4:33445".parse().unwrap(), &bob_pk);
+        assert!(alice.try_add(&bob));
+
+        // bob doesn't answer GetNodes request
+        alice.request_nodes(&bob);
+        alice.remove_timed_out(0);
+        assert_eq!(0, alice.getn_timeout.len());
+        assert!(alice.kbucket.contains(&bob_pk));
+    }
+
     // DhtNode::create_ping_req()
 
     #[test]
@@ -668,55 +1881,163 @@ mod test {
         let alice = DhtNode::new().unwrap();
         let (bob_pk, bob_sk) = gen_keypair();
         let (_, eve_sk) = gen_keypair();
-        let packet1 = alice.create_ping_req(&bob_pk);
+        let (id1, packet1) = alice.create_ping_req(&bob_pk);
         assert_eq!(alice.pk(), &packet1.sender_pk);
         assert_eq!(PacketKind::PingReq, packet1.kind());
 
-        let packet2 = alice.create_ping_req(&bob_pk);
+        let (id2, packet2) = alice.create_ping_req(&bob_pk);
         assert_ne!(packet1, packet2);
 
         // eve can't decrypt it
-        assert_eq!(None, packet1.get_payload::<PingReq>(&eve_sk));
+        assert_eq!(None, packet1.get_payload::<PingRequest>(&eve_sk));
 
-        let payload1: PingReq = packet1.get_payload(&bob_sk)
+        let payload1: PingRequest = packet1.get_payload(&bob_sk)
             .expect("failed to get payload1");
-        let payload2: PingReq = packet2.get_payload(&bob_sk)
+        let payload2: PingRequest = packet2.get_payload(&bob_sk)
             .expect("failed to get payload2");
         assert_ne!(payload1.id(), payload2.id());
+        assert_eq!(id1, payload1.id());
+        assert_eq!(id2, payload2.id());
 
         // wrong packet kind
         cant_parse_as_packet!(packet1, bob_sk,
-            PingResp GetNodes SendNodes);
+            PingResponse GetNodes SendNodes);
     }
 
     // DhtNode::request_ping()
 
     #[test]
     fn dht_node_request_ping_test() {
-        // bob creates & sends PingReq to alice
-        // received PingReq has to be succesfully decrypted
+        // bob creates & sends PingRequest to alice
+        // received PingRequest has to be succesfully decrypted
         create_core!(core, handle);
         node_socket!(handle, alice, alice_socket);
-        let bob = DhtNode::new().unwrap();
+        let mut bob = DhtNode::new().unwrap();
         let alice_addr = alice_socket.local_addr().unwrap();
         let alice_pn = PackedNode::new(true, alice_addr, alice.pk());
 
         let (dest_addr, bob_request) = bob.request_ping(&alice_pn);
+        assert_eq!(1, bob.ping_requests().len());
+        let bob_request = unpack_dht_packet!(bob_request);
         assert_eq!(alice_addr, dest_addr);
 
-        let payload: PingReq = bob_request
+        let payload: PingRequest = bob_request
             .get_payload(alice.sk())
             .expect("Failed to decrypt payload");
 
         assert_eq!(PacketKind::PingReq, payload.kind());
     }
 
+    // DhtNode::ping_requests()
+
+    #[test]
+    fn dht_node_unsolicited_ping_resp_test() {
+        let mut alice = DhtNode::new().unwrap();
+        let mut bob = DhtNode::new().unwrap();
+        let bob_node = PackedNode::new(true, "2.2.2.2:2".parse().unwrap(), bob.pk());
+
+        // forged response to request that alice never sent
+        let precomp = precompute(alice.pk(), bob.sk());
+        let forged = DhtPacket::new(&precomp, bob.pk(), &gen_nonce(),
+            &PingResponse::from(PingRequest::new()));
+        assert_eq!(None, alice.handle_dht_packet(bob_node.socket_addr(), &forged));
+        assert_eq!(1, alice.ping_requests().unsolicited());
+
+        let (addr, request) = alice.request_ping(&bob_node);
+        let (_, response) = bob.handle_packet(addr, &request).unwrap();
+        assert_eq!(None, alice.handle_packet(addr, &response));
+        assert_eq!(1, alice.ping_requests().unsolicited());
+        assert!(alice.ping_requests().is_empty());
+
+        // the same response is unsolicited the second time
+        assert_eq!(None, alice.handle_packet(addr, &response));
+        assert_eq!(2, alice.ping_requests().unsolicited());
+    }
+
+    // DhtNode::getn_requests()
+
+    #[test]
+    fn dht_node_unsolicited_send_nodes_test() {
+        let mut alice = DhtNode::new().unwrap();
+        let mut bob = DhtNode::new().unwrap();
+        let bob_node = PackedNode::new(true, "2.2.2.2:2".parse().unwrap(), bob.pk());
+        let (carol_pk, _) = gen_keypair();
+        assert!(bob.try_add(&PackedNode::new(true, "3.3.3.3:3".parse().unwrap(), &carol_pk)));
+
+        // response to GetNodes that wasn't tracked is dropped
+        let (_, getn) = alice.create_getn(bob.pk());
+        let response = bob.handle_dht_packet(bob_node.socket_addr(), &getn).unwrap();
+        assert_eq!(None, alice.handle_dht_packet(bob_node.socket_addr(), &response));
+        assert_eq!(1, alice.getn_requests().unsolicited());
+        assert!(alice.to_ping_nodes.is_empty());
+
+        assert!(alice.try_add(&bob_node));
+        let (addr, request) = alice.request_nodes_close().remove(0);
+        let (_, response) = bob.handle_packet(addr, &request).unwrap();
+        assert_eq!(None, alice.handle_packet(addr, &response));
+        assert_eq!(1, alice.getn_requests().unsolicited());
+        assert!(alice.to_ping_nodes.iter().any(|node| *node.pk() == carol_pk));
+    }
+
+    // DhtNode::bootstrap()
+
+    #[test]
+    fn dht_node_bootstrap_test() {
+        let mut alice = DhtNode::new().unwrap();
+        let mut bob = DhtNode::new().unwrap();
+        let (carol_pk, _) = gen_keypair();
+        assert!(bob.try_add(&PackedNode::new(true, "3.3.3.3:3".parse().unwrap(), &carol_pk)));
+
+        let bob_addr: SocketAddr = "2.2.2.2:2".parse().unwrap();
+        let nodes = vec![BootstrapNode {
+            pk: *bob.pk(),
+            addrs: vec![bob_addr],
+            tcp_ports: Vec::new(),
+        }];
+        let mut packets = alice.bootstrap(&nodes);
+        assert_eq!(1, packets.len());
+        assert!(alice.kbucket.contains(bob.pk()));
+
+        let (addr, request) = packets.remove(0);
+        assert_eq!(bob_addr, addr);
+        let (_, response) = bob.handle_packet(addr, &request).unwrap();
+        assert_eq!(None, alice.handle_packet(addr, &response));
+        assert!(alice.to_ping_nodes.iter().any(|node| *node.pk() == carol_pk));
+    }
+
+    // DhtNode::public_addr_changes()
+
+    #[test]
+    fn dht_node_public_addr_test() {
+        let mut alice = DhtNode::new().unwrap();
+        let changes = alice.public_addr_changes();
+        let alice_addr: SocketAddr = "1.1.1.1:1".parse().unwrap();
+
+        for i in 0..PUBLIC_ADDR_MIN_REPORTS {
+            let mut bob = DhtNode::new().unwrap();
+            let bob_node = PackedNode::new(true, SocketAddr::new("2.2.2.2".parse().unwrap(), i as u16), bob.pk());
+            assert!(bob.try_add(&PackedNode::new(true, alice_addr, alice.pk())));
+
+            let (_, (addr, request)) = alice.request_nodes(&bob_node);
+            let (_, response) = bob.handle_packet(addr, &request).unwrap();
+            assert_eq!(None, alice.handle_packet(addr, &response));
+        }
+
+        assert_eq!(Some(alice_addr), alice.public_addr_v4());
+        assert_eq!(None, alice.public_addr_v6());
+
+        // dropping the node closes the stream
+        drop(alice);
+        let changes = changes.wait().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(vec![PublicAddrChange { old: None, new: alice_addr }], changes);
+    }
+
     // DhtNode::create_ping_resp()
 
     quickcheck! {
-        fn dht_node_create_ping_resp_test(req: PingReq) -> () {
-            // alice creates DhtPacket containing PingReq request
-            // bob has to respond to it with PingResp
+        fn dht_node_create_ping_resp_test(req: PingRequest) -> () {
+            // alice creates DhtPacket containing PingRequest request
+            // bob has to respond to it with PingResponse
             // alice has to be able to decrypt response
             // eve can't decrypt response
 
@@ -738,29 +2059,29 @@ mod test {
             assert_ne!(resp1, resp2);
 
             // eve can't decrypt
-            assert_eq!(None, resp1.get_payload::<PingResp>(&eve_sk));
+            assert_eq!(None, resp1.get_payload::<PingResponse>(&eve_sk));
 
-            let resp1_payload: PingResp = resp1
+            let resp1_payload: PingResponse = resp1
                 .get_payload(alice.sk()).unwrap();
-            let resp2_payload: PingResp = resp2
+            let resp2_payload: PingResponse = resp2
                 .get_payload(alice.sk()).unwrap();
             assert_eq!(resp1_payload, resp2_payload);
             assert_eq!(req.id(), resp1_payload.id());
             assert_eq!(PacketKind::PingResp, resp1_payload.kind());
 
-            // can't create response from DhtPacket containing PingResp
+            // can't create response from DhtPacket containing PingResponse
             assert!(alice.create_ping_resp(&resp1).is_none());
 
             // wrong packet kind
-            cant_parse_as_packet!(resp1, alice.sk(), PingReq);
+            cant_parse_as_packet!(resp1, alice.sk(), PingRequest);
         }
     }
 
     // DhtNode::respond_ping()
 
     quickcheck! {
-        fn dht_node_respond_ping_test(req: PingReq) -> () {
-            // bob creates a DhtPacket with PingReq, and alice
+        fn dht_node_respond_ping_test(req: PingRequest) -> () {
+            // bob creates a DhtPacket with PingRequest, and alice
             // sends a response to it
             // response has to be successfully decrypted by alice
             // response can't be decrypted by eve
@@ -773,7 +2094,8 @@ mod test {
             let nonce = gen_nonce();
             let bob_ping = DhtPacket::new(&precomp, bob.pk(), &nonce, &req);
 
-            let (alice_sink, _) = alice_socket.framed(ToxCodec).split();
+            let codec = ToxCodec::new(&alice_socket);
+            let (alice_sink, _) = alice_socket.framed(codec).split();
             let alice_send = alice.respond_ping(
                 alice_sink,
                 bob_socket.local_addr().unwrap(),
@@ -787,16 +2109,16 @@ mod test {
             let received = core.run(future_recv).unwrap();
             let (_bob_socket, recv_buf, size, _saddr) = received;
             assert!(size != 0);
-            assert_eq!(size, bob_ping.to_bytes().len());
+            assert_eq!(size, packet_to_bytes(&bob_ping).len());
 
-            let recv_packet = DhtPacket::from_bytes(&recv_buf[..size])
-                .expect("failed to parse as DhtPacket");
+            let (_, recv_packet) = DhtPacket::from_bytes(&recv_buf[..size])
+                .unwrap();
             assert_eq!(PacketKind::PingResp, recv_packet.kind());
 
             // eve can't decrypt it
-            assert_eq!(None, recv_packet.get_payload::<PingResp>(&eve_sk));
+            assert_eq!(None, recv_packet.get_payload::<PingResponse>(&eve_sk));
 
-            let _payload: PingResp = recv_packet
+            let _payload: PingResponse = recv_packet
                 .get_payload(bob.sk()).unwrap();
         }
     }
@@ -847,6 +2169,7 @@ mod test {
         let alice_pn = PackedNode::new(true, alice_addr, alice.pk());
 
         let (id, (dest_addr, bob_request)) = bob.request_nodes(&alice_pn);
+        let bob_request = unpack_dht_packet!(bob_request);
         assert_eq!(alice_addr, dest_addr);
 
         let payload: GetNodes = bob_request
@@ -962,7 +2285,8 @@ mod test {
 
             let (_id, getn) = bob.create_getn(alice.pk());
 
-            let (alice_sink, _) = alice_socket.framed(ToxCodec).split();
+            let codec = ToxCodec::new(&alice_socket);
+            let (alice_sink, _) = alice_socket.framed(codec).split();
             let alice_response = alice.send_nodes(
                 alice_sink,
                 bob_socket.local_addr().unwrap(),
@@ -977,8 +2301,7 @@ mod test {
             let (_bob_socket, recv_buf, size, _saddr) = received;
             assert!(size != 0);
 
-            let _recv_packet = DhtPacket::from_bytes(&recv_buf[..size])
-                .expect("failed to parse as DhtPacket");
+            assert!(DhtPacket::from_bytes(&recv_buf[..size]).is_done());
 
             TestResult::passed()
         }
@@ -989,8 +2312,8 @@ mod test {
     quickcheck! {
         fn dht_node_handle_packet_sendn_test(sn: SendNodes,
                                              gn: GetNodes,
-                                             pq: PingReq,
-                                             pr: PingResp)
+                                             pq: PingRequest,
+                                             pr: PingResponse)
             -> ()
         {
             // bob creates a DhtPacket to alice that contains SendNodes
@@ -1021,21 +2344,87 @@ mod test {
             // now nodes from SendNodes can be processed
             try_add_with!(sn);
 
-            // verify that alice's kbucket's contents are the same as
-            // stand-alone kbucket
-            let mut kbuc = Kbucket::new(KBUCKET_BUCKETS, alice.pk());
+            // nodes aren't added to the kbucket before they respond to
+            // ping, they wait to be pinged instead
+            assert!(alice.kbucket.is_empty());
             for pn in &sn.nodes {
-                kbuc.try_add(pn);
+                assert!(alice.to_ping_nodes.iter().any(|n| n.pk() == pn.pk()));
             }
-            assert_eq!(kbuc, *alice.kbucket);
         }
     }
 
-    // DhtNode::handle_packet()
+    // DhtNode::ping_to_close_nodes()
+
+    #[test]
+    fn dht_node_ping_to_close_nodes_test() {
+        let mut alice = DhtNode::new().unwrap();
+        let mut bob = DhtNode::new().unwrap();
+        let (eve_pk, eve_sk) = gen_keypair();
+        let bob_node = PackedNode::new(true, "2.2.2.2:2".parse().unwrap(), bob.pk());
+        let eve_node = PackedNode::new(true, "3.3.3.3:3".parse().unwrap(), &eve_pk);
+        alice.add_to_ping(&bob_node);
+        alice.add_to_ping(&bob_node);
+        alice.add_to_ping(&eve_node);
+        // own node is never pinged
+        let own_node = PackedNode::new(true, "4.4.4.4:4".parse().unwrap(), alice.pk());
+        alice.add_to_ping(&own_node);
+        assert_eq!(2, alice.to_ping_nodes.len());
+
+        let packets = alice.ping_to_close_nodes();
+        assert_eq!(2, packets.len());
+        assert!(alice.to_ping_nodes.is_empty());
+        assert_eq!(2, alice.to_close_nodes.len());
+        // nodes that are pinged already aren't added again
+        alice.add_to_ping(&bob_node);
+        assert!(alice.to_ping_nodes.is_empty());
+
+        // eve responds with wrong ping id
+        let precomp = precompute(alice.pk(), &eve_sk);
+        let wrong_resp = PingResponse::from(PingRequest::new());
+        let packet = DhtPacket::new(&precomp, &eve_pk, &gen_nonce(), &wrong_resp);
+        assert_eq!(None, alice.handle_dht_packet(eve_node.socket_addr(), &packet));
+        assert!(alice.kbucket.is_empty());
+
+        // bob responds to the ping and is added to the kbucket
+        let (addr, request) = packets.into_iter()
+            .find(|&(addr, _)| addr == bob_node.socket_addr())
+            .unwrap();
+        let (_, response) = bob.handle_packet(addr, &request).unwrap();
+        assert_eq!(None, alice.handle_packet(addr, &response));
+        assert!(alice.kbucket.contains(bob.pk()));
+        assert!(!alice.kbucket.contains(&eve_pk));
+        assert_eq!(1, alice.to_close_nodes.len());
+
+        // the same response can't be used twice
+        alice.kbucket.remove(bob.pk());
+        assert_eq!(None, alice.handle_packet(addr, &response));
+        assert!(!alice.kbucket.contains(bob.pk()));
+    }
+
+    // DhtNode::ping_close_list()
+
+    #[test]
+    fn dht_node_ping_close_list_test() {
+        let mut alice = DhtNode::new().unwrap();
+        let mut bob = DhtNode::new().unwrap();
+        let bob_node = PackedNode::new(true, "2.2.2.2:2".parse().unwrap(), bob.pk());
+        assert!(alice.try_add(&bob_node));
+        // node was just added
+        assert!(alice.ping_close_list().is_empty());
+
+        // response to ping keeps the node in the Close List
+        let (addr, request) = alice.request_ping(&bob_node);
+        let (_, response) = bob.handle_packet(addr, &request).unwrap();
+        assert_eq!(None, alice.handle_packet(addr, &response));
+        assert_eq!(Some(NodeState::Good), alice.kbucket.node_state(bob.pk()));
+        assert!(alice.to_close_nodes.is_empty());
+    }
+
+    // DhtNode::handle_dht_packet()
 
     quickcheck! {
-        fn dht_node_handle_packet(pq: PingReq,
-                                  pr: PingResp,
+        fn dht_node_handle_dht_packet(pq: PingRequest,
+                                  pr: PingResponse,
                                   gn: GetNodes,
                                   sn: SendNodes)
             -> ()
@@ -1044,50 +2433,581 @@ mod test {
             let mut bob = DhtNode::new().unwrap();
             let precom = precompute(bob.pk(), alice.sk());
             let nonce = gen_nonce();
+            let addr = "2.2.2.2:2".parse().unwrap();
 
             // test with
 
             {
-                // PingReq
+                // PingRequest
                 let dp = DhtPacket::new(&precom, alice.pk(), &nonce, &pq);
                 assert_eq!(bob.create_ping_resp(&dp).unwrap().kind(),
-                           bob.handle_packet(&dp).unwrap().kind());
+                           bob.handle_dht_packet(addr, &dp).unwrap().kind());
             }
 
             {
-                // PingResp
+                // PingResponse
                 let dp = DhtPacket::new(&precom, alice.pk(), &nonce, &pr);
-                assert_eq!(None, bob.handle_packet(&dp));
+                assert_eq!(None, bob.handle_dht_packet(addr, &dp));
             }
 
             {
                 // GetNodes with an empty kbucket
                 let dp = DhtPacket::new(&precom, alice.pk(), &nonce, &gn);
-                assert_eq!(None, bob.handle_packet(&dp));
+                assert_eq!(None, bob.handle_dht_packet(addr, &dp));
             }
 
             {
                 // SendNodes
                 let dp = DhtPacket::new(&precom, alice.pk(), &nonce, &sn);
-                assert_eq!(None, bob.handle_packet(&dp));
+                assert_eq!(None, bob.handle_dht_packet(addr, &dp));
                 // bob doesn't have request ID, thus packet is dropped
                 assert!(bob.kbucket.is_empty());
                 // add request ID, so that nods could be processed
                 bob.getn_timeout.add(alice.pk(), sn.id);
-                assert_eq!(None, bob.handle_packet(&dp));
-                assert!(!bob.kbucket.is_empty());
+                assert_eq!(None, bob.handle_dht_packet(addr, &dp));
+                // nodes have to respond to ping before they are added
+                assert!(bob.kbucket.is_empty());
+                assert!(!bob.to_ping_nodes.is_empty());
+                for node in &sn.nodes {
+                    bob.try_add(node);
+                }
             }
 
             {
                 // GetNodes with something in kbucket
                 let dp = DhtPacket::new(&precom, alice.pk(), &nonce, &gn);
                 assert_eq!(bob.create_sendn(&dp).unwrap().kind(),
-                           bob.handle_packet(&dp).unwrap().kind());
+                           bob.handle_dht_packet(addr, &dp).unwrap().kind());
             }
         }
     }
 
 
+    // DhtNode::handle_packet()
+
+    quickcheck! {
+        fn dht_node_handle_packet_dht(pq: PingRequest) -> () {
+            let alice = DhtNode::new().unwrap();
+            let mut bob = DhtNode::new().unwrap();
+            let precom = precompute(bob.pk(), alice.sk());
+            let nonce = gen_nonce();
+            let dp = DhtPacket::new(&precom, alice.pk(), &nonce, &pq);
+            let addr = "1.2.3.4:12345".parse().unwrap();
+
+            let (resp_addr, resp) = bob.handle_packet(addr, &dp.into())
+                .expect("failed to create ping response");
+            // response is sent back to the sender
+            assert_eq!(addr, resp_addr);
+            assert_eq!(PacketKind::PingResp, unpack_dht_packet!(resp).kind());
+        }
+    }
+
+    #[test]
+    fn dht_node_handle_packet_onion_relay() {
+        // client sends onion request to dave through alice, bob and carol
+        // dave's response goes back through carol, bob and alice
+        let mut alice = DhtNode::new().unwrap();
+        let mut bob = DhtNode::new().unwrap();
+        let mut carol = DhtNode::new().unwrap();
+        let client_addr: SocketAddr = "1.1.1.1:1".parse().unwrap();
+        let alice_addr: SocketAddr = "2.2.2.2:2".parse().unwrap();
+        let bob_addr: SocketAddr = "[3::3]:3".parse().unwrap();
+        let carol_addr: SocketAddr = "4.4.4.4:4".parse().unwrap();
+        let dave_addr: SocketAddr = "5.5.5.5:5".parse().unwrap();
+
+        let path = [
+            PackedNode::new(true, alice_addr, alice.pk()),
+            PackedNode::new(true, bob_addr, bob.pk()),
+            PackedNode::new(true, carol_addr, carol.pk()),
+        ];
+        let inner = InnerOnionDataRequest {
+            destination_pk: gen_keypair().0,
+            nonce: gen_nonce(),
+            temporary_pk: gen_keypair().0,
+            payload: vec![42; 123],
+        };
+        let request = create_onion_request(&path, &dave_addr,
+            &InnerOnionRequest::InnerOnionDataRequest(inner.clone())).unwrap();
+
+        let (addr, packet) = alice.handle_packet(client_addr,
+            &UdpPacket::OnionRequest0(request)).unwrap();
+        assert_eq!(bob_addr, addr);
+        let (addr, packet) = bob.handle_packet(alice_addr, &packet).unwrap();
+        assert_eq!(carol_addr, addr);
+        let (addr, packet) = carol.handle_packet(bob_addr, &packet).unwrap();
+        assert_eq!(dave_addr, addr);
+        let onion_return = match packet {
+            UdpPacket::OnionDataRequest(request) => {
+                assert_eq!(inner, request.inner);
+                request.onion_return
+            },
+            p => panic!("Expected OnionDataRequest, got {:?}", p),
+        };
+
+        let response = InnerOnionResponse::OnionDataResponse(OnionDataResponse {
+            nonce: gen_nonce(),
+            temporary_pk: gen_keypair().0,
+            payload: vec![43; 123],
+        });
+        let packet = UdpPacket::OnionResponse3(OnionResponse3 {
+            onion_return: onion_return,
+            payload: response.clone(),
+        });
+
+        let (addr, packet) = carol.handle_packet(dave_addr, &packet).unwrap();
+        assert_eq!(bob_addr, addr);
+        let (addr, packet) = bob.handle_packet(carol_addr, &packet).unwrap();
+        assert_eq!(alice_addr, addr);
+        let (addr, packet) = alice.handle_packet(bob_addr, &packet).unwrap();
+        assert_eq!(client_addr, addr);
+        assert_eq!(UdpPacket::from(response), packet);
+    }
+
+    #[test]
+    fn dht_node_handle_packet_onion_announce_request() {
+        // alice sends announce request to bob through carol
+        // bob responds with the ping ID, alice announces herself with it
+        let mut bob = DhtNode::new().unwrap();
+        let (alice_pk, alice_sk) = gen_keypair();
+        let (alice_data_pk, _) = gen_keypair();
+        let carol_addr: SocketAddr = "1.2.3.4:5".parse().unwrap();
+        let shared_secret = encrypt_precompute(bob.pk(), &alice_sk);
+
+        let node = PackedNode::new(true, "5.6.7.8:9".parse().unwrap(),
+                                   &gen_keypair().0);
+        assert!(bob.try_add(&node));
+
+        let mut payload = OnionAnnounceRequestPayload {
+            ping_id: ::sodiumoxide::crypto::hash::sha256::Digest([0; 32]),
+            search_pk: alice_pk,
+            data_pk: alice_data_pk,
+            sendback_data: 42,
+        };
+
+        macro_rules! announce {
+            ($payload:expr) => ({
+                let onion_return = OnionReturn::new(&new_symmetric_key(),
+                    &"9.9.9.9:9".parse().unwrap(), None);
+                let request = UdpPacket::OnionAnnounceRequest(OnionAnnounceRequest {
+                    inner: InnerOnionAnnounceRequest::new(&shared_secret,
+                        &alice_pk, &$payload),
+                    onion_return: onion_return.clone(),
+                });
+                let (addr, response) = bob.handle_packet(carol_addr, &request)
+                    .unwrap();
+                assert_eq!(carol_addr, addr);
+                let response = match response {
+                    UdpPacket::OnionResponse3(response) => response,
+                    p => panic!("Expected OnionResponse3, got {:?}", p),
+                };
+                assert_eq!(onion_return, response.onion_return);
+                let response = match response.payload {
+                    InnerOnionResponse::OnionAnnounceResponse(response) => response,
+                    p => panic!("Expected OnionAnnounceResponse, got {:?}", p),
+                };
+                assert_eq!(42, response.sendback_data);
+                response.get_payload(&shared_secret).unwrap()
+            })
+        }
+
+        let response = announce!(payload);
+        assert_eq!(AnnounceStatus::Failed, response.announce_status);
+        assert_eq!(1, response.nodes.len());
+        assert_eq!(node.socket_addr(), response.nodes[0].socket_addr());
+
+        payload.ping_id = response.ping_id_or_pk;
+        let response = announce!(payload);
+        assert_eq!(AnnounceStatus::Announced, response.announce_status);
+
+        // announce keys don't go to the cache of DHT keys
+        assert_eq!(0, bob.precomputed_cache().hits());
+        assert_eq!(0, bob.precomputed_cache().misses());
+    }
+
+    #[test]
+    fn dht_node_handle_packet_onion_data_request() {
+        // alice is announced on bob, carol sends data to alice through bob
+        let mut bob = DhtNode::new().unwrap();
+        let (alice_pk, _) = gen_keypair();
+        let alice_addr: SocketAddr = "1.2.3.4:5".parse().unwrap();
+        let alice_return = OnionReturn::new(&new_symmetric_key(),
+            &"9.9.9.9:9".parse().unwrap(), None);
+        let entry = ::toxcore::onion::onion_announce::OnionAnnounceEntry::new(
+            alice_pk, alice_addr, alice_return.clone(), gen_keypair().0);
+        assert!(bob.onion_announce.add_to_entries(entry));
+
+        let inner = InnerOnionDataRequest {
+            destination_pk: alice_pk,
+            nonce: gen_nonce(),
+            temporary_pk: gen_keypair().0,
+            payload: vec![42; 123],
+        };
+        let request = UdpPacket::OnionDataRequest(OnionDataRequest {
+            inner: inner.clone(),
+            onion_return: OnionReturn::new(&new_symmetric_key(),
+                &"8.8.8.8:8".parse().unwrap(), None),
+        });
+
+        let (addr, response) = bob.handle_packet("5.6.7.8:9".parse().unwrap(),
+                                                 &request).unwrap();
+        assert_eq!(alice_addr, addr);
+        assert_eq!(UdpPacket::OnionResponse3(OnionResponse3 {
+            onion_return: alice_return,
+            payload: InnerOnionResponse::OnionDataResponse(OnionDataResponse {
+                nonce: inner.nonce,
+                temporary_pk: inner.temporary_pk,
+                payload: inner.payload,
+            }),
+        }), response);
+    }
+
+    #[test]
+    fn dht_node_handle_packet_onion_request_wrong_key() {
+        // alice can't decrypt request that is meant for bob
+        let mut alice = DhtNode::new().unwrap();
+        let bob = DhtNode::new().unwrap();
+        let addr: SocketAddr = "1.2.3.4:5".parse().unwrap();
+        let path = [
+            PackedNode::new(true, addr, bob.pk()),
+            PackedNode::new(true, addr, bob.pk()),
+            PackedNode::new(true, addr, bob.pk()),
+        ];
+        let inner = InnerOnionDataRequest {
+            destination_pk: gen_keypair().0,
+            nonce: gen_nonce(),
+            temporary_pk: gen_keypair().0,
+            payload: vec![42; 123],
+        };
+        let request = create_onion_request(&path, &addr,
+            &InnerOnionRequest::InnerOnionDataRequest(inner)).unwrap();
+
+        assert_eq!(None, alice.handle_packet(addr,
+            &UdpPacket::OnionRequest0(request)));
+    }
+
+    #[test]
+    fn dht_node_handle_packet_onion_response_wrong_key() {
+        // alice drops response with return address encrypted by bob
+        let mut alice = DhtNode::new().unwrap();
+        let bob = DhtNode::new().unwrap();
+        let addr: SocketAddr = "1.2.3.4:5".parse().unwrap();
+        let onion_return = OnionReturn::new(&bob.onion_symmetric_key, &addr,
+                                            None);
+        let packet = UdpPacket::OnionResponse1(OnionResponse1 {
+            onion_return: onion_return,
+            payload: InnerOnionResponse::OnionDataResponse(OnionDataResponse {
+                nonce: gen_nonce(),
+                temporary_pk: gen_keypair().0,
+                payload: vec![42; 123],
+            }),
+        });
+
+        assert_eq!(None, alice.handle_packet(addr, &packet));
+    }
+
+
+    #[test]
+    fn dht_node_handle_tcp_onion_request_and_response() {
+        // TCP client sends onion request through alice's relay to bob
+        let mut alice = DhtNode::new().unwrap();
+        let client_addr: SocketAddr = "1.2.3.4:5".parse().unwrap();
+        let bob_addr: SocketAddr = "5.6.7.8:9".parse().unwrap();
+        let (tx, rx) = mpsc::unbounded();
+        alice.set_tcp_onion_sink(tx);
+
+        let request = TcpOnionRequest {
+            nonce: gen_nonce(),
+            ip_port: bob_addr,
+            temporary_pk: gen_keypair().0,
+            payload: vec![42; 123],
+        };
+        let (addr, packet) = alice.handle_tcp_onion_request(client_addr, &request);
+        assert_eq!(bob_addr, addr);
+        let packet = match packet {
+            UdpPacket::OnionRequest1(packet) => packet,
+            p => panic!("Expected OnionRequest1, got {:?}", p),
+        };
+        assert_eq!(request.nonce, packet.nonce);
+        assert_eq!(request.temporary_pk, packet.temporary_pk);
+        assert_eq!(request.payload, packet.payload);
+
+        // bob's response goes back to the TCP client
+        let payload = InnerOnionResponse::OnionDataResponse(OnionDataResponse {
+            nonce: gen_nonce(),
+            temporary_pk: gen_keypair().0,
+            payload: vec![42; 123],
+        });
+        let response = UdpPacket::OnionResponse1(OnionResponse1 {
+            onion_return: packet.onion_return,
+            payload: payload.clone(),
+        });
+        assert_eq!(None, alice.handle_packet(bob_addr, &response));
+        drop(alice);
+        let responses = rx.collect().wait().unwrap();
+        assert_eq!(vec![(payload, client_addr)], responses);
+    }
+
+    #[test]
+    fn dht_node_handle_packet_lan_discovery() {
+        let mut alice = DhtNode::new().unwrap();
+        let bob = DhtNode::new().unwrap();
+        let bob_addr: SocketAddr = "192.168.1.2:33445".parse().unwrap();
+        let packet = UdpPacket::LanDiscovery(LanDiscovery { pk: *bob.pk() });
+
+        let (addr, request) = alice.handle_packet(bob_addr, &packet).unwrap();
+        assert_eq!(bob_addr, addr);
+        let request = match request {
+            UdpPacket::Dht(request) => request,
+            p => panic!("Expected DhtPacket, got {:?}", p),
+        };
+        assert_eq!(*alice.pk(), request.sender_pk);
+        assert!(request.get_payload::<PingRequest>(bob.sk()).is_some());
+
+        // packets from non-LAN addresses are ignored
+        assert_eq!(None, alice.handle_packet("8.8.8.8:33445".parse().unwrap(), &packet));
+        // own packets are ignored
+        let own = UdpPacket::LanDiscovery(LanDiscovery { pk: *alice.pk() });
+        assert_eq!(None, alice.handle_packet(bob_addr, &own));
+    }
+
+    #[test]
+    fn dht_node_handle_packet_lan_discovery_adds_node() {
+        let mut alice = DhtNode::new().unwrap();
+        let mut bob = DhtNode::new().unwrap();
+        let alice_addr: SocketAddr = "192.168.1.1:33445".parse().unwrap();
+        let bob_addr: SocketAddr = "192.168.1.2:33445".parse().unwrap();
+        let packet = UdpPacket::LanDiscovery(LanDiscovery { pk: *bob.pk() });
+
+        let (_, request) = alice.handle_packet(bob_addr, &packet).unwrap();
+        assert!(!alice.kbucket.contains(bob.pk()));
+        // repeated LanDiscovery doesn't ping the node again while waiting
+        assert_eq!(None, alice.handle_packet(bob_addr, &packet));
+
+        let (_, response) = bob.handle_packet(alice_addr, &request).unwrap();
+        assert_eq!(None, alice.handle_packet(bob_addr, &response));
+        assert!(alice.kbucket.contains(bob.pk()));
+        assert!(alice.to_close_nodes.is_empty());
+    }
+
+    // DhtNode::lan_discovery_sender()
+
+    #[test]
+    fn dht_node_lan_discovery_sender_test() {
+        let alice = DhtNode::new().unwrap();
+        let packets = alice.lan_discovery_sender(false).packets();
+        assert!(!packets.is_empty());
+        for (_, packet) in packets {
+            assert_eq!(UdpPacket::LanDiscovery(LanDiscovery { pk: *alice.pk() }), packet);
+        }
+    }
+
+    #[test]
+    fn dht_node_lan_discovery_joins_close_list() {
+        let mut alice = DhtNode::new().unwrap();
+        let mut bob = DhtNode::new().unwrap();
+        let alice_addr: SocketAddr = "192.168.1.1:33445".parse().unwrap();
+        let bob_addr: SocketAddr = "192.168.1.2:33445".parse().unwrap();
+
+        // bob broadcasts LanDiscovery and alice receives it
+        let (_, discovery) = bob.lan_discovery_sender(false).packets().remove(0);
+        let (addr, ping_req) = alice.handle_packet(bob_addr, &discovery).unwrap();
+        assert_eq!(bob_addr, addr);
+
+        // bob answers alice's ping
+        let (addr, ping_resp) = bob.handle_packet(alice_addr, &ping_req).unwrap();
+        assert_eq!(alice_addr, addr);
+        assert_eq!(None, alice.handle_packet(bob_addr, &ping_resp));
+
+        let nodes = alice.kbucket.get_closest(bob.pk());
+        assert!(nodes.iter().any(|node| node.pk() == bob.pk() && node.socket_addr() == bob_addr));
+    }
+
+    // DhtNode::request_nodes_friends()
+
+    #[test]
+    fn dht_node_friend_search() {
+        // alice searches her friend, bob knows the friend
+        let mut alice = DhtNode::new().unwrap();
+        let mut bob = DhtNode::new().unwrap();
+        let (friend_pk, _) = gen_keypair();
+        let alice_addr: SocketAddr = "1.1.1.1:1".parse().unwrap();
+        let bob_node = PackedNode::new(true, "2.2.2.2:2".parse().unwrap(), bob.pk());
+        let friend_node = PackedNode::new(true, "3.3.3.3:3".parse().unwrap(), &friend_pk);
+        assert!(alice.try_add(&bob_node));
+        assert!(bob.try_add(&friend_node));
+
+        assert!(alice.add_friend(&friend_pk));
+        assert!(!alice.add_friend(&friend_pk));
+        assert!(alice.friend(&friend_pk).unwrap().close_nodes().contains(bob.pk()));
+        assert_eq!(None, alice.friend_addr(&friend_pk));
+
+        let packets = alice.request_nodes_friends();
+        assert_eq!(1, packets.len());
+        let (addr, packet) = packets[0].clone();
+        assert_eq!(bob_node.socket_addr(), addr);
+        assert!(alice.request_nodes_friends().is_empty());
+
+        let (_, response) = bob.handle_packet(alice_addr, &packet).unwrap();
+        assert_eq!(None, alice.handle_packet(bob_node.socket_addr(), &response));
+        assert_eq!(Some(friend_node.socket_addr()), alice.friend_addr(&friend_pk));
+        // the friend isn't added to the Close List as a regular node
+        // before it responds to ping
+        assert!(!alice.kbucket.contains(&friend_pk));
+        assert!(alice.to_ping_nodes.iter().any(|node| *node.pk() == friend_pk));
+
+        assert!(alice.remove_friend(&friend_pk));
+        assert!(!alice.remove_friend(&friend_pk));
+        assert_eq!(None, alice.friend_addr(&friend_pk));
+    }
+
+    // DhtNode::punch_holes_friends()
+
+    /// Make `relays` report `IP:port`s of `node`'s friends to it.
+    fn find_friends(node: &mut DhtNode, addr: SocketAddr, relays: &mut [(SocketAddr, DhtNode)]) {
+        for (relay_addr, request) in node.request_nodes_friends() {
+            let relay = &mut relays.iter_mut().find(|&&mut (a, _)| a == relay_addr).unwrap().1;
+            let (_, response) = relay.handle_packet(addr, &request).unwrap();
+            assert_eq!(None, node.handle_packet(relay_addr, &response));
+        }
+    }
+
+    #[test]
+    fn dht_node_punch_holes_friends() {
+        // alice and bob are friends, relays know both of them
+        let mut alice = DhtNode::new().unwrap();
+        let mut bob = DhtNode::new().unwrap();
+        let alice_addr: SocketAddr = "1.1.1.1:1".parse().unwrap();
+        let bob_addr: SocketAddr = "2.2.2.2:2".parse().unwrap();
+        let alice_node = PackedNode::new(true, alice_addr, alice.pk());
+        let bob_node = PackedNode::new(true, bob_addr, bob.pk());
+        let mut relays = (0..4)
+            .map(|i| {
+                let addr = SocketAddr::new("3.3.3.3".parse().unwrap(), 10 + i);
+                let mut relay = DhtNode::new().unwrap();
+                assert!(relay.try_add(&alice_node));
+                assert!(relay.try_add(&bob_node));
+                let relay_node = PackedNode::new(true, addr, relay.pk());
+                assert!(alice.try_add(&relay_node));
+                assert!(bob.try_add(&relay_node));
+                (addr, relay)
+            })
+            .collect::<Vec<_>>();
+        assert!(alice.add_friend(bob.pk()));
+        assert!(bob.add_friend(alice.pk()));
+        // nothing to punch until friends are found
+        assert!(alice.punch_holes_friends().is_empty());
+
+        find_friends(&mut alice, alice_addr, &mut relays);
+        find_friends(&mut bob, bob_addr, &mut relays);
+        assert_eq!(Some(bob_addr), alice.friend_addr(bob.pk()));
+
+        // NAT ping requests are sent through every relay
+        let alice_pings = alice.punch_holes_friends();
+        assert_eq!(relays.len(), alice_pings.len());
+        let bob_pings = bob.punch_holes_friends();
+        assert_eq!(relays.len(), bob_pings.len());
+
+        // relay forwards NAT ping requests and responses between friends
+        let (relay_addr, ref mut relay) = relays[0];
+        let (addr, request) = relay.handle_packet(alice_addr, &alice_pings[0].1).unwrap();
+        assert_eq!(bob_addr, addr);
+        let (addr, response) = bob.handle_packet(relay_addr, &request).unwrap();
+        assert_eq!(relay_addr, addr);
+        let (addr, response) = relay.handle_packet(bob_addr, &response).unwrap();
+        assert_eq!(alice_addr, addr);
+        assert_eq!(None, alice.handle_packet(relay_addr, &response));
+
+        let (_, request) = relay.handle_packet(bob_addr, &bob_pings[0].1).unwrap();
+        let (_, response) = alice.handle_packet(relay_addr, &request).unwrap();
+        let (_, response) = relay.handle_packet(alice_addr, &response).unwrap();
+        assert_eq!(None, bob.handle_packet(relay_addr, &response));
+
+        // all relays report the same port so only it is pinged
+        let mut punching = alice.punch_holes_friends();
+        assert_eq!(1, punching.len());
+        let (addr, ping) = punching.remove(0);
+        assert_eq!(bob_addr, addr);
+        assert_eq!(None, alice.friend(bob.pk()).unwrap().direct_addr());
+
+        let (_, pong) = bob.handle_packet(alice_addr, &ping).unwrap();
+        assert_eq!(None, alice.handle_packet(bob_addr, &pong));
+        assert_eq!(Some(bob_addr), alice.friend(bob.pk()).unwrap().direct_addr());
+        // friend is reachable directly, nothing to punch anymore
+        assert!(alice.punch_holes_friends().is_empty());
+    }
+
+    // DhtNode::handle_packet()
+
+    #[test]
+    fn dht_node_handle_packet_dht_request_not_friend() {
+        let mut alice = DhtNode::new().unwrap();
+        let bob = DhtNode::new().unwrap();
+        let (carol_pk, _) = gen_keypair();
+        let bob_addr: SocketAddr = "2.2.2.2:2".parse().unwrap();
+        let payload = DhtRequestPayload::NatPingRequest(NatPingRequest::new());
+
+        // requests to us are accepted only from friends
+        let precomp = precompute(alice.pk(), bob.sk());
+        let request = DhtRequest::new(&precomp, alice.pk(), bob.pk(), &gen_nonce(), &payload);
+        assert_eq!(None, alice.handle_packet(bob_addr, &request.into()));
+
+        // requests to nodes that are not in the Close List are dropped
+        let precomp = precompute(&carol_pk, bob.sk());
+        let request = DhtRequest::new(&precomp, &carol_pk, bob.pk(), &gen_nonce(), &payload);
+        assert_eq!(None, alice.handle_packet(bob_addr, &request.clone().into()));
+
+        let carol_node = PackedNode::new(true, "3.3.3.3:3".parse().unwrap(), &carol_pk);
+        assert!(alice.try_add(&carol_node));
+        assert_eq!(Some((carol_node.socket_addr(), request.clone().into())),
+            alice.handle_packet(bob_addr, &request.into()));
+    }
+
+    // DhtNode::lookup()
+
+    #[test]
+    fn dht_node_lookup() {
+        // alice knows bob, bob knows carol, carol knows bob
+        let mut alice = DhtNode::new().unwrap();
+        let mut bob = DhtNode::new().unwrap();
+        let mut carol = DhtNode::new().unwrap();
+        let alice_addr: SocketAddr = "1.1.1.1:1".parse().unwrap();
+        let bob_node = PackedNode::new(true, "2.2.2.2:2".parse().unwrap(), bob.pk());
+        let carol_node = PackedNode::new(true, "3.3.3.3:3".parse().unwrap(), carol.pk());
+        assert!(alice.try_add(&bob_node));
+        assert!(bob.try_add(&carol_node));
+        assert!(carol.try_add(&bob_node));
+
+        let (target, _) = gen_keypair();
+        let (future, packets) = alice.lookup(&target);
+        assert_eq!(1, packets.len());
+        let (addr, packet) = packets[0].clone();
+        assert_eq!(bob_node.socket_addr(), addr);
+        let (_, response) = bob.handle_packet(alice_addr, &packet).unwrap();
+        assert_eq!(None, alice.handle_packet(bob_node.socket_addr(), &response));
+        assert!(alice.to_ping_nodes.iter().any(|node| node.pk() == carol.pk()));
+
+        let packets = alice.lookup_requests();
+        assert_eq!(1, packets.len());
+        let (addr, packet) = packets[0].clone();
+        assert_eq!(carol_node.socket_addr(), addr);
+        let (_, response) = carol.handle_packet(alice_addr, &packet).unwrap();
+        assert_eq!(None, alice.handle_packet(carol_node.socket_addr(), &response));
+
+        assert!(alice.lookups.is_empty());
+        let mut expected = vec![bob_node, carol_node];
+        expected.sort_by(|n1, n2| target.distance(n1.pk(), n2.pk()));
+        assert_eq!(expected, future.wait().unwrap());
+    }
+
+    #[test]
+    fn dht_node_lookup_without_nodes() {
+        let mut alice = DhtNode::new().unwrap();
+        let (future, packets) = alice.lookup(&gen_keypair().0);
+        assert!(packets.is_empty());
+        assert!(future.wait().unwrap().is_empty());
+        assert!(alice.lookup_requests().is_empty());
+    }
+
     // ToxCodec::
 
     // ToxCodec::decode()
@@ -1095,16 +3015,20 @@ mod test {
     #[test]
     fn tox_codec_decode_test() {
         fn with_dp(dp: DhtPacket, kind: u8) -> TestResult {
-            // need an invalid PacketKind for DhtPacket
-            if kind <= PacketKind::SendN as u8 {
+            // need an invalid PacketKind for DhtPacket that also isn't
+            // a kind of DHT request, onion or LAN discovery packet
+            if kind <= PacketKind::SendN as u8 ||
+                kind == PacketKind::DhtReq as u8 ||
+                kind == PacketKind::LanDisc as u8 ||
+                kind >= PacketKind::OnionReq0 as u8 {
                 return TestResult::discard()
             }
 
             // TODO: random SocketAddr
             let addr = SocketAddr::V4("0.1.2.3:4".parse().unwrap());
-            let mut tc = ToxCodec;
+            let mut tc = ToxCodec { ipv6: false };
 
-            let mut bytes = dp.to_bytes();
+            let mut bytes = packet_to_bytes(&dp);
 
             let (decoded_a, decoded_dp) = tc.decode(&addr, &bytes)
                 .unwrap();
@@ -1112,7 +3036,7 @@ mod test {
             let decoded_dp = decoded_dp.unwrap();
 
             assert_eq!(addr, decoded_a);
-            assert_eq!(dp, decoded_dp);
+            assert_eq!(UdpPacket::Dht(dp), decoded_dp);
 
             // make it error
             bytes[0] = kind;
@@ -1133,15 +3057,61 @@ mod test {
             // TODO: random SocketAddr
             let addr = SocketAddr::V4("5.6.7.8:9".parse().unwrap());
             let mut buf = Vec::new();
-            let mut tc = ToxCodec;
+            let mut tc = ToxCodec { ipv6: false };
 
-            let socket = tc.encode((addr, dp.clone()), &mut buf);
+            let socket = tc.encode((addr, dp.clone().into()), &mut buf);
             assert_eq!(addr, socket);
-            assert_eq!(buf, dp.to_bytes());
+            assert_eq!(buf, packet_to_bytes(&dp));
         }
         quickcheck(with_dp as fn(DhtPacket));
     }
 
+    #[test]
+    fn tox_codec_encode_ipv4_to_ipv6_socket_test() {
+        let (pk, sk) = gen_keypair();
+        let packet = DhtPacket::new(&encrypt_precompute(&pk, &sk), &pk, &gen_nonce(),
+            &PingRequest::new());
+        let mut buf = Vec::new();
+        let mut tc = ToxCodec { ipv6: true };
+
+        let addr = tc.encode(("1.2.3.4:5".parse().unwrap(), packet.clone().into()), &mut buf);
+        assert_eq!("[::ffff:1.2.3.4]:5".parse::<SocketAddr>().unwrap(), addr);
+
+        let v6_addr = "[::1]:5".parse().unwrap();
+        assert_eq!(v6_addr, tc.encode((v6_addr, packet.into()), &mut buf));
+    }
+
+    #[test]
+    fn tox_codec_dual_stack_test() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let socket = bind_udp("::".parse().unwrap(), 2048..65_000, &handle)
+            .expect("failed to bind to socket");
+        let port = socket.local_addr().unwrap().port();
+        let codec = ToxCodec::new(&socket);
+        let (sink, stream) = socket.framed(codec).split();
+
+        // IPv4 peer sends packet to dual-stack socket
+        let v4_socket = ::std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let v4_addr = v4_socket.local_addr().unwrap();
+        let (pk, sk) = gen_keypair();
+        let packet = DhtPacket::new(&encrypt_precompute(&pk, &sk), &pk, &gen_nonce(),
+            &PingRequest::new());
+        v4_socket.send_to(&packet_to_bytes(&packet), ("127.0.0.1", port)).unwrap();
+
+        let (received, _stream) = core.run(stream.into_future())
+            .map_err(|(e, _stream)| e).unwrap();
+        let (addr, received) = received.unwrap();
+        assert_eq!(v4_addr, addr);
+        assert_eq!(Some(UdpPacket::Dht(packet.clone())), received);
+
+        // and gets the reply to IPv4 address
+        core.run(sink.send((addr, packet.clone().into()))).unwrap();
+        let mut buf = [0; MAX_UDP_PACKET_SIZE];
+        let (size, _) = v4_socket.recv_from(&mut buf).unwrap();
+        assert_eq!(&packet_to_bytes(&packet)[..], &buf[..size]);
+    }
+
 
     // receive_packets()
 
@@ -1155,14 +3125,15 @@ mod test {
 
             let a_addr = a_socket.local_addr().unwrap();
             let b_addr = b_socket.local_addr().unwrap();
-            let (_sink, stream) = b_socket.framed(ToxCodec).split();
+            let codec = ToxCodec::new(&b_socket);
+            let (_sink, stream) = b_socket.framed(codec).split();
 
             // start receiving packets
             let to_receive = receive_packets(stream);
 
             let mut a_socket = a_socket;
             for dp in &dps {
-                let send = a_socket.send_dgram(dp.to_bytes(), b_addr);
+                let send = a_socket.send_dgram(packet_to_bytes(dp), b_addr);
                 let (s, _) = core.run(send).unwrap();
                 a_socket = s;
             }
@@ -1172,7 +3143,7 @@ mod test {
 
             for (n, &(ref addr, ref packet)) in received.iter().enumerate() {
                 assert_eq!(a_addr, *addr);
-                assert_eq!(dps[n], *packet);
+                assert_eq!(UdpPacket::Dht(dps[n].clone()), *packet);
             }
 
             TestResult::passed()
@@ -1191,8 +3162,10 @@ mod test {
 
             let a_addr = a_socket.local_addr().unwrap();
             let b_addr = b_socket.local_addr().unwrap();
-            let (sink, _stream) = a_socket.framed(ToxCodec).split();
-            let (_sink, stream) = b_socket.framed(ToxCodec).split();
+            let a_codec = ToxCodec::new(&a_socket);
+            let (sink, _stream) = a_socket.framed(a_codec).split();
+            let b_codec = ToxCodec::new(&b_socket);
+            let (_sink, stream) = b_socket.framed(b_codec).split();
 
             // start receiving/sending packets
             let receiver = receive_packets(stream);
@@ -1201,7 +3174,7 @@ mod test {
             let dps_send = dps.clone();
             for dp in dps_send {
                 let tx = sender.clone();
-                let send = tx.send((b_addr, dp)).then(|_| Ok(()));
+                let send = tx.send((b_addr, dp.into())).then(|_| Ok(()));
                 handle.spawn(send);
             }
 
@@ -1210,7 +3183,7 @@ mod test {
 
             for (n, &(ref addr, ref packet)) in received.iter().enumerate() {
                 assert_eq!(a_addr, *addr);
-                assert_eq!(dps[n], *packet);
+                assert_eq!(UdpPacket::Dht(dps[n].clone()), *packet);
             }
 
             TestResult::passed()
@@ -21,14 +21,45 @@
 /*!
 Module for hole-punching. Build on top of DHT.
 
+Nodes that are close to a friend report to us the `IP:port` of the friend
+as they see it. When at least half of them report the same IP, NAT ping
+requests are sent to the friend through them. After the friend responds,
+bursts of DHT ping requests are sent to ports of the common IP that are
+likely to be opened by the friend's NAT. The address from which the friend
+responds to one of those pings is the direct address of the friend.
+
 https://zetok.github.io/tox-spec/#hole-punching
 */
 
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use toxcore::crypto_core::*;
+use toxcore::dht_new::packet::{NatPingRequest, NatPingResponse};
+
+/// Interval in seconds between NAT ping requests and punching bursts.
+pub const PUNCH_INTERVAL: u64 = 3;
+
+/// Maximum number of ports that are pinged in one punching burst.
+pub const MAX_PUNCHING_PORTS: u32 = 48;
+
+/** Number of punching bursts after which ports starting from
+[`PUNCHING_PORT_START`](./constant.PUNCHING_PORT_START.html) are pinged as
+well.
+*/
+pub const MAX_NORMAL_PUNCHING_TRIES: u32 = 5;
+
+/// Maximum number of nodes close to a friend that report its `IP:port`.
+pub const MAX_FRIEND_CLIENTS: usize = 8;
+
+/// First port that is pinged when port guessing doesn't work.
+pub const PUNCHING_PORT_START: u16 = 1024;
 
 /// Types of NATs.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum NatKind {
     /// Cone NAT. Eeasiest to deal with, since friend will just respond to
-    /// [DHT `Ping` request](../dht/struct.Ping.html). Close to use nodes will
+    /// [DHT `Ping` request](../dht_new/packet/struct.PingRequest.html). Close to use nodes will
     /// return same `IP:port` of friend.
     ///
     /// https://zetok.github.io/tox-spec/#cone-nat
@@ -51,3 +82,374 @@ pub enum NatKind {
     /// hole-punching, hoping that it's just an outdated info.
     Other,
 }
+
+impl NatKind {
+    /**
+    Classify NAT of a friend by `IP:port`s of the friend that were reported
+    by nodes close to it. `responds` should be `true` if the friend
+    responds to DHT ping requests sent directly to it.
+    */
+    pub fn classify(reports: &[SocketAddr], responds: bool) -> NatKind {
+        let ip = match common_ip(reports, reports.len() / 2 + 1) {
+            Some(ip) => ip,
+            None => return NatKind::Other,
+        };
+        let ports = common_ip_ports(reports, ip);
+        if ports.iter().any(|&port| port != ports[0]) {
+            NatKind::SymNat
+        } else if responds {
+            NatKind::ConeNat
+        } else {
+            NatKind::ResConeNat
+        }
+    }
+}
+
+/**
+Get IP that is present at least `min_num` times in `reports`.
+
+Returns `None` if there is no such IP.
+*/
+pub fn common_ip(reports: &[SocketAddr], min_num: usize) -> Option<IpAddr> {
+    reports.iter()
+        .map(|addr| addr.ip())
+        .find(|ip| reports.iter().filter(|addr| addr.ip() == *ip).count() >= min_num)
+}
+
+/// Get ports of all `reports` that have `ip`.
+pub fn common_ip_ports(reports: &[SocketAddr], ip: IpAddr) -> Vec<u16> {
+    reports.iter()
+        .filter(|addr| addr.ip() == ip)
+        .map(|addr| addr.port())
+        .collect()
+}
+
+/**
+State of hole punching with a friend.
+
+It doesn't send anything itself: NAT ping requests and responses should be
+sent to the friend through nodes close to it inside of DHT request
+packets, and DHT ping requests should be sent to the punching addresses.
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HolePunching {
+    /// Whether the friend responded to our NAT ping request
+    is_punching_ready: bool,
+    /// ID of our NAT ping requests
+    ping_id: u64,
+    /// Time when we sent the last NAT ping request
+    last_ping_req_time: Option<Instant>,
+    /// Time when we received the last NAT ping request from the friend
+    last_recv_ping_req_time: Option<Instant>,
+    /// Time of the last punching burst
+    last_punching_time: Option<Instant>,
+    /// Index of the next guessed port
+    punching_index: u32,
+    /// Offset of the next port from `PUNCHING_PORT_START`
+    punching_index2: u32,
+    /// Number of punching bursts
+    tries: u32,
+    /// Address from which the friend responded to us directly
+    direct_addr: Option<SocketAddr>,
+}
+
+impl Default for HolePunching {
+    fn default() -> Self {
+        HolePunching::new()
+    }
+}
+
+impl HolePunching {
+    /// Create new `HolePunching` state.
+    pub fn new() -> HolePunching {
+        HolePunching {
+            is_punching_ready: false,
+            ping_id: random_u64(),
+            last_ping_req_time: None,
+            last_recv_ping_req_time: None,
+            last_punching_time: None,
+            punching_index: 0,
+            punching_index2: 0,
+            tries: 0,
+            direct_addr: None,
+        }
+    }
+
+    /// Address of the friend that can be used to send packets directly.
+    pub fn direct_addr(&self) -> Option<SocketAddr> {
+        self.direct_addr
+    }
+
+    /// Number of punching bursts that were made.
+    pub fn tries(&self) -> u32 {
+        self.tries
+    }
+
+    /**
+    Create [`NatPingRequest`] that should be sent to the friend through
+    nodes close to it.
+
+    Returns `None` if the friend is already reachable directly, less than
+    half of `MAX_FRIEND_CLIENTS` reported its `IP:port` or if the request
+    was sent less than `PUNCH_INTERVAL` seconds ago.
+
+    [`NatPingRequest`]: ../dht_new/packet/struct.NatPingRequest.html
+    */
+    pub fn nat_ping_request(&mut self, reports: &[SocketAddr]) -> Option<NatPingRequest> {
+        if self.direct_addr.is_some() || reports.len() < MAX_FRIEND_CLIENTS / 2 {
+            return None
+        }
+        if !timed_out(self.last_ping_req_time, PUNCH_INTERVAL) {
+            return None
+        }
+        self.last_ping_req_time = Some(Instant::now());
+        Some(NatPingRequest { id: self.ping_id })
+    }
+
+    /**
+    Handle [`NatPingRequest`] received from the friend. The friend is about
+    to start punching so we should punch as well.
+
+    Returns [`NatPingResponse`] that should be sent back to the friend.
+
+    [`NatPingRequest`]: ../dht_new/packet/struct.NatPingRequest.html
+    [`NatPingResponse`]: ../dht_new/packet/struct.NatPingResponse.html
+    */
+    pub fn handle_nat_ping_request(&mut self, request: &NatPingRequest) -> NatPingResponse {
+        self.last_recv_ping_req_time = Some(Instant::now());
+        NatPingResponse { id: request.id }
+    }
+
+    /**
+    Handle [`NatPingResponse`] received from the friend. If it has ID of
+    our request, punching will be done with the next call of
+    [`punching_addrs`](#method.punching_addrs).
+
+    Returns `false` if ID of the response is unknown.
+
+    [`NatPingResponse`]: ../dht_new/packet/struct.NatPingResponse.html
+    */
+    pub fn handle_nat_ping_response(&mut self, response: &NatPingResponse) -> bool {
+        if response.id != self.ping_id {
+            debug!("Received NatPingResponse with unknown id");
+            return false
+        }
+        self.ping_id = random_u64();
+        self.is_punching_ready = true;
+        true
+    }
+
+    /**
+    Handle direct response of the friend to DHT ping request, e.g. one that
+    was sent to a punching address. The friend can be reached at `addr`
+    from now on.
+    */
+    pub fn handle_friend_response(&mut self, addr: SocketAddr) {
+        if self.direct_addr != Some(addr) {
+            debug!("Friend is reachable directly at {}", addr);
+        }
+        self.direct_addr = Some(addr);
+        self.is_punching_ready = false;
+        self.tries = 0;
+    }
+
+    /**
+    Forget the direct address, e.g. when the friend stops responding on it.
+    Hole punching will be started again.
+    */
+    pub fn reset_direct_addr(&mut self) {
+        self.direct_addr = None;
+    }
+
+    /**
+    Get addresses that DHT ping requests should be sent to in order to
+    punch a hole in NATs of both sides.
+
+    Punching is done when the friend responded to our NAT ping request and
+    we received NAT ping request from it recently. If all nodes report the
+    same port for the common IP, only that port is pinged. Otherwise up to
+    `MAX_PUNCHING_PORTS` ports around the reported ones are guessed. After
+    `MAX_NORMAL_PUNCHING_TRIES` bursts ports starting from
+    `PUNCHING_PORT_START` are pinged as well.
+    */
+    pub fn punching_addrs(&mut self, reports: &[SocketAddr]) -> Vec<SocketAddr> {
+        if self.direct_addr.is_some() || !self.is_punching_ready ||
+            !timed_out(self.last_punching_time, PUNCH_INTERVAL) ||
+            timed_out(self.last_recv_ping_req_time, PUNCH_INTERVAL * 2) {
+            return Vec::new()
+        }
+        let ip = match common_ip(reports, MAX_FRIEND_CLIENTS / 2) {
+            Some(ip) => ip,
+            None => return Vec::new(),
+        };
+        let ports = common_ip_ports(reports, ip);
+        let addrs = self.punch_holes(ip, &ports);
+        self.last_punching_time = Some(Instant::now());
+        self.is_punching_ready = false;
+        addrs
+    }
+
+    /// Create one punching burst for `ip` and reported `ports`.
+    fn punch_holes(&mut self, ip: IpAddr, ports: &[u16]) -> Vec<SocketAddr> {
+        let mut addrs = Vec::new();
+        if self.tries < MAX_NORMAL_PUNCHING_TRIES {
+            self.punching_index = 0;
+        }
+        if ports.is_empty() || ports.len() > MAX_FRIEND_CLIENTS {
+            return addrs
+        }
+
+        if ports.iter().all(|&port| port == ports[0]) {
+            // friend's NAT keeps the port so there is nothing to guess
+            addrs.push(SocketAddr::new(ip, ports[0]));
+        } else {
+            let num_ports = ports.len() as u32;
+            for i in 0 .. MAX_PUNCHING_PORTS {
+                let it = i + self.punching_index;
+                let delta = (it / (2 * num_ports)) as u16;
+                let port = ports[((it / 2) % num_ports) as usize];
+                let port = if it % 2 == 1 {
+                    port.wrapping_sub(delta)
+                } else {
+                    port.wrapping_add(delta)
+                };
+                addrs.push(SocketAddr::new(ip, port));
+            }
+            self.punching_index += MAX_PUNCHING_PORTS;
+        }
+
+        if self.tries > MAX_NORMAL_PUNCHING_TRIES {
+            for i in self.punching_index2 .. self.punching_index2 + MAX_PUNCHING_PORTS {
+                addrs.push(SocketAddr::new(ip, PUNCHING_PORT_START.wrapping_add(i as u16)));
+            }
+            self.punching_index2 += MAX_PUNCHING_PORTS / 2;
+        }
+
+        self.tries += 1;
+        addrs
+    }
+}
+
+/// Check whether `secs` passed since `time` or `time` is absent.
+fn timed_out(time: Option<Instant>, secs: u64) -> bool {
+    time.map_or(true, |time| time.elapsed() >= Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(addrs: &[&str]) -> Vec<SocketAddr> {
+        addrs.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn nat_kind_classify() {
+        let same = addrs(&["1.2.3.4:100", "1.2.3.4:100", "1.2.3.4:100", "5.6.7.8:1"]);
+        assert_eq!(NatKind::ConeNat, NatKind::classify(&same, true));
+        assert_eq!(NatKind::ResConeNat, NatKind::classify(&same, false));
+        let sym = addrs(&["1.2.3.4:100", "1.2.3.4:101", "1.2.3.4:105", "5.6.7.8:1"]);
+        assert_eq!(NatKind::SymNat, NatKind::classify(&sym, false));
+        let other = addrs(&["1.2.3.4:100", "1.2.3.4:100", "5.6.7.8:1", "9.9.9.9:1"]);
+        assert_eq!(NatKind::Other, NatKind::classify(&other, false));
+        assert_eq!(NatKind::Other, NatKind::classify(&[], false));
+    }
+
+    #[test]
+    fn common_ip_test() {
+        let reports = addrs(&["1.2.3.4:100", "5.6.7.8:1", "1.2.3.4:101", "[::1]:1"]);
+        assert_eq!(Some("1.2.3.4".parse().unwrap()), common_ip(&reports, 2));
+        assert_eq!(None, common_ip(&reports, 3));
+        assert_eq!(vec![100, 101], common_ip_ports(&reports, "1.2.3.4".parse().unwrap()));
+    }
+
+    #[test]
+    fn nat_ping_request_needs_enough_reports() {
+        let mut hole_punching = HolePunching::new();
+        let reports = addrs(&["1.2.3.4:100", "1.2.3.4:100", "1.2.3.4:100"]);
+        assert_eq!(None, hole_punching.nat_ping_request(&reports));
+    }
+
+    #[test]
+    fn nat_ping_request_interval() {
+        let mut hole_punching = HolePunching::new();
+        let reports = addrs(&["1.2.3.4:100"; 4]);
+        let request = hole_punching.nat_ping_request(&reports).unwrap();
+        assert_eq!(hole_punching.ping_id, request.id);
+        assert_eq!(None, hole_punching.nat_ping_request(&reports));
+    }
+
+    #[test]
+    fn handle_nat_ping_response_unknown_id() {
+        let mut hole_punching = HolePunching::new();
+        let response = NatPingResponse { id: hole_punching.ping_id.wrapping_add(1) };
+        assert!(!hole_punching.handle_nat_ping_response(&response));
+        assert!(!hole_punching.is_punching_ready);
+    }
+
+    #[test]
+    fn punching_same_port() {
+        let mut alice = HolePunching::new();
+        let mut bob = HolePunching::new();
+        let reports = addrs(&["1.2.3.4:100"; 4]);
+
+        // alice and bob exchange NAT pings
+        let request = alice.nat_ping_request(&reports).unwrap();
+        let response = bob.handle_nat_ping_request(&request);
+        let request = bob.nat_ping_request(&reports).unwrap();
+        let old_id = alice.ping_id;
+        assert!(alice.handle_nat_ping_request(&request).id == request.id);
+        assert!(alice.handle_nat_ping_response(&response));
+        assert!(old_id != alice.ping_id);
+
+        assert_eq!(addrs(&["1.2.3.4:100"]), alice.punching_addrs(&reports));
+        assert_eq!(1, alice.tries());
+        // the next burst is done only after the next NAT ping response
+        assert!(alice.punching_addrs(&reports).is_empty());
+
+        alice.handle_friend_response("1.2.3.4:100".parse().unwrap());
+        assert_eq!(Some("1.2.3.4:100".parse().unwrap()), alice.direct_addr());
+        assert_eq!(None, alice.nat_ping_request(&reports));
+    }
+
+    #[test]
+    fn punching_requires_friend_request() {
+        let mut alice = HolePunching::new();
+        let reports = addrs(&["1.2.3.4:100"; 4]);
+        let response = NatPingResponse { id: alice.ping_id };
+        assert!(alice.handle_nat_ping_response(&response));
+        assert!(alice.punching_addrs(&reports).is_empty());
+    }
+
+    #[test]
+    fn punching_guesses_ports() {
+        let mut hole_punching = HolePunching::new();
+        let reports = addrs(&["1.2.3.4:100", "1.2.3.4:200", "1.2.3.4:200", "1.2.3.4:300"]);
+        hole_punching.is_punching_ready = true;
+        hole_punching.last_recv_ping_req_time = Some(Instant::now());
+
+        let punching = hole_punching.punching_addrs(&reports);
+        assert_eq!(MAX_PUNCHING_PORTS as usize, punching.len());
+        let ports = punching.iter().map(|addr| addr.port()).collect::<Vec<_>>();
+        assert_eq!(&[100, 100, 200, 200, 200, 200, 300, 300, 101, 99], &ports[..10]);
+        assert!(punching.iter().all(|addr| addr.ip() == "1.2.3.4".parse::<IpAddr>().unwrap()));
+    }
+
+    #[test]
+    fn punching_after_normal_tries() {
+        let mut hole_punching = HolePunching::new();
+        let ip = "1.2.3.4".parse().unwrap();
+        hole_punching.tries = MAX_NORMAL_PUNCHING_TRIES + 1;
+        let punching = hole_punching.punch_holes(ip, &[100, 200]);
+        assert_eq!(2 * MAX_PUNCHING_PORTS as usize, punching.len());
+        assert_eq!(SocketAddr::new(ip, PUNCHING_PORT_START), punching[MAX_PUNCHING_PORTS as usize]);
+        assert_eq!(MAX_PUNCHING_PORTS, hole_punching.punching_index);
+        assert_eq!(MAX_PUNCHING_PORTS / 2, hole_punching.punching_index2);
+
+        // guessing continues from the previous index
+        let punching = hole_punching.punch_holes(ip, &[100, 200]);
+        assert_eq!(SocketAddr::new(ip, 112), punching[0]);
+        assert_eq!(SocketAddr::new(ip, PUNCHING_PORT_START + (MAX_PUNCHING_PORTS / 2) as u16),
+            punching[MAX_PUNCHING_PORTS as usize]);
+    }
+}
@@ -0,0 +1,194 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*!
+LAN discovery lets nodes on the same local network find each other
+without bootstrap nodes.
+
+Every [`LAN_DISCOVERY_INTERVAL`] seconds [`LanDiscovery`] packet with own
+DHT `PublicKey` is broadcasted to the IPv4 broadcast address and to the
+IPv6 all-nodes multicast address on every default Tox port. Node that
+receives it from a LAN address pings the sender to add it to its close
+list.
+
+https://zetok.github.io/tox-spec/#lan-discovery
+
+[`LAN_DISCOVERY_INTERVAL`]: ./constant.LAN_DISCOVERY_INTERVAL.html
+[`LanDiscovery`]: ../dht_new/packet/struct.LanDiscovery.html
+*/
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use toxcore::crypto_core::*;
+use toxcore::dht_new::packet::LanDiscovery;
+use toxcore::dht_node::{ToxUdpPacket, UdpPacket};
+use toxcore::network::{PORT_MIN, PORT_MAX};
+
+/// Interval in seconds between LAN discovery broadcasts.
+pub const LAN_DISCOVERY_INTERVAL: u64 = 10;
+
+/// IPv6 multicast address of all nodes on the local network, `FF02::1`.
+pub fn ipv6_all_nodes() -> Ipv6Addr {
+    Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0x01)
+}
+
+/**
+Check whether `ip` belongs to the local network.
+
+IPv4 private, shared (carrier-grade NAT, `100.64.0.0/10`), loopback and
+link-local addresses are considered to be LAN addresses. IPv6 loopback,
+link-local and unique local (`fc00::/7`) addresses are LAN addresses as
+well as IPv4-mapped LAN addresses.
+*/
+pub fn is_lan_ip(ip: &IpAddr) -> bool {
+    match *ip {
+        IpAddr::V4(ref ip) => {
+            let octets = ip.octets();
+            ip.is_private() || ip.is_loopback() || ip.is_link_local() ||
+                octets[0] == 100 && octets[1] & 0xc0 == 64
+        },
+        IpAddr::V6(ref ip) => {
+            let first_segment = ip.segments()[0];
+            if ip.is_loopback() || first_segment & 0xffc0 == 0xfe80 ||
+                first_segment & 0xfe00 == 0xfc00 {
+                return true
+            }
+            match ip.to_ipv4() {
+                // `to_ipv4` converts IPv4-compatible addresses as well, so
+                // check that the address is IPv4-mapped
+                Some(ref ipv4) if ip.segments()[5] == 0xffff => is_lan_ip(&IpAddr::V4(*ipv4)),
+                _ => false,
+            }
+        },
+    }
+}
+
+/**
+Creates [`LanDiscovery`] packets that should be broadcasted periodically.
+
+Packets are sent to every port in the default Tox port range since other
+nodes on the same machine or network may be bound to any of them.
+
+[`LanDiscovery`]: ../dht_new/packet/struct.LanDiscovery.html
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LanDiscoverySender {
+    /// Own DHT `PublicKey`
+    dht_pk: PublicKey,
+    /// Whether packets are sent from IPv6 socket
+    ipv6: bool,
+}
+
+impl LanDiscoverySender {
+    /**
+    Create new `LanDiscoverySender`.
+
+    `ipv6` should be `true` if packets will be sent from IPv6 socket. In
+    that case IPv4 broadcast address is sent as IPv4-mapped IPv6 address
+    and IPv6 multicast address is used as well.
+    */
+    pub fn new(dht_pk: PublicKey, ipv6: bool) -> LanDiscoverySender {
+        LanDiscoverySender {
+            dht_pk: dht_pk,
+            ipv6: ipv6,
+        }
+    }
+
+    /// IP addresses that LAN discovery packets are broadcasted to.
+    pub fn broadcast_ips(&self) -> Vec<IpAddr> {
+        let broadcast = Ipv4Addr::new(255, 255, 255, 255);
+        if self.ipv6 {
+            vec![
+                IpAddr::V6(ipv6_all_nodes()),
+                IpAddr::V6(broadcast.to_ipv6_mapped()),
+            ]
+        } else {
+            vec![IpAddr::V4(broadcast)]
+        }
+    }
+
+    /// Socket addresses that LAN discovery packets are broadcasted to.
+    pub fn broadcast_addresses(&self) -> Vec<SocketAddr> {
+        let mut addresses = Vec::new();
+        for ip in self.broadcast_ips() {
+            for port in PORT_MIN .. PORT_MAX + 1 {
+                addresses.push(SocketAddr::new(ip, port));
+            }
+        }
+        addresses
+    }
+
+    /**
+    Create [`LanDiscovery`] packets for all broadcast addresses. Should be
+    called every [`LAN_DISCOVERY_INTERVAL`] seconds.
+
+    [`LAN_DISCOVERY_INTERVAL`]: ./constant.LAN_DISCOVERY_INTERVAL.html
+    [`LanDiscovery`]: ../dht_new/packet/struct.LanDiscovery.html
+    */
+    pub fn packets(&self) -> Vec<ToxUdpPacket> {
+        let packet = LanDiscovery { pk: self.dht_pk };
+        self.broadcast_addresses().into_iter()
+            .map(|addr| (addr, UdpPacket::LanDiscovery(packet)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_lan_ip_test() {
+        let lan: &[&str] = &["192.168.1.1", "10.0.0.1", "172.16.0.1", "127.0.0.1",
+            "169.254.0.1", "100.64.0.1", "100.127.255.254", "::1", "fe80::1",
+            "fc00::1", "fd12:3456::1", "::ffff:192.168.1.1", "::ffff:100.64.0.1"];
+        for ip in lan {
+            assert!(is_lan_ip(&ip.parse().unwrap()), "{} should be LAN", ip);
+        }
+        let not_lan: &[&str] = &["8.8.8.8", "172.32.0.1", "100.63.255.255",
+            "100.128.0.1", "2001:db8::1", "fe00::1", "fbff::1", "::ffff:8.8.8.8",
+            "::192.168.1.1"];
+        for ip in not_lan {
+            assert!(!is_lan_ip(&ip.parse().unwrap()), "{} should not be LAN", ip);
+        }
+    }
+
+    #[test]
+    fn lan_discovery_sender_ipv4() {
+        let (pk, _) = gen_keypair();
+        let sender = LanDiscoverySender::new(pk, false);
+        let packets = sender.packets();
+        assert_eq!((PORT_MAX - PORT_MIN + 1) as usize, packets.len());
+        for (addr, packet) in packets {
+            assert_eq!(IpAddr::V4(Ipv4Addr::new(255, 255, 255, 255)), addr.ip());
+            assert!(addr.port() >= PORT_MIN && addr.port() <= PORT_MAX);
+            assert_eq!(UdpPacket::LanDiscovery(LanDiscovery { pk: pk }), packet);
+        }
+    }
+
+    #[test]
+    fn lan_discovery_sender_ipv6() {
+        let (pk, _) = gen_keypair();
+        let sender = LanDiscoverySender::new(pk, true);
+        let ips = sender.broadcast_ips();
+        assert!(ips.contains(&IpAddr::V6(ipv6_all_nodes())));
+        assert!(ips.contains(&"::ffff:255.255.255.255".parse().unwrap()));
+        assert_eq!(2 * (PORT_MAX - PORT_MIN + 1) as usize, sender.broadcast_addresses().len());
+    }
+}
@@ -0,0 +1,358 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! Congestion control of net crypto connections.
+
+The rate at which lossless packets are sent is adapted to the link the
+same way c-toxcore does it. Every
+[`PACKET_COUNTER_AVERAGE_INTERVAL`](./constant.PACKET_COUNTER_AVERAGE_INTERVAL.html)
+milliseconds the size of the send queue is recorded. If the queue grows it
+means that the other node receives packets slower than we send them, so
+the send rate is decreased. If the queue doesn't grow and no packets were
+lost recently the rate is increased.
+
+Sending is limited by a token bucket that is filled according to the
+current send rate.
+*/
+
+use std::time::{Duration, Instant};
+
+/// Interval in milliseconds between updates of the send rate.
+pub const PACKET_COUNTER_AVERAGE_INTERVAL: u64 = 50;
+
+/// Number of send queue sizes that are used to calculate the send rate.
+pub const CONGESTION_QUEUE_ARRAY_SIZE: usize = 12;
+
+/// Number of remembered counters of sent packets. It's bigger than
+/// [`CONGESTION_QUEUE_ARRAY_SIZE`](./constant.CONGESTION_QUEUE_ARRAY_SIZE.html)
+/// to compensate the delay of acknowledgements.
+pub const CONGESTION_LAST_SENT_ARRAY_SIZE: usize = CONGESTION_QUEUE_ARRAY_SIZE * 2;
+
+/// Minimal send rate in packets per second.
+pub const CRYPTO_PACKET_MIN_RATE: f64 = 4.0;
+
+/// Send queue doesn't limit the send rate while it's shorter than this.
+/// It's also the maximum number of packets that can be sent at once.
+pub const CRYPTO_MIN_QUEUE_LENGTH: u32 = 64;
+
+/// Maximum ratio of the send queue size to the send rate after which the
+/// send rate is decreased.
+pub const SEND_QUEUE_RATIO: f64 = 2.0;
+
+/// Number of milliseconds after a lost packet during which the send rate
+/// isn't increased.
+pub const CONGESTION_EVENT_TIMEOUT: u64 = 1000;
+
+/// RTT in milliseconds that is used until it's measured.
+pub const DEFAULT_RTT: u64 = 1000;
+
+/// Convert `Duration` to milliseconds.
+fn as_millis(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1000.0 + f64::from(duration.subsec_nanos()) / 1_000_000.0
+}
+
+/// State of the congestion control of a single connection.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CongestionControl {
+    /// Current rate of sending new packets in packets per second
+    packet_send_rate: f64,
+    /// Current rate of resending requested packets in packets per second
+    packet_send_rate_requested: f64,
+    /// Number of new packets that can be sent now
+    packets_left: f64,
+    /// Number of requested packets that can be resent now
+    packets_left_requested: f64,
+    /// Time when `packets_left` was refilled
+    packets_left_time: Instant,
+    /// Number of new packets sent since the last update
+    packets_sent: u32,
+    /// Number of packets resent since the last update
+    packets_resent: u32,
+    /// Recorded sizes of the send queue
+    last_sendqueue_size: [u32; CONGESTION_QUEUE_ARRAY_SIZE],
+    /// Number of recorded sizes of the send queue
+    last_sendqueue_counter: usize,
+    /// Recorded numbers of sent packets
+    last_num_packets_sent: [u32; CONGESTION_LAST_SENT_ARRAY_SIZE],
+    /// Recorded numbers of resent packets
+    last_num_packets_resent: [u32; CONGESTION_LAST_SENT_ARRAY_SIZE],
+    /// Number of recorded numbers of sent packets
+    last_num_packets_counter: usize,
+    /// Time of the last update of the send rate
+    last_update: Instant,
+    /// Time when the other node requested a lost packet last time
+    last_congestion_event: Option<Instant>,
+    /// Minimal measured round trip time
+    rtt: Duration,
+}
+
+impl Default for CongestionControl {
+    fn default() -> Self {
+        CongestionControl::new()
+    }
+}
+
+impl CongestionControl {
+    /// Create new `CongestionControl` with minimal send rate.
+    pub fn new() -> CongestionControl {
+        let now = Instant::now();
+        CongestionControl {
+            packet_send_rate: CRYPTO_PACKET_MIN_RATE,
+            packet_send_rate_requested: CRYPTO_PACKET_MIN_RATE,
+            packets_left: f64::from(CRYPTO_MIN_QUEUE_LENGTH),
+            packets_left_requested: f64::from(CRYPTO_MIN_QUEUE_LENGTH),
+            packets_left_time: now,
+            packets_sent: 0,
+            packets_resent: 0,
+            last_sendqueue_size: [0; CONGESTION_QUEUE_ARRAY_SIZE],
+            last_sendqueue_counter: 0,
+            last_num_packets_sent: [0; CONGESTION_LAST_SENT_ARRAY_SIZE],
+            last_num_packets_resent: [0; CONGESTION_LAST_SENT_ARRAY_SIZE],
+            last_num_packets_counter: 0,
+            last_update: now,
+            last_congestion_event: None,
+            rtt: Duration::from_millis(DEFAULT_RTT),
+        }
+    }
+
+    /// Current rate of sending new packets in packets per second.
+    pub fn packet_send_rate(&self) -> f64 {
+        self.packet_send_rate
+    }
+
+    /// Current rate of resending requested packets in packets per second.
+    pub fn packet_send_rate_requested(&self) -> f64 {
+        self.packet_send_rate_requested
+    }
+
+    /// Minimal measured round trip time.
+    pub fn rtt(&self) -> Duration {
+        self.rtt
+    }
+
+    /// Update round trip time with a new measurement. Only the minimal
+    /// value is kept since bigger ones are caused by queues.
+    pub fn update_rtt(&mut self, rtt: Duration) {
+        if rtt < self.rtt {
+            self.rtt = rtt;
+        }
+    }
+
+    /// Remember that the other node lost a packet.
+    pub fn congestion_event(&mut self) {
+        self.last_congestion_event = Some(Instant::now());
+    }
+
+    /// Fill the token buckets according to the current send rates.
+    fn refill(&mut self) {
+        let elapsed = as_millis(self.packets_left_time.elapsed()) / 1000.0;
+        let max_packets = f64::from(CRYPTO_MIN_QUEUE_LENGTH);
+        self.packets_left = (self.packets_left + self.packet_send_rate * elapsed).min(max_packets);
+        self.packets_left_requested = (self.packets_left_requested +
+            self.packet_send_rate_requested * elapsed).min(max_packets);
+        self.packets_left_time = Instant::now();
+    }
+
+    /// Check whether a new packet can be sent now without exceeding the
+    /// send rate.
+    pub fn can_send(&mut self) -> bool {
+        self.refill();
+        self.packets_left >= 1.0
+    }
+
+    /// Number of requested packets that can be resent now.
+    pub fn requested_allowance(&mut self) -> usize {
+        self.refill();
+        self.packets_left_requested as usize
+    }
+
+    /// Count new sent packet.
+    pub fn on_packet_sent(&mut self) {
+        self.packets_sent += 1;
+        self.packets_left = (self.packets_left - 1.0).max(0.0);
+    }
+
+    /// Count resent packet.
+    pub fn on_packet_resent(&mut self) {
+        self.packets_resent += 1;
+        self.packets_left_requested = (self.packets_left_requested - 1.0).max(0.0);
+    }
+
+    /**
+    Recalculate send rates using current size of the send queue. Should be
+    called periodically, does nothing if less than
+    [`PACKET_COUNTER_AVERAGE_INTERVAL`](./constant.PACKET_COUNTER_AVERAGE_INTERVAL.html)
+    milliseconds passed since the last update.
+    */
+    pub fn update(&mut self, send_queue_size: u32) {
+        if self.last_update.elapsed() < Duration::from_millis(PACKET_COUNTER_AVERAGE_INTERVAL) {
+            return
+        }
+        self.last_update = Instant::now();
+
+        // growth of the send queue during the last intervals
+        let pos = self.last_sendqueue_counter % CONGESTION_QUEUE_ARRAY_SIZE;
+        self.last_sendqueue_size[pos] = send_queue_size;
+        self.last_sendqueue_counter += 1;
+        let oldest_pos = (pos + 1) % CONGESTION_QUEUE_ARRAY_SIZE;
+        let queue_growth = i64::from(send_queue_size) - i64::from(self.last_sendqueue_size[oldest_pos]);
+
+        let counter_pos = self.last_num_packets_counter % CONGESTION_LAST_SENT_ARRAY_SIZE;
+        self.last_num_packets_sent[counter_pos] = self.packets_sent;
+        self.last_num_packets_resent[counter_pos] = self.packets_resent;
+        self.last_num_packets_counter += 1;
+        self.packets_sent = 0;
+        self.packets_resent = 0;
+
+        // packets sent one RTT ago are acknowledged now, so use counters
+        // delayed by RTT
+        let delay = (as_millis(self.rtt) / PACKET_COUNTER_AVERAGE_INTERVAL as f64 + 0.5) as usize;
+        let delay = delay.min(CONGESTION_LAST_SENT_ARRAY_SIZE - CONGESTION_QUEUE_ARRAY_SIZE);
+        let mut total_sent = 0i64;
+        let mut total_resent = 0i64;
+        for i in 0 .. CONGESTION_QUEUE_ARRAY_SIZE {
+            let pos = (self.last_num_packets_counter + CONGESTION_LAST_SENT_ARRAY_SIZE * 2 - i - delay - 1)
+                % CONGESTION_LAST_SENT_ARRAY_SIZE;
+            total_sent += i64::from(self.last_num_packets_sent[pos]);
+            total_resent += i64::from(self.last_num_packets_resent[pos]);
+        }
+        if queue_growth > 0 {
+            // packets that stayed in the queue weren't received
+            total_sent -= queue_growth;
+        } else if total_resent > -queue_growth {
+            total_resent = -queue_growth;
+        }
+
+        let interval = CONGESTION_QUEUE_ARRAY_SIZE as f64 * PACKET_COUNTER_AVERAGE_INTERVAL as f64;
+        let min_speed = (1000.0 * total_sent as f64 / interval).max(CRYPTO_PACKET_MIN_RATE);
+        let min_speed_requested = 1000.0 * (total_sent + total_resent) as f64 / interval;
+
+        let send_array_ratio = f64::from(send_queue_size) / min_speed;
+        self.packet_send_rate = if send_array_ratio > SEND_QUEUE_RATIO && send_queue_size > CRYPTO_MIN_QUEUE_LENGTH {
+            min_speed * SEND_QUEUE_RATIO / send_array_ratio
+        } else if self.last_congestion_event.map_or(true, |time|
+            time.elapsed() > Duration::from_millis(CONGESTION_EVENT_TIMEOUT)) {
+            min_speed * 1.2
+        } else {
+            min_speed * 0.9
+        };
+        self.packet_send_rate = self.packet_send_rate.max(CRYPTO_PACKET_MIN_RATE);
+        self.packet_send_rate_requested = (min_speed_requested * 1.2).max(self.packet_send_rate);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pretend that the last update was a long time ago.
+    fn expire_update(congestion: &mut CongestionControl) {
+        congestion.last_update -= Duration::from_millis(PACKET_COUNTER_AVERAGE_INTERVAL);
+    }
+
+    #[test]
+    fn congestion_control_new() {
+        let congestion = CongestionControl::new();
+        assert_eq!(CRYPTO_PACKET_MIN_RATE, congestion.packet_send_rate());
+        assert_eq!(CRYPTO_PACKET_MIN_RATE, congestion.packet_send_rate_requested());
+        assert_eq!(Duration::from_millis(DEFAULT_RTT), congestion.rtt());
+    }
+
+    #[test]
+    fn congestion_control_update_rtt() {
+        let mut congestion = CongestionControl::new();
+        congestion.update_rtt(Duration::from_millis(100));
+        assert_eq!(Duration::from_millis(100), congestion.rtt());
+        congestion.update_rtt(Duration::from_millis(200));
+        assert_eq!(Duration::from_millis(100), congestion.rtt());
+    }
+
+    #[test]
+    fn congestion_control_can_send() {
+        let mut congestion = CongestionControl::new();
+        for _ in 0 .. CRYPTO_MIN_QUEUE_LENGTH {
+            assert!(congestion.can_send());
+            congestion.on_packet_sent();
+        }
+        assert!(!congestion.can_send());
+    }
+
+    #[test]
+    fn congestion_control_rate_grows() {
+        let mut congestion = CongestionControl::new();
+        congestion.update_rtt(Duration::from_millis(0));
+        for _ in 0 .. CONGESTION_QUEUE_ARRAY_SIZE {
+            for _ in 0 .. 10 {
+                congestion.on_packet_sent();
+            }
+            expire_update(&mut congestion);
+            congestion.update(0);
+        }
+        // 10 packets every 50 ms
+        assert!(congestion.packet_send_rate() > 200.0);
+    }
+
+    #[test]
+    fn congestion_control_rate_decreases_on_congestion() {
+        let mut congestion = CongestionControl::new();
+        congestion.update_rtt(Duration::from_millis(0));
+        congestion.congestion_event();
+        for _ in 0 .. CONGESTION_QUEUE_ARRAY_SIZE {
+            for _ in 0 .. 10 {
+                congestion.on_packet_sent();
+            }
+            expire_update(&mut congestion);
+            congestion.update(0);
+        }
+        assert!(congestion.packet_send_rate() < 200.0);
+        assert!(congestion.packet_send_rate() > 100.0);
+    }
+
+    #[test]
+    fn congestion_control_rate_decreases_on_queue_growth() {
+        let mut congestion = CongestionControl::new();
+        congestion.update_rtt(Duration::from_millis(0));
+        for i in 0 .. CONGESTION_QUEUE_ARRAY_SIZE as u32 {
+            for _ in 0 .. 10 {
+                congestion.on_packet_sent();
+            }
+            expire_update(&mut congestion);
+            // nothing is acknowledged
+            congestion.update(i * 10);
+        }
+        assert!(congestion.packet_send_rate() < 10.0);
+    }
+
+    #[test]
+    fn congestion_control_update_too_often() {
+        let mut congestion = CongestionControl::new();
+        congestion.update_rtt(Duration::from_millis(0));
+        for _ in 0 .. 10 {
+            congestion.on_packet_sent();
+        }
+        expire_update(&mut congestion);
+        congestion.update(0);
+        let rate = congestion.packet_send_rate();
+        for _ in 0 .. 10 {
+            congestion.on_packet_sent();
+        }
+        congestion.update(0);
+        assert_eq!(rate, congestion.packet_send_rate());
+    }
+}
@@ -0,0 +1,350 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! Cookies that are used to establish net crypto connections.
+
+Node sends its cookie in response to
+[`CookieRequest`](../packet/struct.CookieRequest.html). The cookie is
+encrypted with a symmetric key known only to this node, so it can't be
+read or forged by anyone else. The other node has to send the cookie back
+in [`CryptoHandshake`](../packet/struct.CryptoHandshake.html) which lets us
+avoid storing any state until the handshake is received.
+*/
+
+use nom::be_u64;
+
+use sodiumoxide::crypto::hash::sha512;
+
+use std::time::{Duration, Instant};
+
+use toxcore::crypto_core::*;
+use toxcore::dht_new::binary_io::*;
+use toxcore::timeout::unix_time;
+
+/// Number of seconds during which issued cookie is accepted.
+pub const COOKIE_TIMEOUT: u64 = 15;
+
+/**
+Number of seconds after which the symmetric key used to encrypt cookies is
+replaced with a new one.
+
+Cookies encrypted with the previous key are still accepted, so it has to
+be not less than [`COOKIE_TIMEOUT`](./constant.COOKIE_TIMEOUT.html).
+*/
+pub const COOKIE_KEY_REFRESH_INTERVAL: u64 = 2 * 60;
+
+/// Size of serialized [`Cookie`](./struct.Cookie.html) in bytes.
+pub const COOKIE_SIZE: usize = 8 + PUBLICKEYBYTES * 2;
+
+/// Size of serialized [`EncryptedCookie`](./struct.EncryptedCookie.html)
+/// in bytes.
+pub const ENCRYPTED_COOKIE_SIZE: usize = NONCEBYTES + COOKIE_SIZE + MACBYTES;
+
+/** Unencrypted cookie.
+
+Serialized form:
+
+Length | Content
+------ | ------
+`8`    | Unix time when the cookie was issued in BigEndian
+`32`   | Real `PublicKey` of the node the cookie was issued to
+`32`   | DHT `PublicKey` of the node the cookie was issued to
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Cookie {
+    /// Unix time when the cookie was issued
+    pub time: u64,
+    /// Real `PublicKey` of the node the cookie was issued to
+    pub real_pk: PublicKey,
+    /// DHT `PublicKey` of the node the cookie was issued to
+    pub dht_pk: PublicKey,
+}
+
+impl FromBytes for Cookie {
+    named!(from_bytes<Cookie>, do_parse!(
+        time: be_u64 >>
+        real_pk: call!(PublicKey::from_bytes) >>
+        dht_pk: call!(PublicKey::from_bytes) >>
+        (Cookie {
+            time: time,
+            real_pk: real_pk,
+            dht_pk: dht_pk,
+        })
+    ));
+}
+
+impl ToBytes for Cookie {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u64!(self.time) >>
+            gen_slice!(self.real_pk.as_ref()) >>
+            gen_slice!(self.dht_pk.as_ref())
+        )
+    }
+}
+
+impl Cookie {
+    /// Create new `Cookie` issued right now.
+    pub fn new(real_pk: PublicKey, dht_pk: PublicKey) -> Cookie {
+        Cookie {
+            time: unix_time(),
+            real_pk: real_pk,
+            dht_pk: dht_pk,
+        }
+    }
+
+    /// Check whether the cookie was issued more than
+    /// [`COOKIE_TIMEOUT`](./constant.COOKIE_TIMEOUT.html) seconds ago or
+    /// in the future.
+    pub fn is_timed_out(&self) -> bool {
+        let now = unix_time();
+        self.time > now || now - self.time > COOKIE_TIMEOUT
+    }
+}
+
+/** Cookie encrypted with the symmetric key of the node that issued it.
+
+Serialized form:
+
+Length | Content
+------ | ------
+`24`   | Nonce
+`88`   | Encrypted [`Cookie`](./struct.Cookie.html)
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct EncryptedCookie {
+    /// Nonce for the current encrypted payload
+    pub nonce: Nonce,
+    /// Encrypted payload
+    pub payload: Vec<u8>,
+}
+
+impl FromBytes for EncryptedCookie {
+    named!(from_bytes<EncryptedCookie>, do_parse!(
+        nonce: call!(Nonce::from_bytes) >>
+        payload: take!(COOKIE_SIZE + MACBYTES) >>
+        (EncryptedCookie {
+            nonce: nonce,
+            payload: payload.to_vec(),
+        })
+    ));
+}
+
+impl ToBytes for EncryptedCookie {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_slice!(self.nonce.as_ref()) >>
+            gen_slice!(self.payload.as_slice())
+        )
+    }
+}
+
+impl EncryptedCookie {
+    /// Create new `EncryptedCookie` with `cookie` encrypted by
+    /// `symmetric_key`.
+    pub fn new(symmetric_key: &PrecomputedKey, cookie: &Cookie) -> EncryptedCookie {
+        let nonce = gen_nonce();
+        let mut buf = [0; COOKIE_SIZE];
+        let (_, size) = cookie.to_bytes((&mut buf, 0))
+            .expect("Cookie should fit in COOKIE_SIZE");
+        EncryptedCookie {
+            nonce: nonce,
+            payload: encrypt_data_symmetric(symmetric_key, &nonce, &buf[..size]),
+        }
+    }
+
+    /** Decrypt payload with `symmetric_key` and try to parse it as
+    [`Cookie`](./struct.Cookie.html).
+
+    Returns `None` in case of failure.
+    */
+    pub fn get_payload(&self, symmetric_key: &PrecomputedKey) -> Option<Cookie> {
+        let decrypted = match decrypt_data_symmetric(symmetric_key, &self.nonce, &self.payload) {
+            Ok(decrypted) => decrypted,
+            Err(()) => {
+                debug!("Decrypting EncryptedCookie failed!");
+                return None
+            },
+        };
+        Cookie::from_bytes(&decrypted).to_full_result().ok()
+    }
+
+    /// Calculate SHA512 hash of the serialized cookie. It's sent in
+    /// [`CryptoHandshakePayload`](../packet/struct.CryptoHandshakePayload.html)
+    /// to prove that the handshake was made for this cookie.
+    pub fn hash(&self) -> sha512::Digest {
+        let mut buf = [0; ENCRYPTED_COOKIE_SIZE];
+        let (_, size) = self.to_bytes((&mut buf, 0))
+            .expect("EncryptedCookie should fit in ENCRYPTED_COOKIE_SIZE");
+        sha512::hash(&buf[..size])
+    }
+}
+
+/**
+Symmetric keys used to issue cookies and to open cookies sent back by
+other nodes.
+
+The key is replaced with a new one every
+[`COOKIE_KEY_REFRESH_INTERVAL`] seconds. Cookies encrypted with the
+previous key are still accepted until they time out.
+
+[`COOKIE_KEY_REFRESH_INTERVAL`]: ./constant.COOKIE_KEY_REFRESH_INTERVAL.html
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CookieSecret {
+    /// key that is used to encrypt new cookies
+    current_key: PrecomputedKey,
+    /// key that was used before `current_key`
+    previous_key: PrecomputedKey,
+    /// time when `current_key` was generated
+    key_time: Instant,
+}
+
+impl Default for CookieSecret {
+    fn default() -> Self {
+        CookieSecret::new()
+    }
+}
+
+impl CookieSecret {
+    /// Create new `CookieSecret` with random keys.
+    pub fn new() -> CookieSecret {
+        CookieSecret {
+            current_key: new_symmetric_key(),
+            previous_key: new_symmetric_key(),
+            key_time: Instant::now(),
+        }
+    }
+
+    /// Replace the current key with a new one if it's older than
+    /// [`COOKIE_KEY_REFRESH_INTERVAL`](./constant.COOKIE_KEY_REFRESH_INTERVAL.html).
+    fn refresh(&mut self) {
+        if self.key_time.elapsed() >= Duration::from_secs(COOKIE_KEY_REFRESH_INTERVAL) {
+            debug!("Refreshing cookie symmetric key");
+            self.previous_key = self.current_key.clone();
+            self.current_key = new_symmetric_key();
+            self.key_time = Instant::now();
+        }
+    }
+
+    /// Issue new cookie for the node with `real_pk` and `dht_pk`.
+    pub fn issue_cookie(&mut self, real_pk: PublicKey, dht_pk: PublicKey) -> EncryptedCookie {
+        self.refresh();
+        EncryptedCookie::new(&self.current_key, &Cookie::new(real_pk, dht_pk))
+    }
+
+    /**
+    Decrypt cookie that was issued by this node and check that it's not
+    timed out.
+
+    Returns `None` if cookie wasn't issued by this node or is timed out.
+    */
+    pub fn open_cookie(&mut self, cookie: &EncryptedCookie) -> Option<Cookie> {
+        self.refresh();
+        let cookie = match cookie.get_payload(&self.current_key) {
+            Some(cookie) => cookie,
+            None => match cookie.get_payload(&self.previous_key) {
+                Some(cookie) => cookie,
+                None => return None,
+            },
+        };
+        if cookie.is_timed_out() {
+            debug!("Received timed out cookie");
+            None
+        } else {
+            Some(cookie)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cookie_encode_decode() {
+        let cookie = Cookie::new(gen_keypair().0, gen_keypair().0);
+        let mut buf = [0; COOKIE_SIZE];
+        let (_, size) = cookie.to_bytes((&mut buf, 0)).unwrap();
+        assert_eq!(COOKIE_SIZE, size);
+        let (rest, decoded) = Cookie::from_bytes(&buf[..size]).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(cookie, decoded);
+    }
+
+    #[test]
+    fn encrypted_cookie_encode_decode() {
+        let cookie = EncryptedCookie::new(&new_symmetric_key(),
+            &Cookie::new(gen_keypair().0, gen_keypair().0));
+        let mut buf = [0; ENCRYPTED_COOKIE_SIZE];
+        let (_, size) = cookie.to_bytes((&mut buf, 0)).unwrap();
+        assert_eq!(ENCRYPTED_COOKIE_SIZE, size);
+        let (rest, decoded) = EncryptedCookie::from_bytes(&buf[..size]).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(cookie, decoded);
+    }
+
+    #[test]
+    fn cookie_is_timed_out() {
+        let mut cookie = Cookie::new(gen_keypair().0, gen_keypair().0);
+        assert!(!cookie.is_timed_out());
+        cookie.time -= COOKIE_TIMEOUT + 1;
+        assert!(cookie.is_timed_out());
+        cookie.time = unix_time() + 10;
+        assert!(cookie.is_timed_out());
+    }
+
+    #[test]
+    fn cookie_secret_issue_open() {
+        let mut cookie_secret = CookieSecret::new();
+        let (real_pk, _) = gen_keypair();
+        let (dht_pk, _) = gen_keypair();
+
+        let encrypted_cookie = cookie_secret.issue_cookie(real_pk, dht_pk);
+        let cookie = cookie_secret.open_cookie(&encrypted_cookie).unwrap();
+        assert_eq!(real_pk, cookie.real_pk);
+        assert_eq!(dht_pk, cookie.dht_pk);
+
+        // cookie issued by the other node
+        let mut other_secret = CookieSecret::new();
+        assert!(other_secret.open_cookie(&encrypted_cookie).is_none());
+    }
+
+    #[test]
+    fn cookie_secret_open_with_previous_key() {
+        let mut cookie_secret = CookieSecret::new();
+        let encrypted_cookie = cookie_secret.issue_cookie(gen_keypair().0, gen_keypair().0);
+
+        cookie_secret.previous_key = cookie_secret.current_key.clone();
+        cookie_secret.current_key = new_symmetric_key();
+        assert!(cookie_secret.open_cookie(&encrypted_cookie).is_some());
+
+        cookie_secret.previous_key = new_symmetric_key();
+        assert!(cookie_secret.open_cookie(&encrypted_cookie).is_none());
+    }
+
+    #[test]
+    fn cookie_secret_open_timed_out() {
+        let mut cookie_secret = CookieSecret::new();
+        let mut cookie = Cookie::new(gen_keypair().0, gen_keypair().0);
+        cookie.time -= COOKIE_TIMEOUT + 1;
+        let encrypted_cookie = EncryptedCookie::new(&cookie_secret.current_key, &cookie);
+        assert!(cookie_secret.open_cookie(&encrypted_cookie).is_none());
+    }
+}
@@ -0,0 +1,853 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! State of a single net crypto connection.
+
+Connection goes through the following states:
+
+- `CookieRequesting` – we sent [`CookieRequest`] and wait for the cookie
+- `HandshakeSending` – we sent [`CryptoHandshake`] and wait for the
+  handshake of the other node
+- `NotConfirmed` – handshakes were exchanged but nothing was received over
+  the session yet
+- `Established` – the other node proved that it knows the session key
+
+Connections initiated by the other node start in `NotConfirmed` state
+since we answer its handshake with our own one right away.
+
+Once handshakes were exchanged data is sent in [`CryptoData`] packets.
+Lossless packets are numbered and stored in the send buffer until the
+other node acknowledges them. Missing lossless packets are requested with
+request packets and resent. The rate of sending lossless packets is
+limited by [congestion control](../congestion/index.html).
+
+[`CookieRequest`]: ../packet/struct.CookieRequest.html
+[`CryptoHandshake`]: ../packet/struct.CryptoHandshake.html
+[`CryptoData`]: ../packet/struct.CryptoData.html
+*/
+
+use byteorder::{BigEndian, ByteOrder};
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use toxcore::crypto_core::*;
+use toxcore::net_crypto::congestion::*;
+use toxcore::net_crypto::cookie::*;
+use toxcore::net_crypto::packet::*;
+use toxcore::net_crypto::packets_array::*;
+
+/**
+Maximum difference between the last 2 bytes of the received nonce and the
+base nonce of the other node. When it's exceeded the base nonce is moved
+forward so that it doesn't fall behind.
+*/
+pub const DATA_NUM_THRESHOLD: u16 = 21_845;
+
+/// Interval in seconds between request packets that tell the other node
+/// which lossless packets we didn't receive.
+pub const REQUEST_PACKET_INTERVAL: u64 = 1;
+
+/// Status of a net crypto connection.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ConnectionStatus {
+    /// We sent cookie request and wait for the cookie
+    CookieRequesting {
+        /// ID of the sent cookie request
+        cookie_request_id: u64,
+        /// Key precomputed with DHT `PublicKey` of the other node that is
+        /// used to decrypt the cookie response
+        dht_precomputed_key: PrecomputedKey,
+        /// Sent cookie request that should be resent if it got lost
+        packet: CookieRequest,
+    },
+    /// We sent our handshake and wait for the handshake of the other node
+    HandshakeSending {
+        /// Sent handshake that should be resent if it got lost
+        packet: CryptoHandshake,
+    },
+    /// Handshakes were exchanged but the other node didn't confirm that
+    /// it knows the session key yet
+    NotConfirmed {
+        /// Base nonce of the other node
+        received_nonce: Nonce,
+        /// Session `PublicKey` of the other node
+        peer_session_pk: PublicKey,
+        /// Key that is used to encrypt and decrypt data of the session
+        session_precomputed_key: PrecomputedKey,
+        /// Sent handshake that should be resent until the connection is
+        /// confirmed
+        packet: CryptoHandshake,
+    },
+    /// Connection is established and data can be exchanged
+    Established {
+        /// Base nonce of the other node
+        received_nonce: Nonce,
+        /// Session `PublicKey` of the other node
+        peer_session_pk: PublicKey,
+        /// Key that is used to encrypt and decrypt data of the session
+        session_precomputed_key: PrecomputedKey,
+    },
+}
+
+/// Lossless packet stored in the send buffer until it's acknowledged.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SentPacket {
+    /// Data of the packet
+    pub data: Vec<u8>,
+    /// Time when the packet was sent the last time or `None` if it was
+    /// requested by the other node and should be resent
+    pub sent_time: Option<Instant>,
+}
+
+/// Result of handling of received data.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum CryptoDataEvent {
+    /// Lossless data that is delivered in the order it was sent
+    Lossless(Vec<u8>),
+    /// Lossy data that is delivered as soon as it's received
+    Lossy(Vec<u8>),
+    /// The other node closed the connection
+    Killed,
+}
+
+/// Net crypto connection with a node identified by its real `PublicKey`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CryptoConnection {
+    /// Real `PublicKey` of the other node
+    pub peer_real_pk: PublicKey,
+    /// DHT `PublicKey` of the other node
+    pub peer_dht_pk: PublicKey,
+    /// Our session `PublicKey` of this connection
+    pub session_pk: PublicKey,
+    /// Our session `SecretKey` of this connection
+    session_sk: SecretKey,
+    /// Our base nonce that is used to encrypt data of the session
+    pub sent_nonce: Nonce,
+    /// Current status of the connection
+    pub status: ConnectionStatus,
+    /// UDP address of the other node if known
+    pub udp_addr: Option<SocketAddr>,
+    /// Lossless packets that weren't acknowledged by the other node yet
+    pub send_array: PacketsArray<SentPacket>,
+    /// Received lossless packets that can't be delivered yet because some
+    /// previous packets are missing
+    pub recv_array: PacketsArray<Vec<u8>>,
+    /// Time when the last request packet was sent
+    request_packet_sent_time: Option<Instant>,
+    /// Congestion control of lossless packets
+    congestion: CongestionControl,
+}
+
+impl CryptoConnection {
+    /** Create new connection initiated by us.
+
+    Connection starts in `CookieRequesting` status with `CookieRequest`
+    that should be sent to the other node's DHT address.
+    */
+    pub fn new(dht_sk: &SecretKey, dht_pk: &PublicKey, real_pk: &PublicKey,
+               peer_real_pk: PublicKey, peer_dht_pk: PublicKey) -> CryptoConnection {
+        let (session_pk, session_sk) = gen_keypair();
+        let cookie_request_id = random_u64();
+        let cookie_request_payload = CookieRequestPayload {
+            pk: *real_pk,
+            id: cookie_request_id,
+        };
+        let dht_precomputed_key = encrypt_precompute(&peer_dht_pk, dht_sk);
+        let packet = CookieRequest::new(&dht_precomputed_key, dht_pk, &cookie_request_payload);
+        CryptoConnection {
+            peer_real_pk: peer_real_pk,
+            peer_dht_pk: peer_dht_pk,
+            session_pk: session_pk,
+            session_sk: session_sk,
+            sent_nonce: gen_nonce(),
+            status: ConnectionStatus::CookieRequesting {
+                cookie_request_id: cookie_request_id,
+                dht_precomputed_key: dht_precomputed_key,
+                packet: packet,
+            },
+            udp_addr: None,
+            send_array: PacketsArray::new(),
+            recv_array: PacketsArray::new(),
+            request_packet_sent_time: None,
+            congestion: CongestionControl::new(),
+        }
+    }
+
+    /** Create new connection initiated by the other node that sent us
+    its handshake with `payload`.
+
+    Connection starts in `NotConfirmed` status. Our handshake that should
+    be sent in response is created using `our_cookie` and the cookie of
+    the other node from `payload`.
+    */
+    pub fn new_not_confirmed(real_sk: &SecretKey, peer_real_pk: PublicKey, peer_dht_pk: PublicKey,
+                             payload: &CryptoHandshakePayload, our_cookie: EncryptedCookie) -> CryptoConnection {
+        let (session_pk, session_sk) = gen_keypair();
+        let sent_nonce = gen_nonce();
+        let handshake_payload = CryptoHandshakePayload {
+            base_nonce: sent_nonce,
+            session_pk: session_pk,
+            cookie_hash: payload.cookie.hash(),
+            cookie: our_cookie,
+        };
+        let packet = CryptoHandshake::new(&encrypt_precompute(&peer_real_pk, real_sk),
+                                          payload.cookie.clone(), &handshake_payload);
+        CryptoConnection {
+            peer_real_pk: peer_real_pk,
+            peer_dht_pk: peer_dht_pk,
+            session_pk: session_pk,
+            sent_nonce: sent_nonce,
+            status: ConnectionStatus::NotConfirmed {
+                received_nonce: payload.base_nonce,
+                peer_session_pk: payload.session_pk,
+                session_precomputed_key: encrypt_precompute(&payload.session_pk, &session_sk),
+                packet: packet,
+            },
+            session_sk: session_sk,
+            udp_addr: None,
+            send_array: PacketsArray::new(),
+            recv_array: PacketsArray::new(),
+            request_packet_sent_time: None,
+            congestion: CongestionControl::new(),
+        }
+    }
+
+    /** Create our handshake for this connection.
+
+    - `peer_cookie` is the cookie issued by the other node, it's sent in
+      plain text so the other node can check that handshake is valid
+    - `our_cookie` is the cookie that the other node should use for its
+      handshake
+    */
+    pub fn create_handshake(&self, real_sk: &SecretKey, peer_cookie: EncryptedCookie,
+                            our_cookie: EncryptedCookie) -> CryptoHandshake {
+        let payload = CryptoHandshakePayload {
+            base_nonce: self.sent_nonce,
+            session_pk: self.session_pk,
+            cookie_hash: peer_cookie.hash(),
+            cookie: our_cookie,
+        };
+        CryptoHandshake::new(&encrypt_precompute(&self.peer_real_pk, real_sk),
+                             peer_cookie, &payload)
+    }
+
+    /// Move connection to `HandshakeSending` status after our handshake
+    /// `packet` was created.
+    pub fn set_handshake_sending(&mut self, packet: CryptoHandshake) {
+        self.status = ConnectionStatus::HandshakeSending {
+            packet: packet,
+        };
+    }
+
+    /** Move connection to `NotConfirmed` status after the handshake with
+    `payload` was received from the other node.
+
+    Session key is derived from the session `PublicKey` of the other node
+    and our session `SecretKey`. If the other node started a new session
+    the data buffers are cleared.
+    */
+    pub fn set_not_confirmed(&mut self, payload: &CryptoHandshakePayload, packet: CryptoHandshake) {
+        // data of the previous session is useless for the new one
+        if self.peer_session_pk().is_some() {
+            self.send_array = PacketsArray::new();
+            self.recv_array = PacketsArray::new();
+        }
+        self.status = ConnectionStatus::NotConfirmed {
+            received_nonce: payload.base_nonce,
+            peer_session_pk: payload.session_pk,
+            session_precomputed_key: encrypt_precompute(&payload.session_pk, &self.session_sk),
+            packet: packet,
+        };
+    }
+
+    /** Move connection from `NotConfirmed` to `Established` status. Should
+    be called once the first valid packet of the session is received.
+
+    Returns `false` if connection isn't in `NotConfirmed` status.
+    */
+    pub fn set_established(&mut self) -> bool {
+        let status = match self.status {
+            ConnectionStatus::NotConfirmed { received_nonce, peer_session_pk, ref session_precomputed_key, .. } =>
+                ConnectionStatus::Established {
+                    received_nonce: received_nonce,
+                    peer_session_pk: peer_session_pk,
+                    session_precomputed_key: session_precomputed_key.clone(),
+                },
+            _ => return false,
+        };
+        self.status = status;
+        true
+    }
+
+    /// Check whether the connection is waiting for the cookie response.
+    pub fn is_cookie_requesting(&self) -> bool {
+        match self.status {
+            ConnectionStatus::CookieRequesting { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Check whether the connection is established.
+    pub fn is_established(&self) -> bool {
+        match self.status {
+            ConnectionStatus::Established { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Session `PublicKey` of the other node if handshakes were exchanged.
+    pub fn peer_session_pk(&self) -> Option<&PublicKey> {
+        match self.status {
+            ConnectionStatus::NotConfirmed { ref peer_session_pk, .. } |
+            ConnectionStatus::Established { ref peer_session_pk, .. } => Some(peer_session_pk),
+            _ => None,
+        }
+    }
+
+    /// Current rate of sending new lossless packets in packets per second.
+    pub fn packet_send_rate(&self) -> f64 {
+        self.congestion.packet_send_rate()
+    }
+
+    /// Number of lossless packets that weren't acknowledged by the other
+    /// node yet.
+    pub fn send_queue_size(&self) -> u32 {
+        self.send_array.len()
+    }
+
+    /// Minimal measured round trip time of the connection.
+    pub fn rtt(&self) -> Duration {
+        self.congestion.rtt()
+    }
+
+    /// Key of the session if handshakes were exchanged.
+    fn session_precomputed_key(&self) -> Option<PrecomputedKey> {
+        match self.status {
+            ConnectionStatus::NotConfirmed { ref session_precomputed_key, .. } |
+            ConnectionStatus::Established { ref session_precomputed_key, .. } =>
+                Some(session_precomputed_key.clone()),
+            _ => None,
+        }
+    }
+
+    /// Base nonce of the other node if handshakes were exchanged.
+    fn received_nonce_mut(&mut self) -> Option<&mut Nonce> {
+        match self.status {
+            ConnectionStatus::NotConfirmed { ref mut received_nonce, .. } |
+            ConnectionStatus::Established { ref mut received_nonce, .. } =>
+                Some(received_nonce),
+            _ => None,
+        }
+    }
+
+    /** Create `CryptoData` packet with `packet_number` and `data` encrypted
+    with the session key. Our base nonce is incremented after each packet.
+
+    Returns `None` if handshakes weren't exchanged yet.
+    */
+    fn create_crypto_data(&mut self, packet_number: u32, data: Vec<u8>) -> Option<CryptoData> {
+        let session_precomputed_key = match self.session_precomputed_key() {
+            Some(session_precomputed_key) => session_precomputed_key,
+            None => return None,
+        };
+        let payload = CryptoDataPayload {
+            buffer_start: self.recv_array.buffer_start(),
+            packet_number: packet_number,
+            data: data,
+        };
+        let packet = CryptoData::new(&session_precomputed_key, &self.sent_nonce, &payload);
+        increment_nonce(&mut self.sent_nonce);
+        Some(packet)
+    }
+
+    /** Send lossless `data`. First byte of the data is packet ID that
+    should be in lossless range.
+
+    Data is stored in the send buffer until it's acknowledged by the
+    other node. Returns `None` if the connection isn't established, the
+    data is invalid, the send buffer is full or the send rate is exceeded.
+    */
+    pub fn send_lossless(&mut self, data: Vec<u8>) -> Option<CryptoData> {
+        if !self.is_established() || data.is_empty() || data.len() > MAX_CRYPTO_DATA_SIZE {
+            return None
+        }
+        if data[0] < PACKET_ID_LOSSLESS_RANGE_START || data[0] > PACKET_ID_LOSSLESS_RANGE_END {
+            debug!("Packet ID {} is not in lossless range", data[0]);
+            return None
+        }
+        if !self.congestion.can_send() {
+            debug!("Send rate of connection with {:?} is exceeded", self.peer_real_pk);
+            return None
+        }
+        let packet = SentPacket {
+            data: data.clone(),
+            sent_time: Some(Instant::now()),
+        };
+        let packet_number = match self.send_array.push_back(packet) {
+            Some(packet_number) => packet_number,
+            None => {
+                debug!("Send buffer of connection with {:?} is full", self.peer_real_pk);
+                return None
+            },
+        };
+        self.congestion.on_packet_sent();
+        self.create_crypto_data(packet_number, data)
+    }
+
+    /** Send lossy `data`. First byte of the data is packet ID that should
+    be in lossy range.
+
+    Returns `None` if the connection isn't established or the data is
+    invalid.
+    */
+    pub fn send_lossy(&mut self, data: Vec<u8>) -> Option<CryptoData> {
+        if !self.is_established() || data.is_empty() || data.len() > MAX_CRYPTO_DATA_SIZE {
+            return None
+        }
+        if data[0] < PACKET_ID_LOSSY_RANGE_START || data[0] > PACKET_ID_LOSSY_RANGE_END {
+            debug!("Packet ID {} is not in lossy range", data[0]);
+            return None
+        }
+        let packet_number = self.send_array.buffer_end();
+        self.create_crypto_data(packet_number, data)
+    }
+
+    /// Create packet that tells the other node that the connection is
+    /// closed. Returns `None` if handshakes weren't exchanged yet.
+    pub fn send_kill(&mut self) -> Option<CryptoData> {
+        let packet_number = self.send_array.buffer_end();
+        self.create_crypto_data(packet_number, vec![PACKET_ID_KILL])
+    }
+
+    /** Create data of the request packet.
+
+    After the packet ID it contains one byte for each missing packet of
+    the receive buffer which is the distance to the previous missing
+    packet. Zero byte means that the distance is more than 255 and the next
+    byte should be added to it.
+    */
+    fn create_request_data(&self) -> Vec<u8> {
+        let mut data = vec![PACKET_ID_REQUEST];
+        let mut n = 1u32;
+        let mut index = self.recv_array.buffer_start();
+        while index != self.recv_array.buffer_end() && data.len() < MAX_CRYPTO_DATA_SIZE {
+            if !self.recv_array.contains(index) {
+                data.push(n as u8);
+                n = 0;
+            } else if n == 255 {
+                data.push(0);
+                n = 0;
+            }
+            n += 1;
+            index = index.wrapping_add(1);
+        }
+        data
+    }
+
+    /** Handle data of the request packet without packet ID.
+
+    Requested packets are marked to be resent unless they were sent less
+    than RTT ago, packets before them that weren't requested are received
+    by the other node and are removed from the send buffer.
+    */
+    fn handle_request_data(&mut self, mut data: &[u8]) {
+        let rtt = self.congestion.rtt();
+        let mut n = 1u32;
+        let mut requested = false;
+        let mut latest_sent_time = None;
+        let mut index = self.send_array.buffer_start();
+        while index != self.send_array.buffer_end() && !data.is_empty() {
+            if n == u32::from(data[0]) {
+                if let Some(packet) = self.send_array.get_mut(index) {
+                    if packet.sent_time.map_or(false, |time| time.elapsed() >= rtt) {
+                        packet.sent_time = None;
+                    }
+                    requested = true;
+                }
+                data = &data[1..];
+                n = 0;
+            } else if let Some(packet) = self.send_array.remove(index) {
+                latest_sent_time = latest_sent_time.max(packet.sent_time);
+            }
+            if n == 255 {
+                n = 1;
+                if data.is_empty() || data[0] != 0 {
+                    debug!("Received invalid request packet");
+                    break
+                }
+                data = &data[1..];
+            } else {
+                n += 1;
+            }
+            index = index.wrapping_add(1);
+        }
+        if requested {
+            self.congestion.congestion_event();
+        }
+        if let Some(time) = latest_sent_time {
+            self.congestion.update_rtt(time.elapsed());
+        }
+    }
+
+    /// Latest time when one of the packets before `index` was sent.
+    fn latest_sent_time_before(&self, index: u32) -> Option<Instant> {
+        let mut latest_sent_time = None;
+        let mut i = self.send_array.buffer_start();
+        while i != index && self.send_array.in_range(i) {
+            let sent_time = self.send_array.get(i).and_then(|packet| packet.sent_time);
+            latest_sent_time = latest_sent_time.max(sent_time);
+            i = i.wrapping_add(1);
+        }
+        latest_sent_time
+    }
+
+    /** Handle `CryptoData` received from the other node.
+
+    The first valid packet moves connection to `Established` status.
+    Returns data that should be delivered to the user, or `None` if the
+    packet is invalid.
+    */
+    pub fn handle_crypto_data(&mut self, packet: &CryptoData) -> Option<Vec<CryptoDataEvent>> {
+        let session_precomputed_key = match self.session_precomputed_key() {
+            Some(session_precomputed_key) => session_precomputed_key,
+            None => {
+                debug!("Received CryptoData before handshake");
+                return None
+            },
+        };
+        let payload = {
+            let received_nonce = match self.received_nonce_mut() {
+                Some(received_nonce) => received_nonce,
+                None => return None,
+            };
+            let diff = {
+                let Nonce(ref nonce_bytes) = *received_nonce;
+                packet.nonce_last_bytes.wrapping_sub(BigEndian::read_u16(&nonce_bytes[NONCEBYTES - 2..]))
+            };
+            let mut nonce = *received_nonce;
+            increment_nonce_number(&mut nonce, diff as usize);
+            let payload = match packet.get_payload(&session_precomputed_key, &nonce) {
+                Some(payload) => payload,
+                None => {
+                    debug!("Failed to decrypt CryptoData");
+                    return None
+                },
+            };
+            if diff > DATA_NUM_THRESHOLD * 2 {
+                increment_nonce_number(received_nonce, DATA_NUM_THRESHOLD as usize);
+            }
+            payload
+        };
+        self.set_established();
+
+        let latest_sent_time = self.latest_sent_time_before(payload.buffer_start);
+        if !self.send_array.set_buffer_start(payload.buffer_start) {
+            debug!("Received CryptoData with invalid buffer start");
+            return None
+        }
+        if let Some(time) = latest_sent_time {
+            self.congestion.update_rtt(time.elapsed());
+        }
+
+        let data = match payload.data.iter().position(|&byte| byte != PACKET_ID_PADDING) {
+            Some(start) => &payload.data[start..],
+            None => return Some(Vec::new()),
+        };
+        let mut events = Vec::new();
+        match data[0] {
+            PACKET_ID_REQUEST => self.handle_request_data(&data[1..]),
+            PACKET_ID_KILL => events.push(CryptoDataEvent::Killed),
+            PACKET_ID_LOSSLESS_RANGE_START ... PACKET_ID_LOSSLESS_RANGE_END => {
+                if !self.recv_array.insert(payload.packet_number, data.to_vec()) {
+                    trace!("Received duplicate or too far lossless packet {}", payload.packet_number);
+                }
+                while let Some(data) = self.recv_array.pop_front() {
+                    events.push(CryptoDataEvent::Lossless(data));
+                }
+            },
+            PACKET_ID_LOSSY_RANGE_START ... PACKET_ID_LOSSY_RANGE_END =>
+                events.push(CryptoDataEvent::Lossy(data.to_vec())),
+            id => {
+                debug!("Received CryptoData with unknown packet ID {}", id);
+                return None
+            },
+        }
+        Some(events)
+    }
+
+    /** Get `CryptoData` packets that should be sent periodically: request
+    packet and lossless packets requested by the other node. Number of
+    resent packets is limited by congestion control.
+
+    Returns nothing if handshakes weren't exchanged yet.
+    */
+    pub fn packets_to_send(&mut self) -> Vec<CryptoData> {
+        let mut packets = Vec::new();
+        if self.session_precomputed_key().is_none() {
+            return packets
+        }
+        self.congestion.update(self.send_array.len());
+
+        let request_packet_timed_out = self.request_packet_sent_time.map_or(true, |time|
+            time.elapsed() >= Duration::from_secs(REQUEST_PACKET_INTERVAL)
+        );
+        if request_packet_timed_out {
+            let data = self.create_request_data();
+            let packet_number = self.send_array.buffer_end();
+            packets.extend(self.create_crypto_data(packet_number, data));
+            self.request_packet_sent_time = Some(Instant::now());
+        }
+
+        let allowance = self.congestion.requested_allowance();
+        let mut requested = Vec::new();
+        let mut index = self.send_array.buffer_start();
+        while index != self.send_array.buffer_end() && requested.len() < allowance {
+            if let Some(packet) = self.send_array.get_mut(index) {
+                if packet.sent_time.is_none() {
+                    packet.sent_time = Some(Instant::now());
+                    requested.push((index, packet.data.clone()));
+                }
+            }
+            index = index.wrapping_add(1);
+        }
+        for (packet_number, data) in requested {
+            self.congestion.on_packet_resent();
+            packets.extend(self.create_crypto_data(packet_number, data));
+        }
+        packets
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crypto_connection_new() {
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _) = gen_keypair();
+        let (peer_real_pk, _) = gen_keypair();
+        let (peer_dht_pk, peer_dht_sk) = gen_keypair();
+        let connection = CryptoConnection::new(&dht_sk, &dht_pk, &real_pk,
+                                               peer_real_pk, peer_dht_pk);
+
+        let (cookie_request_id, dht_precomputed_key, packet) = match connection.status {
+            ConnectionStatus::CookieRequesting { cookie_request_id, ref dht_precomputed_key, ref packet } =>
+                (cookie_request_id, dht_precomputed_key.clone(), packet.clone()),
+            ref status => panic!("Unexpected status {:?}", status),
+        };
+        assert_eq!(dht_pk, packet.pk);
+        assert_eq!(encrypt_precompute(&peer_dht_pk, &dht_sk), dht_precomputed_key);
+        let payload = packet.get_payload(&encrypt_precompute(&dht_pk, &peer_dht_sk)).unwrap();
+        assert_eq!(real_pk, payload.pk);
+        assert_eq!(cookie_request_id, payload.id);
+        assert!(!connection.is_established());
+        assert!(connection.peer_session_pk().is_none());
+    }
+
+    #[test]
+    fn crypto_connection_set_established() {
+        let (real_pk, real_sk) = gen_keypair();
+        let (peer_real_pk, peer_real_sk) = gen_keypair();
+        let (peer_session_pk, peer_session_sk) = gen_keypair();
+        let payload = CryptoHandshakePayload {
+            base_nonce: gen_nonce(),
+            session_pk: peer_session_pk,
+            cookie_hash: CookieSecret::new().issue_cookie(real_pk, gen_keypair().0).hash(),
+            cookie: CookieSecret::new().issue_cookie(real_pk, gen_keypair().0),
+        };
+        let our_cookie = CookieSecret::new().issue_cookie(peer_real_pk, gen_keypair().0);
+        let mut connection = CryptoConnection::new_not_confirmed(&real_sk, peer_real_pk,
+            gen_keypair().0, &payload, our_cookie.clone());
+
+        match connection.status {
+            ConnectionStatus::NotConfirmed { received_nonce, ref session_precomputed_key, ref packet, .. } => {
+                assert_eq!(payload.base_nonce, received_nonce);
+                assert_eq!(encrypt_precompute(&connection.session_pk, &peer_session_sk),
+                           *session_precomputed_key);
+                // our handshake is sent with the cookie of the other node
+                assert_eq!(payload.cookie, packet.cookie);
+                let handshake_payload = packet.get_payload(
+                    &encrypt_precompute(&real_pk, &peer_real_sk)).unwrap();
+                assert_eq!(connection.sent_nonce, handshake_payload.base_nonce);
+                assert_eq!(connection.session_pk, handshake_payload.session_pk);
+                assert_eq!(payload.cookie.hash(), handshake_payload.cookie_hash);
+                assert_eq!(our_cookie, handshake_payload.cookie);
+            },
+            ref status => panic!("Unexpected status {:?}", status),
+        }
+        assert_eq!(Some(&peer_session_pk), connection.peer_session_pk());
+
+        assert!(connection.set_established());
+        assert!(connection.is_established());
+        // can't be established twice
+        assert!(!connection.set_established());
+    }
+
+    fn create_established_connection() -> CryptoConnection {
+        let (real_pk, real_sk) = gen_keypair();
+        let payload = CryptoHandshakePayload {
+            base_nonce: gen_nonce(),
+            session_pk: gen_keypair().0,
+            cookie_hash: CookieSecret::new().issue_cookie(real_pk, gen_keypair().0).hash(),
+            cookie: CookieSecret::new().issue_cookie(real_pk, gen_keypair().0),
+        };
+        let our_cookie = CookieSecret::new().issue_cookie(gen_keypair().0, gen_keypair().0);
+        let mut connection = CryptoConnection::new_not_confirmed(&real_sk, gen_keypair().0,
+            gen_keypair().0, &payload, our_cookie);
+        assert!(connection.set_established());
+        connection
+    }
+
+    #[test]
+    fn crypto_connection_request_packet() {
+        let mut receiver = create_established_connection();
+        for &index in &[1, 2, 5] {
+            assert!(receiver.recv_array.insert(index, vec![16]));
+        }
+        let request_data = receiver.create_request_data();
+        assert_eq!(vec![PACKET_ID_REQUEST, 1, 3, 1], request_data);
+
+        let mut sender = create_established_connection();
+        sender.congestion.update_rtt(Duration::from_millis(0));
+        for _ in 0 .. 6 {
+            sender.send_array.push_back(SentPacket {
+                data: vec![16],
+                sent_time: Some(Instant::now()),
+            });
+        }
+        sender.handle_request_data(&request_data[1..]);
+        for &index in &[0, 3, 4] {
+            assert_eq!(None, sender.send_array.get(index).unwrap().sent_time);
+        }
+        // packets before requested ones were received
+        assert!(!sender.send_array.contains(1));
+        assert!(!sender.send_array.contains(2));
+        // nothing is known about the last one
+        assert!(sender.send_array.get(5).unwrap().sent_time.is_some());
+
+        // request packet and requested packets
+        assert_eq!(4, sender.packets_to_send().len());
+        assert!(sender.packets_to_send().is_empty());
+    }
+
+    #[test]
+    fn crypto_connection_request_packet_big_gap() {
+        let mut receiver = create_established_connection();
+        assert!(receiver.recv_array.insert(300, vec![16]));
+        assert!(receiver.recv_array.insert(301, vec![16]));
+        let request_data = receiver.create_request_data();
+        // one packet is missing after 300 received ones
+        let mut expected = vec![PACKET_ID_REQUEST];
+        expected.extend(::std::iter::repeat(1).take(300));
+        assert_eq!(expected, request_data);
+
+        let mut receiver = create_established_connection();
+        assert!(receiver.recv_array.insert(0, vec![16]));
+        receiver.recv_array.pop_front();
+        for index in 1 .. 300 {
+            assert!(receiver.recv_array.insert(index, vec![16]));
+        }
+        assert!(receiver.recv_array.insert(301, vec![16]));
+        let request_data = receiver.create_request_data();
+        // 299 received packets, then missing one
+        assert_eq!(vec![PACKET_ID_REQUEST, 0, 45], request_data);
+
+        let mut sender = create_established_connection();
+        sender.congestion.update_rtt(Duration::from_millis(0));
+        for _ in 0 .. 302 {
+            sender.send_array.push_back(SentPacket {
+                data: vec![16],
+                sent_time: Some(Instant::now()),
+            });
+        }
+        sender.send_array.set_buffer_start(1);
+        sender.handle_request_data(&request_data[1..]);
+        assert_eq!(None, sender.send_array.get(300).unwrap().sent_time);
+        assert!(!sender.send_array.contains(299));
+    }
+
+    #[test]
+    fn crypto_connection_received_nonce_threshold() {
+        let mut connection = create_established_connection();
+        let (base_nonce, key) = match connection.status {
+            ConnectionStatus::Established { received_nonce, ref session_precomputed_key, .. } =>
+                (received_nonce, session_precomputed_key.clone()),
+            ref status => panic!("Unexpected status {:?}", status),
+        };
+        let payload = CryptoDataPayload {
+            buffer_start: 0,
+            packet_number: 0,
+            data: vec![PACKET_ID_LOSSY_RANGE_START],
+        };
+
+        // received nonce stays the same while the difference isn't bigger
+        // than the doubled threshold
+        let mut nonce = base_nonce;
+        increment_nonce_number(&mut nonce, DATA_NUM_THRESHOLD as usize * 2);
+        assert!(connection.handle_crypto_data(&CryptoData::new(&key, &nonce, &payload)).is_some());
+        assert_eq!(Some(base_nonce), connection.received_nonce_mut().map(|nonce| *nonce));
+
+        // and is advanced by the threshold once the difference is bigger
+        increment_nonce(&mut nonce);
+        assert!(connection.handle_crypto_data(&CryptoData::new(&key, &nonce, &payload)).is_some());
+        let mut expected_nonce = base_nonce;
+        increment_nonce_number(&mut expected_nonce, DATA_NUM_THRESHOLD as usize);
+        assert_eq!(Some(expected_nonce), connection.received_nonce_mut().map(|nonce| *nonce));
+    }
+
+    #[test]
+    fn crypto_connection_request_packet_not_resent_before_rtt() {
+        let mut sender = create_established_connection();
+        sender.send_lossless(vec![16]).unwrap();
+        sender.send_lossless(vec![16]).unwrap();
+        sender.handle_request_data(&[1]);
+        // default RTT didn't pass since the packet was sent
+        assert!(sender.send_array.get(0).unwrap().sent_time.is_some());
+        assert_eq!(2, sender.send_queue_size());
+    }
+
+    #[test]
+    fn crypto_connection_send_rate_limited() {
+        let mut connection = create_established_connection();
+        assert_eq!(CRYPTO_PACKET_MIN_RATE, connection.packet_send_rate());
+        for _ in 0 .. CRYPTO_MIN_QUEUE_LENGTH {
+            assert!(connection.send_lossless(vec![16]).is_some());
+        }
+        assert!(connection.send_lossless(vec![16]).is_none());
+        assert_eq!(CRYPTO_MIN_QUEUE_LENGTH, connection.send_queue_size());
+        // lossy packets are not limited
+        assert!(connection.send_lossy(vec![192]).is_some());
+    }
+
+    #[test]
+    fn crypto_connection_rtt() {
+        let mut connection = create_established_connection();
+        connection.send_lossless(vec![16]).unwrap();
+        connection.send_lossless(vec![16]).unwrap();
+        let latest_sent_time = connection.latest_sent_time_before(2).unwrap();
+        assert_eq!(connection.send_array.get(1).unwrap().sent_time, Some(latest_sent_time));
+        assert!(connection.latest_sent_time_before(0).is_none());
+
+        connection.handle_request_data(&[2]);
+        // the first packet was acknowledged
+        assert!(connection.rtt() < Duration::from_millis(DEFAULT_RTT));
+    }
+}
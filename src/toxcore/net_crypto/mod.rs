@@ -0,0 +1,647 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! Net crypto module allows nodes to establish secure connections with
+each other using their long term `PublicKey`s and to exchange data over
+these connections.
+
+https://zetok.github.io/tox-spec/#net-crypto
+*/
+
+pub mod congestion;
+pub mod cookie;
+pub mod crypto_connection;
+pub mod packet;
+pub mod packets_array;
+
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+
+use toxcore::crypto_core::*;
+use toxcore::net_crypto::cookie::*;
+use toxcore::net_crypto::crypto_connection::*;
+use toxcore::net_crypto::packet::*;
+
+/** Maximum number of connections initiated by other nodes that are not
+confirmed yet. Handshakes creating new connections are ignored while there
+are that many of them.
+*/
+pub const MAX_NOT_CONFIRMED_CONNECTIONS: usize = 32;
+
+/** Net crypto connections manager.
+
+Handles cookie requests, cookie responses, handshakes and data packets and
+keeps state of the connections with other nodes. Connections are identified
+by real `PublicKey` of the other node. Data packets are matched with
+connections by UDP address they were received from.
+
+Handlers return the packet that should be sent back to the sender of the
+handled packet, if any.
+
+Other nodes can start connections only if their real `PublicKey`s were
+allowed with [`allow_connection`](#method.allow_connection), e.g. because
+they are our friends.
+*/
+pub struct NetCrypto {
+    /// Our DHT `PublicKey`
+    dht_pk: PublicKey,
+    /// Our DHT `SecretKey`
+    dht_sk: SecretKey,
+    /// Our real `PublicKey`
+    real_pk: PublicKey,
+    /// Our real `SecretKey`
+    real_sk: SecretKey,
+    /// Keys that are used to issue and open cookies
+    cookie_secret: CookieSecret,
+    /// Connections with other nodes indexed by their real `PublicKey`s
+    connections: HashMap<PublicKey, CryptoConnection>,
+    /// Real `PublicKey`s of nodes that are allowed to connect to us
+    allowed_pks: HashSet<PublicKey>,
+}
+
+impl NetCrypto {
+    /// Create new `NetCrypto` with our DHT and real key pairs.
+    pub fn new(dht_pk: PublicKey, dht_sk: SecretKey, real_pk: PublicKey, real_sk: SecretKey) -> NetCrypto {
+        NetCrypto {
+            dht_pk: dht_pk,
+            dht_sk: dht_sk,
+            real_pk: real_pk,
+            real_sk: real_sk,
+            cookie_secret: CookieSecret::new(),
+            connections: HashMap::new(),
+            allowed_pks: HashSet::new(),
+        }
+    }
+
+    /** Start connecting to the node with `peer_real_pk` and `peer_dht_pk`.
+
+    Returns `CookieRequest` that should be sent to `addr`, the DHT address
+    of the node, or `None` if connection with this node already exists.
+    `addr` is remembered as UDP address of the connection, so the cookie
+    response is expected from it.
+    */
+    pub fn add_connection(&mut self, peer_real_pk: PublicKey, peer_dht_pk: PublicKey, addr: SocketAddr)
+        -> Option<CookieRequest> {
+        if self.connections.contains_key(&peer_real_pk) {
+            debug!("Connection with {:?} already exists", peer_real_pk);
+            return None
+        }
+        let mut connection = CryptoConnection::new(&self.dht_sk, &self.dht_pk, &self.real_pk,
+                                                   peer_real_pk, peer_dht_pk);
+        connection.udp_addr = Some(addr);
+        let packet = match connection.status {
+            ConnectionStatus::CookieRequesting { ref packet, .. } => packet.clone(),
+            _ => unreachable!("New connection should request cookie"),
+        };
+        self.connections.insert(peer_real_pk, connection);
+        Some(packet)
+    }
+
+    /// Remove connection with the node with `peer_real_pk`. Returns
+    /// `false` if there was no such connection.
+    pub fn kill_connection(&mut self, peer_real_pk: &PublicKey) -> bool {
+        self.connections.remove(peer_real_pk).is_some()
+    }
+
+    /// Allow the node with `peer_real_pk` to start a connection with us.
+    pub fn allow_connection(&mut self, peer_real_pk: PublicKey) {
+        self.allowed_pks.insert(peer_real_pk);
+    }
+
+    /// Stop accepting new connections from the node with `peer_real_pk`.
+    /// Existing connection isn't removed. Returns `false` if the node
+    /// wasn't allowed to connect.
+    pub fn disallow_connection(&mut self, peer_real_pk: &PublicKey) -> bool {
+        self.allowed_pks.remove(peer_real_pk)
+    }
+
+    /// Get connection with the node with `peer_real_pk`.
+    pub fn connection(&self, peer_real_pk: &PublicKey) -> Option<&CryptoConnection> {
+        self.connections.get(peer_real_pk)
+    }
+
+    /** Handle `CookieRequest` and create `CookieResponse` with the cookie
+    for the sender.
+
+    Doesn't change any state, so cookie requests from unknown nodes are
+    answered as well.
+    */
+    pub fn handle_cookie_request(&mut self, packet: &CookieRequest) -> Option<CookieResponse> {
+        let shared_secret = encrypt_precompute(&packet.pk, &self.dht_sk);
+        let payload = match packet.get_payload(&shared_secret) {
+            Some(payload) => payload,
+            None => {
+                debug!("Failed to decrypt CookieRequest from {:?}", packet.pk);
+                return None
+            },
+        };
+        let response_payload = CookieResponsePayload {
+            cookie: self.cookie_secret.issue_cookie(payload.pk, packet.pk),
+            id: payload.id,
+        };
+        Some(CookieResponse::new(&shared_secret, &response_payload))
+    }
+
+    /** Handle `CookieResponse` to our `CookieRequest` received from `addr`.
+
+    Returns our `CryptoHandshake` that should be sent to the node we
+    requested the cookie from. Connection is moved to `HandshakeSending`
+    status.
+
+    The connection is found by the address the cookie request was sent
+    to, and the response is decrypted with the key precomputed when the
+    request was created.
+    */
+    pub fn handle_cookie_response(&mut self, addr: SocketAddr, packet: &CookieResponse) -> Option<CryptoHandshake> {
+        let connection = match self.connections.values_mut().find(|connection|
+            connection.udp_addr == Some(addr) && connection.is_cookie_requesting()
+        ) {
+            Some(connection) => connection,
+            None => {
+                debug!("Received CookieResponse from unknown address {}", addr);
+                return None
+            },
+        };
+        let payload = match connection.status {
+            ConnectionStatus::CookieRequesting { cookie_request_id, ref dht_precomputed_key, .. } =>
+                packet.get_payload(dht_precomputed_key).and_then(|payload|
+                    if payload.id == cookie_request_id { Some(payload) } else { None }
+                ),
+            _ => unreachable!("Connection should request cookie"),
+        };
+        let payload = match payload {
+            Some(payload) => payload,
+            None => {
+                debug!("Failed to decrypt CookieResponse from {}", addr);
+                return None
+            },
+        };
+        let our_cookie = self.cookie_secret.issue_cookie(connection.peer_real_pk, connection.peer_dht_pk);
+        let handshake = connection.create_handshake(&self.real_sk, payload.cookie, our_cookie);
+        connection.set_handshake_sending(handshake.clone());
+        Some(handshake)
+    }
+
+    /** Handle `CryptoHandshake` from the other node.
+
+    Handshake is accepted only if it contains a valid cookie issued by us.
+    Handshake that would create a new connection is accepted only if the
+    node is allowed to connect and there are less than
+    `MAX_NOT_CONFIRMED_CONNECTIONS` not confirmed connections.
+
+    Returns our `CryptoHandshake` that should be sent back if the other
+    node doesn't have it yet, i.e. if we didn't send our handshake before
+    or if the other node started a new session.
+
+    `addr` is remembered as UDP address of the connection.
+    */
+    pub fn handle_crypto_handshake(&mut self, addr: SocketAddr, packet: &CryptoHandshake) -> Option<CryptoHandshake> {
+        let cookie = match self.cookie_secret.open_cookie(&packet.cookie) {
+            Some(cookie) => cookie,
+            None => {
+                debug!("Received CryptoHandshake with invalid cookie");
+                return None
+            },
+        };
+        let payload = match packet.get_payload(&encrypt_precompute(&cookie.real_pk, &self.real_sk)) {
+            Some(payload) => payload,
+            None => {
+                debug!("Failed to decrypt CryptoHandshake from {:?}", cookie.real_pk);
+                return None
+            },
+        };
+        if payload.cookie_hash != packet.cookie.hash() {
+            debug!("Received CryptoHandshake with invalid cookie hash");
+            return None
+        }
+
+        if let Some(connection) = self.connections.get_mut(&cookie.real_pk) {
+            connection.peer_dht_pk = cookie.dht_pk;
+            connection.udp_addr = Some(addr);
+            if connection.peer_session_pk() == Some(&payload.session_pk) {
+                trace!("Received already known CryptoHandshake from {:?}", cookie.real_pk);
+                return None
+            }
+            let sent_handshake = match connection.status {
+                ConnectionStatus::HandshakeSending { ref packet } => Some(packet.clone()),
+                _ => None,
+            };
+            if let Some(handshake) = sent_handshake {
+                connection.set_not_confirmed(&payload, handshake);
+                return None
+            }
+            // we didn't send our handshake yet or the other node started
+            // a new session
+            let our_cookie = self.cookie_secret.issue_cookie(cookie.real_pk, cookie.dht_pk);
+            let handshake = connection.create_handshake(&self.real_sk, payload.cookie.clone(), our_cookie);
+            connection.set_not_confirmed(&payload, handshake.clone());
+            return Some(handshake)
+        }
+
+        if !self.allowed_pks.contains(&cookie.real_pk) {
+            debug!("Received CryptoHandshake from not allowed node {:?}", cookie.real_pk);
+            return None
+        }
+        let not_confirmed = self.connections.values()
+            .filter(|connection| match connection.status {
+                ConnectionStatus::NotConfirmed { .. } => true,
+                _ => false,
+            })
+            .count();
+        if not_confirmed >= MAX_NOT_CONFIRMED_CONNECTIONS {
+            debug!("Too many not confirmed connections, ignoring CryptoHandshake from {:?}", cookie.real_pk);
+            return None
+        }
+
+        debug!("Received CryptoHandshake from new node {:?}", cookie.real_pk);
+        let our_cookie = self.cookie_secret.issue_cookie(cookie.real_pk, cookie.dht_pk);
+        let mut connection = CryptoConnection::new_not_confirmed(&self.real_sk, cookie.real_pk,
+            cookie.dht_pk, &payload, our_cookie);
+        connection.udp_addr = Some(addr);
+        let handshake = match connection.status {
+            ConnectionStatus::NotConfirmed { ref packet, .. } => packet.clone(),
+            _ => unreachable!("New incoming connection should be not confirmed"),
+        };
+        self.connections.insert(cookie.real_pk, connection);
+        Some(handshake)
+    }
+
+    /** Handle `CryptoData` received from `addr`.
+
+    Returns real `PublicKey` of the node the data was received from and
+    data that should be delivered to the user. Connection is removed if the
+    other node killed it.
+    */
+    pub fn handle_crypto_data(&mut self, addr: SocketAddr, packet: &CryptoData)
+        -> Option<(PublicKey, Vec<CryptoDataEvent>)> {
+        let (peer_real_pk, events) = {
+            let connection = match self.connections.values_mut().find(|connection| connection.udp_addr == Some(addr)) {
+                Some(connection) => connection,
+                None => {
+                    debug!("Received CryptoData from unknown address {}", addr);
+                    return None
+                },
+            };
+            match connection.handle_crypto_data(packet) {
+                Some(events) => (connection.peer_real_pk, events),
+                None => return None,
+            }
+        };
+        if events.contains(&CryptoDataEvent::Killed) {
+            debug!("Connection with {:?} was killed", peer_real_pk);
+            self.connections.remove(&peer_real_pk);
+        }
+        Some((peer_real_pk, events))
+    }
+
+    /** Send lossless `data` to the node with `peer_real_pk`.
+
+    Returns `CryptoData` that should be sent to the returned address or
+    `None` if the data can't be sent.
+    */
+    pub fn send_lossless(&mut self, peer_real_pk: &PublicKey, data: Vec<u8>) -> Option<(SocketAddr, CryptoData)> {
+        let connection = match self.connections.get_mut(peer_real_pk) {
+            Some(connection) => connection,
+            None => return None,
+        };
+        let addr = match connection.udp_addr {
+            Some(addr) => addr,
+            None => return None,
+        };
+        connection.send_lossless(data).map(|packet| (addr, packet))
+    }
+
+    /** Send lossy `data` to the node with `peer_real_pk`.
+
+    Returns `CryptoData` that should be sent to the returned address or
+    `None` if the data can't be sent.
+    */
+    pub fn send_lossy(&mut self, peer_real_pk: &PublicKey, data: Vec<u8>) -> Option<(SocketAddr, CryptoData)> {
+        let connection = match self.connections.get_mut(peer_real_pk) {
+            Some(connection) => connection,
+            None => return None,
+        };
+        let addr = match connection.udp_addr {
+            Some(addr) => addr,
+            None => return None,
+        };
+        connection.send_lossy(data).map(|packet| (addr, packet))
+    }
+
+    /// Get `CryptoData` packets of all connections that should be sent
+    /// periodically: request packets and requested lossless packets.
+    pub fn packets_to_send(&mut self) -> Vec<(SocketAddr, CryptoData)> {
+        let mut packets = Vec::new();
+        for connection in self.connections.values_mut() {
+            if let Some(addr) = connection.udp_addr {
+                packets.extend(connection.packets_to_send().into_iter().map(|packet| (addr, packet)));
+            }
+        }
+        packets
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_net_crypto() -> (NetCrypto, PublicKey, PublicKey) {
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        (NetCrypto::new(dht_pk, dht_sk, real_pk, real_sk), dht_pk, real_pk)
+    }
+
+    fn addr() -> SocketAddr {
+        "127.0.0.1:33445".parse().unwrap()
+    }
+
+    fn session_key(connection: &CryptoConnection) -> PrecomputedKey {
+        match connection.status {
+            ConnectionStatus::NotConfirmed { ref session_precomputed_key, .. } |
+            ConnectionStatus::Established { ref session_precomputed_key, .. } =>
+                session_precomputed_key.clone(),
+            ref status => panic!("Unexpected status {:?}", status),
+        }
+    }
+
+    #[test]
+    fn net_crypto_handshake() {
+        let (mut alice, alice_dht_pk, alice_real_pk) = create_net_crypto();
+        let (mut bob, bob_dht_pk, bob_real_pk) = create_net_crypto();
+
+        let cookie_request = alice.add_connection(bob_real_pk, bob_dht_pk, addr()).unwrap();
+        // connection already exists
+        assert!(alice.add_connection(bob_real_pk, bob_dht_pk, addr()).is_none());
+
+        let cookie_response = bob.handle_cookie_request(&cookie_request).unwrap();
+        // cookie requests don't create connections
+        assert!(bob.connection(&alice_real_pk).is_none());
+        bob.allow_connection(alice_real_pk);
+
+        let alice_handshake = alice.handle_cookie_response(addr(), &cookie_response).unwrap();
+        match alice.connection(&bob_real_pk).unwrap().status {
+            ConnectionStatus::HandshakeSending { .. } => {},
+            ref status => panic!("Unexpected status {:?}", status),
+        }
+
+        let bob_handshake = bob.handle_crypto_handshake(addr(), &alice_handshake).unwrap();
+        assert!(alice.handle_crypto_handshake(addr(), &bob_handshake).is_none());
+        // retransmitted handshake is ignored
+        assert!(bob.handle_crypto_handshake(addr(), &alice_handshake).is_none());
+
+        let alice_connection = alice.connection(&bob_real_pk).unwrap();
+        let bob_connection = bob.connection(&alice_real_pk).unwrap();
+        assert_eq!(bob_dht_pk, alice_connection.peer_dht_pk);
+        assert_eq!(alice_dht_pk, bob_connection.peer_dht_pk);
+        assert_eq!(Some(&bob_connection.session_pk), alice_connection.peer_session_pk());
+        assert_eq!(Some(&alice_connection.session_pk), bob_connection.peer_session_pk());
+        assert_eq!(session_key(alice_connection), session_key(bob_connection));
+        match (&alice_connection.status, &bob_connection.status) {
+            (&ConnectionStatus::NotConfirmed { received_nonce: alice_received_nonce, .. },
+             &ConnectionStatus::NotConfirmed { received_nonce: bob_received_nonce, .. }) => {
+                assert_eq!(bob_connection.sent_nonce, alice_received_nonce);
+                assert_eq!(alice_connection.sent_nonce, bob_received_nonce);
+            },
+            statuses => panic!("Unexpected statuses {:?}", statuses),
+        }
+    }
+
+    #[test]
+    fn net_crypto_handle_cookie_response_unknown() {
+        let (mut alice, _, _) = create_net_crypto();
+        let (mut bob, bob_dht_pk, bob_real_pk) = create_net_crypto();
+        let (mut eve, _, _) = create_net_crypto();
+
+        let cookie_request = alice.add_connection(bob_real_pk, bob_dht_pk, addr()).unwrap();
+        let cookie_response = bob.handle_cookie_request(&cookie_request).unwrap();
+        assert!(eve.handle_cookie_response(addr(), &cookie_response).is_none());
+        // response from another address
+        assert!(alice.handle_cookie_response("127.0.0.1:33446".parse().unwrap(), &cookie_response).is_none());
+
+        assert!(alice.kill_connection(&bob_real_pk));
+        assert!(!alice.kill_connection(&bob_real_pk));
+        assert!(alice.handle_cookie_response(addr(), &cookie_response).is_none());
+    }
+
+    #[test]
+    fn net_crypto_handle_crypto_handshake_invalid() {
+        let (mut alice, _, _) = create_net_crypto();
+        let (mut bob, bob_dht_pk, bob_real_pk) = create_net_crypto();
+        let (mut eve, _, _) = create_net_crypto();
+
+        let cookie_request = alice.add_connection(bob_real_pk, bob_dht_pk, addr()).unwrap();
+        let cookie_response = bob.handle_cookie_request(&cookie_request).unwrap();
+        let mut handshake = alice.handle_cookie_response(addr(), &cookie_response).unwrap();
+
+        // cookie wasn't issued by eve
+        assert!(eve.handle_crypto_handshake(addr(), &handshake).is_none());
+
+        // cookie doesn't match its hash
+        handshake.cookie = bob.cookie_secret.issue_cookie(alice.real_pk, alice.dht_pk);
+        assert!(bob.handle_crypto_handshake(addr(), &handshake).is_none());
+    }
+
+    /// Create `CryptoHandshake` from a new node to `bob`.
+    fn new_handshake(bob: &mut NetCrypto) -> (PublicKey, CryptoHandshake) {
+        let (mut alice, _, alice_real_pk) = create_net_crypto();
+        let cookie_request = alice.add_connection(bob.real_pk, bob.dht_pk, addr()).unwrap();
+        let cookie_response = bob.handle_cookie_request(&cookie_request).unwrap();
+        let handshake = alice.handle_cookie_response(addr(), &cookie_response).unwrap();
+        (alice_real_pk, handshake)
+    }
+
+    #[test]
+    fn net_crypto_handle_crypto_handshake_not_allowed() {
+        let (mut bob, _, _) = create_net_crypto();
+        let (alice_real_pk, handshake) = new_handshake(&mut bob);
+
+        assert!(bob.handle_crypto_handshake(addr(), &handshake).is_none());
+        assert!(bob.connection(&alice_real_pk).is_none());
+
+        bob.allow_connection(alice_real_pk);
+        assert!(bob.disallow_connection(&alice_real_pk));
+        assert!(!bob.disallow_connection(&alice_real_pk));
+        assert!(bob.handle_crypto_handshake(addr(), &handshake).is_none());
+        assert!(bob.connection(&alice_real_pk).is_none());
+
+        bob.allow_connection(alice_real_pk);
+        assert!(bob.handle_crypto_handshake(addr(), &handshake).is_some());
+        assert!(bob.connection(&alice_real_pk).is_some());
+    }
+
+    #[test]
+    fn net_crypto_handle_crypto_handshake_too_many() {
+        let (mut bob, _, _) = create_net_crypto();
+        for _ in 0 .. MAX_NOT_CONFIRMED_CONNECTIONS {
+            let (alice_real_pk, handshake) = new_handshake(&mut bob);
+            bob.allow_connection(alice_real_pk);
+            assert!(bob.handle_crypto_handshake(addr(), &handshake).is_some());
+        }
+
+        let (alice_real_pk, handshake) = new_handshake(&mut bob);
+        bob.allow_connection(alice_real_pk);
+        assert!(bob.handle_crypto_handshake(addr(), &handshake).is_none());
+        assert!(bob.connection(&alice_real_pk).is_none());
+        assert_eq!(MAX_NOT_CONFIRMED_CONNECTIONS, bob.connections.len());
+    }
+
+    #[test]
+    fn net_crypto_simultaneous_connect() {
+        let (mut alice, alice_dht_pk, alice_real_pk) = create_net_crypto();
+        let (mut bob, bob_dht_pk, bob_real_pk) = create_net_crypto();
+
+        let alice_cookie_request = alice.add_connection(bob_real_pk, bob_dht_pk, addr()).unwrap();
+        let bob_cookie_request = bob.add_connection(alice_real_pk, alice_dht_pk, addr()).unwrap();
+        let alice_cookie_response = bob.handle_cookie_request(&alice_cookie_request).unwrap();
+        let bob_cookie_response = alice.handle_cookie_request(&bob_cookie_request).unwrap();
+        let alice_handshake = alice.handle_cookie_response(addr(), &alice_cookie_response).unwrap();
+        let bob_handshake = bob.handle_cookie_response(addr(), &bob_cookie_response).unwrap();
+
+        // both nodes already sent handshakes
+        assert!(bob.handle_crypto_handshake(addr(), &alice_handshake).is_none());
+        assert!(alice.handle_crypto_handshake(addr(), &bob_handshake).is_none());
+
+        let alice_connection = alice.connection(&bob_real_pk).unwrap();
+        let bob_connection = bob.connection(&alice_real_pk).unwrap();
+        assert_eq!(session_key(alice_connection), session_key(bob_connection));
+    }
+
+    /// Create two nodes with established connection.
+    fn establish_connection() -> (NetCrypto, SocketAddr, PublicKey, NetCrypto, SocketAddr, PublicKey) {
+        let (mut alice, _, alice_real_pk) = create_net_crypto();
+        let (mut bob, bob_dht_pk, bob_real_pk) = create_net_crypto();
+        let alice_addr = "127.0.0.1:33445".parse().unwrap();
+        let bob_addr = "127.0.0.1:33446".parse().unwrap();
+
+        bob.allow_connection(alice_real_pk);
+        let cookie_request = alice.add_connection(bob_real_pk, bob_dht_pk, bob_addr).unwrap();
+        let cookie_response = bob.handle_cookie_request(&cookie_request).unwrap();
+        let alice_handshake = alice.handle_cookie_response(bob_addr, &cookie_response).unwrap();
+        let bob_handshake = bob.handle_crypto_handshake(alice_addr, &alice_handshake).unwrap();
+        assert!(alice.handle_crypto_handshake(bob_addr, &bob_handshake).is_none());
+
+        // request packets confirm the connection
+        for (addr, packet) in alice.packets_to_send() {
+            assert_eq!(bob_addr, addr);
+            assert_eq!(Some((alice_real_pk, Vec::new())), bob.handle_crypto_data(alice_addr, &packet));
+        }
+        for (addr, packet) in bob.packets_to_send() {
+            assert_eq!(alice_addr, addr);
+            assert_eq!(Some((bob_real_pk, Vec::new())), alice.handle_crypto_data(bob_addr, &packet));
+        }
+        assert!(alice.connection(&bob_real_pk).unwrap().is_established());
+        assert!(bob.connection(&alice_real_pk).unwrap().is_established());
+
+        (alice, alice_addr, alice_real_pk, bob, bob_addr, bob_real_pk)
+    }
+
+    #[test]
+    fn net_crypto_send_lossless() {
+        let (mut alice, alice_addr, alice_real_pk, mut bob, bob_addr, bob_real_pk) = establish_connection();
+
+        let (_, first) = alice.send_lossless(&bob_real_pk, vec![16, 1, 2, 3]).unwrap();
+        let (_, second) = alice.send_lossless(&bob_real_pk, vec![17, 4, 5, 6]).unwrap();
+        // second packet can't be delivered before the first one
+        assert_eq!(Some((alice_real_pk, Vec::new())), bob.handle_crypto_data(alice_addr, &second));
+        assert_eq!(Some((alice_real_pk, vec![
+            CryptoDataEvent::Lossless(vec![16, 1, 2, 3]),
+            CryptoDataEvent::Lossless(vec![17, 4, 5, 6]),
+        ])), bob.handle_crypto_data(alice_addr, &first));
+        // duplicate is not delivered twice
+        assert_eq!(Some((alice_real_pk, Vec::new())), bob.handle_crypto_data(alice_addr, &first));
+        assert_eq!(2, alice.connection(&bob_real_pk).unwrap().send_array.len());
+
+        // any packet from bob acknowledges received packets
+        let (_, packet) = bob.send_lossy(&alice_real_pk, vec![200]).unwrap();
+        alice.handle_crypto_data(bob_addr, &packet).unwrap();
+        assert!(alice.connection(&bob_real_pk).unwrap().send_array.is_empty());
+    }
+
+    #[test]
+    fn net_crypto_send_lossy() {
+        let (mut alice, alice_addr, alice_real_pk, mut bob, _, bob_real_pk) = establish_connection();
+
+        let (_, packet) = alice.send_lossy(&bob_real_pk, vec![192, 1, 2, 3]).unwrap();
+        assert_eq!(Some((alice_real_pk, vec![CryptoDataEvent::Lossy(vec![192, 1, 2, 3])])),
+                   bob.handle_crypto_data(alice_addr, &packet));
+        // lossy packets are not stored
+        assert!(alice.connection(&bob_real_pk).unwrap().send_array.is_empty());
+    }
+
+    #[test]
+    fn net_crypto_send_invalid() {
+        let (mut alice, _, _, _, _, bob_real_pk) = establish_connection();
+
+        assert!(alice.send_lossless(&bob_real_pk, vec![]).is_none());
+        assert!(alice.send_lossless(&bob_real_pk, vec![PACKET_ID_KILL]).is_none());
+        assert!(alice.send_lossless(&bob_real_pk, vec![192]).is_none());
+        assert!(alice.send_lossless(&bob_real_pk, vec![16; MAX_CRYPTO_DATA_SIZE + 1]).is_none());
+        assert!(alice.send_lossy(&bob_real_pk, vec![16]).is_none());
+        assert!(alice.send_lossy(&bob_real_pk, vec![255]).is_none());
+        assert!(alice.send_lossy(&gen_keypair().0, vec![192]).is_none());
+    }
+
+    #[test]
+    fn net_crypto_send_not_established() {
+        let (mut alice, _, _) = create_net_crypto();
+        let (_, bob_dht_pk, bob_real_pk) = create_net_crypto();
+        alice.add_connection(bob_real_pk, bob_dht_pk, addr()).unwrap();
+
+        assert!(alice.send_lossless(&bob_real_pk, vec![16]).is_none());
+        assert!(alice.packets_to_send().is_empty());
+    }
+
+    #[test]
+    fn net_crypto_handle_crypto_data_invalid() {
+        let (mut alice, alice_addr, _, mut bob, bob_addr, bob_real_pk) = establish_connection();
+
+        let (_, packet) = alice.send_lossless(&bob_real_pk, vec![16]).unwrap();
+        // unknown address
+        assert!(bob.handle_crypto_data(bob_addr, &packet).is_none());
+        // invalid payload
+        let mut invalid_packet = packet.clone();
+        invalid_packet.payload[0] ^= 0xff;
+        assert!(bob.handle_crypto_data(alice_addr, &invalid_packet).is_none());
+        assert!(bob.handle_crypto_data(alice_addr, &packet).is_some());
+    }
+
+    #[test]
+    fn net_crypto_kill() {
+        let (mut alice, alice_addr, alice_real_pk, mut bob, _, bob_real_pk) = establish_connection();
+
+        let packet = alice.connections.get_mut(&bob_real_pk).unwrap().send_kill().unwrap();
+        assert_eq!(Some((alice_real_pk, vec![CryptoDataEvent::Killed])),
+                   bob.handle_crypto_data(alice_addr, &packet));
+        assert!(bob.connection(&alice_real_pk).is_none());
+    }
+
+    #[test]
+    fn net_crypto_nonce_far_ahead() {
+        let (mut alice, alice_addr, alice_real_pk, mut bob, _, bob_real_pk) = establish_connection();
+
+        // received nonce can't follow jumps bigger than the threshold
+        for _ in 0 .. 5 {
+            increment_nonce_number(&mut alice.connections.get_mut(&bob_real_pk).unwrap().sent_nonce,
+                                   DATA_NUM_THRESHOLD as usize - 1);
+            let (_, packet) = alice.send_lossy(&bob_real_pk, vec![192]).unwrap();
+            assert_eq!(Some((alice_real_pk, vec![CryptoDataEvent::Lossy(vec![192])])),
+                       bob.handle_crypto_data(alice_addr, &packet));
+        }
+    }
+}
@@ -0,0 +1,684 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! Net crypto packet part of the toxcore.
+    * takes care of the serializing and de-serializing net crypto packets
+*/
+
+use byteorder::{BigEndian, ByteOrder};
+use nom::{be_u16, be_u32, be_u64, rest};
+
+use sodiumoxide::crypto::hash::sha512;
+
+use toxcore::crypto_core::*;
+use toxcore::dht_new::binary_io::*;
+use toxcore::net_crypto::cookie::*;
+
+/// Size of [`CookieRequestPayload`](./struct.CookieRequestPayload.html)
+/// in bytes.
+const COOKIE_REQUEST_PAYLOAD_SIZE: usize = PUBLICKEYBYTES * 2 + 8;
+
+/// Size of [`CookieResponsePayload`](./struct.CookieResponsePayload.html)
+/// in bytes.
+const COOKIE_RESPONSE_PAYLOAD_SIZE: usize = ENCRYPTED_COOKIE_SIZE + 8;
+
+/// Maximum size in bytes of any serialized net crypto packet.
+pub const MAX_CRYPTO_PACKET_SIZE: usize = 1400;
+
+/** Serialize `payload` and encrypt it with `shared_secret`.
+
+Panics if serialized payload doesn't fit in
+[`MAX_CRYPTO_PACKET_SIZE`](./constant.MAX_CRYPTO_PACKET_SIZE.html) bytes.
+*/
+fn encrypt_payload<P: ToBytes>(shared_secret: &PrecomputedKey, nonce: &Nonce, payload: &P) -> Vec<u8> {
+    let mut buf = [0; MAX_CRYPTO_PACKET_SIZE];
+    let (_, size) = payload.to_bytes((&mut buf, 0))
+        .expect("Net crypto payload should fit in MAX_CRYPTO_PACKET_SIZE");
+    encrypt_data_symmetric(shared_secret, nonce, &buf[..size])
+}
+
+/** Decrypt `payload` with `shared_secret` and parse it as `P`.
+
+Returns `None` if decryption or parsing failed.
+*/
+fn decrypt_payload<P: FromBytes>(shared_secret: &PrecomputedKey, nonce: &Nonce, payload: &[u8]) -> Option<P> {
+    let decrypted = match decrypt_data_symmetric(shared_secret, nonce, payload) {
+        Ok(decrypted) => decrypted,
+        Err(()) => {
+            debug!("Decrypting net crypto payload failed!");
+            return None
+        },
+    };
+    P::from_bytes(&decrypted).to_full_result().ok()
+}
+
+/** Request for the cookie that is needed to start a handshake. It's sent
+to the node we want to connect to using its DHT `PublicKey`.
+
+Payload is encrypted with DHT `SecretKey` of the sender and DHT
+`PublicKey` of the receiver.
+
+Serialized form:
+
+Length | Content
+------ | ------
+`1`    | `0x18`
+`32`   | DHT `PublicKey` of the sender
+`24`   | Nonce
+`88`   | Encrypted [`CookieRequestPayload`](./struct.CookieRequestPayload.html)
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CookieRequest {
+    /// DHT `PublicKey` of the sender
+    pub pk: PublicKey,
+    /// Nonce for the current encrypted payload
+    pub nonce: Nonce,
+    /// Encrypted payload
+    pub payload: Vec<u8>,
+}
+
+impl FromBytes for CookieRequest {
+    named!(from_bytes<CookieRequest>, do_parse!(
+        tag!(&[0x18][..]) >>
+        pk: call!(PublicKey::from_bytes) >>
+        nonce: call!(Nonce::from_bytes) >>
+        payload: take!(COOKIE_REQUEST_PAYLOAD_SIZE + MACBYTES) >>
+        (CookieRequest {
+            pk: pk,
+            nonce: nonce,
+            payload: payload.to_vec(),
+        })
+    ));
+}
+
+impl ToBytes for CookieRequest {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(0x18) >>
+            gen_slice!(self.pk.as_ref()) >>
+            gen_slice!(self.nonce.as_ref()) >>
+            gen_slice!(self.payload.as_slice())
+        )
+    }
+}
+
+impl CookieRequest {
+    /// Create new `CookieRequest` object with `payload` encrypted by
+    /// `shared_secret`.
+    pub fn new(shared_secret: &PrecomputedKey, dht_pk: &PublicKey, payload: &CookieRequestPayload) -> CookieRequest {
+        let nonce = gen_nonce();
+        CookieRequest {
+            pk: *dht_pk,
+            nonce: nonce,
+            payload: encrypt_payload(shared_secret, &nonce, payload),
+        }
+    }
+
+    /** Decrypt payload with `shared_secret` and try to parse it as
+    [`CookieRequestPayload`](./struct.CookieRequestPayload.html).
+
+    Returns `None` in case of failure.
+    */
+    pub fn get_payload(&self, shared_secret: &PrecomputedKey) -> Option<CookieRequestPayload> {
+        decrypt_payload(shared_secret, &self.nonce, &self.payload)
+    }
+}
+
+/** Unencrypted payload of [`CookieRequest`](./struct.CookieRequest.html).
+
+Serialized form:
+
+Length | Content
+------ | ------
+`32`   | Real `PublicKey` of the sender
+`32`   | Zeroes
+`8`    | Request ID in BigEndian
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CookieRequestPayload {
+    /// Real `PublicKey` of the sender
+    pub pk: PublicKey,
+    /// Request ID that is sent back in the response
+    pub id: u64,
+}
+
+impl FromBytes for CookieRequestPayload {
+    named!(from_bytes<CookieRequestPayload>, do_parse!(
+        pk: call!(PublicKey::from_bytes) >>
+        take!(PUBLICKEYBYTES) >>
+        id: be_u64 >>
+        eof!() >>
+        (CookieRequestPayload {
+            pk: pk,
+            id: id,
+        })
+    ));
+}
+
+impl ToBytes for CookieRequestPayload {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_slice!(self.pk.as_ref()) >>
+            gen_slice!(&[0; PUBLICKEYBYTES]) >>
+            gen_be_u64!(self.id)
+        )
+    }
+}
+
+/** Response to [`CookieRequest`](./struct.CookieRequest.html) that
+contains the cookie.
+
+Payload is encrypted with DHT `SecretKey` of the sender and DHT
+`PublicKey` of the receiver.
+
+Serialized form:
+
+Length | Content
+------ | ------
+`1`    | `0x19`
+`24`   | Nonce
+`136`  | Encrypted [`CookieResponsePayload`](./struct.CookieResponsePayload.html)
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CookieResponse {
+    /// Nonce for the current encrypted payload
+    pub nonce: Nonce,
+    /// Encrypted payload
+    pub payload: Vec<u8>,
+}
+
+impl FromBytes for CookieResponse {
+    named!(from_bytes<CookieResponse>, do_parse!(
+        tag!(&[0x19][..]) >>
+        nonce: call!(Nonce::from_bytes) >>
+        payload: take!(COOKIE_RESPONSE_PAYLOAD_SIZE + MACBYTES) >>
+        (CookieResponse {
+            nonce: nonce,
+            payload: payload.to_vec(),
+        })
+    ));
+}
+
+impl ToBytes for CookieResponse {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(0x19) >>
+            gen_slice!(self.nonce.as_ref()) >>
+            gen_slice!(self.payload.as_slice())
+        )
+    }
+}
+
+impl CookieResponse {
+    /// Create new `CookieResponse` object with `payload` encrypted by
+    /// `shared_secret`.
+    pub fn new(shared_secret: &PrecomputedKey, payload: &CookieResponsePayload) -> CookieResponse {
+        let nonce = gen_nonce();
+        CookieResponse {
+            nonce: nonce,
+            payload: encrypt_payload(shared_secret, &nonce, payload),
+        }
+    }
+
+    /** Decrypt payload with `shared_secret` and try to parse it as
+    [`CookieResponsePayload`](./struct.CookieResponsePayload.html).
+
+    Returns `None` in case of failure.
+    */
+    pub fn get_payload(&self, shared_secret: &PrecomputedKey) -> Option<CookieResponsePayload> {
+        decrypt_payload(shared_secret, &self.nonce, &self.payload)
+    }
+}
+
+/** Unencrypted payload of [`CookieResponse`](./struct.CookieResponse.html).
+
+Serialized form:
+
+Length | Content
+------ | ------
+`112`  | [`EncryptedCookie`](../cookie/struct.EncryptedCookie.html)
+`8`    | Request ID in BigEndian
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CookieResponsePayload {
+    /// Cookie of the sender
+    pub cookie: EncryptedCookie,
+    /// ID of the request this response is sent to
+    pub id: u64,
+}
+
+impl FromBytes for CookieResponsePayload {
+    named!(from_bytes<CookieResponsePayload>, do_parse!(
+        cookie: call!(EncryptedCookie::from_bytes) >>
+        id: be_u64 >>
+        eof!() >>
+        (CookieResponsePayload {
+            cookie: cookie,
+            id: id,
+        })
+    ));
+}
+
+impl ToBytes for CookieResponsePayload {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_call!(|buf, cookie| EncryptedCookie::to_bytes(cookie, buf), &self.cookie) >>
+            gen_be_u64!(self.id)
+        )
+    }
+}
+
+/// Size of [`CryptoHandshakePayload`](./struct.CryptoHandshakePayload.html)
+/// in bytes.
+const CRYPTO_HANDSHAKE_PAYLOAD_SIZE: usize = NONCEBYTES + PUBLICKEYBYTES +
+    sha512::DIGESTBYTES + ENCRYPTED_COOKIE_SIZE;
+
+/** Packet that is used to establish the net crypto session. It's sent
+after the cookie was received in response to
+[`CookieRequest`](./struct.CookieRequest.html), or in response to the
+handshake of the other node.
+
+Payload is encrypted with real `SecretKey` of the sender and real
+`PublicKey` of the receiver. The receiver takes real `PublicKey` of the
+sender from the cookie, so it doesn't have to be sent in plain text.
+
+Serialized form:
+
+Length | Content
+------ | ------
+`1`    | `0x1a`
+`112`  | [`EncryptedCookie`](../cookie/struct.EncryptedCookie.html) issued by the receiver
+`24`   | Nonce
+`248`  | Encrypted [`CryptoHandshakePayload`](./struct.CryptoHandshakePayload.html)
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CryptoHandshake {
+    /// Cookie that was issued by the receiver of this packet
+    pub cookie: EncryptedCookie,
+    /// Nonce for the current encrypted payload
+    pub nonce: Nonce,
+    /// Encrypted payload
+    pub payload: Vec<u8>,
+}
+
+impl FromBytes for CryptoHandshake {
+    named!(from_bytes<CryptoHandshake>, do_parse!(
+        tag!(&[0x1a][..]) >>
+        cookie: call!(EncryptedCookie::from_bytes) >>
+        nonce: call!(Nonce::from_bytes) >>
+        payload: take!(CRYPTO_HANDSHAKE_PAYLOAD_SIZE + MACBYTES) >>
+        (CryptoHandshake {
+            cookie: cookie,
+            nonce: nonce,
+            payload: payload.to_vec(),
+        })
+    ));
+}
+
+impl ToBytes for CryptoHandshake {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(0x1a) >>
+            gen_call!(|buf, cookie| EncryptedCookie::to_bytes(cookie, buf), &self.cookie) >>
+            gen_slice!(self.nonce.as_ref()) >>
+            gen_slice!(self.payload.as_slice())
+        )
+    }
+}
+
+impl CryptoHandshake {
+    /// Create new `CryptoHandshake` object with `payload` encrypted by
+    /// `shared_secret`.
+    pub fn new(shared_secret: &PrecomputedKey, cookie: EncryptedCookie, payload: &CryptoHandshakePayload) -> CryptoHandshake {
+        let nonce = gen_nonce();
+        CryptoHandshake {
+            cookie: cookie,
+            nonce: nonce,
+            payload: encrypt_payload(shared_secret, &nonce, payload),
+        }
+    }
+
+    /** Decrypt payload with `shared_secret` and try to parse it as
+    [`CryptoHandshakePayload`](./struct.CryptoHandshakePayload.html).
+
+    Returns `None` in case of failure.
+    */
+    pub fn get_payload(&self, shared_secret: &PrecomputedKey) -> Option<CryptoHandshakePayload> {
+        decrypt_payload(shared_secret, &self.nonce, &self.payload)
+    }
+}
+
+/** Unencrypted payload of [`CryptoHandshake`](./struct.CryptoHandshake.html).
+
+Serialized form:
+
+Length | Content
+------ | ------
+`24`   | Base nonce that will be used to encrypt data sent by the sender
+`32`   | Session `PublicKey` of the sender
+`64`   | SHA512 hash of the cookie sent in the handshake packet
+`112`  | [`EncryptedCookie`](../cookie/struct.EncryptedCookie.html) issued by the sender
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CryptoHandshakePayload {
+    /// Base nonce of the sender
+    pub base_nonce: Nonce,
+    /// Session `PublicKey` of the sender
+    pub session_pk: PublicKey,
+    /// Hash of the cookie sent in the handshake packet
+    pub cookie_hash: sha512::Digest,
+    /// Cookie the receiver should use for its handshake
+    pub cookie: EncryptedCookie,
+}
+
+impl FromBytes for CryptoHandshakePayload {
+    named!(from_bytes<CryptoHandshakePayload>, do_parse!(
+        base_nonce: call!(Nonce::from_bytes) >>
+        session_pk: call!(PublicKey::from_bytes) >>
+        cookie_hash: call!(sha512::Digest::from_bytes) >>
+        cookie: call!(EncryptedCookie::from_bytes) >>
+        eof!() >>
+        (CryptoHandshakePayload {
+            base_nonce: base_nonce,
+            session_pk: session_pk,
+            cookie_hash: cookie_hash,
+            cookie: cookie,
+        })
+    ));
+}
+
+impl ToBytes for CryptoHandshakePayload {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_slice!(self.base_nonce.as_ref()) >>
+            gen_slice!(self.session_pk.as_ref()) >>
+            gen_slice!(self.cookie_hash.as_ref()) >>
+            gen_call!(|buf, cookie| EncryptedCookie::to_bytes(cookie, buf), &self.cookie)
+        )
+    }
+}
+
+/// ID of padding bytes that may precede the data of
+/// [`CryptoDataPayload`](./struct.CryptoDataPayload.html).
+pub const PACKET_ID_PADDING: u8 = 0;
+/// ID of the packet that requests lossless packets the sender didn't
+/// receive.
+pub const PACKET_ID_REQUEST: u8 = 1;
+/// ID of the packet that closes the connection.
+pub const PACKET_ID_KILL: u8 = 2;
+/// First ID of lossless packets. IDs before it are reserved for net crypto.
+pub const PACKET_ID_LOSSLESS_RANGE_START: u8 = 16;
+/// Last ID of lossless packets.
+pub const PACKET_ID_LOSSLESS_RANGE_END: u8 = 191;
+/// First ID of lossy packets.
+pub const PACKET_ID_LOSSY_RANGE_START: u8 = 192;
+/// Last ID of lossy packets.
+pub const PACKET_ID_LOSSY_RANGE_END: u8 = 254;
+
+/// Minimal size of [`CryptoData`](./struct.CryptoData.html) packet in
+/// bytes, i.e. size of the packet with empty data.
+pub const CRYPTO_DATA_PACKET_MIN_SIZE: usize = 1 + 2 + 4 + 4 + MACBYTES;
+
+/// Maximum size of data that can be sent in
+/// [`CryptoData`](./struct.CryptoData.html) packet.
+pub const MAX_CRYPTO_DATA_SIZE: usize = MAX_CRYPTO_PACKET_SIZE - CRYPTO_DATA_PACKET_MIN_SIZE;
+
+/** Packet that carries data of the established net crypto session.
+
+Payload is encrypted with the session key. Only last 2 bytes of the nonce
+are sent, the receiver restores the full nonce using the base nonce of the
+sender which it got from the handshake.
+
+Serialized form:
+
+Length   | Content
+-------- | ------
+`1`      | `0x1b`
+`2`      | Last 2 bytes of the nonce in BigEndian
+variable | Encrypted [`CryptoDataPayload`](./struct.CryptoDataPayload.html)
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CryptoData {
+    /// Last 2 bytes of the nonce used to encrypt the payload
+    pub nonce_last_bytes: u16,
+    /// Encrypted payload
+    pub payload: Vec<u8>,
+}
+
+impl FromBytes for CryptoData {
+    named!(from_bytes<CryptoData>, do_parse!(
+        tag!(&[0x1b][..]) >>
+        nonce_last_bytes: be_u16 >>
+        payload_len: map!(peek!(rest), |rest: &[u8]| rest.len()) >>
+        verify!(value!(payload_len), |len| len >= CRYPTO_DATA_PACKET_MIN_SIZE - 3 &&
+            len <= MAX_CRYPTO_PACKET_SIZE - 3) >>
+        payload: rest >>
+        (CryptoData {
+            nonce_last_bytes: nonce_last_bytes,
+            payload: payload.to_vec(),
+        })
+    ));
+}
+
+impl ToBytes for CryptoData {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(0x1b) >>
+            gen_be_u16!(self.nonce_last_bytes) >>
+            gen_slice!(self.payload.as_slice())
+        )
+    }
+}
+
+impl CryptoData {
+    /// Create new `CryptoData` object with `payload` encrypted by
+    /// `shared_secret` and `nonce`.
+    pub fn new(shared_secret: &PrecomputedKey, nonce: &Nonce, payload: &CryptoDataPayload) -> CryptoData {
+        let Nonce(nonce_bytes) = *nonce;
+        CryptoData {
+            nonce_last_bytes: BigEndian::read_u16(&nonce_bytes[NONCEBYTES - 2..]),
+            payload: encrypt_payload(shared_secret, nonce, payload),
+        }
+    }
+
+    /** Decrypt payload with `shared_secret` and full `nonce` and try to
+    parse it as [`CryptoDataPayload`](./struct.CryptoDataPayload.html).
+
+    Returns `None` in case of failure.
+    */
+    pub fn get_payload(&self, shared_secret: &PrecomputedKey, nonce: &Nonce) -> Option<CryptoDataPayload> {
+        decrypt_payload(shared_secret, nonce, &self.payload)
+    }
+}
+
+/** Unencrypted payload of [`CryptoData`](./struct.CryptoData.html).
+
+Data starts with packet ID that may be preceded by zero padding bytes.
+
+Serialized form:
+
+Length   | Content
+-------- | ------
+`4`      | Number of the first lossless packet the sender didn't receive in BigEndian
+`4`      | Number of this packet in BigEndian
+variable | Data
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CryptoDataPayload {
+    /// Number of the first lossless packet the sender didn't receive, all
+    /// previous packets are acknowledged by it
+    pub buffer_start: u32,
+    /// Number of this packet
+    pub packet_number: u32,
+    /// Data of the packet
+    pub data: Vec<u8>,
+}
+
+impl FromBytes for CryptoDataPayload {
+    named!(from_bytes<CryptoDataPayload>, do_parse!(
+        buffer_start: be_u32 >>
+        packet_number: be_u32 >>
+        data: rest >>
+        (CryptoDataPayload {
+            buffer_start: buffer_start,
+            packet_number: packet_number,
+            data: data.to_vec(),
+        })
+    ));
+}
+
+impl ToBytes for CryptoDataPayload {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u32!(self.buffer_start) >>
+            gen_be_u32!(self.packet_number) >>
+            gen_slice!(self.data.as_slice())
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Serialize `$packet` and check that it's parsed back into the same
+    /// value.
+    macro_rules! encode_decode_test {
+        ($test:ident, $packet:expr) => (
+            #[test]
+            fn $test() {
+                let value = $packet;
+                let mut buf = [0; MAX_CRYPTO_PACKET_SIZE];
+                let (_, size) = value.to_bytes((&mut buf, 0)).unwrap();
+                let (rest, decoded_value) = FromBytes::from_bytes(&buf[..size]).unwrap();
+                // this helps compiler to infer type of decoded_value
+                // i.e. no need to pass the type as the macro argument
+                assert_eq!(value, decoded_value);
+                assert!(rest.is_empty());
+            }
+        )
+    }
+
+    encode_decode_test!(
+        cookie_request_encode_decode,
+        CookieRequest::new(&new_symmetric_key(), &gen_keypair().0, &CookieRequestPayload {
+            pk: gen_keypair().0,
+            id: 42,
+        })
+    );
+
+    encode_decode_test!(
+        cookie_response_encode_decode,
+        CookieResponse::new(&new_symmetric_key(), &CookieResponsePayload {
+            cookie: CookieSecret::new().issue_cookie(gen_keypair().0, gen_keypair().0),
+            id: 42,
+        })
+    );
+
+    encode_decode_test!(
+        crypto_handshake_encode_decode,
+        CryptoHandshake::new(&new_symmetric_key(),
+            CookieSecret::new().issue_cookie(gen_keypair().0, gen_keypair().0),
+            &CryptoHandshakePayload {
+                base_nonce: gen_nonce(),
+                session_pk: gen_keypair().0,
+                cookie_hash: sha512::hash(&[42; ENCRYPTED_COOKIE_SIZE]),
+                cookie: CookieSecret::new().issue_cookie(gen_keypair().0, gen_keypair().0),
+            }
+        )
+    );
+
+    encode_decode_test!(
+        crypto_data_encode_decode,
+        CryptoData::new(&new_symmetric_key(), &gen_nonce(), &CryptoDataPayload {
+            buffer_start: 12,
+            packet_number: 42,
+            data: vec![42; 123],
+        })
+    );
+
+    #[test]
+    fn cookie_request_get_payload() {
+        let (alice_pk, alice_sk) = gen_keypair();
+        let (bob_pk, bob_sk) = gen_keypair();
+        let (_, eve_sk) = gen_keypair();
+        let payload = CookieRequestPayload {
+            pk: gen_keypair().0,
+            id: 42,
+        };
+        let request = CookieRequest::new(&encrypt_precompute(&bob_pk, &alice_sk),
+                                         &alice_pk, &payload);
+
+        assert!(request.get_payload(&encrypt_precompute(&request.pk, &eve_sk)).is_none());
+        let decrypted = request.get_payload(&encrypt_precompute(&request.pk, &bob_sk))
+            .unwrap();
+        assert_eq!(payload, decrypted);
+    }
+
+    #[test]
+    fn cookie_response_get_payload() {
+        let shared_secret = new_symmetric_key();
+        let payload = CookieResponsePayload {
+            cookie: CookieSecret::new().issue_cookie(gen_keypair().0, gen_keypair().0),
+            id: 42,
+        };
+        let response = CookieResponse::new(&shared_secret, &payload);
+
+        assert!(response.get_payload(&new_symmetric_key()).is_none());
+        assert_eq!(payload, response.get_payload(&shared_secret).unwrap());
+    }
+    #[test]
+    fn crypto_handshake_get_payload() {
+        let shared_secret = new_symmetric_key();
+        let cookie = CookieSecret::new().issue_cookie(gen_keypair().0, gen_keypair().0);
+        let payload = CryptoHandshakePayload {
+            base_nonce: gen_nonce(),
+            session_pk: gen_keypair().0,
+            cookie_hash: cookie.hash(),
+            cookie: CookieSecret::new().issue_cookie(gen_keypair().0, gen_keypair().0),
+        };
+        let handshake = CryptoHandshake::new(&shared_secret, cookie, &payload);
+
+        assert!(handshake.get_payload(&new_symmetric_key()).is_none());
+        assert_eq!(payload, handshake.get_payload(&shared_secret).unwrap());
+    }
+    #[test]
+    fn crypto_data_get_payload() {
+        let shared_secret = new_symmetric_key();
+        let nonce = gen_nonce();
+        let payload = CryptoDataPayload {
+            buffer_start: 12,
+            packet_number: 42,
+            data: vec![42; 123],
+        };
+        let packet = CryptoData::new(&shared_secret, &nonce, &payload);
+        let Nonce(nonce_bytes) = nonce;
+        assert_eq!(BigEndian::read_u16(&nonce_bytes[NONCEBYTES - 2..]), packet.nonce_last_bytes);
+
+        assert!(packet.get_payload(&shared_secret, &gen_nonce()).is_none());
+        assert!(packet.get_payload(&new_symmetric_key(), &nonce).is_none());
+        assert_eq!(payload, packet.get_payload(&shared_secret, &nonce).unwrap());
+    }
+
+    #[test]
+    fn crypto_data_too_small() {
+        let buf = [0x1b, 0, 42, 1, 2, 3];
+        assert!(!CryptoData::from_bytes(&buf).is_done());
+    }
+}
@@ -0,0 +1,279 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! Ring buffer of numbered packets that is used to send and receive
+lossless net crypto data.
+
+Packets are indexed with `u32` numbers that wrap around. The buffer holds
+packets with numbers from `buffer_start` to `buffer_end` (exclusive), some
+of them can be missing.
+*/
+
+/// Maximum number of packets that can be stored in
+/// [`PacketsArray`](./struct.PacketsArray.html). Must be a power of 2.
+pub const CRYPTO_PACKET_BUFFER_SIZE: u32 = 32768;
+
+/// Ring buffer of numbered packets.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PacketsArray<T> {
+    /// Storage of the packets, index of a packet is its number modulo
+    /// `CRYPTO_PACKET_BUFFER_SIZE`
+    buffer: Vec<Option<Box<T>>>,
+    /// Number of the first packet in the buffer
+    buffer_start: u32,
+    /// Number of the packet after the last one in the buffer
+    buffer_end: u32,
+}
+
+impl<T> Default for PacketsArray<T> {
+    fn default() -> Self {
+        PacketsArray::new()
+    }
+}
+
+impl<T> PacketsArray<T> {
+    /// Create new empty `PacketsArray`.
+    pub fn new() -> PacketsArray<T> {
+        PacketsArray {
+            buffer: (0 .. CRYPTO_PACKET_BUFFER_SIZE).map(|_| None).collect(),
+            buffer_start: 0,
+            buffer_end: 0,
+        }
+    }
+
+    /// Position of the packet with number `index` in the storage.
+    fn real_index(index: u32) -> usize {
+        (index % CRYPTO_PACKET_BUFFER_SIZE) as usize
+    }
+
+    /// Number of the first packet in the buffer.
+    pub fn buffer_start(&self) -> u32 {
+        self.buffer_start
+    }
+
+    /// Number of the packet after the last one in the buffer.
+    pub fn buffer_end(&self) -> u32 {
+        self.buffer_end
+    }
+
+    /// Number of slots between `buffer_start` and `buffer_end` including
+    /// the slots of missing packets.
+    pub fn len(&self) -> u32 {
+        self.buffer_end.wrapping_sub(self.buffer_start)
+    }
+
+    /// Check whether there are no slots between `buffer_start` and
+    /// `buffer_end`.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Check whether new packets can't be pushed to the end of the buffer.
+    pub fn is_full(&self) -> bool {
+        self.len() >= CRYPTO_PACKET_BUFFER_SIZE
+    }
+
+    /// Check whether `index` is between `buffer_start` and `buffer_end`.
+    pub fn in_range(&self, index: u32) -> bool {
+        index.wrapping_sub(self.buffer_start) < self.len()
+    }
+
+    /// Check whether the packet with number `index` is stored.
+    pub fn contains(&self, index: u32) -> bool {
+        self.get(index).is_some()
+    }
+
+    /// Get the packet with number `index`.
+    pub fn get(&self, index: u32) -> Option<&T> {
+        if !self.in_range(index) {
+            return None
+        }
+        self.buffer[Self::real_index(index)].as_ref().map(|packet| &**packet)
+    }
+
+    /// Get mutable reference to the packet with number `index`.
+    pub fn get_mut(&mut self, index: u32) -> Option<&mut T> {
+        if !self.in_range(index) {
+            return None
+        }
+        self.buffer[Self::real_index(index)].as_mut().map(|packet| &mut **packet)
+    }
+
+    /** Push `packet` to the end of the buffer.
+
+    Returns the number of the pushed packet or `None` if the buffer is
+    full.
+    */
+    pub fn push_back(&mut self, packet: T) -> Option<u32> {
+        if self.is_full() {
+            return None
+        }
+        let index = self.buffer_end;
+        self.buffer[Self::real_index(index)] = Some(Box::new(packet));
+        self.buffer_end = self.buffer_end.wrapping_add(1);
+        Some(index)
+    }
+
+    /** Insert `packet` with number `index`. The end of the buffer is moved
+    if `index` is after it.
+
+    Returns `false` if the packet is already stored or `index` is before
+    `buffer_start` or too far from it.
+    */
+    pub fn insert(&mut self, index: u32, packet: T) -> bool {
+        if index.wrapping_sub(self.buffer_start) >= CRYPTO_PACKET_BUFFER_SIZE {
+            return false
+        }
+        if self.contains(index) {
+            return false
+        }
+        if !self.in_range(index) {
+            self.buffer_end = index.wrapping_add(1);
+        }
+        self.buffer[Self::real_index(index)] = Some(Box::new(packet));
+        true
+    }
+
+    /// Remove the packet with number `index` and return it.
+    pub fn remove(&mut self, index: u32) -> Option<T> {
+        if !self.in_range(index) {
+            return None
+        }
+        self.buffer[Self::real_index(index)].take().map(|packet| *packet)
+    }
+
+    /** Remove the first packet of the buffer and move `buffer_start` to
+    the next one.
+
+    Returns `None` if the buffer is empty or the first packet is missing.
+    */
+    pub fn pop_front(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None
+        }
+        let packet = self.buffer[Self::real_index(self.buffer_start)].take();
+        if packet.is_some() {
+            self.buffer_start = self.buffer_start.wrapping_add(1);
+        }
+        packet.map(|packet| *packet)
+    }
+
+    /** Remove all packets before `index` and move `buffer_start` to it.
+
+    Returns `false` if `index` is not between `buffer_start` and
+    `buffer_end` (inclusive).
+    */
+    pub fn set_buffer_start(&mut self, index: u32) -> bool {
+        if index.wrapping_sub(self.buffer_start) > self.len() {
+            return false
+        }
+        while self.buffer_start != index {
+            self.buffer[Self::real_index(self.buffer_start)] = None;
+            self.buffer_start = self.buffer_start.wrapping_add(1);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packets_array_push_back() {
+        let mut array = PacketsArray::new();
+        assert!(array.is_empty());
+        assert_eq!(Some(0), array.push_back(42));
+        assert_eq!(Some(1), array.push_back(43));
+        assert_eq!(2, array.len());
+        assert_eq!(Some(&42), array.get(0));
+        assert_eq!(Some(&43), array.get(1));
+        assert_eq!(None, array.get(2));
+    }
+
+    #[test]
+    fn packets_array_push_back_full() {
+        let mut array = PacketsArray::new();
+        for i in 0 .. CRYPTO_PACKET_BUFFER_SIZE {
+            assert_eq!(Some(i), array.push_back(()));
+        }
+        assert!(array.is_full());
+        assert_eq!(None, array.push_back(()));
+    }
+
+    #[test]
+    fn packets_array_insert() {
+        let mut array = PacketsArray::new();
+        assert!(array.insert(2, 42));
+        assert_eq!(3, array.len());
+        assert!(!array.contains(0));
+        assert!(!array.contains(1));
+        assert!(array.contains(2));
+        // already stored
+        assert!(!array.insert(2, 43));
+        assert!(array.insert(0, 41));
+        assert_eq!(3, array.len());
+        // too far from the start
+        assert!(!array.insert(CRYPTO_PACKET_BUFFER_SIZE, 44));
+    }
+
+    #[test]
+    fn packets_array_pop_front() {
+        let mut array = PacketsArray::new();
+        assert_eq!(None, array.pop_front());
+        assert!(array.insert(1, 42));
+        // the first packet is missing
+        assert_eq!(None, array.pop_front());
+        assert!(array.insert(0, 41));
+        assert_eq!(Some(41), array.pop_front());
+        assert_eq!(Some(42), array.pop_front());
+        assert_eq!(None, array.pop_front());
+        assert_eq!(2, array.buffer_start());
+        assert!(array.is_empty());
+    }
+
+    #[test]
+    fn packets_array_set_buffer_start() {
+        let mut array = PacketsArray::new();
+        for i in 0 .. 5 {
+            array.push_back(i);
+        }
+        assert!(array.set_buffer_start(3));
+        assert_eq!(3, array.buffer_start());
+        assert_eq!(None, array.get(2));
+        assert_eq!(Some(&3), array.get(3));
+        assert!(!array.set_buffer_start(6));
+        assert!(!array.set_buffer_start(2));
+        assert!(array.set_buffer_start(5));
+        assert!(array.is_empty());
+    }
+
+    #[test]
+    fn packets_array_wrapping() {
+        let mut array = PacketsArray::new();
+        array.buffer_start = u32::max_value();
+        array.buffer_end = u32::max_value();
+        assert_eq!(Some(u32::max_value()), array.push_back(1));
+        assert_eq!(Some(0), array.push_back(2));
+        assert_eq!(2, array.len());
+        assert_eq!(Some(1), array.pop_front());
+        assert_eq!(Some(2), array.pop_front());
+        assert_eq!(1, array.buffer_start());
+    }
+}
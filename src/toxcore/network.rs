@@ -30,9 +30,10 @@ use std::rc::Rc;
 use std::cell::RefCell;
 use std::io::{ self, ErrorKind };
 use std::ops::{ Range, RangeFrom, RangeTo, RangeFull };
-use std::net::{ IpAddr, Ipv6Addr, SocketAddr, ToSocketAddrs };
+use std::net::{ IpAddr, Ipv6Addr, SocketAddr, SocketAddrV4, ToSocketAddrs };
 use std::collections::HashMap;
 
+use net2::UdpBuilder;
 use tokio_core::net::UdpSocket;
 use tokio_core::reactor::Handle;
 
@@ -103,8 +104,6 @@ impl NetworkingCore {
         );
 
         if let IpAddr::V6(_) = ip {
-            // TODO Dual-stack: set only_v6 to false.
-
             let res = sock.join_multicast_v6(&Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0x01), 0);
             match res {
                 Ok(_) => debug!("Local multicast group FF02::1 joined successfully"),
@@ -146,9 +145,17 @@ impl NetworkingCore {
         }
     }
 
-    /// Function to send packet (`data`) to SocketAddr.
+    /** Function to send packet (`data`) to SocketAddr.
+
+    IPv4 addresses are converted to IPv4-mapped IPv6 addresses if the
+    socket is bound to IPv6 address.
+    */
     pub fn send_packet(&self, addr: SocketAddr, data: &[u8]) -> io::Result<usize> {
-        // XXX need check target ip type?
+        let addr = match (addr, self.sock.local_addr()) {
+            (SocketAddr::V4(v4), Ok(SocketAddr::V6(_))) =>
+                SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port()),
+            _ => addr,
+        };
         let res = self.sock.send_to(data, &addr);
 
         // TODO debug
@@ -182,9 +189,67 @@ impl NetworkingCore {
     }
 }
 
-/** Bind to an UDP socket on `0.0.0.0` with a port in range [`PORT_MIN`]
+/** Check whether IPv6 address is IPv4-mapped, i.e. `::ffff:a.b.c.d`.
+*/
+pub fn is_ipv4_mapped(ip: &Ipv6Addr) -> bool {
+    let segments = ip.segments();
+    segments[..5].iter().all(|&s| s == 0) && segments[5] == 0xffff
+}
+
+/** Convert IPv4-mapped IPv6 socket address into IPv4 socket address.
+
+Dual-stack sockets receive packets from IPv4 peers with IPv4-mapped
+addresses, so this should be applied to addresses before storing them to
+avoid having different addresses for the same node.
+
+Other addresses are returned unchanged.
+
+```
+# use tox::toxcore::network::normalize_addr;
+let mapped = "[::ffff:1.2.3.4]:33445".parse().unwrap();
+assert_eq!("1.2.3.4:33445".parse(), Ok(normalize_addr(mapped)));
+
+let v6 = "[::1]:33445".parse().unwrap();
+assert_eq!(v6, normalize_addr(v6));
+```
+*/
+pub fn normalize_addr(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(ref v6) if is_ipv4_mapped(v6.ip()) => {
+            let octets = v6.ip().octets();
+            let ip = [octets[12], octets[13], octets[14], octets[15]];
+            SocketAddr::V4(SocketAddrV4::new(ip.into(), v6.port()))
+        },
+        _ => addr,
+    }
+}
+
+/** Bind UDP socket to `addr`.
+
+IPv6 socket is made dual-stack, so that it can be used to communicate with
+IPv4 peers as well through IPv4-mapped addresses. If the OS doesn't allow
+that, socket stays IPv6-only.
+*/
+fn bind_udp_addr(addr: &SocketAddr, handle: &Handle) -> io::Result<UdpSocket> {
+    match *addr {
+        SocketAddr::V4(_) => UdpSocket::bind(addr, handle),
+        SocketAddr::V6(_) => {
+            let builder = try!(UdpBuilder::new_v6());
+            if let Err(e) = builder.only_v6(false) {
+                warn!(target: "Port", "Failed to make IPv6 socket dual-stack: {}", e);
+            }
+            let socket = try!(builder.bind(addr));
+            UdpSocket::from_socket(socket, handle)
+        },
+    }
+}
+
+/** Bind to an UDP socket on `ip` with a port in range [`PORT_MIN`]
 (./constant.PORT_MIN.html):[`PORT_MAX`](./constant.PORT_MAX.html).
 
+When `ip` is IPv6 address, socket is dual-stack if possible, see
+[`normalize_addr()`](./fn.normalize_addr.html).
+
 Returns `None` if failed to bind to port within range.
 */
 pub fn bind_udp(ip: IpAddr, port_range: Range<u16>, handle: &Handle)
@@ -194,7 +259,7 @@ pub fn bind_udp(ip: IpAddr, port_range: Range<u16>, handle: &Handle)
         match (ip, port).to_socket_addrs().ok()
             .and_then(|mut addrs| addrs.next())
             .ok_or_else(|| io::Error::new(ErrorKind::AddrNotAvailable, "Socket Addr Not Available."))
-            .and_then(|addr| UdpSocket::bind(&addr, handle))
+            .and_then(|addr| bind_udp_addr(&addr, handle))
         {
             Ok(s) => {
                 debug!(target: "Port", "Bind to port {} successful.", port);
@@ -0,0 +1,33 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! Onion module allows nodes to announce themselves and to send data to
+each other without revealing their real addresses.
+
+https://zetok.github.io/tox-spec/#onion
+*/
+
+pub mod onion_announce;
+pub mod packet;
+
+/**
+Number of seconds after which the symmetric key that is used to encrypt
+return addresses of relayed onion packets is replaced with a new one.
+*/
+pub const ONION_KEY_REFRESH_INTERVAL: u64 = 2 * 60 * 60;
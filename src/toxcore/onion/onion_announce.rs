@@ -0,0 +1,454 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! Storage of nodes announced through the onion.
+
+Nodes announce themselves by sending
+[`OnionAnnounceRequest`](../packet/struct.OnionAnnounceRequest.html)s with
+their long term `PublicKey` to the nodes that are closest to this key. Then
+other nodes can find them by sending announce requests that search for the
+key and send them data using the return path stored in the announce entry.
+*/
+
+use byteorder::{BigEndian, ByteOrder};
+use sodiumoxide::crypto::hash::sha256;
+
+use std::cmp::Ordering;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use toxcore::crypto_core::*;
+use toxcore::dht_new::kbucket::Distance;
+use toxcore::onion::packet::*;
+use toxcore::timeout::unix_time;
+
+/// Maximum number of entries in the onion announce list.
+pub const ONION_ANNOUNCE_MAX_ENTRIES: usize = 160;
+
+/**
+Number of seconds during which generated ping ID is the current one.
+
+Both current ping ID and the next one are accepted, so ping ID is valid
+for `PING_ID_TIMEOUT` to `2 * PING_ID_TIMEOUT` seconds.
+*/
+pub const PING_ID_TIMEOUT: u64 = 20;
+
+/// Number of seconds after which announced node is removed from the onion
+/// announce list if it doesn't announce itself again.
+pub const ONION_ANNOUNCE_TIMEOUT: u64 = 300;
+
+/// Node announced through the onion.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionAnnounceEntry {
+    /// Long term `PublicKey` of the announced node
+    pub pk: PublicKey,
+    /// Address of the last node of the onion path used by the announced node
+    pub ip_port: SocketAddr,
+    /// Return path to the announced node
+    pub onion_return: OnionReturn,
+    /// `PublicKey` that should be used to encrypt data packets for the
+    /// announced node
+    pub data_pk: PublicKey,
+    /// Time when the node was announced
+    time: Instant,
+}
+
+impl OnionAnnounceEntry {
+    /// Create new `OnionAnnounceEntry` announced right now.
+    pub fn new(pk: PublicKey, ip_port: SocketAddr, onion_return: OnionReturn, data_pk: PublicKey) -> OnionAnnounceEntry {
+        OnionAnnounceEntry {
+            pk: pk,
+            ip_port: ip_port,
+            onion_return: onion_return,
+            data_pk: data_pk,
+            time: Instant::now(),
+        }
+    }
+
+    /// Check whether the node wasn't announced again for
+    /// [`ONION_ANNOUNCE_TIMEOUT`](./constant.ONION_ANNOUNCE_TIMEOUT.html).
+    pub fn is_timed_out(&self) -> bool {
+        self.time.elapsed() >= Duration::from_secs(ONION_ANNOUNCE_TIMEOUT)
+    }
+}
+
+/**
+List of nodes announced through the onion.
+
+Up to [`ONION_ANNOUNCE_MAX_ENTRIES`] nodes are stored. When the list is
+full, a new node replaces the node whose `PublicKey` is the farthest from
+own DHT `PublicKey`, but only if the new node is closer.
+
+[`ONION_ANNOUNCE_MAX_ENTRIES`]: ./constant.ONION_ANNOUNCE_MAX_ENTRIES.html
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionAnnounce {
+    /// random bytes that are used to generate ping IDs
+    secret_bytes: [u8; 32],
+    /// announced nodes
+    entries: Vec<OnionAnnounceEntry>,
+    /// own DHT `PublicKey`
+    dht_pk: PublicKey,
+}
+
+impl OnionAnnounce {
+    /// Create new empty `OnionAnnounce` for the node with `dht_pk`.
+    pub fn new(dht_pk: PublicKey) -> OnionAnnounce {
+        let mut secret_bytes = [0; 32];
+        randombytes_into(&mut secret_bytes);
+        OnionAnnounce {
+            secret_bytes: secret_bytes,
+            entries: Vec::with_capacity(ONION_ANNOUNCE_MAX_ENTRIES),
+            dht_pk: dht_pk,
+        }
+    }
+
+    /**
+    Generate ping ID for the node with `pk` that sends announce requests
+    from `ip_port` at unix `time`.
+
+    Ping ID is the same during [`PING_ID_TIMEOUT`] seconds and can't be
+    guessed by other nodes.
+
+    [`PING_ID_TIMEOUT`]: ./constant.PING_ID_TIMEOUT.html
+    */
+    pub fn ping_id(&self, time: u64, pk: &PublicKey, ip_port: &SocketAddr) -> sha256::Digest {
+        let mut data = Vec::with_capacity(32 + 8 + PUBLICKEYBYTES + 16 + 2);
+        data.extend_from_slice(&self.secret_bytes);
+        let mut time_bytes = [0; 8];
+        BigEndian::write_u64(&mut time_bytes, time / PING_ID_TIMEOUT);
+        data.extend_from_slice(&time_bytes);
+        data.extend_from_slice(pk.as_ref());
+        match ip_port.ip() {
+            IpAddr::V4(ip) => data.extend_from_slice(&ip.octets()),
+            IpAddr::V6(ip) => data.extend_from_slice(&ip.octets()),
+        }
+        let mut port_bytes = [0; 2];
+        BigEndian::write_u16(&mut port_bytes, ip_port.port());
+        data.extend_from_slice(&port_bytes);
+        sha256::hash(&data)
+    }
+
+    /// Number of announced nodes, including timed out ones.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Check whether there are no announced nodes.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Find announced node with `pk` that hasn't timed out yet.
+    pub fn find_in_entries(&self, pk: &PublicKey) -> Option<&OnionAnnounceEntry> {
+        self.entries.iter()
+            .find(|entry| entry.pk == *pk && !entry.is_timed_out())
+    }
+
+    /**
+    Add node to the announce list or update the stored one with the same
+    `PublicKey`.
+
+    Timed out nodes are removed before adding. When the list is full, the
+    node replaces the one that is the farthest from own DHT `PublicKey`
+    if it's closer.
+
+    Returns `true` if node was stored.
+    */
+    pub fn add_to_entries(&mut self, entry: OnionAnnounceEntry) -> bool {
+        self.entries.retain(|e| !e.is_timed_out());
+
+        let pos = self.entries.iter().position(|e| e.pk == entry.pk);
+        if let Some(pos) = pos {
+            trace!("Updating announced node");
+            self.entries[pos] = entry;
+            return true
+        }
+
+        if self.entries.len() < ONION_ANNOUNCE_MAX_ENTRIES {
+            trace!("Adding announced node");
+            self.entries.push(entry);
+            return true
+        }
+
+        let dht_pk = self.dht_pk;
+        let farthest = self.entries.iter()
+            .enumerate()
+            .max_by(|&(_, a), &(_, b)| dht_pk.distance(&a.pk, &b.pk))
+            .map(|(pos, _)| pos);
+        match farthest {
+            Some(pos) if dht_pk.distance(&entry.pk, &self.entries[pos].pk) == Ordering::Less => {
+                trace!("Replacing the farthest announced node");
+                self.entries[pos] = entry;
+                true
+            },
+            _ => false,
+        }
+    }
+
+    /**
+    Handle announce request with decrypted `payload` that was sent with
+    `request_pk` and received from `addr` together with `onion_return`.
+
+    If the request contains a valid ping ID, the sender is announced.
+    Otherwise the node with `search_pk` is searched in the announce list.
+
+    Returns status of the request and either ping ID for the next request
+    or data `PublicKey` of the found node, to be sent in
+    [`OnionAnnounceResponsePayload`].
+
+    [`OnionAnnounceResponsePayload`]: ../packet/struct.OnionAnnounceResponsePayload.html
+    */
+    pub fn handle_onion_announce_request(&mut self,
+                                         payload: &OnionAnnounceRequestPayload,
+                                         request_pk: PublicKey,
+                                         onion_return: OnionReturn,
+                                         addr: SocketAddr)
+        -> (AnnounceStatus, sha256::Digest)
+    {
+        let time = unix_time();
+        let ping_id_1 = self.ping_id(time, &request_pk, &addr);
+        let ping_id_2 = self.ping_id(time + PING_ID_TIMEOUT, &request_pk, &addr);
+
+        let entry = if payload.ping_id == ping_id_1 || payload.ping_id == ping_id_2 {
+            let entry = OnionAnnounceEntry::new(request_pk, addr, onion_return, payload.data_pk);
+            if self.add_to_entries(entry) {
+                self.find_in_entries(&request_pk)
+            } else {
+                None
+            }
+        } else {
+            self.find_in_entries(&payload.search_pk)
+        };
+
+        match entry {
+            Some(entry) if entry.pk == request_pk => {
+                if entry.data_pk == payload.data_pk {
+                    debug!("Node is announced");
+                    (AnnounceStatus::Announced, ping_id_2)
+                } else {
+                    (AnnounceStatus::Failed, ping_id_2)
+                }
+            },
+            Some(entry) => {
+                debug!("Searched node is found");
+                let PublicKey(data_pk) = entry.data_pk;
+                (AnnounceStatus::Found, sha256::Digest(data_pk))
+            },
+            None => (AnnounceStatus::Failed, ping_id_2),
+        }
+    }
+
+    /**
+    Handle data request by forwarding its payload to the announced node
+    with `destination_pk` using the stored return path.
+
+    Returns `None` if the destination node isn't announced. Otherwise
+    returns address where [`OnionResponse3`] with
+    [`OnionDataResponse`] should be sent.
+
+    [`OnionResponse3`]: ../packet/struct.OnionResponse3.html
+    [`OnionDataResponse`]: ../packet/struct.OnionDataResponse.html
+    */
+    pub fn handle_data_request(&self, request: &OnionDataRequest)
+        -> Option<(SocketAddr, OnionResponse3)>
+    {
+        let entry = match self.find_in_entries(&request.inner.destination_pk) {
+            Some(entry) => entry,
+            None => {
+                debug!("Destination node of data request isn't announced");
+                return None
+            },
+        };
+        let response = OnionDataResponse {
+            nonce: request.inner.nonce,
+            temporary_pk: request.inner.temporary_pk,
+            payload: request.inner.payload.clone(),
+        };
+        let response_packet = OnionResponse3 {
+            onion_return: entry.onion_return.clone(),
+            payload: InnerOnionResponse::OnionDataResponse(response),
+        };
+        Some((entry.ip_port, response_packet))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn onion_return() -> OnionReturn {
+        OnionReturn::new(&new_symmetric_key(), &"1.2.3.4:12345".parse().unwrap(), None)
+    }
+
+    fn entry(pk: PublicKey) -> OnionAnnounceEntry {
+        OnionAnnounceEntry::new(pk, "5.6.7.8:12345".parse().unwrap(),
+                                onion_return(), gen_keypair().0)
+    }
+
+    #[test]
+    fn ping_id_same_during_timeout() {
+        let onion_announce = OnionAnnounce::new(gen_keypair().0);
+        let pk = gen_keypair().0;
+        let addr = "1.2.3.4:12345".parse().unwrap();
+        let time = PING_ID_TIMEOUT * 100;
+
+        let ping_id = onion_announce.ping_id(time, &pk, &addr);
+        assert_eq!(ping_id, onion_announce.ping_id(time + PING_ID_TIMEOUT - 1, &pk, &addr));
+        assert_ne!(ping_id, onion_announce.ping_id(time + PING_ID_TIMEOUT, &pk, &addr));
+        assert_ne!(ping_id, onion_announce.ping_id(time, &gen_keypair().0, &addr));
+        assert_ne!(ping_id, onion_announce.ping_id(time, &pk, &"1.2.3.4:12346".parse().unwrap()));
+
+        // other node generates different ping IDs
+        let other_announce = OnionAnnounce::new(gen_keypair().0);
+        assert_ne!(ping_id, other_announce.ping_id(time, &pk, &addr));
+    }
+
+    #[test]
+    fn add_to_entries_update() {
+        let mut onion_announce = OnionAnnounce::new(gen_keypair().0);
+        let pk = gen_keypair().0;
+
+        assert!(onion_announce.add_to_entries(entry(pk)));
+        let new_entry = entry(pk);
+        assert!(onion_announce.add_to_entries(new_entry.clone()));
+
+        assert_eq!(1, onion_announce.len());
+        assert_eq!(Some(&new_entry), onion_announce.find_in_entries(&pk));
+    }
+
+    #[test]
+    fn add_to_entries_evicts_farthest() {
+        let dht_pk = PublicKey([0; PUBLICKEYBYTES]);
+        let mut onion_announce = OnionAnnounce::new(dht_pk);
+
+        for i in 0..ONION_ANNOUNCE_MAX_ENTRIES {
+            let mut pk = [0xff; PUBLICKEYBYTES];
+            pk[PUBLICKEYBYTES - 1] = i as u8;
+            assert!(onion_announce.add_to_entries(entry(PublicKey(pk))));
+        }
+        assert_eq!(ONION_ANNOUNCE_MAX_ENTRIES, onion_announce.len());
+
+        // farther than every stored node
+        let far_pk = PublicKey([0xff; PUBLICKEYBYTES]);
+        assert!(!onion_announce.add_to_entries(entry(far_pk)));
+        assert!(onion_announce.find_in_entries(&far_pk).is_none());
+
+        // closer than every stored node replaces the farthest one
+        let close_pk = PublicKey([1; PUBLICKEYBYTES]);
+        let mut farthest_pk = [0xff; PUBLICKEYBYTES];
+        farthest_pk[PUBLICKEYBYTES - 1] = (ONION_ANNOUNCE_MAX_ENTRIES - 1) as u8;
+        let farthest_pk = PublicKey(farthest_pk);
+        assert!(onion_announce.find_in_entries(&farthest_pk).is_some());
+        assert!(onion_announce.add_to_entries(entry(close_pk)));
+        assert_eq!(ONION_ANNOUNCE_MAX_ENTRIES, onion_announce.len());
+        assert!(onion_announce.find_in_entries(&close_pk).is_some());
+        assert!(onion_announce.find_in_entries(&farthest_pk).is_none());
+    }
+
+    #[test]
+    fn handle_onion_announce_request_announce_and_find() {
+        let mut onion_announce = OnionAnnounce::new(gen_keypair().0);
+        let (alice_pk, _) = gen_keypair();
+        let (alice_data_pk, _) = gen_keypair();
+        let (bob_pk, _) = gen_keypair();
+        let addr = "1.2.3.4:12345".parse().unwrap();
+
+        // alice requests a ping ID
+        let mut payload = OnionAnnounceRequestPayload {
+            ping_id: sha256::Digest([0; sha256::DIGESTBYTES]),
+            search_pk: alice_pk,
+            data_pk: alice_data_pk,
+            sendback_data: 42,
+        };
+        let (status, ping_id) = onion_announce.handle_onion_announce_request(
+            &payload, alice_pk, onion_return(), addr);
+        assert_eq!(AnnounceStatus::Failed, status);
+        assert!(onion_announce.is_empty());
+
+        // alice announces herself with received ping ID
+        payload.ping_id = ping_id;
+        let (status, _) = onion_announce.handle_onion_announce_request(
+            &payload, alice_pk, onion_return(), addr);
+        assert_eq!(AnnounceStatus::Announced, status);
+        assert!(onion_announce.find_in_entries(&alice_pk).is_some());
+
+        // bob searches for alice
+        let payload = OnionAnnounceRequestPayload {
+            ping_id: sha256::Digest([0; sha256::DIGESTBYTES]),
+            search_pk: alice_pk,
+            data_pk: gen_keypair().0,
+            sendback_data: 42,
+        };
+        let (status, data_pk) = onion_announce.handle_onion_announce_request(
+            &payload, bob_pk, onion_return(), addr);
+        assert_eq!(AnnounceStatus::Found, status);
+        assert_eq!(alice_data_pk.as_ref(), data_pk.as_ref());
+    }
+
+    #[test]
+    fn handle_data_request_forward() {
+        let mut onion_announce = OnionAnnounce::new(gen_keypair().0);
+        let (alice_pk, _) = gen_keypair();
+        let alice_entry = entry(alice_pk);
+        assert!(onion_announce.add_to_entries(alice_entry.clone()));
+
+        let mut request = OnionDataRequest {
+            inner: InnerOnionDataRequest {
+                destination_pk: alice_pk,
+                nonce: gen_nonce(),
+                temporary_pk: gen_keypair().0,
+                payload: vec![42; 123],
+            },
+            onion_return: onion_return(),
+        };
+
+        let (addr, response) = onion_announce.handle_data_request(&request)
+            .unwrap();
+        assert_eq!(alice_entry.ip_port, addr);
+        assert_eq!(alice_entry.onion_return, response.onion_return);
+        assert_eq!(InnerOnionResponse::OnionDataResponse(OnionDataResponse {
+            nonce: request.inner.nonce,
+            temporary_pk: request.inner.temporary_pk,
+            payload: request.inner.payload.clone(),
+        }), response.payload);
+
+        // unknown destination
+        request.inner.destination_pk = gen_keypair().0;
+        assert!(onion_announce.handle_data_request(&request).is_none());
+    }
+
+    #[test]
+    fn handle_onion_announce_request_wrong_ping_id() {
+        let mut onion_announce = OnionAnnounce::new(gen_keypair().0);
+        let (alice_pk, _) = gen_keypair();
+        let addr = "1.2.3.4:12345".parse().unwrap();
+        let payload = OnionAnnounceRequestPayload {
+            ping_id: onion_announce.ping_id(unix_time(), &alice_pk, &"1.2.3.4:1".parse().unwrap()),
+            search_pk: alice_pk,
+            data_pk: gen_keypair().0,
+            sendback_data: 42,
+        };
+
+        let (status, _) = onion_announce.handle_onion_announce_request(
+            &payload, alice_pk, onion_return(), addr);
+        assert_eq!(AnnounceStatus::Failed, status);
+        assert!(onion_announce.is_empty());
+    }
+}
@@ -0,0 +1,1623 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*
+    Packet structure
+
+    client → A       A → B            B → C            C → D
+    ---------------+----------------+----------------+---------------------
+    OnionRequest0  | OnionRequest1  | OnionRequest2  | OnionAnnounceRequest
+                   |                |                | OnionDataRequest
+    ---------------+----------------+----------------+---------------------
+
+    D → C                C → B            B → A            A → client
+    -------------------+----------------+----------------+-----------------
+    OnionResponse3     | OnionResponse2 | OnionResponse1 | InnerOnionResponse
+    -------------------+----------------+----------------+-----------------
+*/
+
+/*! Onion packet part of the toxcore.
+    * takes care of the serializing and de-serializing onion packets
+    * builds and peels the layers of onion requests
+*/
+
+use nom::{le_u8, be_u16, be_u64, rest, IResult};
+use sodiumoxide::crypto::hash::sha256;
+
+use std::net::{
+    IpAddr,
+    Ipv4Addr,
+    Ipv6Addr,
+    SocketAddr,
+};
+
+use toxcore::crypto_core::*;
+use toxcore::dht_new::binary_io::*;
+use toxcore::dht_new::packed_node::PackedNode;
+
+/// Size of IPv4 address in bytes.
+const SIZE_IP4: usize = 4;
+
+/// Size of IPv6 address in bytes.
+const SIZE_IP6: usize = 16;
+
+/** Size of serialized IP address and port as used by onion packets.
+
+IPv4 addresses are padded with zeroes to the size of IPv6 address, so that
+encrypted return paths don't reveal the IP family of the node.
+*/
+pub const SIZE_IPPORT: usize = 1 + SIZE_IP6 + 2;
+
+/// Size of first `OnionReturn` struct with no inner `OnionReturn`.
+pub const ONION_RETURN_1_SIZE: usize = NONCEBYTES + SIZE_IPPORT + MACBYTES; // 59
+
+/// Size of second `OnionReturn` struct with one inner `OnionReturn`.
+pub const ONION_RETURN_2_SIZE: usize = NONCEBYTES + SIZE_IPPORT + MACBYTES + ONION_RETURN_1_SIZE; // 118
+
+/// Size of third `OnionReturn` struct with two inner `OnionReturn`s.
+pub const ONION_RETURN_3_SIZE: usize = NONCEBYTES + SIZE_IPPORT + MACBYTES + ONION_RETURN_2_SIZE; // 177
+
+/// Maximum size in bytes of any serialized onion packet.
+pub const ONION_MAX_PACKET_SIZE: usize = 1400;
+
+/// Size of encrypted layer overhead added by each node of the onion path:
+/// IP_Port of the next node, temporary `PublicKey` and MAC.
+const ONION_SEND_BASE: usize = PUBLICKEYBYTES + SIZE_IPPORT + MACBYTES;
+
+/// Overhead of [`OnionRequest0`](./struct.OnionRequest0.html) over the
+/// data it carries.
+const ONION_SEND_1: usize = NONCEBYTES + ONION_SEND_BASE * 3;
+
+/// Maximum size in bytes of data that can be sent through an onion path.
+pub const ONION_MAX_DATA_SIZE: usize = ONION_MAX_PACKET_SIZE - (ONION_SEND_1 + 1);
+
+/// Size in bytes of the encrypted payload of
+/// [`InnerOnionAnnounceRequest`](./struct.InnerOnionAnnounceRequest.html):
+/// ping id, search PK, data PK, sendback data and MAC.
+pub const ONION_ANNOUNCE_REQUEST_PAYLOAD_SIZE: usize = sha256::DIGESTBYTES + PUBLICKEYBYTES * 2 + 8 + MACBYTES;
+
+/// Maximum number of nodes in
+/// [`OnionAnnounceResponsePayload`](./struct.OnionAnnounceResponsePayload.html).
+pub const ONION_ANNOUNCE_MAX_NODES: usize = 4;
+
+/// IP type of IPv4 address of a TCP relay client stored in `OnionReturn`.
+const TCP_INET: u8 = 130;
+
+/// IP type of IPv6 address of a TCP relay client stored in `OnionReturn`.
+const TCP_INET6: u8 = 138;
+
+/** Parse IP address and port in the fixed-size onion format.
+
+Serialized form:
+
+Length | Content
+------ | -------
+`1`    | IP type (`2` for IPv4, `10` for IPv6)
+`16`   | IPv4 address padded with zeroes or IPv6 address
+`2`    | port in BigEndian
+*/
+pub fn parse_ip_port(input: &[u8]) -> IResult<&[u8], SocketAddr> {
+    parse_onion_ip_port(input)
+}
+
+// Parser behind `parse_ip_port`, doc comments can't be attached to `named!`
+named!(parse_onion_ip_port<SocketAddr>, do_parse!(
+    ip: switch!(le_u8,
+        2  => terminated!(
+            map!(Ipv4Addr::from_bytes, IpAddr::V4),
+            take!(SIZE_IP6 - SIZE_IP4)
+        ) |
+        10 => map!(Ipv6Addr::from_bytes, IpAddr::V6)
+    ) >>
+    port: be_u16 >>
+    (SocketAddr::new(ip, port))
+));
+
+// Parse IP address and port of a TCP relay client in the fixed-size onion
+// format. Same as `parse_ip_port` but IP type is `130` for IPv4 and `138`
+// for IPv6.
+named!(parse_tcp_ip_port<SocketAddr>, do_parse!(
+    ip: switch!(le_u8,
+        TCP_INET  => terminated!(
+            map!(Ipv4Addr::from_bytes, IpAddr::V4),
+            take!(SIZE_IP6 - SIZE_IP4)
+        ) |
+        TCP_INET6 => map!(Ipv6Addr::from_bytes, IpAddr::V6)
+    ) >>
+    port: be_u16 >>
+    (SocketAddr::new(ip, port))
+));
+
+/// Serialize IP address and port in the fixed-size onion format.
+pub fn gen_ip_port<'a>(buf: (&'a mut [u8], usize), ip_port: &SocketAddr) -> Result<(&'a mut [u8], usize), GenError> {
+    gen_ip_port_with_types(buf, ip_port, 2, 10)
+}
+
+/// Serialize IP address and port of a TCP relay client in the fixed-size
+/// onion format.
+fn gen_tcp_ip_port<'a>(buf: (&'a mut [u8], usize), ip_port: &SocketAddr) -> Result<(&'a mut [u8], usize), GenError> {
+    gen_ip_port_with_types(buf, ip_port, TCP_INET, TCP_INET6)
+}
+
+/// Serialize IP address and port with given IP types for IPv4 and IPv6.
+fn gen_ip_port_with_types<'a>(buf: (&'a mut [u8], usize), ip_port: &SocketAddr, ipv4_type: u8, ipv6_type: u8)
+    -> Result<(&'a mut [u8], usize), GenError>
+{
+    do_gen!(buf,
+        gen_if_else!(ip_port.is_ipv4(), gen_be_u8!(ipv4_type), gen_be_u8!(ipv6_type)) >>
+        gen_call!(|buf, ip| IpAddr::to_bytes(ip, buf), &ip_port.ip()) >>
+        gen_cond!(ip_port.is_ipv4(), gen_slice!(&[0; SIZE_IP6 - SIZE_IP4])) >>
+        gen_be_u16!(ip_port.port())
+    )
+}
+
+/** Serialize `payload` and encrypt it with `shared_secret`.
+
+Panics if serialized payload doesn't fit in
+[`ONION_MAX_PACKET_SIZE`](./constant.ONION_MAX_PACKET_SIZE.html) bytes.
+*/
+fn encrypt_payload<P: ToBytes>(shared_secret: &PrecomputedKey, nonce: &Nonce, payload: &P) -> Vec<u8> {
+    let mut buf = [0; ONION_MAX_PACKET_SIZE];
+    let (_, size) = payload.to_bytes((&mut buf, 0))
+        .expect("Onion payload should fit in ONION_MAX_PACKET_SIZE");
+    encrypt_data_symmetric(shared_secret, nonce, &buf[..size])
+}
+
+/** Decrypt `payload` with `shared_secret` and parse it as `P`.
+
+Returns `None` if decryption or parsing failed.
+*/
+fn decrypt_payload<P: FromBytes>(shared_secret: &PrecomputedKey, nonce: &Nonce, payload: &[u8]) -> Option<P> {
+    let decrypted = match decrypt_data_symmetric(shared_secret, nonce, payload) {
+        Ok(decrypted) => decrypted,
+        Err(()) => {
+            debug!("Decrypting onion payload failed!");
+            return None
+        },
+    };
+    P::from_bytes(&decrypted).to_full_result().ok()
+}
+
+/** Encrypted IP address and port of the previous node in the onion path
+along with `OnionReturn` of the previous node if any.
+
+Onion responses travel back through the path using these structures. Each
+node of the path encrypts it with its own symmetric key that is never
+shared with anyone, so only that node is able to read where to send the
+response.
+
+Serialized form:
+
+Length   | Content
+-------- | ------
+`24`     | Nonce
+variable | Encrypted IP_Port and inner `OnionReturn`
+
+Size of serialized `OnionReturn` is
+[`ONION_RETURN_1_SIZE`](./constant.ONION_RETURN_1_SIZE.html),
+[`ONION_RETURN_2_SIZE`](./constant.ONION_RETURN_2_SIZE.html) or
+[`ONION_RETURN_3_SIZE`](./constant.ONION_RETURN_3_SIZE.html) depending on
+the number of inner `OnionReturn`s.
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionReturn {
+    /// Nonce for the current encrypted payload
+    pub nonce: Nonce,
+    /// Encrypted payload
+    pub payload: Vec<u8>,
+}
+
+impl FromBytes for OnionReturn {
+    named!(from_bytes<OnionReturn>, do_parse!(
+        nonce: call!(Nonce::from_bytes) >>
+        payload: rest >>
+        (OnionReturn { nonce: nonce, payload: payload.to_vec() })
+    ));
+}
+
+impl ToBytes for OnionReturn {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_slice!(self.nonce.as_ref()) >>
+            gen_slice!(self.payload.as_slice())
+        )
+    }
+}
+
+named!(parse_onion_return_payload<(SocketAddr, Option<OnionReturn>)>, do_parse!(
+    ip_port: call!(parse_ip_port) >>
+    inner: opt!(complete!(OnionReturn::from_bytes)) >>
+    eof!() >>
+    (ip_port, inner)
+));
+
+named!(parse_tcp_onion_return_payload<SocketAddr>, do_parse!(
+    ip_port: call!(parse_tcp_ip_port) >>
+    eof!() >>
+    (ip_port)
+));
+
+impl OnionReturn {
+    /** Create new `OnionReturn` that contains `ip_port` and optional `inner`
+    `OnionReturn`, encrypted with `symmetric_key`.
+    */
+    pub fn new(symmetric_key: &PrecomputedKey, ip_port: &SocketAddr, inner: Option<&OnionReturn>) -> OnionReturn {
+        let nonce = gen_nonce();
+        let mut buf = [0; ONION_RETURN_2_SIZE + SIZE_IPPORT];
+        let (_, size) = gen_ip_port((&mut buf, 0), ip_port)
+            .and_then(|buf| match inner {
+                Some(inner) => inner.to_bytes(buf),
+                None => Ok(buf),
+            })
+            .expect("OnionReturn payload should fit in buffer");
+        let payload = encrypt_data_symmetric(symmetric_key, &nonce, &buf[..size]);
+
+        OnionReturn {
+            nonce: nonce,
+            payload: payload,
+        }
+    }
+
+    /** Create new `OnionReturn` that contains `ip_port` of a client connected
+    to our TCP relay, encrypted with `symmetric_key`. Such client is always
+    the owner of the onion path, so there is no inner `OnionReturn`.
+    */
+    pub fn new_tcp(symmetric_key: &PrecomputedKey, ip_port: &SocketAddr) -> OnionReturn {
+        let nonce = gen_nonce();
+        let mut buf = [0; SIZE_IPPORT];
+        let (_, size) = gen_tcp_ip_port((&mut buf, 0), ip_port)
+            .expect("OnionReturn payload should fit in buffer");
+        let payload = encrypt_data_symmetric(symmetric_key, &nonce, &buf[..size]);
+
+        OnionReturn {
+            nonce: nonce,
+            payload: payload,
+        }
+    }
+
+    /** Decrypt payload with `symmetric_key`.
+    */
+    fn decrypt(&self, symmetric_key: &PrecomputedKey) -> Option<Vec<u8>> {
+        match decrypt_data_symmetric(symmetric_key, &self.nonce, &self.payload) {
+            Ok(decrypted) => Some(decrypted),
+            Err(()) => {
+                debug!("Decrypting OnionReturn failed!");
+                None
+            },
+        }
+    }
+
+    /** Decrypt payload with `symmetric_key` and get IP address and port of
+    the previous node along with inner `OnionReturn` if any.
+
+    Returns `None` if payload can't be decrypted or parsed, including the
+    case when it was created by [`new_tcp`](#method.new_tcp).
+    */
+    pub fn get_payload(&self, symmetric_key: &PrecomputedKey) -> Option<(SocketAddr, Option<OnionReturn>)> {
+        self.decrypt(symmetric_key).and_then(|decrypted|
+            parse_onion_return_payload(&decrypted).to_full_result().ok()
+        )
+    }
+
+    /** Decrypt payload with `symmetric_key` and get IP address and port of
+    the TCP relay client stored by [`new_tcp`](#method.new_tcp).
+
+    Returns `None` if payload can't be decrypted or doesn't contain TCP
+    relay client address.
+    */
+    pub fn get_tcp_payload(&self, symmetric_key: &PrecomputedKey) -> Option<SocketAddr> {
+        self.decrypt(symmetric_key).and_then(|decrypted|
+            parse_tcp_onion_return_payload(&decrypted).to_full_result().ok()
+        )
+    }
+}
+
+/** First onion request packet. It's sent from DHT node to the first node
+from onion chain. Payload can be encrypted with either temporary generated
+`SecretKey` or DHT `SecretKey` of sender and with DHT `PublicKey` of
+receiver.
+
+Serialized form:
+
+Length   | Content
+-------- | ------
+`1`      | `0x80`
+`24`     | Nonce
+`32`     | Temporary or DHT `PublicKey`
+variable | Encrypted [`OnionRequest0Payload`](./struct.OnionRequest0Payload.html)
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionRequest0 {
+    /// Nonce for the current encrypted payload
+    pub nonce: Nonce,
+    /// Temporary or DHT `PublicKey` of the sender
+    pub temporary_pk: PublicKey,
+    /// Encrypted payload
+    pub payload: Vec<u8>,
+}
+
+impl FromBytes for OnionRequest0 {
+    named!(from_bytes<OnionRequest0>, do_parse!(
+        tag!(&[0x80][..]) >>
+        nonce: call!(Nonce::from_bytes) >>
+        temporary_pk: call!(PublicKey::from_bytes) >>
+        payload: rest >>
+        (OnionRequest0 {
+            nonce: nonce,
+            temporary_pk: temporary_pk,
+            payload: payload.to_vec(),
+        })
+    ));
+}
+
+impl ToBytes for OnionRequest0 {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(0x80) >>
+            gen_slice!(self.nonce.as_ref()) >>
+            gen_slice!(self.temporary_pk.as_ref()) >>
+            gen_slice!(self.payload.as_slice())
+        )
+    }
+}
+
+impl OnionRequest0 {
+    /// Create new `OnionRequest0` object with `payload` encrypted by
+    /// `shared_secret`.
+    pub fn new(shared_secret: &PrecomputedKey, temporary_pk: &PublicKey, nonce: &Nonce, payload: &OnionRequest0Payload) -> OnionRequest0 {
+        OnionRequest0 {
+            nonce: *nonce,
+            temporary_pk: *temporary_pk,
+            payload: encrypt_payload(shared_secret, nonce, payload),
+        }
+    }
+
+    /** Decrypt payload with own `SecretKey` and try to parse it as
+    [`OnionRequest0Payload`](./struct.OnionRequest0Payload.html).
+
+    Returns `None` in case of failure.
+    */
+    pub fn get_payload(&self, secret_key: &SecretKey) -> Option<OnionRequest0Payload> {
+        let shared_secret = encrypt_precompute(&self.temporary_pk, secret_key);
+        decrypt_payload(&shared_secret, &self.nonce, &self.payload)
+    }
+}
+
+/** Unencrypted payload of [`OnionRequest0`](./struct.OnionRequest0.html).
+
+Serialized form:
+
+Length   | Content
+-------- | ------
+`19`     | IP_Port of the next node
+`32`     | Temporary `PublicKey` for the next node
+variable | Encrypted payload for the next node
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionRequest0Payload {
+    /// Address of the next node in the onion path
+    pub ip_port: SocketAddr,
+    /// Temporary `PublicKey` for the next node
+    pub temporary_pk: PublicKey,
+    /// Encrypted payload for the next node
+    pub inner: Vec<u8>,
+}
+
+impl FromBytes for OnionRequest0Payload {
+    named!(from_bytes<OnionRequest0Payload>, do_parse!(
+        ip_port: call!(parse_ip_port) >>
+        temporary_pk: call!(PublicKey::from_bytes) >>
+        inner: rest >>
+        (OnionRequest0Payload {
+            ip_port: ip_port,
+            temporary_pk: temporary_pk,
+            inner: inner.to_vec(),
+        })
+    ));
+}
+
+impl ToBytes for OnionRequest0Payload {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_call!(|buf, ip_port| gen_ip_port(buf, ip_port), &self.ip_port) >>
+            gen_slice!(self.temporary_pk.as_ref()) >>
+            gen_slice!(self.inner.as_slice())
+        )
+    }
+}
+
+/** Second onion request packet. It's sent from the first to the second node
+from onion chain. Payload should be encrypted with temporary generated
+`SecretKey` and with DHT `PublicKey` of receiver.
+
+Serialized form:
+
+Length   | Content
+-------- | ------
+`1`      | `0x81`
+`24`     | Nonce
+`32`     | Temporary `PublicKey`
+variable | Encrypted [`OnionRequest1Payload`](./struct.OnionRequest1Payload.html)
+`59`     | `OnionReturn`
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionRequest1 {
+    /// Nonce for the current encrypted payload
+    pub nonce: Nonce,
+    /// Temporary `PublicKey` of the sender
+    pub temporary_pk: PublicKey,
+    /// Encrypted payload
+    pub payload: Vec<u8>,
+    /// Return address encrypted by the first node from onion chain
+    pub onion_return: OnionReturn,
+}
+
+impl FromBytes for OnionRequest1 {
+    named!(from_bytes<OnionRequest1>, do_parse!(
+        tag!(&[0x81][..]) >>
+        nonce: call!(Nonce::from_bytes) >>
+        temporary_pk: call!(PublicKey::from_bytes) >>
+        rest_len: map!(peek!(rest), |rest: &[u8]| rest.len()) >>
+        verify!(value!(rest_len), |len| len > ONION_RETURN_1_SIZE) >>
+        payload: take!(rest_len - ONION_RETURN_1_SIZE) >>
+        onion_return: flat_map!(take!(ONION_RETURN_1_SIZE), OnionReturn::from_bytes) >>
+        (OnionRequest1 {
+            nonce: nonce,
+            temporary_pk: temporary_pk,
+            payload: payload.to_vec(),
+            onion_return: onion_return,
+        })
+    ));
+}
+
+impl ToBytes for OnionRequest1 {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(0x81) >>
+            gen_slice!(self.nonce.as_ref()) >>
+            gen_slice!(self.temporary_pk.as_ref()) >>
+            gen_slice!(self.payload.as_slice()) >>
+            gen_call!(|buf, onion_return| OnionReturn::to_bytes(onion_return, buf), &self.onion_return)
+        )
+    }
+}
+
+impl OnionRequest1 {
+    /** Decrypt payload with own `SecretKey` and try to parse it as
+    [`OnionRequest1Payload`](./struct.OnionRequest1Payload.html).
+
+    Returns `None` in case of failure.
+    */
+    pub fn get_payload(&self, secret_key: &SecretKey) -> Option<OnionRequest1Payload> {
+        let shared_secret = encrypt_precompute(&self.temporary_pk, secret_key);
+        decrypt_payload(&shared_secret, &self.nonce, &self.payload)
+    }
+}
+
+/** Unencrypted payload of [`OnionRequest1`](./struct.OnionRequest1.html).
+
+Serialized form:
+
+Length   | Content
+-------- | ------
+`19`     | IP_Port of the next node
+`32`     | Temporary `PublicKey` for the next node
+variable | Encrypted payload for the next node
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionRequest1Payload {
+    /// Address of the next node in the onion path
+    pub ip_port: SocketAddr,
+    /// Temporary `PublicKey` for the next node
+    pub temporary_pk: PublicKey,
+    /// Encrypted payload for the next node
+    pub inner: Vec<u8>,
+}
+
+impl FromBytes for OnionRequest1Payload {
+    named!(from_bytes<OnionRequest1Payload>, do_parse!(
+        ip_port: call!(parse_ip_port) >>
+        temporary_pk: call!(PublicKey::from_bytes) >>
+        inner: rest >>
+        (OnionRequest1Payload {
+            ip_port: ip_port,
+            temporary_pk: temporary_pk,
+            inner: inner.to_vec(),
+        })
+    ));
+}
+
+impl ToBytes for OnionRequest1Payload {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_call!(|buf, ip_port| gen_ip_port(buf, ip_port), &self.ip_port) >>
+            gen_slice!(self.temporary_pk.as_ref()) >>
+            gen_slice!(self.inner.as_slice())
+        )
+    }
+}
+
+/** Third onion request packet. It's sent from the second to the third node
+from onion chain. Payload should be encrypted with temporary generated
+`SecretKey` and with DHT `PublicKey` of receiver.
+
+Serialized form:
+
+Length   | Content
+-------- | ------
+`1`      | `0x82`
+`24`     | Nonce
+`32`     | Temporary `PublicKey`
+variable | Encrypted [`OnionRequest2Payload`](./struct.OnionRequest2Payload.html)
+`118`    | `OnionReturn`
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionRequest2 {
+    /// Nonce for the current encrypted payload
+    pub nonce: Nonce,
+    /// Temporary `PublicKey` of the sender
+    pub temporary_pk: PublicKey,
+    /// Encrypted payload
+    pub payload: Vec<u8>,
+    /// Return address encrypted by the second node from onion chain
+    pub onion_return: OnionReturn,
+}
+
+impl FromBytes for OnionRequest2 {
+    named!(from_bytes<OnionRequest2>, do_parse!(
+        tag!(&[0x82][..]) >>
+        nonce: call!(Nonce::from_bytes) >>
+        temporary_pk: call!(PublicKey::from_bytes) >>
+        rest_len: map!(peek!(rest), |rest: &[u8]| rest.len()) >>
+        verify!(value!(rest_len), |len| len > ONION_RETURN_2_SIZE) >>
+        payload: take!(rest_len - ONION_RETURN_2_SIZE) >>
+        onion_return: flat_map!(take!(ONION_RETURN_2_SIZE), OnionReturn::from_bytes) >>
+        (OnionRequest2 {
+            nonce: nonce,
+            temporary_pk: temporary_pk,
+            payload: payload.to_vec(),
+            onion_return: onion_return,
+        })
+    ));
+}
+
+impl ToBytes for OnionRequest2 {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(0x82) >>
+            gen_slice!(self.nonce.as_ref()) >>
+            gen_slice!(self.temporary_pk.as_ref()) >>
+            gen_slice!(self.payload.as_slice()) >>
+            gen_call!(|buf, onion_return| OnionReturn::to_bytes(onion_return, buf), &self.onion_return)
+        )
+    }
+}
+
+impl OnionRequest2 {
+    /** Decrypt payload with own `SecretKey` and try to parse it as
+    [`OnionRequest2Payload`](./struct.OnionRequest2Payload.html).
+
+    Returns `None` in case of failure.
+    */
+    pub fn get_payload(&self, secret_key: &SecretKey) -> Option<OnionRequest2Payload> {
+        let shared_secret = encrypt_precompute(&self.temporary_pk, secret_key);
+        decrypt_payload(&shared_secret, &self.nonce, &self.payload)
+    }
+}
+
+/** Unencrypted payload of [`OnionRequest2`](./struct.OnionRequest2.html).
+
+Serialized form:
+
+Length   | Content
+-------- | ------
+`19`     | IP_Port of the destination node
+variable | [`InnerOnionRequest`](./enum.InnerOnionRequest.html)
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionRequest2Payload {
+    /// Address of the destination node
+    pub ip_port: SocketAddr,
+    /// Request for the destination node
+    pub inner: InnerOnionRequest,
+}
+
+impl FromBytes for OnionRequest2Payload {
+    named!(from_bytes<OnionRequest2Payload>, do_parse!(
+        ip_port: call!(parse_ip_port) >>
+        inner: call!(InnerOnionRequest::from_bytes) >>
+        (OnionRequest2Payload {
+            ip_port: ip_port,
+            inner: inner,
+        })
+    ));
+}
+
+impl ToBytes for OnionRequest2Payload {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_call!(|buf, ip_port| gen_ip_port(buf, ip_port), &self.ip_port) >>
+            gen_call!(|buf, inner| InnerOnionRequest::to_bytes(inner, buf), &self.inner)
+        )
+    }
+}
+
+/** Request that is delivered through the onion path to the destination
+node.
+
+The destination node receives it together with `OnionReturn`, see
+[`OnionAnnounceRequest`](./struct.OnionAnnounceRequest.html) and
+[`OnionDataRequest`](./struct.OnionDataRequest.html).
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum InnerOnionRequest {
+    /// [`InnerOnionAnnounceRequest`](./struct.InnerOnionAnnounceRequest.html) structure.
+    InnerOnionAnnounceRequest(InnerOnionAnnounceRequest),
+    /// [`InnerOnionDataRequest`](./struct.InnerOnionDataRequest.html) structure.
+    InnerOnionDataRequest(InnerOnionDataRequest),
+}
+
+impl FromBytes for InnerOnionRequest {
+    named!(from_bytes<InnerOnionRequest>, alt!(
+        map!(InnerOnionAnnounceRequest::from_bytes, InnerOnionRequest::InnerOnionAnnounceRequest) |
+        map!(InnerOnionDataRequest::from_bytes, InnerOnionRequest::InnerOnionDataRequest)
+    ));
+}
+
+impl ToBytes for InnerOnionRequest {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        match *self {
+            InnerOnionRequest::InnerOnionAnnounceRequest(ref p) => p.to_bytes(buf),
+            InnerOnionRequest::InnerOnionDataRequest(ref p) => p.to_bytes(buf),
+        }
+    }
+}
+
+/** Announce request that is sent through the onion path to the node that
+stores announced nodes. Used both to announce own real `PublicKey` and to
+search for announced friends.
+
+Payload is encrypted with either temporary generated `SecretKey` or real
+`SecretKey` of the sender and with DHT `PublicKey` of receiver.
+
+Serialized form:
+
+Length   | Content
+-------- | ------
+`1`      | `0x83`
+`24`     | Nonce
+`32`     | Temporary or real `PublicKey`
+`120`    | Encrypted payload
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct InnerOnionAnnounceRequest {
+    /// Nonce for the current encrypted payload
+    pub nonce: Nonce,
+    /// Temporary or real `PublicKey` of the sender
+    pub pk: PublicKey,
+    /// Encrypted payload
+    pub payload: Vec<u8>,
+}
+
+impl FromBytes for InnerOnionAnnounceRequest {
+    named!(from_bytes<InnerOnionAnnounceRequest>, do_parse!(
+        tag!(&[0x83][..]) >>
+        nonce: call!(Nonce::from_bytes) >>
+        pk: call!(PublicKey::from_bytes) >>
+        payload: take!(ONION_ANNOUNCE_REQUEST_PAYLOAD_SIZE) >>
+        (InnerOnionAnnounceRequest {
+            nonce: nonce,
+            pk: pk,
+            payload: payload.to_vec(),
+        })
+    ));
+}
+
+impl ToBytes for InnerOnionAnnounceRequest {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(0x83) >>
+            gen_slice!(self.nonce.as_ref()) >>
+            gen_slice!(self.pk.as_ref()) >>
+            gen_slice!(self.payload.as_slice())
+        )
+    }
+}
+
+impl InnerOnionAnnounceRequest {
+    /// Create new `InnerOnionAnnounceRequest` object with `payload`
+    /// encrypted by `shared_secret`.
+    pub fn new(shared_secret: &PrecomputedKey, pk: &PublicKey, payload: &OnionAnnounceRequestPayload) -> InnerOnionAnnounceRequest {
+        let nonce = gen_nonce();
+        InnerOnionAnnounceRequest {
+            nonce: nonce,
+            pk: *pk,
+            payload: encrypt_payload(shared_secret, &nonce, payload),
+        }
+    }
+
+    /** Decrypt payload with key precomputed from own DHT `SecretKey` and
+    `pk` of the packet and try to parse it as
+    [`OnionAnnounceRequestPayload`](./struct.OnionAnnounceRequestPayload.html).
+
+    The same key is used to encrypt the response, so it's passed in rather
+    than computed here. Returns `None` in case of failure.
+    */
+    pub fn get_payload(&self, shared_secret: &PrecomputedKey) -> Option<OnionAnnounceRequestPayload> {
+        decrypt_payload(shared_secret, &self.nonce, &self.payload)
+    }
+}
+
+/** Unencrypted payload of
+[`InnerOnionAnnounceRequest`](./struct.InnerOnionAnnounceRequest.html).
+
+When `ping_id` is correct, the sender of the request is announced with
+`PublicKey` from the request, otherwise node only searches for
+`search_pk`.
+
+Serialized form:
+
+Length | Content
+------ | ------
+`32`   | Ping ID
+`32`   | `PublicKey` of the node we are searching for
+`32`   | `PublicKey` that should be used for sending data packets
+`8`    | Sendback data
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionAnnounceRequestPayload {
+    /// Ping ID received in the previous response or zeroes
+    pub ping_id: sha256::Digest,
+    /// `PublicKey` of the node we are searching for
+    pub search_pk: PublicKey,
+    /// `PublicKey` that should be used for sending data packets
+    pub data_pk: PublicKey,
+    /// Data that is returned as is in the response
+    pub sendback_data: u64,
+}
+
+impl FromBytes for OnionAnnounceRequestPayload {
+    named!(from_bytes<OnionAnnounceRequestPayload>, do_parse!(
+        ping_id: call!(sha256::Digest::from_bytes) >>
+        search_pk: call!(PublicKey::from_bytes) >>
+        data_pk: call!(PublicKey::from_bytes) >>
+        sendback_data: be_u64 >>
+        eof!() >>
+        (OnionAnnounceRequestPayload {
+            ping_id: ping_id,
+            search_pk: search_pk,
+            data_pk: data_pk,
+            sendback_data: sendback_data,
+        })
+    ));
+}
+
+impl ToBytes for OnionAnnounceRequestPayload {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_slice!(self.ping_id.as_ref()) >>
+            gen_slice!(self.search_pk.as_ref()) >>
+            gen_slice!(self.data_pk.as_ref()) >>
+            gen_be_u64!(self.sendback_data)
+        )
+    }
+}
+
+/** Data request that is sent through the onion path to the node that
+stores announced nodes, so that it could be delivered to the announced
+node with `destination_pk`.
+
+Serialized form:
+
+Length   | Content
+-------- | ------
+`1`      | `0x85`
+`32`     | Real `PublicKey` of the destination
+`24`     | Nonce
+`32`     | Temporary `PublicKey`
+variable | Encrypted payload
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct InnerOnionDataRequest {
+    /// Real `PublicKey` of the destination
+    pub destination_pk: PublicKey,
+    /// Nonce for the current encrypted payload
+    pub nonce: Nonce,
+    /// Temporary `PublicKey` of the sender
+    pub temporary_pk: PublicKey,
+    /// Encrypted payload
+    pub payload: Vec<u8>,
+}
+
+impl FromBytes for InnerOnionDataRequest {
+    named!(from_bytes<InnerOnionDataRequest>, do_parse!(
+        tag!(&[0x85][..]) >>
+        destination_pk: call!(PublicKey::from_bytes) >>
+        nonce: call!(Nonce::from_bytes) >>
+        temporary_pk: call!(PublicKey::from_bytes) >>
+        payload: rest >>
+        (InnerOnionDataRequest {
+            destination_pk: destination_pk,
+            nonce: nonce,
+            temporary_pk: temporary_pk,
+            payload: payload.to_vec(),
+        })
+    ));
+}
+
+impl ToBytes for InnerOnionDataRequest {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(0x85) >>
+            gen_slice!(self.destination_pk.as_ref()) >>
+            gen_slice!(self.nonce.as_ref()) >>
+            gen_slice!(self.temporary_pk.as_ref()) >>
+            gen_slice!(self.payload.as_slice())
+        )
+    }
+}
+
+/** Announce request as it's received by the destination node from the
+third node of the onion path.
+
+Serialized form:
+
+Length   | Content
+-------- | ------
+`153`    | [`InnerOnionAnnounceRequest`](./struct.InnerOnionAnnounceRequest.html)
+`177`    | `OnionReturn`
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionAnnounceRequest {
+    /// Announce request sent by the owner of the onion path
+    pub inner: InnerOnionAnnounceRequest,
+    /// Return address encrypted by the third node from onion chain
+    pub onion_return: OnionReturn,
+}
+
+impl FromBytes for OnionAnnounceRequest {
+    named!(from_bytes<OnionAnnounceRequest>, do_parse!(
+        rest_len: map!(peek!(rest), |rest: &[u8]| rest.len()) >>
+        verify!(value!(rest_len), |len| len > ONION_RETURN_3_SIZE) >>
+        inner: flat_map!(take!(rest_len - ONION_RETURN_3_SIZE), InnerOnionAnnounceRequest::from_bytes) >>
+        onion_return: flat_map!(take!(ONION_RETURN_3_SIZE), OnionReturn::from_bytes) >>
+        (OnionAnnounceRequest {
+            inner: inner,
+            onion_return: onion_return,
+        })
+    ));
+}
+
+impl ToBytes for OnionAnnounceRequest {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_call!(|buf, inner| InnerOnionAnnounceRequest::to_bytes(inner, buf), &self.inner) >>
+            gen_call!(|buf, onion_return| OnionReturn::to_bytes(onion_return, buf), &self.onion_return)
+        )
+    }
+}
+
+/** Data request as it's received by the destination node from the third
+node of the onion path.
+
+Serialized form:
+
+Length   | Content
+-------- | ------
+variable | [`InnerOnionDataRequest`](./struct.InnerOnionDataRequest.html)
+`177`    | `OnionReturn`
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionDataRequest {
+    /// Data request sent by the owner of the onion path
+    pub inner: InnerOnionDataRequest,
+    /// Return address encrypted by the third node from onion chain
+    pub onion_return: OnionReturn,
+}
+
+impl FromBytes for OnionDataRequest {
+    named!(from_bytes<OnionDataRequest>, do_parse!(
+        rest_len: map!(peek!(rest), |rest: &[u8]| rest.len()) >>
+        verify!(value!(rest_len), |len| len > ONION_RETURN_3_SIZE) >>
+        inner: flat_map!(take!(rest_len - ONION_RETURN_3_SIZE), InnerOnionDataRequest::from_bytes) >>
+        onion_return: flat_map!(take!(ONION_RETURN_3_SIZE), OnionReturn::from_bytes) >>
+        (OnionDataRequest {
+            inner: inner,
+            onion_return: onion_return,
+        })
+    ));
+}
+
+impl ToBytes for OnionDataRequest {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_call!(|buf, inner| InnerOnionDataRequest::to_bytes(inner, buf), &self.inner) >>
+            gen_call!(|buf, onion_return| OnionReturn::to_bytes(onion_return, buf), &self.onion_return)
+        )
+    }
+}
+
+/** Response that is delivered through the onion path back to the owner of
+the path.
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum InnerOnionResponse {
+    /// [`OnionAnnounceResponse`](./struct.OnionAnnounceResponse.html) structure.
+    OnionAnnounceResponse(OnionAnnounceResponse),
+    /// [`OnionDataResponse`](./struct.OnionDataResponse.html) structure.
+    OnionDataResponse(OnionDataResponse),
+}
+
+impl FromBytes for InnerOnionResponse {
+    named!(from_bytes<InnerOnionResponse>, alt!(
+        map!(OnionAnnounceResponse::from_bytes, InnerOnionResponse::OnionAnnounceResponse) |
+        map!(OnionDataResponse::from_bytes, InnerOnionResponse::OnionDataResponse)
+    ));
+}
+
+impl ToBytes for InnerOnionResponse {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        match *self {
+            InnerOnionResponse::OnionAnnounceResponse(ref p) => p.to_bytes(buf),
+            InnerOnionResponse::OnionDataResponse(ref p) => p.to_bytes(buf),
+        }
+    }
+}
+
+/** Response to [`InnerOnionAnnounceRequest`]
+(./struct.InnerOnionAnnounceRequest.html).
+
+Payload is encrypted with DHT `SecretKey` of the sender and with the same
+`PublicKey` that was used in the request.
+
+Serialized form:
+
+Length   | Content
+-------- | ------
+`1`      | `0x84`
+`8`      | Sendback data
+`24`     | Nonce
+variable | Encrypted payload
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionAnnounceResponse {
+    /// Data that was sent in the request and is returned as is
+    pub sendback_data: u64,
+    /// Nonce for the current encrypted payload
+    pub nonce: Nonce,
+    /// Encrypted payload
+    pub payload: Vec<u8>,
+}
+
+impl FromBytes for OnionAnnounceResponse {
+    named!(from_bytes<OnionAnnounceResponse>, do_parse!(
+        tag!(&[0x84][..]) >>
+        sendback_data: be_u64 >>
+        nonce: call!(Nonce::from_bytes) >>
+        payload: rest >>
+        (OnionAnnounceResponse {
+            sendback_data: sendback_data,
+            nonce: nonce,
+            payload: payload.to_vec(),
+        })
+    ));
+}
+
+impl ToBytes for OnionAnnounceResponse {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(0x84) >>
+            gen_be_u64!(self.sendback_data) >>
+            gen_slice!(self.nonce.as_ref()) >>
+            gen_slice!(self.payload.as_slice())
+        )
+    }
+}
+
+impl OnionAnnounceResponse {
+    /// Create new `OnionAnnounceResponse` object with `payload` encrypted
+    /// by `shared_secret`.
+    pub fn new(shared_secret: &PrecomputedKey, sendback_data: u64, payload: &OnionAnnounceResponsePayload) -> OnionAnnounceResponse {
+        let nonce = gen_nonce();
+        OnionAnnounceResponse {
+            sendback_data: sendback_data,
+            nonce: nonce,
+            payload: encrypt_payload(shared_secret, &nonce, payload),
+        }
+    }
+
+    /** Decrypt payload with `shared_secret` and try to parse it as
+    [`OnionAnnounceResponsePayload`](./struct.OnionAnnounceResponsePayload.html).
+
+    Returns `None` in case of failure.
+    */
+    pub fn get_payload(&self, shared_secret: &PrecomputedKey) -> Option<OnionAnnounceResponsePayload> {
+        decrypt_payload(shared_secret, &self.nonce, &self.payload)
+    }
+}
+
+/** Status of the announce request, sent back in
+[`OnionAnnounceResponsePayload`](./struct.OnionAnnounceResponsePayload.html).
+
+Serialized form:
+
+Value | Status
+----- | ------
+`0`   | `Failed`
+`1`   | `Found`
+`2`   | `Announced`
+*/
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AnnounceStatus {
+    /// Node wasn't announced and the searched node wasn't found
+    Failed = 0,
+    /// Searched node is announced on this node
+    Found = 1,
+    /// Node that sent the request is announced on this node
+    Announced = 2,
+}
+
+impl FromBytes for AnnounceStatus {
+    named!(from_bytes<AnnounceStatus>, switch!(le_u8,
+        0 => value!(AnnounceStatus::Failed) |
+        1 => value!(AnnounceStatus::Found) |
+        2 => value!(AnnounceStatus::Announced)
+    ));
+}
+
+impl ToBytes for AnnounceStatus {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        gen_be_u8!(buf, *self as u8)
+    }
+}
+
+/** Unencrypted payload of
+[`OnionAnnounceResponse`](./struct.OnionAnnounceResponse.html).
+
+Serialized form:
+
+Length     | Content
+---------- | ------
+`1`        | [`AnnounceStatus`](./enum.AnnounceStatus.html)
+`32`       | Ping ID or data `PublicKey` of the searched node
+`[0, 204]` | Nodes in packed format
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionAnnounceResponsePayload {
+    /// Status of the announce request
+    pub announce_status: AnnounceStatus,
+    /** Data `PublicKey` of the searched node if `announce_status` is
+    `Found`, otherwise ping ID that should be used in the next announce
+    request.
+    */
+    pub ping_id_or_pk: sha256::Digest,
+    /// Up to 4 nodes closest to the searched `PublicKey`
+    pub nodes: Vec<PackedNode>,
+}
+
+impl FromBytes for OnionAnnounceResponsePayload {
+    named!(from_bytes<OnionAnnounceResponsePayload>, do_parse!(
+        announce_status: call!(AnnounceStatus::from_bytes) >>
+        ping_id_or_pk: call!(sha256::Digest::from_bytes) >>
+        nodes: many0!(complete!(PackedNode::from_bytes)) >>
+        verify!(value!(nodes.len()), |len| len <= ONION_ANNOUNCE_MAX_NODES) >>
+        eof!() >>
+        (OnionAnnounceResponsePayload {
+            announce_status: announce_status,
+            ping_id_or_pk: ping_id_or_pk,
+            nodes: nodes,
+        })
+    ));
+}
+
+impl ToBytes for OnionAnnounceResponsePayload {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_call!(|buf, status| AnnounceStatus::to_bytes(status, buf), &self.announce_status) >>
+            gen_slice!(self.ping_id_or_pk.as_ref()) >>
+            gen_cond!(self.nodes.len() > ONION_ANNOUNCE_MAX_NODES,
+                gen_call!(|_, _| Err(GenError::CustomError(0)), 1)) >>
+            gen_many_ref!(&self.nodes, |buf, node| PackedNode::to_bytes(node, buf))
+        )
+    }
+}
+
+/** Data that was sent with [`InnerOnionDataRequest`]
+(./struct.InnerOnionDataRequest.html) and is delivered to the announced
+node.
+
+Serialized form:
+
+Length   | Content
+-------- | ------
+`1`      | `0x86`
+`24`     | Nonce
+`32`     | Temporary `PublicKey`
+variable | Encrypted payload
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionDataResponse {
+    /// Nonce for the current encrypted payload
+    pub nonce: Nonce,
+    /// Temporary `PublicKey` of the sender
+    pub temporary_pk: PublicKey,
+    /// Encrypted payload
+    pub payload: Vec<u8>,
+}
+
+impl FromBytes for OnionDataResponse {
+    named!(from_bytes<OnionDataResponse>, do_parse!(
+        tag!(&[0x86][..]) >>
+        nonce: call!(Nonce::from_bytes) >>
+        temporary_pk: call!(PublicKey::from_bytes) >>
+        payload: rest >>
+        (OnionDataResponse {
+            nonce: nonce,
+            temporary_pk: temporary_pk,
+            payload: payload.to_vec(),
+        })
+    ));
+}
+
+impl ToBytes for OnionDataResponse {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(0x86) >>
+            gen_slice!(self.nonce.as_ref()) >>
+            gen_slice!(self.temporary_pk.as_ref()) >>
+            gen_slice!(self.payload.as_slice())
+        )
+    }
+}
+
+/** First onion response packet. It's sent back from the destination node
+to the third node from onion chain.
+
+Serialized form:
+
+Length   | Content
+-------- | ------
+`1`      | `0x8c`
+`177`    | `OnionReturn`
+variable | [`InnerOnionResponse`](./enum.InnerOnionResponse.html)
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionResponse3 {
+    /// Return address encrypted by the third node from onion chain
+    pub onion_return: OnionReturn,
+    /// Response to the onion request
+    pub payload: InnerOnionResponse,
+}
+
+impl FromBytes for OnionResponse3 {
+    named!(from_bytes<OnionResponse3>, do_parse!(
+        tag!(&[0x8c][..]) >>
+        onion_return: flat_map!(take!(ONION_RETURN_3_SIZE), OnionReturn::from_bytes) >>
+        payload: call!(InnerOnionResponse::from_bytes) >>
+        (OnionResponse3 {
+            onion_return: onion_return,
+            payload: payload,
+        })
+    ));
+}
+
+impl ToBytes for OnionResponse3 {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(0x8c) >>
+            gen_call!(|buf, onion_return| OnionReturn::to_bytes(onion_return, buf), &self.onion_return) >>
+            gen_call!(|buf, payload| InnerOnionResponse::to_bytes(payload, buf), &self.payload)
+        )
+    }
+}
+
+/** Second onion response packet. It's sent back from the third to the
+second node from onion chain.
+
+Serialized form:
+
+Length   | Content
+-------- | ------
+`1`      | `0x8d`
+`118`    | `OnionReturn`
+variable | [`InnerOnionResponse`](./enum.InnerOnionResponse.html)
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionResponse2 {
+    /// Return address encrypted by the second node from onion chain
+    pub onion_return: OnionReturn,
+    /// Response to the onion request
+    pub payload: InnerOnionResponse,
+}
+
+impl FromBytes for OnionResponse2 {
+    named!(from_bytes<OnionResponse2>, do_parse!(
+        tag!(&[0x8d][..]) >>
+        onion_return: flat_map!(take!(ONION_RETURN_2_SIZE), OnionReturn::from_bytes) >>
+        payload: call!(InnerOnionResponse::from_bytes) >>
+        (OnionResponse2 {
+            onion_return: onion_return,
+            payload: payload,
+        })
+    ));
+}
+
+impl ToBytes for OnionResponse2 {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(0x8d) >>
+            gen_call!(|buf, onion_return| OnionReturn::to_bytes(onion_return, buf), &self.onion_return) >>
+            gen_call!(|buf, payload| InnerOnionResponse::to_bytes(payload, buf), &self.payload)
+        )
+    }
+}
+
+/** Third onion response packet. It's sent back from the second to the
+first node from onion chain.
+
+Serialized form:
+
+Length   | Content
+-------- | ------
+`1`      | `0x8e`
+`59`     | `OnionReturn`
+variable | [`InnerOnionResponse`](./enum.InnerOnionResponse.html)
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionResponse1 {
+    /// Return address encrypted by the first node from onion chain
+    pub onion_return: OnionReturn,
+    /// Response to the onion request
+    pub payload: InnerOnionResponse,
+}
+
+impl FromBytes for OnionResponse1 {
+    named!(from_bytes<OnionResponse1>, do_parse!(
+        tag!(&[0x8e][..]) >>
+        onion_return: flat_map!(take!(ONION_RETURN_1_SIZE), OnionReturn::from_bytes) >>
+        payload: call!(InnerOnionResponse::from_bytes) >>
+        (OnionResponse1 {
+            onion_return: onion_return,
+            payload: payload,
+        })
+    ));
+}
+
+impl ToBytes for OnionResponse1 {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(0x8e) >>
+            gen_call!(|buf, onion_return| OnionReturn::to_bytes(onion_return, buf), &self.onion_return) >>
+            gen_call!(|buf, payload| InnerOnionResponse::to_bytes(payload, buf), &self.payload)
+        )
+    }
+}
+
+/** Create [`OnionRequest0`](./struct.OnionRequest0.html) that delivers
+`inner` request to the node with `destination` address through the onion
+`path` of 3 nodes.
+
+A new temporary key pair is generated for each node of the path, so nodes
+can't link the request with the DHT `PublicKey` of the sender. The request
+has to be sent to the first node of the path.
+
+Returns `None` if `inner` request is too big to be sent through the onion.
+*/
+pub fn create_onion_request(path: &[PackedNode; 3], destination: &SocketAddr, inner: &InnerOnionRequest) -> Option<OnionRequest0> {
+    let mut buf = [0; ONION_MAX_PACKET_SIZE];
+    match inner.to_bytes((&mut buf, 0)) {
+        Ok((_, size)) if size <= ONION_MAX_DATA_SIZE => {},
+        _ => {
+            debug!("Request is too big to be sent through the onion");
+            return None
+        },
+    }
+
+    let nonce = gen_nonce();
+    let (temporary_pk_1, temporary_sk_1) = gen_keypair();
+    let (temporary_pk_2, temporary_sk_2) = gen_keypair();
+    let (temporary_pk_3, temporary_sk_3) = gen_keypair();
+
+    let payload_3 = OnionRequest2Payload {
+        ip_port: *destination,
+        inner: inner.clone(),
+    };
+    let shared_secret_3 = encrypt_precompute(path[2].pk(), &temporary_sk_3);
+    let payload_2 = OnionRequest1Payload {
+        ip_port: path[2].socket_addr(),
+        temporary_pk: temporary_pk_3,
+        inner: encrypt_payload(&shared_secret_3, &nonce, &payload_3),
+    };
+    let shared_secret_2 = encrypt_precompute(path[1].pk(), &temporary_sk_2);
+    let payload_1 = OnionRequest0Payload {
+        ip_port: path[1].socket_addr(),
+        temporary_pk: temporary_pk_2,
+        inner: encrypt_payload(&shared_secret_2, &nonce, &payload_2),
+    };
+    let shared_secret_1 = encrypt_precompute(path[0].pk(), &temporary_sk_1);
+
+    Some(OnionRequest0::new(&shared_secret_1, &temporary_pk_1, &nonce, &payload_1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use nom::IResult;
+
+    /// Serialize `$packet` and check that it's parsed back into the same
+    /// value.
+    macro_rules! encode_decode_test {
+        ($test:ident, $packet:expr) => (
+            #[test]
+            fn $test() {
+                let value = $packet;
+                let mut buf = [0; ONION_MAX_PACKET_SIZE];
+                let (_, size) = value.to_bytes((&mut buf, 0)).unwrap();
+                let (rest, decoded_value) = FromBytes::from_bytes(&buf[..size]).unwrap();
+                // this helps compiler to infer type of decoded_value
+                // i.e. no need to pass the type as the macro argument
+                assert_eq!(value, decoded_value);
+                assert!(rest.is_empty());
+            }
+        )
+    }
+
+    fn onion_return_1() -> OnionReturn {
+        OnionReturn::new(&new_symmetric_key(), &"1.2.3.4:12345".parse().unwrap(), None)
+    }
+
+    fn onion_return_2() -> OnionReturn {
+        OnionReturn::new(&new_symmetric_key(), &"[::1]:12345".parse().unwrap(), Some(&onion_return_1()))
+    }
+
+    fn onion_return_3() -> OnionReturn {
+        OnionReturn::new(&new_symmetric_key(), &"5.6.7.8:12345".parse().unwrap(), Some(&onion_return_2()))
+    }
+
+    fn inner_announce_request() -> InnerOnionAnnounceRequest {
+        InnerOnionAnnounceRequest {
+            nonce: gen_nonce(),
+            pk: gen_keypair().0,
+            payload: vec![42; ONION_ANNOUNCE_REQUEST_PAYLOAD_SIZE],
+        }
+    }
+
+    fn inner_data_request() -> InnerOnionDataRequest {
+        InnerOnionDataRequest {
+            destination_pk: gen_keypair().0,
+            nonce: gen_nonce(),
+            temporary_pk: gen_keypair().0,
+            payload: vec![42; 123],
+        }
+    }
+
+    fn announce_response() -> InnerOnionResponse {
+        InnerOnionResponse::OnionAnnounceResponse(OnionAnnounceResponse {
+            sendback_data: 12345,
+            nonce: gen_nonce(),
+            payload: vec![42; 123],
+        })
+    }
+
+    fn data_response() -> InnerOnionResponse {
+        InnerOnionResponse::OnionDataResponse(OnionDataResponse {
+            nonce: gen_nonce(),
+            temporary_pk: gen_keypair().0,
+            payload: vec![42; 123],
+        })
+    }
+
+    encode_decode_test!(
+        onion_request_0_encode_decode,
+        OnionRequest0 {
+            nonce: gen_nonce(),
+            temporary_pk: gen_keypair().0,
+            payload: vec![42; 123],
+        }
+    );
+
+    encode_decode_test!(
+        onion_request_1_encode_decode,
+        OnionRequest1 {
+            nonce: gen_nonce(),
+            temporary_pk: gen_keypair().0,
+            payload: vec![42; 123],
+            onion_return: onion_return_1(),
+        }
+    );
+
+    encode_decode_test!(
+        onion_request_2_encode_decode,
+        OnionRequest2 {
+            nonce: gen_nonce(),
+            temporary_pk: gen_keypair().0,
+            payload: vec![42; 123],
+            onion_return: onion_return_2(),
+        }
+    );
+
+    encode_decode_test!(
+        onion_announce_request_encode_decode,
+        OnionAnnounceRequest {
+            inner: inner_announce_request(),
+            onion_return: onion_return_3(),
+        }
+    );
+
+    encode_decode_test!(
+        onion_data_request_encode_decode,
+        OnionDataRequest {
+            inner: inner_data_request(),
+            onion_return: onion_return_3(),
+        }
+    );
+
+    encode_decode_test!(
+        onion_response_3_encode_decode,
+        OnionResponse3 {
+            onion_return: onion_return_3(),
+            payload: announce_response(),
+        }
+    );
+
+    encode_decode_test!(
+        onion_response_2_encode_decode,
+        OnionResponse2 {
+            onion_return: onion_return_2(),
+            payload: data_response(),
+        }
+    );
+
+    encode_decode_test!(
+        onion_response_1_encode_decode,
+        OnionResponse1 {
+            onion_return: onion_return_1(),
+            payload: announce_response(),
+        }
+    );
+
+    #[test]
+    fn onion_return_sizes() {
+        let mut buf = [0; ONION_MAX_PACKET_SIZE];
+        assert_eq!(ONION_RETURN_1_SIZE, onion_return_1().to_bytes((&mut buf, 0)).unwrap().1);
+        assert_eq!(ONION_RETURN_2_SIZE, onion_return_2().to_bytes((&mut buf, 0)).unwrap().1);
+        assert_eq!(ONION_RETURN_3_SIZE, onion_return_3().to_bytes((&mut buf, 0)).unwrap().1);
+    }
+
+    #[test]
+    fn onion_return_get_payload() {
+        let key_1 = new_symmetric_key();
+        let key_2 = new_symmetric_key();
+        let addr_1 = "1.2.3.4:12345".parse().unwrap();
+        let addr_2 = "[1:2:3:4:5:6:7:8]:54321".parse().unwrap();
+
+        let return_1 = OnionReturn::new(&key_1, &addr_1, None);
+        let return_2 = OnionReturn::new(&key_2, &addr_2, Some(&return_1));
+
+        // can't be decrypted with the wrong key
+        assert!(return_2.get_payload(&key_1).is_none());
+
+        let (ip_port, inner) = return_2.get_payload(&key_2).unwrap();
+        assert_eq!(addr_2, ip_port);
+        let inner = inner.unwrap();
+        assert_eq!(return_1, inner);
+
+        let (ip_port, inner) = inner.get_payload(&key_1).unwrap();
+        assert_eq!(addr_1, ip_port);
+        assert!(inner.is_none());
+    }
+
+    #[test]
+    fn onion_return_get_tcp_payload() {
+        let key = new_symmetric_key();
+        let addr_v4 = "1.2.3.4:12345".parse().unwrap();
+        let addr_v6 = "[1:2:3:4:5:6:7:8]:54321".parse().unwrap();
+
+        let tcp_return = OnionReturn::new_tcp(&key, &addr_v4);
+        assert_eq!(ONION_RETURN_1_SIZE, tcp_return.to_bytes((&mut [0; ONION_RETURN_1_SIZE], 0)).unwrap().1);
+        assert_eq!(Some(addr_v4), tcp_return.get_tcp_payload(&key));
+        // TCP address can't be mistaken for UDP one
+        assert!(tcp_return.get_payload(&key).is_none());
+
+        let tcp_return = OnionReturn::new_tcp(&key, &addr_v6);
+        assert_eq!(Some(addr_v6), tcp_return.get_tcp_payload(&key));
+        assert!(tcp_return.get_tcp_payload(&new_symmetric_key()).is_none());
+
+        let udp_return = OnionReturn::new(&key, &addr_v4, None);
+        assert!(udp_return.get_tcp_payload(&key).is_none());
+    }
+
+    #[test]
+    fn onion_request_0_too_short() {
+        // tag, nonce and a part of PK
+        let mut bytes = vec![0x80];
+        bytes.extend_from_slice(&[0; NONCEBYTES + 10]);
+        assert!(!OnionRequest0::from_bytes(&bytes).is_done());
+    }
+
+    #[test]
+    fn onion_request_1_without_onion_return() {
+        let mut bytes = vec![0x81];
+        bytes.extend_from_slice(&[0; NONCEBYTES + PUBLICKEYBYTES + ONION_RETURN_1_SIZE]);
+        assert!(match OnionRequest1::from_bytes(&bytes) {
+            IResult::Error(_) => true,
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn create_onion_request_peel_layers() {
+        let (pk_a, sk_a) = gen_keypair();
+        let (pk_b, sk_b) = gen_keypair();
+        let (pk_c, sk_c) = gen_keypair();
+        let addr_a = "1.2.3.4:1".parse().unwrap();
+        let addr_b = "[::2]:2".parse().unwrap();
+        let addr_c = "5.6.7.8:3".parse().unwrap();
+        let destination = "9.10.11.12:4".parse().unwrap();
+        let path = [
+            PackedNode::new(true, addr_a, &pk_a),
+            PackedNode::new(true, addr_b, &pk_b),
+            PackedNode::new(true, addr_c, &pk_c),
+        ];
+        let inner = InnerOnionRequest::InnerOnionDataRequest(inner_data_request());
+
+        let request_0 = create_onion_request(&path, &destination, &inner).unwrap();
+
+        // only A can peel the first layer
+        assert!(request_0.get_payload(&sk_b).is_none());
+        let payload_0 = request_0.get_payload(&sk_a).unwrap();
+        assert_eq!(addr_b, payload_0.ip_port);
+
+        let request_1 = OnionRequest1 {
+            nonce: request_0.nonce,
+            temporary_pk: payload_0.temporary_pk,
+            payload: payload_0.inner,
+            onion_return: onion_return_1(),
+        };
+        assert!(request_1.get_payload(&sk_a).is_none());
+        let payload_1 = request_1.get_payload(&sk_b).unwrap();
+        assert_eq!(addr_c, payload_1.ip_port);
+
+        let request_2 = OnionRequest2 {
+            nonce: request_1.nonce,
+            temporary_pk: payload_1.temporary_pk,
+            payload: payload_1.inner,
+            onion_return: onion_return_2(),
+        };
+        assert!(request_2.get_payload(&sk_b).is_none());
+        let payload_2 = request_2.get_payload(&sk_c).unwrap();
+        assert_eq!(destination, payload_2.ip_port);
+        assert_eq!(inner, payload_2.inner);
+    }
+
+    #[test]
+    fn create_onion_request_too_big() {
+        let path = [
+            PackedNode::new(true, "1.2.3.4:1".parse().unwrap(), &gen_keypair().0),
+            PackedNode::new(true, "1.2.3.4:2".parse().unwrap(), &gen_keypair().0),
+            PackedNode::new(true, "1.2.3.4:3".parse().unwrap(), &gen_keypair().0),
+        ];
+        let mut request = inner_data_request();
+        request.payload = vec![42; ONION_MAX_DATA_SIZE];
+        let inner = InnerOnionRequest::InnerOnionDataRequest(request);
+
+        assert!(create_onion_request(&path, &"1.2.3.4:4".parse().unwrap(), &inner).is_none());
+    }
+}
@@ -22,7 +22,7 @@
 
     Used by:
 
-    * [`dht`](../dht/index.html)
+    * [`dht_new`](../dht_new/index.html)
 */
 
 use nom::le_u8;
@@ -0,0 +1,178 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*!
+Cache of `PrecomputedKey`s for DHT peers.
+
+Computing shared key for every sent and received DHT packet is the most
+costly crypto operation, so keys for recently used peers are stored in
+the cache and the least recently used key is dropped when the cache is
+full.
+*/
+
+use linked_hash_map::LinkedHashMap;
+
+use toxcore::crypto_core::*;
+
+/// Default number of `PrecomputedKey`s that cache can hold.
+pub const PRECOMPUTED_CACHE_SIZE: usize = 1024;
+
+/**
+LRU cache of `PrecomputedKey`s between own `SecretKey` and peers'
+`PublicKey`s.
+
+Keys are kept in the order of their use, so every access moves the key to
+the back and the least recently used key is always at the front. Both
+lookup and eviction take constant time.
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PrecomputedCache {
+    /// Own `SecretKey` that keys are computed with
+    sk: SecretKey,
+    /// Maximum number of stored keys
+    capacity: usize,
+    /// Stored keys from the least to the most recently used one
+    keys: LinkedHashMap<PublicKey, PrecomputedKey>,
+    /// Number of times the key was found in the cache
+    hits: u64,
+    /// Number of times the key had to be computed
+    misses: u64,
+}
+
+impl PrecomputedCache {
+    /**
+    Create new `PrecomputedCache` for own `SecretKey` that can hold up to
+    `capacity` keys. If `capacity` is `0` it's treated as `1`.
+    */
+    pub fn new(sk: &SecretKey, capacity: usize) -> PrecomputedCache {
+        let capacity = if capacity == 0 { 1 } else { capacity };
+        PrecomputedCache {
+            sk: sk.clone(),
+            capacity: capacity,
+            keys: LinkedHashMap::with_capacity(capacity),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /**
+    Get `PrecomputedKey` for peer with `pk`. The key is computed and stored
+    if it's not in the cache yet.
+    */
+    pub fn get(&mut self, pk: &PublicKey) -> PrecomputedKey {
+        if let Some(key) = self.keys.get_refresh(pk) {
+            self.hits += 1;
+            return key.clone()
+        }
+
+        self.misses += 1;
+        if self.keys.len() >= self.capacity {
+            self.remove_least_recently_used();
+        }
+        let key = encrypt_precompute(pk, &self.sk);
+        self.keys.insert(*pk, key.clone());
+        key
+    }
+
+    /// Remove the key that wasn't used for the longest time.
+    fn remove_least_recently_used(&mut self) {
+        if let Some((pk, _)) = self.keys.pop_front() {
+            trace!("Removing PrecomputedKey for {:?} from cache", pk);
+        }
+    }
+
+    /// Check whether the key for peer with `pk` is stored in the cache.
+    pub fn contains(&self, pk: &PublicKey) -> bool {
+        self.keys.contains_key(pk)
+    }
+
+    /// Number of stored keys.
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Check whether the cache is empty.
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Maximum number of stored keys.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Number of times the key was found in the cache.
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// Number of times the key had to be computed.
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn precomputed_cache_get() {
+        let (own_pk, own_sk) = gen_keypair();
+        let (pk, sk) = gen_keypair();
+        let mut cache = PrecomputedCache::new(&own_sk, 2);
+        assert!(cache.is_empty());
+
+        let key = cache.get(&pk);
+        assert_eq!(encrypt_precompute(&own_pk, &sk), key);
+        assert_eq!((0, 1), (cache.hits(), cache.misses()));
+        assert_eq!(key, cache.get(&pk));
+        assert_eq!((1, 1), (cache.hits(), cache.misses()));
+        assert_eq!(1, cache.len());
+    }
+
+    #[test]
+    fn precomputed_cache_removes_least_recently_used() {
+        let (_, own_sk) = gen_keypair();
+        let (pk1, _) = gen_keypair();
+        let (pk2, _) = gen_keypair();
+        let (pk3, _) = gen_keypair();
+        let mut cache = PrecomputedCache::new(&own_sk, 2);
+        let key1 = cache.get(&pk1);
+        let _ = cache.get(&pk2);
+        // pk1 becomes more recently used than pk2
+        assert_eq!(key1, cache.get(&pk1));
+        let _ = cache.get(&pk3);
+        assert_eq!(2, cache.len());
+        assert!(cache.contains(&pk1));
+        assert!(!cache.contains(&pk2));
+        assert!(cache.contains(&pk3));
+        assert_eq!((1, 3), (cache.hits(), cache.misses()));
+    }
+
+    #[test]
+    fn precomputed_cache_zero_capacity() {
+        let (_, own_sk) = gen_keypair();
+        let mut cache = PrecomputedCache::new(&own_sk, 0);
+        assert_eq!(1, cache.capacity());
+        let _ = cache.get(&gen_keypair().0);
+        let _ = cache.get(&gen_keypair().0);
+        assert_eq!(1, cache.len());
+    }
+}
@@ -0,0 +1,264 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*!
+Detection of own public `IP:port`.
+
+Nodes that respond to our [`GetNodes`] requests with [`SendNodes`] can
+include own DHT `PublicKey` along with the address they see us from. These
+reports are collected, and the address that is reported by at least
+[`PUBLIC_ADDR_MIN_REPORTS`] nodes more often than any other address is
+considered to be the public one. It's determined separately for IPv4 and
+IPv6.
+
+[`GetNodes`]: ../dht_new/packet/struct.GetNodes.html
+[`PUBLIC_ADDR_MIN_REPORTS`]: ./constant.PUBLIC_ADDR_MIN_REPORTS.html
+[`SendNodes`]: ../dht_new/packet/struct.SendNodes.html
+*/
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+
+use toxcore::crypto_core::*;
+
+/// Maximum number of stored reports of own `IP:port`.
+pub const PUBLIC_ADDR_MAX_REPORTS: usize = 32;
+
+/// Minimum number of nodes that should report the same `IP:port` before it
+/// is considered to be the public one.
+pub const PUBLIC_ADDR_MIN_REPORTS: usize = 3;
+
+/// Change of own public `IP:port` of some IP family.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PublicAddrChange {
+    /// Previously detected `IP:port`, `None` if it wasn't known.
+    pub old: Option<SocketAddr>,
+    /// Newly detected `IP:port`.
+    pub new: SocketAddr,
+}
+
+/**
+Collected reports of own `IP:port` and public addresses determined from
+them.
+
+Each node can have only one report: a new report from the same node
+replaces the old one. When there are more than [`PUBLIC_ADDR_MAX_REPORTS`]
+reports the oldest one is removed.
+
+[`PUBLIC_ADDR_MAX_REPORTS`]: ./constant.PUBLIC_ADDR_MAX_REPORTS.html
+*/
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PublicAddr {
+    /// reported `IP:port`s together with `PublicKey`s of reporting nodes,
+    /// sorted from the oldest to the newest
+    reports: VecDeque<(PublicKey, SocketAddr)>,
+    /// detected public IPv4 `IP:port`
+    addr_v4: Option<SocketAddr>,
+    /// detected public IPv6 `IP:port`
+    addr_v6: Option<SocketAddr>,
+}
+
+impl PublicAddr {
+    /// Create new `PublicAddr` without any reports.
+    pub fn new() -> PublicAddr {
+        PublicAddr::default()
+    }
+
+    /// Get detected public IPv4 `IP:port`.
+    pub fn addr_v4(&self) -> Option<SocketAddr> {
+        self.addr_v4
+    }
+
+    /// Get detected public IPv6 `IP:port`.
+    pub fn addr_v6(&self) -> Option<SocketAddr> {
+        self.addr_v6
+    }
+
+    /// Number of stored reports.
+    pub fn len(&self) -> usize {
+        self.reports.len()
+    }
+
+    /// Check if there are no stored reports.
+    pub fn is_empty(&self) -> bool {
+        self.reports.is_empty()
+    }
+
+    /**
+    Add `addr` that was reported by node with `pk` as own `IP:port`.
+
+    Returns [`PublicAddrChange`] if public `IP:port` of the family of `addr`
+    has changed after the report.
+
+    [`PublicAddrChange`]: ./struct.PublicAddrChange.html
+    */
+    pub fn add_report(&mut self, pk: &PublicKey, addr: SocketAddr) -> Option<PublicAddrChange> {
+        trace!(target: "PublicAddr", "Node {:?} reported own address {}", pk, addr);
+        self.reports.retain(|&(ref report_pk, _)| report_pk != pk);
+        if self.reports.len() >= PUBLIC_ADDR_MAX_REPORTS {
+            self.reports.pop_front();
+        }
+        self.reports.push_back((*pk, addr));
+
+        let is_ipv4 = addr.is_ipv4();
+        let old = if is_ipv4 { self.addr_v4 } else { self.addr_v6 };
+        let new = match self.consensus(is_ipv4, old) {
+            Some(new) => new,
+            None => return None,
+        };
+        if old == Some(new) {
+            return None
+        }
+
+        debug!(target: "PublicAddr", "Own public address changed to {}", new);
+        if is_ipv4 {
+            self.addr_v4 = Some(new);
+        } else {
+            self.addr_v6 = Some(new);
+        }
+        Some(PublicAddrChange { old: old, new: new })
+    }
+
+    /**
+    Get `IP:port` of the given family that is reported most often, by at
+    least [`PUBLIC_ADDR_MIN_REPORTS`] nodes.
+
+    In case of a tie `current` address is preferred, so that the address
+    doesn't flap between equally reported ones.
+
+    [`PUBLIC_ADDR_MIN_REPORTS`]: ./constant.PUBLIC_ADDR_MIN_REPORTS.html
+    */
+    fn consensus(&self, is_ipv4: bool, current: Option<SocketAddr>) -> Option<SocketAddr> {
+        let count = |addr: &SocketAddr|
+            self.reports.iter().filter(|&&(_, a)| a == *addr).count();
+
+        let mut best = current.map(|addr| (addr, count(&addr)));
+        for &(_, addr) in &self.reports {
+            if addr.is_ipv4() != is_ipv4 {
+                continue
+            }
+            let num = count(&addr);
+            if best.map_or(true, |(_, best_num)| num > best_num) {
+                best = Some((addr, num));
+            }
+        }
+
+        match best {
+            Some((addr, num)) if num >= PUBLIC_ADDR_MIN_REPORTS => Some(addr),
+            _ => current,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gen_pk() -> PublicKey {
+        gen_keypair().0
+    }
+
+    #[test]
+    fn public_addr_new_test() {
+        let public_addr = PublicAddr::new();
+        assert!(public_addr.is_empty());
+        assert_eq!(None, public_addr.addr_v4());
+        assert_eq!(None, public_addr.addr_v6());
+    }
+
+    #[test]
+    fn public_addr_add_report_test() {
+        let addr = "1.2.3.4:33445".parse().unwrap();
+        let mut public_addr = PublicAddr::new();
+
+        for _ in 0..PUBLIC_ADDR_MIN_REPORTS - 1 {
+            assert_eq!(None, public_addr.add_report(&gen_pk(), addr));
+        }
+        assert_eq!(None, public_addr.addr_v4());
+
+        let change = public_addr.add_report(&gen_pk(), addr);
+        assert_eq!(Some(PublicAddrChange { old: None, new: addr }), change);
+        assert_eq!(Some(addr), public_addr.addr_v4());
+        assert_eq!(None, public_addr.addr_v6());
+
+        // the same address doesn't produce a change
+        assert_eq!(None, public_addr.add_report(&gen_pk(), addr));
+    }
+
+    #[test]
+    fn public_addr_add_report_same_node_test() {
+        let pk = gen_pk();
+        let addr = "1.2.3.4:33445".parse().unwrap();
+        let mut public_addr = PublicAddr::new();
+
+        for _ in 0..PUBLIC_ADDR_MIN_REPORTS {
+            assert_eq!(None, public_addr.add_report(&pk, addr));
+        }
+        assert_eq!(1, public_addr.len());
+        assert_eq!(None, public_addr.addr_v4());
+    }
+
+    #[test]
+    fn public_addr_add_report_change_test() {
+        let old = "1.2.3.4:33445".parse().unwrap();
+        let new = "1.2.3.4:33446".parse().unwrap();
+        let mut public_addr = PublicAddr::new();
+
+        let pks = (0..PUBLIC_ADDR_MIN_REPORTS * 2 + 1).map(|_| gen_pk()).collect::<Vec<_>>();
+        for pk in &pks[..PUBLIC_ADDR_MIN_REPORTS] {
+            public_addr.add_report(pk, old);
+        }
+        assert_eq!(Some(old), public_addr.addr_v4());
+
+        // tie keeps the current address
+        for pk in &pks[PUBLIC_ADDR_MIN_REPORTS..PUBLIC_ADDR_MIN_REPORTS * 2] {
+            assert_eq!(None, public_addr.add_report(pk, new));
+        }
+        assert_eq!(Some(old), public_addr.addr_v4());
+
+        let change = public_addr.add_report(&pks[PUBLIC_ADDR_MIN_REPORTS * 2], new);
+        assert_eq!(Some(PublicAddrChange { old: Some(old), new: new }), change);
+        assert_eq!(Some(new), public_addr.addr_v4());
+    }
+
+    #[test]
+    fn public_addr_add_report_families_test() {
+        let addr_v4 = "1.2.3.4:33445".parse().unwrap();
+        let addr_v6 = "[1234:5678::1]:33445".parse().unwrap();
+        let mut public_addr = PublicAddr::new();
+
+        for _ in 0..PUBLIC_ADDR_MIN_REPORTS {
+            public_addr.add_report(&gen_pk(), addr_v4);
+            public_addr.add_report(&gen_pk(), addr_v6);
+        }
+        assert_eq!(Some(addr_v4), public_addr.addr_v4());
+        assert_eq!(Some(addr_v6), public_addr.addr_v6());
+    }
+
+    #[test]
+    fn public_addr_max_reports_test() {
+        let addr = "1.2.3.4:33445".parse().unwrap();
+        let mut public_addr = PublicAddr::new();
+
+        for _ in 0..PUBLIC_ADDR_MAX_REPORTS + 1 {
+            public_addr.add_report(&gen_pk(), addr);
+        }
+        assert_eq!(PUBLIC_ADDR_MAX_REPORTS, public_addr.len());
+    }
+}
@@ -21,6 +21,7 @@
 //! better will become available.*
 
 use std::default::Default;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use byteorder::{BigEndian, LittleEndian, WriteBytesExt};
 #[cfg(test)]
 use byteorder::ByteOrder;
@@ -28,7 +29,11 @@ use nom::{be_u16, le_u16, le_u8, le_u32, le_u64, rest};
 
 use toxcore::binary_io::*;
 use toxcore::crypto_core::*;
-use toxcore::dht::*;
+use toxcore::dht_new::binary_io::{
+    FromBytes as NodeFromBytes,
+    ToBytes as NodeToBytes,
+};
+use toxcore::dht_new::packed_node::PackedNode;
 use toxcore::toxid::{NoSpam, NOSPAMBYTES};
 
 
@@ -305,6 +310,46 @@ impl ToBytes for NospamKeys {
 }
 
 
+/// Size of serialized `PackedNode` with IPv6 address.
+const PACKED_NODE_IPV6_SIZE: usize = 51;
+
+/// IP types of TCP relays are IP types of UDP nodes plus this value.
+const TCP_IP_TYPE_OFFSET: u8 = 128;
+
+// Parse `PackedNode` of TCP relay which is stored with TCP IP type.
+named!(parse_tcp_node<PackedNode>, do_parse!(
+    ip: switch!(le_u8,
+        130 => map!(call!(<Ipv4Addr as NodeFromBytes>::from_bytes), IpAddr::V4) |
+        138 => map!(call!(<Ipv6Addr as NodeFromBytes>::from_bytes), IpAddr::V6)
+    ) >>
+    port: be_u16 >>
+    pk: call!(<PublicKey as NodeFromBytes>::from_bytes) >>
+    (PackedNode::new(false, SocketAddr::new(ip, port), &pk))
+));
+
+/** Parse `PackedNode` of DHT node, or of TCP relay if `tcp` is `true`.
+*/
+fn parse_packed_node(bytes: &[u8], tcp: bool) -> ParseResult<PackedNode> {
+    if tcp {
+        parse_tcp_node(bytes)
+    } else {
+        <PackedNode as NodeFromBytes>::from_bytes(bytes)
+    }
+}
+
+/** Serialize `PackedNode` of DHT node, or of TCP relay if `tcp` is `true`.
+*/
+fn packed_node_to_bytes(node: &PackedNode, tcp: bool) -> Vec<u8> {
+    let mut buf = [0; PACKED_NODE_IPV6_SIZE];
+    let (_, size) = node.to_bytes((&mut buf, 0))
+        .expect("PackedNode always fits into the buffer");
+    if tcp {
+        buf[0] += TCP_IP_TYPE_OFFSET;
+    }
+    buf[..size].to_vec()
+}
+
+
 /** DHT section of the old state format.
 
 https://zetok.github.io/tox-spec/#dht-0x02
@@ -314,7 +359,7 @@ Default is empty, no Nodes.
 ```
 # use std::default::Default;
 # use tox::toxcore::state_format::old::DhtState;
-# use tox::toxcore::dht::PackedNode;
+# use tox::toxcore::dht_new::packed_node::PackedNode;
 assert_eq!(&[] as &[PackedNode], DhtState::default().0.as_slice());
 ```
 */
@@ -360,7 +405,6 @@ E.g. de-serialization with an empty list:
 
 ```
 use self::tox::toxcore::binary_io::*;
-use self::tox::toxcore::dht::*;
 use self::tox::toxcore::state_format::old::*;
 
 let serialized = vec![
@@ -379,7 +423,7 @@ from_bytes!(DhtState, do_parse!(
     nodes: le_u32 >>
     verify!(le_u16, |value| value == DHT_SECTION_TYPE) >> // check DHT section type
     verify!(le_u16, |value| value == DHT_2ND_MAGICAL) >> // check whether yet another magic number matches
-    pns: flat_map!(take!(nodes as usize), many0!(PackedNode::parse_bytes)) >>
+    pns: flat_map!(take!(nodes as usize), many0!(apply!(parse_packed_node, false))) >>
     (DhtState(pns))
 ));
 
@@ -387,7 +431,6 @@ from_bytes!(DhtState, do_parse!(
 
 ```
 use self::tox::toxcore::binary_io::*;
-use self::tox::toxcore::dht::*;
 use self::tox::toxcore::state_format::old::*;
 
 let result = vec![
@@ -411,7 +454,7 @@ impl ToBytes for DhtState {
             let mut bytes = Vec::with_capacity(
                                     PACKED_NODE_IPV6_SIZE * self.0.len());
             for pn in &self.0 {
-                bytes.extend_from_slice(&pn.to_bytes());
+                bytes.extend_from_slice(&packed_node_to_bytes(pn, false));
             }
             bytes
         };
@@ -933,19 +976,19 @@ from_bytes!(StatusMsg, map!(alt_complete!(take!(STATUS_MSG_LEN) | rest), StatusM
 impl_to_bytes_for_bytes_struct!(StatusMsg, status_msg_to_bytes_test);
 
 macro_rules! nodes_list {
-    ($($name:ident, $tname:ident),+) => ($(
+    ($($name:ident, $tcp:expr, $tname:ident),+) => ($(
         /// Contains list in `PackedNode` format.
         #[derive(Clone, Debug, Default, Eq, PartialEq)]
         pub struct $name(pub Vec<PackedNode>);
 
-        from_bytes!($name, map!(many0!(PackedNode::parse_bytes), $name));
+        from_bytes!($name, map!(many0!(apply!(parse_packed_node, $tcp)), $name));
 
         impl ToBytes for $name {
             fn to_bytes(&self) -> Vec<u8> {
                 let mut result = Vec::with_capacity(
                             PACKED_NODE_IPV6_SIZE * self.0.len());
                 for node in &self.0 {
-                    result.append(&mut node.to_bytes());
+                    result.append(&mut packed_node_to_bytes(node, $tcp));
                 }
                 result
             }
@@ -961,7 +1004,7 @@ macro_rules! nodes_list {
             fn with_pns(pns: Vec<PackedNode>) {
                 let mut bytes = Vec::new();
                 for pn in &pns {
-                    bytes.append(&mut pn.to_bytes());
+                    bytes.append(&mut packed_node_to_bytes(pn, $tcp));
                 }
                 {
                     let (r_bytes, p) = $name::parse_bytes(&bytes).unwrap();
@@ -980,8 +1023,8 @@ macro_rules! nodes_list {
     )+)
 }
 
-nodes_list!(TcpRelays, tcp_relays_test,
-            PathNodes, path_nodes_test);
+nodes_list!(TcpRelays, true, tcp_relays_test,
+            PathNodes, false, path_nodes_test);
 
 
 /// End of the state format data.
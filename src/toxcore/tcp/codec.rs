@@ -120,6 +120,7 @@ impl Encoder for Codec {
 mod tests {
     use ::toxcore::crypto_core::*;
     use ::toxcore::tcp::codec::*;
+    use ::toxcore::onion::packet::{self as onion, InnerOnionResponse};
 
     fn create_channels() -> (Channel, Channel) {
         let alice_session = Session::new();
@@ -157,6 +158,19 @@ mod tests {
             Packet::PongResponse( PongResponse { ping_id: 4242 } ),
             Packet::OobSend( OobSend { destination_pk: pk, data: vec![13; 42] } ),
             Packet::OobReceive( OobReceive { sender_pk: pk, data: vec![13; 24] } ),
+            Packet::OnionDataRequest( OnionDataRequest {
+                nonce: gen_nonce(),
+                ip_port: "1.2.3.4:12345".parse().unwrap(),
+                temporary_pk: pk,
+                payload: vec![13; 170]
+            } ),
+            Packet::OnionDataResponse( OnionDataResponse {
+                payload: InnerOnionResponse::OnionDataResponse(onion::OnionDataResponse {
+                    nonce: gen_nonce(),
+                    temporary_pk: pk,
+                    payload: vec![42; 123]
+                })
+            } ),
             Packet::Data( Data { connection_id: 42, data: vec![13; 2031] } )
         ];
         for packet in test_packets {
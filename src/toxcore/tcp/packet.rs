@@ -25,8 +25,11 @@
 
 use toxcore::crypto_core::*;
 use toxcore::tcp::binary_io::*;
+use toxcore::dht_new::binary_io as onion_binary_io;
+use toxcore::onion::packet::{parse_ip_port, gen_ip_port, InnerOnionResponse};
 
 use nom::{be_u8, be_u16, be_u64, rest};
+use std::net::SocketAddr;
 
 /** Top-level TCP packet.
 
@@ -50,10 +53,10 @@ pub enum Packet {
     OobSend(OobSend),
     /// [`OobReceive`](./struct.OobReceive.html) structure.
     OobReceive(OobReceive),
-    /// TODO
-    //OnionDataRequest,
-    /// TODO
-    //OnionDataResponse,
+    /// [`OnionDataRequest`](./struct.OnionDataRequest.html) structure.
+    OnionDataRequest(OnionDataRequest),
+    /// [`OnionDataResponse`](./struct.OnionDataResponse.html) structure.
+    OnionDataResponse(OnionDataResponse),
     /// [`Data`](./struct.Data.html) structure.
     Data(Data)
 }
@@ -71,6 +74,8 @@ impl FromBytes for Packet {
         map!(PongResponse::from_bytes, Packet::PongResponse) |
         map!(OobSend::from_bytes, Packet::OobSend) |
         map!(OobReceive::from_bytes, Packet::OobReceive) |
+        map!(OnionDataRequest::from_bytes, Packet::OnionDataRequest) |
+        map!(OnionDataResponse::from_bytes, Packet::OnionDataResponse) |
         map!(Data::from_bytes, Packet::Data)
     ));
 }
@@ -86,6 +91,8 @@ impl ToBytes for Packet {
             Packet::PongResponse(ref p) => p.to_bytes(buf),
             Packet::OobSend(ref p) => p.to_bytes(buf),
             Packet::OobReceive(ref p) => p.to_bytes(buf),
+            Packet::OnionDataRequest(ref p) => p.to_bytes(buf),
+            Packet::OnionDataResponse(ref p) => p.to_bytes(buf),
             Packet::Data(ref p) => p.to_bytes(buf),
         }
     }
@@ -457,6 +464,100 @@ impl ToBytes for OobReceive {
     }
 }
 
+/** Sent by client to server.
+The server will pass the packet to the UDP part of the node that will send
+it to the first node of the onion path as `OnionRequest1` like it does with
+the payload of `OnionRequest0`. The return address stored in `OnionRequest1`
+points to the client so that the response comes back as
+[`OnionDataResponse`](./struct.OnionDataResponse.html).
+
+Serialized form:
+
+Length   | Content
+-------- | ------
+`1`      | `0x08`
+`24`     | Nonce
+`19`     | IP_Port of the first onion node
+`32`     | Temporary `PublicKey`
+variable | Encrypted payload for the first onion node
+
+*/
+#[derive(Debug, PartialEq, Clone)]
+pub struct OnionDataRequest {
+    /// Nonce that was used for payload encryption
+    pub nonce: Nonce,
+    /// Address of the first node of the onion path
+    pub ip_port: SocketAddr,
+    /// Temporary `PublicKey` for the first node of the onion path
+    pub temporary_pk: PublicKey,
+    /// Encrypted payload for the first node of the onion path
+    pub payload: Vec<u8>
+}
+
+impl FromBytes for OnionDataRequest {
+    named!(from_bytes<OnionDataRequest>, do_parse!(
+        tag!("\x08") >>
+        nonce: call!(Nonce::from_bytes) >>
+        ip_port: call!(parse_ip_port) >>
+        temporary_pk: call!(PublicKey::from_bytes) >>
+        payload: rest >>
+        (OnionDataRequest {
+            nonce: nonce,
+            ip_port: ip_port,
+            temporary_pk: temporary_pk,
+            payload: payload.to_vec()
+        })
+    ));
+}
+
+impl ToBytes for OnionDataRequest {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(0x08) >>
+            gen_slice!(self.nonce.as_ref()) >>
+            gen_call!(|buf, ip_port| gen_ip_port(buf, ip_port), &self.ip_port) >>
+            gen_slice!(self.temporary_pk.as_ref()) >>
+            gen_slice!(self.payload)
+        )
+    }
+}
+
+/** Sent by server to client.
+The server sends the response that came back through the onion path to the
+client that has sent the corresponding
+[`OnionDataRequest`](./struct.OnionDataRequest.html).
+
+Serialized form:
+
+Length   | Content
+-------- | ------
+`1`      | `0x09`
+variable | [`InnerOnionResponse`](../../onion/packet/enum.InnerOnionResponse.html)
+
+*/
+#[derive(Debug, PartialEq, Clone)]
+pub struct OnionDataResponse {
+    /// Response to the onion request
+    pub payload: InnerOnionResponse
+}
+
+impl FromBytes for OnionDataResponse {
+    named!(from_bytes<OnionDataResponse>, do_parse!(
+        tag!("\x09") >>
+        payload: call!(<InnerOnionResponse as onion_binary_io::FromBytes>::from_bytes) >>
+        (OnionDataResponse { payload: payload })
+    ));
+}
+
+impl ToBytes for OnionDataResponse {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(0x09) >>
+            gen_call!(|buf, payload| <InnerOnionResponse as onion_binary_io::ToBytes>::to_bytes(payload, buf), &self.payload)
+        )
+    }
+}
+
 /** Sent by client to server.
 The client sends data with `connection_id` and the server
 relays it to the given connection
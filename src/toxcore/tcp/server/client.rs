@@ -23,21 +23,32 @@
 
 use toxcore::crypto_core::*;
 use toxcore::tcp::packet::*;
+use toxcore::tcp::server::limits::{Limits, RateLimiter};
+use toxcore::onion::packet::InnerOnionResponse;
 
 use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
 use std::slice::Iter;
+use std::time::{Duration, Instant};
 
 use futures::{Sink, Future};
 use futures::sync::mpsc;
 
 use tokio_io::IoFuture;
 
+/// Interval in seconds for sending PingRequest to clients
+pub const TCP_PING_FREQUENCY: u64 = 30;
+/// Interval in seconds to wait for PongResponse before disconnecting the client
+pub const TCP_PING_TIMEOUT: u64 = 10;
+
 /** Structure that represents how Server keeps connected clients. A write-only socket with
 human interface. A client cannot send a message directly to another client, whereas server can.
 */
 pub struct Client {
     /// PublicKey of the client.
     pk: PublicKey,
+    /// Address of the client's TCP connection.
+    addr: SocketAddr,
     /// The transmission end of a channel which is used to send values.
     tx: mpsc::UnboundedSender<Packet>,
     /** links - a table of indexing links from this client to another
@@ -51,19 +62,33 @@ pub struct Client {
     inside this module.
     */
     links: [Option<PublicKey>; 240],
-    /// Used to check whether PongResponse is correct
-    ping_id: u64
+    /// Used to check whether PongResponse is correct, 0 if there is no
+    /// unanswered PingRequest
+    ping_id: u64,
+    /// Last time we sent PingRequest or created the client
+    last_pinged: Instant,
+    /// Limiter of RouteRequest packets from the client
+    route_requests: RateLimiter,
+    /// Limiter of OobSend packets from the client
+    oob_sends: RateLimiter,
+    /// Limiter of bytes sent by the client with Data packets
+    data_bytes: RateLimiter
 }
 
 impl Client {
     /** Create new Client
     */
-    pub fn new(tx: mpsc::UnboundedSender<Packet>, pk: &PublicKey) -> Client {
+    pub fn new(tx: mpsc::UnboundedSender<Packet>, pk: &PublicKey, addr: SocketAddr) -> Client {
         Client {
             pk: *pk,
+            addr: addr,
             tx: tx,
             links: [None; 240],
-            ping_id: 0
+            ping_id: 0,
+            last_pinged: Instant::now(),
+            route_requests: RateLimiter::new(),
+            oob_sends: RateLimiter::new(),
+            data_bytes: RateLimiter::new()
         }
     }
 
@@ -73,12 +98,73 @@ impl Client {
         self.pk
     }
 
+    /** Address of the client's TCP connection
+    */
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
     /** Last ping_id sent to client.
     */
     pub fn ping_id(&self) -> u64 {
         self.ping_id
     }
 
+    /** Check if PongResponse is correct and mark PingRequest as answered
+    */
+    pub fn handle_pong(&mut self, ping_id: u64) -> bool {
+        if ping_id != 0 && ping_id == self.ping_id {
+            self.ping_id = 0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /** Check if the client hasn't answered last PingRequest for
+    `TCP_PING_TIMEOUT` seconds.
+    */
+    pub fn is_pong_timedout(&self) -> bool {
+        self.is_pong_timedout_at(Instant::now())
+    }
+
+    /// Check if the client hasn't answered last PingRequest at `now`.
+    fn is_pong_timedout_at(&self, now: Instant) -> bool {
+        self.ping_id != 0 &&
+            now.duration_since(self.last_pinged) >= Duration::from_secs(TCP_PING_TIMEOUT)
+    }
+
+    /** Check if it's time to send a new PingRequest to the client: previous
+    one was answered and `TCP_PING_FREQUENCY` seconds passed.
+    */
+    pub fn is_ping_interval_passed(&self) -> bool {
+        self.is_ping_interval_passed_at(Instant::now())
+    }
+
+    /// Check if it's time to send a new PingRequest to the client at `now`.
+    fn is_ping_interval_passed_at(&self, now: Instant) -> bool {
+        self.ping_id == 0 &&
+            now.duration_since(self.last_pinged) >= Duration::from_secs(TCP_PING_FREQUENCY)
+    }
+
+    /** Check if one more RouteRequest from the client fits into `limits`
+    */
+    pub fn is_route_request_allowed(&mut self, limits: &Limits) -> bool {
+        self.route_requests.try_take(&limits.route_requests, 1)
+    }
+
+    /** Check if one more OobSend from the client fits into `limits`
+    */
+    pub fn is_oob_send_allowed(&mut self, limits: &Limits) -> bool {
+        self.oob_sends.try_take(&limits.oob_sends, 1)
+    }
+
+    /** Check if `len` more bytes of Data from the client fit into `limits`
+    */
+    pub fn is_data_allowed(&mut self, limits: &Limits, len: usize) -> bool {
+        self.data_bytes.try_take(&limits.data_bytes, len as u32)
+    }
+
     /** Return index of of the link by PK
 
     Some(index + 16) if link exists
@@ -182,6 +268,19 @@ impl Client {
             })
         )
     }
+    /** Construct PingRequest with a new random ping_id, remember it and send
+    it to Client ignoring IO error
+    */
+    pub fn send_ping_request(&mut self) -> IoFuture<()> {
+        let ping_id = gen_ping_id();
+        self.ping_id = ping_id;
+        self.last_pinged = Instant::now();
+        self.send_ignore_error(
+            Packet::PingRequest(PingRequest {
+                ping_id: ping_id
+            })
+        )
+    }
     /** Construct PongResponse and send it to Client
     */
     pub fn send_pong_response(&self, ping_id: u64) -> IoFuture<()> {
@@ -201,6 +300,15 @@ impl Client {
             })
         )
     }
+    /** Construct OnionDataResponse and send it to Client
+    */
+    pub fn send_onion_response(&self, payload: InnerOnionResponse) -> IoFuture<()> {
+        self.send(
+            Packet::OnionDataResponse(OnionDataResponse {
+                payload: payload
+            })
+        )
+    }
     /** Construct Data and send it to Client
     */
     pub fn send_data(&self, connection_id: u8, data: Vec<u8>) -> IoFuture<()> {
@@ -212,3 +320,89 @@ impl Client {
         )
     }
 }
+
+/** Generate random non-zero ping_id
+*/
+fn gen_ping_id() -> u64 {
+    let mut ping_id = 0;
+    while ping_id == 0 {
+        ping_id = random_u64();
+    }
+    ping_id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use toxcore::tcp::server::limits::RateLimit;
+
+    use futures::Stream;
+
+    #[test]
+    fn send_ping_request_and_handle_pong() {
+        let (pk, _) = gen_keypair();
+        let (tx, rx) = mpsc::unbounded();
+        let mut client = Client::new(tx, &pk, "1.2.3.4:12345".parse().unwrap());
+        assert!(!client.is_pong_timedout());
+
+        client.send_ping_request().wait().unwrap();
+        let (packet, _rx) = rx.into_future().wait().unwrap();
+        let ping_id = match packet.unwrap() {
+            Packet::PingRequest(PingRequest { ping_id }) => ping_id,
+            packet => panic!("Unexpected packet {:?}", packet),
+        };
+        assert_ne!(ping_id, 0);
+        assert_eq!(client.ping_id(), ping_id);
+
+        assert!(!client.handle_pong(ping_id.wrapping_add(1)));
+        assert!(client.handle_pong(ping_id));
+        assert_eq!(client.ping_id(), 0);
+        // the same pong can't be handled twice
+        assert!(!client.handle_pong(ping_id));
+    }
+
+    #[test]
+    fn ping_timeouts() {
+        let (pk, _) = gen_keypair();
+        let (tx, _rx) = mpsc::unbounded();
+        let mut client = Client::new(tx, &pk, "1.2.3.4:12345".parse().unwrap());
+        assert!(!client.is_ping_interval_passed());
+
+        let now = client.last_pinged + Duration::from_secs(TCP_PING_FREQUENCY);
+        assert!(client.is_ping_interval_passed_at(now));
+        assert!(!client.is_pong_timedout_at(now));
+
+        client.send_ping_request().wait().unwrap();
+        assert!(!client.is_ping_interval_passed());
+        assert!(!client.is_pong_timedout());
+
+        let now = client.last_pinged + Duration::from_secs(TCP_PING_TIMEOUT);
+        assert!(client.is_pong_timedout_at(now));
+    }
+
+    #[test]
+    fn rate_limits() {
+        let (pk, _) = gen_keypair();
+        let (tx, _rx) = mpsc::unbounded();
+        let mut client = Client::new(tx, &pk, "1.2.3.4:12345".parse().unwrap());
+        let limits = Limits {
+            max_connections_per_ip: 1,
+            route_requests: RateLimit { rate: 1, burst: 2 },
+            oob_sends: RateLimit { rate: 1, burst: 1 },
+            data_bytes: RateLimit { rate: 1, burst: 100 },
+        };
+
+        assert!(client.is_route_request_allowed(&limits));
+        assert!(client.is_route_request_allowed(&limits));
+        assert!(!client.is_route_request_allowed(&limits));
+
+        assert!(client.is_oob_send_allowed(&limits));
+        assert!(!client.is_oob_send_allowed(&limits));
+
+        assert!(!client.is_data_allowed(&limits, 101));
+        assert!(client.is_data_allowed(&limits, 60));
+        assert!(!client.is_data_allowed(&limits, 60));
+        assert!(client.is_data_allowed(&limits, 40));
+    }
+}
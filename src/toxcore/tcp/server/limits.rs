@@ -0,0 +1,148 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2017 Roman Proskuryakov <humbug@deeptown.org>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! Limits protecting relay server from abusive clients
+*/
+
+use std::time::Instant;
+
+/** Rate of some action allowed for a client: `rate` actions per second on
+average with bursts of up to `burst` actions.
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Number of actions per second
+    pub rate: u32,
+    /// Maximum number of actions at once
+    pub burst: u32,
+}
+
+/** Limits of the relay server. Clients which exceed any of rate limits are
+disconnected, connections from IP which already has
+`max_connections_per_ip` connections are dropped before the handshake.
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    /// Maximum number of concurrent connections from a single IP
+    pub max_connections_per_ip: usize,
+    /// Rate of RouteRequest packets from a single client
+    pub route_requests: RateLimit,
+    /// Rate of OobSend packets from a single client
+    pub oob_sends: RateLimit,
+    /// Rate of bytes relayed by Data packets from a single client
+    pub data_bytes: RateLimit,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_connections_per_ip: 32,
+            route_requests: RateLimit { rate: 10, burst: 256 },
+            oob_sends: RateLimit { rate: 20, burst: 100 },
+            data_bytes: RateLimit { rate: 1024 * 1024, burst: 4 * 1024 * 1024 },
+        }
+    }
+}
+
+/** Token bucket which checks whether an action fits into `RateLimit`.
+
+The limit itself is not stored in the bucket but passed on every use, so
+the bucket is full when created and gets its real size on the first use.
+*/
+pub struct RateLimiter {
+    /// Number of actions that can be taken right now
+    tokens: f64,
+    /// Last time tokens were added to the bucket
+    last_update: Instant,
+}
+
+impl RateLimiter {
+    /** Create new full bucket
+    */
+    pub fn new() -> RateLimiter {
+        RateLimiter {
+            tokens: ::std::f64::INFINITY,
+            last_update: Instant::now(),
+        }
+    }
+
+    /** Try to take `amount` actions within `limit`. Returns false if the
+    limit is exceeded, nothing is taken in that case.
+    */
+    pub fn try_take(&mut self, limit: &RateLimit, amount: u32) -> bool {
+        self.try_take_at(limit, amount, Instant::now())
+    }
+
+    fn try_take_at(&mut self, limit: &RateLimit, amount: u32, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.last_update);
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        self.last_update = now;
+        self.tokens = (self.tokens + elapsed * f64::from(limit.rate)).min(f64::from(limit.burst));
+        if self.tokens >= f64::from(amount) {
+            self.tokens -= f64::from(amount);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[test]
+    fn rate_limiter_burst() {
+        let limit = RateLimit { rate: 1, burst: 3 };
+        let mut limiter = RateLimiter::new();
+        let now = Instant::now();
+        assert!(limiter.try_take_at(&limit, 2, now));
+        assert!(limiter.try_take_at(&limit, 1, now));
+        assert!(!limiter.try_take_at(&limit, 1, now));
+    }
+
+    #[test]
+    fn rate_limiter_refill() {
+        let limit = RateLimit { rate: 10, burst: 10 };
+        let mut limiter = RateLimiter::new();
+        let now = Instant::now();
+        assert!(limiter.try_take_at(&limit, 10, now));
+        assert!(!limiter.try_take_at(&limit, 5, now));
+        // half a second gives 5 tokens
+        let now = now + Duration::from_millis(500);
+        assert!(limiter.try_take_at(&limit, 5, now));
+        assert!(!limiter.try_take_at(&limit, 1, now));
+        // bucket can't hold more than burst
+        let now = now + Duration::from_secs(10);
+        assert!(!limiter.try_take_at(&limit, 11, now));
+        assert!(limiter.try_take_at(&limit, 10, now));
+    }
+
+    #[test]
+    fn rate_limiter_too_big_amount() {
+        let limit = RateLimit { rate: 100, burst: 100 };
+        let mut limiter = RateLimiter::new();
+        assert!(!limiter.try_take(&limit, 101));
+        // failed attempt doesn't take anything
+        assert!(limiter.try_take(&limit, 100));
+    }
+}
@@ -0,0 +1,462 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2017 Roman Proskuryakov <humbug@deeptown.org>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! The network part of relay server: accepting connections, handshaking
+clients and moving packets between sockets and `Server`
+*/
+
+use toxcore::crypto_core::*;
+use toxcore::tcp::make_server_handshake;
+use toxcore::tcp::codec;
+use toxcore::tcp::server::{Client, Server};
+
+use std::io::{Error, ErrorKind};
+use std::net::{self, SocketAddr};
+use std::thread;
+use std::time::Duration;
+
+use futures::{Stream, Sink, Future, future};
+use futures::sync::mpsc;
+
+use tokio_io::AsyncRead;
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::{Core, Handle};
+use tokio_timer::{self, Timer};
+
+/** Tick duration of the timer used for pings and send timeouts, in
+milliseconds. It has to be finer than `PING_CHECK_INTERVAL`: the timer counts
+a sleep as expired one tick before its deadline, so with equal durations every
+ping interval would expire right away and never yield to the event loop.
+*/
+const TIMER_TICK: u64 = 100;
+
+/// How often we check whether clients should be pinged or disconnected, in seconds
+const PING_CHECK_INTERVAL: u64 = 1;
+
+/// How long sending of a single packet to a client may take, in seconds
+pub const SEND_TIMEOUT: u64 = 30;
+
+/// How long the handshake with a new client may take, in seconds
+pub const HANDSHAKE_TIMEOUT: u64 = 10;
+
+/** Run relay server on the listener. Every accepted connection is handshaked
+with `server_sk`, registered in `server` and processed until it's closed,
+then the client is removed via `Server::shutdown_client`. Connections and
+clients exceeding `Limits` of `server` are dropped, as well as connections
+that don't complete the handshake within `HANDSHAKE_TIMEOUT`. Connections are
+spawned on `handle`, so errors of a single client don't stop the server.
+Clients are pinged periodically and disconnected if they don't answer.
+
+The returned future resolves only when the listener fails.
+*/
+pub fn serve(listener: TcpListener,
+             server_sk: SecretKey,
+             server: Server,
+             handle: Handle) -> Box<Future<Item = (), Error = Error>>
+{
+    let timer = new_timer();
+    let pings = ping_clients(server.clone(), &timer);
+    let accept = accept_clients(listener, server_sk, server, handle, timer);
+    Box::new(accept.select(pings)
+        .map(|_| ())
+        .map_err(|(e, _select_next)| e)
+    )
+}
+
+/** Bind listeners to every address of `addrs` and run relay server on all
+of them sharing the same `server`. Fails immediately if any address can't
+be bound.
+*/
+pub fn serve_addrs(addrs: &[SocketAddr],
+                   server_sk: SecretKey,
+                   server: Server,
+                   handle: Handle) -> Box<Future<Item = (), Error = Error>>
+{
+    let listeners = addrs.iter()
+        .map(|addr| TcpListener::bind(addr, &handle))
+        .collect::<Result<Vec<_>, _>>();
+    let listeners = match listeners {
+        Ok(listeners) => listeners,
+        Err(e) => return Box::new(future::err(e)),
+    };
+    let timer = new_timer();
+    let pings = ping_clients(server.clone(), &timer);
+    let accepts = listeners.into_iter()
+        .map(|listener|
+            accept_clients(listener, server_sk.clone(), server.clone(), handle.clone(), timer.clone())
+        )
+        .collect::<Vec<_>>();
+    Box::new(future::join_all(accepts)
+        .map(|_| ())
+        .select(pings)
+        .map(|_| ())
+        .map_err(|(e, _select_next)| e)
+    )
+}
+
+/** Run relay server on `threads_count` threads, each with its own event
+loop. Connections are accepted from `listener` on the current thread and
+passed to the threads in turn, all of them share the same `server`. Clients
+are pinged from the first thread.
+
+Blocks the current thread until accepting a connection fails or one of the
+threads stops.
+*/
+pub fn serve_threaded(listener: net::TcpListener,
+                      server_sk: SecretKey,
+                      server: Server,
+                      threads_count: usize) -> Result<(), Error>
+{
+    if threads_count == 0 {
+        return Err(Error::new(ErrorKind::InvalidInput, "Relay needs at least one thread"))
+    }
+    let workers = (0..threads_count)
+        .map(|i| {
+            let (tx, rx) = mpsc::unbounded::<(net::TcpStream, SocketAddr)>();
+            let server_sk = server_sk.clone();
+            let server = server.clone();
+            thread::spawn(move || {
+                let mut core = match Core::new() {
+                    Ok(core) => core,
+                    Err(e) => {
+                        error!("Failed to create event loop for relay thread: {}", e);
+                        return
+                    },
+                };
+                let handle = core.handle();
+                let timer = new_timer();
+                if i == 0 {
+                    handle.spawn(ping_clients(server.clone(), &timer)
+                        .map_err(|e| error!("Pinging clients stopped: {}", e))
+                    );
+                }
+                let handle_c = handle.clone();
+                // stops when the accepting thread drops tx
+                let connections = rx.for_each(move |(stream, addr)| {
+                    match TcpStream::from_stream(stream, &handle_c) {
+                        Ok(socket) => spawn_connection(socket, addr, server_sk.clone(),
+                            server.clone(), timer.clone(), &handle_c),
+                        Err(e) => error!("Failed to register connection from {}: {}", addr, e),
+                    }
+                    Ok(())
+                });
+                // rx can't fail
+                core.run(connections).ok();
+            });
+            tx
+        })
+        .collect::<Vec<_>>();
+
+    for worker in workers.iter().cycle() {
+        let (stream, addr) = listener.accept()?;
+        if worker.unbounded_send((stream, addr)).is_err() {
+            return Err(Error::new(ErrorKind::Other, "Relay thread has stopped"))
+        }
+    }
+    unreachable!("workers can't be empty")
+}
+
+/** Create timer used for pings and send timeouts.
+*/
+fn new_timer() -> Timer {
+    tokio_timer::wheel()
+        .tick_duration(Duration::from_millis(TIMER_TICK))
+        .build()
+}
+
+/** Accept connections from the listener and spawn processing of each of them.
+*/
+fn accept_clients(listener: TcpListener,
+                  server_sk: SecretKey,
+                  server: Server,
+                  handle: Handle,
+                  timer: Timer) -> Box<Future<Item = (), Error = Error>>
+{
+    let accept = listener.incoming().for_each(move |(socket, addr)| {
+        spawn_connection(socket, addr, server_sk.clone(), server.clone(), timer.clone(), &handle);
+        Ok(())
+    });
+    Box::new(accept)
+}
+
+/** Spawn processing of the accepted connection on `handle`. The connection
+is dropped if there are too many connections from its IP.
+*/
+fn spawn_connection(socket: TcpStream,
+                    addr: SocketAddr,
+                    server_sk: SecretKey,
+                    server: Server,
+                    timer: Timer,
+                    handle: &Handle)
+{
+    if !server.add_connection(addr.ip()) {
+        debug!("Too many connections from {}, dropping the new one", addr.ip());
+        return
+    }
+    debug!("A new client connected from {}", addr);
+    let server_c = server.clone();
+    let connection = process_connection(socket, addr, server_sk, server, timer);
+    handle.spawn(connection.then(move |r| {
+        debug!("End of processing client from {} with result {:?}", addr, r);
+        server_c.remove_connection(addr.ip());
+        Ok(())
+    }));
+}
+
+/** Periodically send PingRequest to clients and disconnect those which
+don't answer with PongResponse in time.
+*/
+fn ping_clients(server: Server, timer: &Timer) -> Box<Future<Item = (), Error = Error>> {
+    let pings = timer.interval(Duration::from_secs(PING_CHECK_INTERVAL))
+        .map_err(|e| Error::new(ErrorKind::Other, format!("Ping timer error: {:?}", e)))
+        .for_each(move |()| {
+            // failed pings must not stop the server
+            server.send_pings().or_else(|e| {
+                error!("Failed to send pings: {}", e);
+                Ok(())
+            })
+        });
+    Box::new(pings)
+}
+
+/** Handshake the connection, register the client in `server` and process
+packets in both directions until one of the sides is closed.
+*/
+fn process_connection(socket: TcpStream,
+                      addr: SocketAddr,
+                      server_sk: SecretKey,
+                      server: Server,
+                      timer: Timer) -> Box<Future<Item = (), Error = Error>>
+{
+    let server_c = server.clone();
+    let handshake = make_server_handshake(socket, server_sk);
+    let register_client = timer.timeout(handshake, Duration::from_secs(HANDSHAKE_TIMEOUT))
+        .map_err(|e| {
+            error!("Handshake error: {}", e);
+            e
+        })
+        .map(move |(socket, channel, client_pk)| {
+            debug!("Handshake for client {:?} completed", &client_pk);
+            let (tx, rx) = mpsc::unbounded();
+            server_c.insert(Client::new(tx, &client_pk, addr));
+            (socket, channel, client_pk, rx)
+        });
+
+    let process = register_client.and_then(move |(socket, channel, client_pk, rx)| {
+        let secure_socket = socket.framed(codec::Codec::new(channel));
+        let (to_client, from_client) = secure_socket.split();
+
+        // reader = for each Packet from client process it
+        let server_c = server.clone();
+        let reader = from_client.for_each(move |packet| {
+            debug!("Handle {:?} => {:?}", client_pk, packet);
+            server_c.handle_packet(&client_pk, packet)
+        });
+
+        // writer = for each Packet from rx send it to client
+        let writer = rx
+            .map_err(|()| unreachable!("rx can't fail"))
+            .fold(to_client, move |to_client, packet| {
+                debug!("Send {:?} => {:?}", client_pk, packet);
+                timer.timeout(to_client.send(packet), Duration::from_secs(SEND_TIMEOUT))
+            })
+            // drop to_client when rx stream is exhausted
+            .map(|_to_client| ());
+
+        reader.select(writer)
+            .map(|_| ())
+            .map_err(move |(err, _select_next)| {
+                error!("Processing client {:?} ended with error: {:?}", &client_pk, err);
+                err
+            })
+            .then(move |r_processing| {
+                debug!("Shutdown PK {:?}", &client_pk);
+                server.shutdown_client(&client_pk, addr)
+                    .then(move |r_shutdown| r_processing.and(r_shutdown))
+            })
+    });
+    Box::new(process)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use toxcore::tcp::make_client_handshake;
+    use toxcore::tcp::packet::*;
+
+    use tokio_core::reactor::Timeout;
+    use tokio_io::codec::Framed;
+
+    type ClientSocket = Framed<TcpStream, codec::Codec>;
+
+    /// Connect to the relay and complete the handshake with it
+    fn connect(core: &mut Core, addr: &SocketAddr, server_pk: PublicKey) -> (PublicKey, ClientSocket) {
+        let (client_pk, client_sk) = gen_keypair();
+        (client_pk, connect_as(core, addr, server_pk, client_pk, client_sk))
+    }
+
+    /// Connect to the relay with the given client keys
+    fn connect_as(core: &mut Core,
+                  addr: &SocketAddr,
+                  server_pk: PublicKey,
+                  client_pk: PublicKey,
+                  client_sk: SecretKey) -> ClientSocket {
+        let client = TcpStream::connect(addr, &core.handle())
+            .and_then(move |socket| {
+                make_client_handshake(socket, client_pk, client_sk, server_pk)
+            })
+            .map(|(socket, channel)| socket.framed(codec::Codec::new(channel)));
+        core.run(client).unwrap()
+    }
+
+    /// Send the packet to the relay
+    fn send(core: &mut Core, socket: ClientSocket, packet: Packet) -> ClientSocket {
+        core.run(socket.send(packet)).unwrap()
+    }
+
+    /// Receive the next packet from the relay
+    fn recv(core: &mut Core, socket: ClientSocket) -> (Packet, ClientSocket) {
+        let (packet, socket) = core.run(socket.into_future().map_err(|(e, _socket)| e)).unwrap();
+        (packet.unwrap(), socket)
+    }
+
+    /// Connect to the relay, send PingRequest and return the response
+    fn ping_pong(core: &mut Core, addr: &SocketAddr, server_pk: PublicKey) -> Option<Packet> {
+        let (client_pk, client_sk) = gen_keypair();
+        let client = TcpStream::connect(addr, &core.handle())
+            .and_then(move |socket| {
+                make_client_handshake(socket, client_pk, client_sk, server_pk)
+            })
+            .and_then(|(socket, channel)| {
+                socket.framed(codec::Codec::new(channel))
+                    .send(Packet::PingRequest(PingRequest { ping_id: 42 }))
+            })
+            .and_then(|secure_socket| {
+                secure_socket.into_future().map_err(|(e, _secure_socket)| e)
+            })
+            .map(|(packet, _secure_socket)| packet);
+        core.run(client).unwrap()
+    }
+
+    #[test]
+    fn serve_ping_pong() {
+        let (server_pk, server_sk) = gen_keypair();
+
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+        handle.spawn(serve(listener, server_sk, Server::new(), handle.clone()).map_err(|_| ()));
+
+        let packet = ping_pong(&mut core, &addr, server_pk);
+        assert_eq!(packet, Some(Packet::PongResponse(PongResponse { ping_id: 42 })));
+    }
+
+    #[test]
+    fn serve_reconnected_client() {
+        let (server_pk, server_sk) = gen_keypair();
+
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+        handle.spawn(serve(listener, server_sk, Server::new(), handle.clone()).map_err(|_| ()));
+
+        // the same client reconnects before the old connection is closed
+        let (client_pk, client_sk) = gen_keypair();
+        let socket_1 = connect_as(&mut core, &addr, server_pk, client_pk, client_sk.clone());
+        let socket_2 = connect_as(&mut core, &addr, server_pk, client_pk, client_sk);
+        assert!(socket_1.get_ref().local_addr().unwrap() != socket_2.get_ref().local_addr().unwrap());
+
+        // let the relay handle closing of the old connection
+        drop(socket_1);
+        core.run(Timeout::new(Duration::from_millis(200), &handle).unwrap()).unwrap();
+
+        // the new connection is still served
+        let socket_2 = send(&mut core, socket_2, Packet::PingRequest(PingRequest { ping_id: 42 }));
+        let (packet, _socket_2) = recv(&mut core, socket_2);
+        assert_eq!(packet, Packet::PongResponse(PongResponse { ping_id: 42 }));
+    }
+
+    #[test]
+    fn serve_route_data() {
+        let (server_pk, server_sk) = gen_keypair();
+
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+        handle.spawn(serve(listener, server_sk, Server::new(), handle.clone()).map_err(|_| ()));
+
+        let (client_pk_1, socket_1) = connect(&mut core, &addr, server_pk);
+        let (client_pk_2, socket_2) = connect(&mut core, &addr, server_pk);
+
+        // link clients with each other
+        let socket_1 = send(&mut core, socket_1, Packet::RouteRequest(RouteRequest { pk: client_pk_2 }));
+        let (packet, socket_1) = recv(&mut core, socket_1);
+        let connection_id_1 = match packet {
+            Packet::RouteResponse(RouteResponse { pk, connection_id }) => {
+                assert_eq!(pk, client_pk_2);
+                connection_id
+            },
+            packet => panic!("Unexpected packet {:?}", packet),
+        };
+        let socket_2 = send(&mut core, socket_2, Packet::RouteRequest(RouteRequest { pk: client_pk_1 }));
+        let (packet_1, socket_2) = recv(&mut core, socket_2);
+        let (packet_2, socket_2) = recv(&mut core, socket_2);
+        let connection_id_2 = match packet_1 {
+            Packet::RouteResponse(RouteResponse { pk, connection_id }) => {
+                assert_eq!(pk, client_pk_1);
+                connection_id
+            },
+            packet => panic!("Unexpected packet {:?}", packet),
+        };
+        assert_eq!(packet_2, Packet::ConnectNotification(ConnectNotification { connection_id: connection_id_2 }));
+        let (packet, socket_1) = recv(&mut core, socket_1);
+        assert_eq!(packet, Packet::ConnectNotification(ConnectNotification { connection_id: connection_id_1 }));
+
+        // route data from the first client to the second one
+        let data = vec![42; 10];
+        send(&mut core, socket_1, Packet::Data(Data { connection_id: connection_id_1, data: data.clone() }));
+        let (packet, _socket_2) = recv(&mut core, socket_2);
+        assert_eq!(packet, Packet::Data(Data { connection_id: connection_id_2, data: data }));
+    }
+
+    #[test]
+    fn serve_threaded_ping_pong() {
+        let (server_pk, server_sk) = gen_keypair();
+
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        // the relay runs until the test process exits
+        thread::spawn(move || serve_threaded(listener, server_sk, Server::new(), 2));
+
+        let mut core = Core::new().unwrap();
+        // connections are handled by different threads
+        for _ in 0..2 {
+            let packet = ping_pong(&mut core, &addr, server_pk);
+            assert_eq!(packet, Some(Packet::PongResponse(PongResponse { ping_id: 42 })));
+        }
+    }
+}
@@ -22,7 +22,11 @@
 */
 
 mod client;
+mod limits;
 mod server;
+mod listener;
 
-pub use self::client::Client;
-pub use self::server::Server;
+pub use self::client::{Client, TCP_PING_FREQUENCY, TCP_PING_TIMEOUT};
+pub use self::limits::{Limits, RateLimit};
+pub use self::server::{Server, Stats};
+pub use self::listener::{serve, serve_addrs, serve_threaded, SEND_TIMEOUT, HANDSHAKE_TIMEOUT};
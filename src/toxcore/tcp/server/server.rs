@@ -23,18 +23,38 @@
 
 use toxcore::crypto_core::*;
 use toxcore::tcp::server::client::Client;
+use toxcore::tcp::server::limits::Limits;
 use toxcore::tcp::packet::*;
+use toxcore::onion::packet::{InnerOnionResponse, ONION_MAX_PACKET_SIZE, ONION_RETURN_1_SIZE};
 
 use std::io::{Error, ErrorKind};
 use std::collections::HashMap;
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use futures::{Stream, Future, future, stream};
+use futures::sync::mpsc;
 
 
 use tokio_io::IoFuture;
 
+/// Number of shards connected clients are spread across by their PK
+const SHARDS_COUNT: usize = 64;
+
+/** Maximum size of `OnionDataRequest` payload. The UDP part of the node
+relays it as `OnionRequest1` with the client's `OnionReturn` appended, which
+has to fit in `ONION_MAX_PACKET_SIZE`.
+*/
+const ONION_DATA_REQUEST_MAX_PAYLOAD_SIZE: usize =
+    ONION_MAX_PACKET_SIZE - (1 + NONCEBYTES + PUBLICKEYBYTES + ONION_RETURN_1_SIZE);
+
+/// Connected client that can be locked independently of others
+type ClientRef = Arc<Mutex<Client>>;
+
+/// A part of connected clients guarded by its own lock
+type Shard = RwLock<HashMap<PublicKey, ClientRef>>;
+
 /** A `Server` is a structure that holds connected clients, manages their links and handles
 their responses. Notice that there is no actual network code here, the `Server` accepts packets
 by value from `Server::handle_packet`, sends packets back to clients via
@@ -42,10 +62,92 @@ by value from `Server::handle_packet`, sends packets back to clients via
 connections, get packets from clients, pass them into `Server::handle_packet`,
 create `mpsc` chanel, take packets from `futures::sync::mpsc::UnboundedReceiver<Packet>` send them back
 to clients via network.
+
+`Server` is `Send + Sync`, its clones share the same state and can be used from
+different threads. Connected clients are spread across shards by their PK, each
+shard and each client has its own lock. When two clients have to be locked at
+once they are locked in a fixed order, so handling packets can't deadlock.
+Clients are also indexed by the address of their TCP connection, the index
+is always locked after a shard and before a client.
+
+Clients are restricted by `Limits` set with `Server::set_limits`. A client
+which exceeds any of rate limits gets an error from `Server::handle_packet`
+so that its connection is closed. How often that happens can be seen with
+`Server::stats`.
+
+Onion requests from clients are passed to the UDP part of the node via the
+sink set by `Server::set_udp_onion_sink`, responses to them should be passed
+back into `Server::handle_udp_onion_response`.
 */
 #[derive(Clone)]
 pub struct Server {
-    connected_clients: Rc<RefCell<HashMap<PublicKey, Client>>>,
+    shards: Arc<Vec<Shard>>,
+    /// PKs of connected clients by the address of their TCP connection
+    addrs: Arc<RwLock<HashMap<SocketAddr, PublicKey>>>,
+    onion_sink: Option<mpsc::UnboundedSender<(OnionDataRequest, SocketAddr)>>,
+    limits: Limits,
+    /// Number of open connections from each IP
+    connections: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    counters: Arc<Counters>,
+}
+
+/** Counters of `Server` shared between its clones
+*/
+#[derive(Default)]
+struct Counters {
+    rejected_connections: AtomicUsize,
+    route_request_floods: AtomicUsize,
+    oob_send_floods: AtomicUsize,
+    data_floods: AtomicUsize,
+}
+
+/** Statistics of limits enforced by `Server`
+*/
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Number of open connections
+    pub connections: usize,
+    /// Number of connections dropped because of too many connections from the same IP
+    pub rejected_connections: usize,
+    /// Number of clients disconnected for sending too many RouteRequest packets
+    pub route_request_floods: usize,
+    /// Number of clients disconnected for sending too many OobSend packets
+    pub oob_send_floods: usize,
+    /// Number of clients disconnected for sending too much Data
+    pub data_floods: usize,
+}
+
+/** Lock the client. A client is locked only for a short synchronous piece of
+code, so if the lock is poisoned there is nothing to recover.
+*/
+fn lock(client: &ClientRef) -> MutexGuard<Client> {
+    client.lock().expect("Client lock is poisoned")
+}
+
+/** Lock two different clients in the order of their addresses in memory so
+that concurrent locking of the same pair can't deadlock.
+*/
+fn lock_pair<'a>(client_a: &'a ClientRef, client_b: &'a ClientRef) -> (MutexGuard<'a, Client>, MutexGuard<'a, Client>) {
+    let a_ptr: *const Mutex<Client> = &**client_a;
+    let b_ptr: *const Mutex<Client> = &**client_b;
+    if a_ptr < b_ptr {
+        let a = lock(client_a);
+        let b = lock(client_b);
+        (a, b)
+    } else {
+        let b = lock(client_b);
+        let a = lock(client_a);
+        (a, b)
+    }
+}
+
+/** Remove `addr` from the index of clients' addresses if it still belongs
+to the client with `pk`.
+*/
+fn remove_addr(addrs: &mut HashMap<SocketAddr, PublicKey>, addr: &SocketAddr, pk: &PublicKey) {
+    if addrs.get(addr) == Some(pk) {
+        addrs.remove(addr);
+    }
 }
 
 impl Server {
@@ -53,14 +155,101 @@ impl Server {
     */
     pub fn new() -> Server {
         Server {
-            connected_clients: Rc::new(RefCell::new(HashMap::new()))
+            shards: Arc::new((0..SHARDS_COUNT).map(|_| RwLock::new(HashMap::new())).collect()),
+            addrs: Arc::new(RwLock::new(HashMap::new())),
+            onion_sink: None,
+            limits: Limits::default(),
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            counters: Arc::new(Counters::default()),
+        }
+    }
+    /** Set limits for clients. Set them before cloning the `Server`.
+    */
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+    /** Register a new connection from `ip`. Returns false if there are
+    already `Limits::max_connections_per_ip` connections from that IP, the
+    connection should be dropped then. Every registered connection should be
+    unregistered with `Server::remove_connection` when it's closed.
+    */
+    pub fn add_connection(&self, ip: IpAddr) -> bool {
+        let mut connections = self.connections.lock().expect("Connections lock is poisoned");
+        let count = connections.entry(ip).or_insert(0);
+        if *count >= self.limits.max_connections_per_ip {
+            self.counters.rejected_connections.fetch_add(1, Ordering::Relaxed);
+            false
+        } else {
+            *count += 1;
+            true
+        }
+    }
+    /** Unregister a closed connection from `ip`
+    */
+    pub fn remove_connection(&self, ip: IpAddr) {
+        let mut connections = self.connections.lock().expect("Connections lock is poisoned");
+        let is_last = if let Some(count) = connections.get_mut(&ip) {
+            *count -= 1;
+            *count == 0
+        } else {
+            false
+        };
+        if is_last {
+            connections.remove(&ip);
+        }
+    }
+    /** Get current statistics of the server
+    */
+    pub fn stats(&self) -> Stats {
+        let connections = self.connections.lock().expect("Connections lock is poisoned")
+            .values().sum();
+        Stats {
+            connections: connections,
+            rejected_connections: self.counters.rejected_connections.load(Ordering::Relaxed),
+            route_request_floods: self.counters.route_request_floods.load(Ordering::Relaxed),
+            oob_send_floods: self.counters.oob_send_floods.load(Ordering::Relaxed),
+            data_floods: self.counters.data_floods.load(Ordering::Relaxed),
+        }
+    }
+    /** Set the sink for onion requests received from clients. Each request is
+    sent along with the address of the client's TCP connection which should
+    be used as the return address of the onion path. Set it before cloning
+    the `Server`. If the sink is not set, onion requests are ignored.
+    */
+    pub fn set_udp_onion_sink(&mut self, onion_sink: mpsc::UnboundedSender<(OnionDataRequest, SocketAddr)>) {
+        self.onion_sink = Some(onion_sink);
+    }
+    /** Get the shard the client with `pk` belongs to
+    */
+    fn shard(&self, pk: &PublicKey) -> &Shard {
+        &self.shards[pk.0[0] as usize % SHARDS_COUNT]
+    }
+    /** Get connected client by PK
+    */
+    fn get_client(&self, pk: &PublicKey) -> Option<ClientRef> {
+        self.shard(pk).read().expect("Shard lock is poisoned")
+            .get(pk).cloned()
+    }
+    /** Get all connected clients
+    */
+    fn clients(&self) -> Vec<ClientRef> {
+        let mut clients = Vec::new();
+        for shard in self.shards.iter() {
+            clients.extend(shard.read().expect("Shard lock is poisoned").values().cloned());
         }
+        clients
     }
     /** Insert the client into connected_clients. Do nothing else.
     */
     pub fn insert(&self, client: Client) {
-        self.connected_clients.borrow_mut()
-            .insert(client.pk(), client);
+        let pk = client.pk();
+        let addr = client.addr();
+        let mut shard = self.shard(&pk).write().expect("Shard lock is poisoned");
+        let mut addrs = self.addrs.write().expect("Addrs lock is poisoned");
+        if let Some(old_client) = shard.insert(pk, Arc::new(Mutex::new(client))) {
+            remove_addr(&mut addrs, &lock(&old_client).addr(), &pk);
+        }
+        addrs.insert(addr, pk);
     }
     /**The main processing function. Call in on each incoming packet from connected and
     handshaked client.
@@ -75,27 +264,69 @@ impl Server {
             Packet::PongResponse(packet) => self.handle_pong_response(pk, packet),
             Packet::OobSend(packet) => self.handle_oob_send(pk, packet),
             Packet::OobReceive(packet) => self.handle_oob_receive(pk, packet),
+            Packet::OnionDataRequest(packet) => self.handle_onion_request(pk, packet),
+            Packet::OnionDataResponse(packet) => self.handle_onion_response(pk, packet),
             Packet::Data(packet) => self.handle_data(pk, packet),
         }
     }
+    /** Send the response that came back through the onion path to the client
+    with TCP connection address `addr`.
+    */
+    pub fn handle_udp_onion_response(&self, addr: SocketAddr, payload: InnerOnionResponse) -> IoFuture<()> {
+        let pk = self.addrs.read().expect("Addrs lock is poisoned")
+            .get(&addr).cloned();
+        let client = pk.and_then(|pk| self.get_client(&pk))
+            .and_then(|client| if lock(&client).addr() == addr { Some(client) } else { None });
+        if let Some(client) = client {
+            let client = lock(&client);
+            client.send_onion_response(payload)
+        } else {
+            Box::new( future::err(
+                Error::new(ErrorKind::Other,
+                    "Cannot find client by addr to send onion response"
+            )))
+        }
+    }
     /** Gracefully shutdown client by pk. Remove it from the list of connected clients.
     If there are any clients mutually linked to current client, we send them corresponding
     DisconnectNotification.
+
+    The client is removed only if it's connected from `addr`, so that closing
+    of an old connection doesn't remove the client that has reconnected with
+    the same PK.
     */
-    pub fn shutdown_client(&self, pk: &PublicKey) -> IoFuture<()> {
-        let client_a = if let Some(client_a) = self.connected_clients.borrow_mut().remove(pk) {
+    pub fn shutdown_client(&self, pk: &PublicKey, addr: SocketAddr) -> IoFuture<()> {
+        let removed = {
+            let mut shard = self.shard(pk).write().expect("Shard lock is poisoned");
+            let is_same_connection = shard.get(pk)
+                .map_or(false, |client| lock(client).addr() == addr);
+            let removed = if is_same_connection {
+                shard.remove(pk)
+            } else {
+                None
+            };
+            if removed.is_some() {
+                let mut addrs = self.addrs.write().expect("Addrs lock is poisoned");
+                remove_addr(&mut addrs, &addr, pk);
+            }
+            removed
+        };
+        let client_a = if let Some(client_a) = removed {
             client_a
         } else {
             return Box::new( future::err(
                 Error::new(ErrorKind::Other,
-                    "Cannot find client by pk to shutdown it"
+                    "Cannot find client by pk and addr to shutdown it"
             )))
         };
-        let notifications = client_a.iter_links()
+        let links = lock(&client_a).iter_links()
             // foreach link that is Some(client_b_pk)
             .filter_map(|&client_b_pk| client_b_pk)
+            .collect::<Vec<_>>();
+        let notifications = links.into_iter()
             .map(|client_b_pk| {
-                if let Some(client_b) = self.connected_clients.borrow().get(&client_b_pk) {
+                if let Some(client_b) = self.get_client(&client_b_pk) {
+                    let client_b = lock(&client_b);
                     // check if client_a is linked in client_b
                     if let Some(a_id_in_client_b) = client_b.get_connection_id(pk) {
                         // it is linked, we should notify client_b
@@ -108,41 +339,85 @@ impl Server {
                     // client_b is not connected to the server
                     Box::new( future::ok(()) )
                 }
-            });
+            })
+            .collect::<Vec<_>>();
         Box::new( stream::futures_unordered(notifications).for_each(Ok) )
     }
 
+    /** Send PingRequest to clients that answered the previous one at least
+    `TCP_PING_FREQUENCY` seconds ago and shutdown clients that haven't sent
+    PongResponse within `TCP_PING_TIMEOUT` seconds. Should be called
+    periodically.
+    */
+    pub fn send_pings(&self) -> IoFuture<()> {
+        let mut timedout = Vec::new();
+        let mut pings = Vec::new();
+        for client in self.clients() {
+            let mut client = lock(&client);
+            if client.is_pong_timedout() {
+                timedout.push((client.pk(), client.addr()));
+            } else if client.is_ping_interval_passed() {
+                pings.push(client.send_ping_request());
+            }
+        }
+        let shutdowns = timedout.iter()
+            .map(|&(ref pk, addr)| {
+                debug!("PongResponse timeout for {:?}", pk);
+                self.shutdown_client(pk, addr)
+            })
+            .collect::<Vec<_>>();
+        Box::new(
+            stream::futures_unordered(pings).for_each(Ok)
+                .join(stream::futures_unordered(shutdowns).for_each(Ok))
+                .map(|_| ())
+        )
+    }
+
     // Here start the impl of `handle_***` methods
 
     fn handle_route_request(&self, pk: &PublicKey, packet: RouteRequest) -> IoFuture<()> {
-        let b_id_in_client_a = {
-            // check if client was already linked to pk
-            let mut clients = self.connected_clients.borrow_mut();
-            if let Some(client_a) = clients.get_mut(pk) {
-                if pk == &packet.pk {
-                    // send RouteResponse(0) if client requests its own pk
-                    return client_a.send_route_response(pk, 0)
-                }
-                if let Some(b_id_in_client_a) = client_a.get_connection_id(&packet.pk) {
-                    // send RouteResponse if client was already linked to pk
-                    return client_a.send_route_response(&packet.pk, b_id_in_client_a)
-                } else if let Some(b_id_in_client_a) = client_a.insert_connection_id(&packet.pk) {
-                    // new link was inserted into client.links
-                    b_id_in_client_a
-                } else {
-                    // send RouteResponse(0) if no space to insert new link
-                    return client_a.send_route_response(&packet.pk, 0)
-                }
-            } else {
-                return Box::new( future::err(
-                    Error::new(ErrorKind::Other,
-                        "RouteRequest: no such PK"
-                )))
-            }
+        let client_a = if let Some(client_a) = self.get_client(pk) {
+            client_a
+        } else {
+            return Box::new( future::err(
+                Error::new(ErrorKind::Other,
+                    "RouteRequest: no such PK"
+            )))
+        };
+        let is_allowed = lock(&client_a).is_route_request_allowed(&self.limits);
+        if !is_allowed {
+            self.counters.route_request_floods.fetch_add(1, Ordering::Relaxed);
+            return Box::new( future::err(
+                Error::new(ErrorKind::Other,
+                    "RouteRequest rate limit exceeded"
+            )))
+        }
+        if pk == &packet.pk {
+            // send RouteResponse(0) if client requests its own pk
+            return lock(&client_a).send_route_response(pk, 0)
+        }
+        // both clients are locked to check their links atomically
+        let client_b = self.get_client(&packet.pk);
+        let (mut client_a, client_b) = match client_b {
+            Some(ref client_b) => {
+                let (client_a, client_b) = lock_pair(&client_a, client_b);
+                (client_a, Some(client_b))
+            },
+            None => (lock(&client_a), None),
+        };
+        // check if client was already linked to pk
+        let linked_id = client_a.get_connection_id(&packet.pk);
+        let b_id_in_client_a = if let Some(b_id_in_client_a) = linked_id {
+            // send RouteResponse if client was already linked to pk
+            return client_a.send_route_response(&packet.pk, b_id_in_client_a)
+        } else if let Some(b_id_in_client_a) = client_a.insert_connection_id(&packet.pk) {
+            // new link was inserted into client.links
+            b_id_in_client_a
+        } else {
+            // send RouteResponse(0) if no space to insert new link
+            return client_a.send_route_response(&packet.pk, 0)
         };
-        let clients = self.connected_clients.borrow();
-        let client_a = clients.get(pk).unwrap(); // can not fail
-        if let Some(client_b) = clients.get(&packet.pk) {
+        if let Some(client_b) = client_b {
             // check if current pk is linked inside other_client
             if let Some(a_id_in_client_b) = client_b.get_connection_id(pk) {
                 // the are both linked, send RouteResponse and
@@ -150,7 +425,7 @@ impl Server {
                 // we don't care if connect notifications fail
                 let client_a_notification = client_a.send_connect_notification(b_id_in_client_a);
                 let client_b_notification = client_b.send_connect_notification(a_id_in_client_b);
-                return Box::new(
+                Box::new(
                     client_a.send_route_response(&packet.pk, b_id_in_client_a)
                         .join(client_a_notification)
                         .join(client_b_notification)
@@ -184,28 +459,27 @@ impl Server {
                     "DisconnectNotification.connection_id < 16"
             )))
         }
-        let mut clients = self.connected_clients.borrow_mut();
-        let client_b_pk = {
-            if let Some(client_a) = clients.get_mut(pk) {
-                // unlink other_pk from client.links if any
-                // and return previous value
-                if let Some(client_b_pk) = client_a.take_link(packet.connection_id) {
-                    client_b_pk
-                } else {
-                    return Box::new( future::err(
-                        Error::new(ErrorKind::Other,
-                            "DisconnectNotification.connection_id is not linked"
-                    )))
-                }
+        let client_b_pk = if let Some(client_a) = self.get_client(pk) {
+            // unlink other_pk from client.links if any
+            // and return previous value
+            let taken_link = lock(&client_a).take_link(packet.connection_id);
+            if let Some(client_b_pk) = taken_link {
+                client_b_pk
             } else {
                 return Box::new( future::err(
                     Error::new(ErrorKind::Other,
-                        "DisconnectNotification: no such PK"
+                        "DisconnectNotification.connection_id is not linked"
                 )))
             }
+        } else {
+            return Box::new( future::err(
+                Error::new(ErrorKind::Other,
+                    "DisconnectNotification: no such PK"
+            )))
         };
 
-        if let Some(client_b) = clients.get_mut(&client_b_pk) {
+        if let Some(client_b) = self.get_client(&client_b_pk) {
+            let mut client_b = lock(&client_b);
             if let Some(a_id_in_client_b) = client_b.get_connection_id(pk) {
                 // unlink pk from client_b it and send notification
                 client_b.take_link(a_id_in_client_b);
@@ -227,8 +501,8 @@ impl Server {
                     "PingRequest.ping_id == 0"
             )))
         }
-        let clients = self.connected_clients.borrow();
-        if let Some(client_a) = clients.get(pk) {
+        if let Some(client_a) = self.get_client(pk) {
+            let client_a = lock(&client_a);
             client_a.send_pong_response(packet.ping_id)
         } else {
             Box::new( future::err(
@@ -244,9 +518,8 @@ impl Server {
                     "PongResponse.ping_id == 0"
             )))
         }
-        let clients = self.connected_clients.borrow();
-        if let Some(client_a) = clients.get(pk) {
-            if packet.ping_id == client_a.ping_id() {
+        if let Some(client_a) = self.get_client(pk) {
+            if lock(&client_a).handle_pong(packet.ping_id) {
                 Box::new( future::ok(()) )
             } else {
                 Box::new( future::err(
@@ -267,8 +540,18 @@ impl Server {
                     "OobSend wrong data length"
             )))
         }
-        let clients = self.connected_clients.borrow();
-        if let Some(client_b) = clients.get(&packet.destination_pk) {
+        if let Some(client_a) = self.get_client(pk) {
+            let is_allowed = lock(&client_a).is_oob_send_allowed(&self.limits);
+            if !is_allowed {
+                self.counters.oob_send_floods.fetch_add(1, Ordering::Relaxed);
+                return Box::new( future::err(
+                    Error::new(ErrorKind::Other,
+                        "OobSend rate limit exceeded"
+                )))
+            }
+        }
+        if let Some(client_b) = self.get_client(&packet.destination_pk) {
+            let client_b = lock(&client_b);
             client_b.send_oob(pk, packet.data)
         } else {
             // Do nothing because client_b is not connected to server
@@ -281,6 +564,39 @@ impl Server {
                 "Client must not send OobReceive to server"
         )))
     }
+    fn handle_onion_request(&self, pk: &PublicKey, packet: OnionDataRequest) -> IoFuture<()> {
+        if packet.payload.is_empty() || packet.payload.len() > ONION_DATA_REQUEST_MAX_PAYLOAD_SIZE {
+            return Box::new( future::err(
+                Error::new(ErrorKind::Other,
+                    "OnionDataRequest wrong payload length"
+            )))
+        }
+        let onion_sink = if let Some(ref onion_sink) = self.onion_sink {
+            onion_sink
+        } else {
+            // Do nothing because onion is not enabled
+            return Box::new( future::ok(()) )
+        };
+        if let Some(client) = self.get_client(pk) {
+            let addr = lock(&client).addr();
+            if onion_sink.unbounded_send((packet, addr)).is_err() {
+                // UDP part of the node is gone, it's not the client's fault
+                error!("Failed to pass OnionDataRequest to UDP");
+            }
+            Box::new( future::ok(()) )
+        } else {
+            Box::new( future::err(
+                Error::new(ErrorKind::Other,
+                    "OnionDataRequest: no such PK"
+            )))
+        }
+    }
+    fn handle_onion_response(&self, _pk: &PublicKey, _packet: OnionDataResponse) -> IoFuture<()> {
+        Box::new( future::err(
+            Error::new(ErrorKind::Other,
+                "Client must not send OnionDataResponse to server"
+        )))
+    }
     fn handle_data(&self, pk: &PublicKey, packet: Data) -> IoFuture<()> {
         if packet.connection_id < 16 {
             return Box::new( future::err(
@@ -288,25 +604,36 @@ impl Server {
                     "Data.connection_id < 16"
             )))
         }
-        let clients = self.connected_clients.borrow();
-        let client_b_pk = {
-            if let Some(client_a) = clients.get(pk) {
-                if let Some(client_b_pk) = client_a.get_link(packet.connection_id) {
-                    client_b_pk
-                } else {
-                    return Box::new( future::err(
-                        Error::new(ErrorKind::Other,
-                            "Data.connection_id is not linked"
-                    )))
-                }
+        let client_b_pk = if let Some(client_a) = self.get_client(pk) {
+            let (is_allowed, link) = {
+                let mut client_a = lock(&client_a);
+                let is_allowed = client_a.is_data_allowed(&self.limits, packet.data.len());
+                let link = client_a.get_link(packet.connection_id);
+                (is_allowed, link)
+            };
+            if !is_allowed {
+                self.counters.data_floods.fetch_add(1, Ordering::Relaxed);
+                return Box::new( future::err(
+                    Error::new(ErrorKind::Other,
+                        "Data rate limit exceeded"
+                )))
+            }
+            if let Some(client_b_pk) = link {
+                client_b_pk
             } else {
                 return Box::new( future::err(
                     Error::new(ErrorKind::Other,
-                        "Data: no such PK"
+                        "Data.connection_id is not linked"
                 )))
             }
+        } else {
+            return Box::new( future::err(
+                Error::new(ErrorKind::Other,
+                    "Data: no such PK"
+            )))
         };
-        if let Some(client_b) = clients.get(&client_b_pk) {
+        if let Some(client_b) = self.get_client(&client_b_pk) {
+            let client_b = lock(&client_b);
             if let Some(a_id_in_client_b) = client_b.get_connection_id(pk) {
                 client_b.send_data(a_id_in_client_b, packet.data)
             } else {
@@ -325,25 +652,83 @@ impl Server {
 mod tests {
     use ::toxcore::crypto_core::*;
     use ::toxcore::tcp::packet::*;
-    use ::toxcore::tcp::server::{Client, Server};
+    use ::toxcore::tcp::server::{Client, Server, Stats, Limits, RateLimit};
+    use super::{lock, ONION_DATA_REQUEST_MAX_PAYLOAD_SIZE};
+    use ::toxcore::onion::packet::{self as onion, InnerOnionResponse};
     use futures::sync::mpsc;
     use futures::{Stream, Future};
 
+    use std::net::{IpAddr, SocketAddr};
+    use std::thread;
+
     #[test]
     fn server_is_clonable() {
         let server = Server::new();
-        add_random_client(&server);
+        let (_client_pk, _client_addr, _rx) = add_random_client(&server);
         let _cloned = server.clone();
         // that's all.
     }
 
-    /// A function that generates random keypair, creates mpsc channel
-    ///  and inserts them as a mock Client into Server
-    fn add_random_client(server: &Server) -> (PublicKey, mpsc::UnboundedReceiver<Packet>) {
+    #[test]
+    fn server_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>(_: &T) {}
+        assert_send_sync(&Server::new());
+    }
+
+    #[test]
+    fn handle_data_from_threads() {
+        let server = Server::new();
+        let mut receivers = Vec::new();
+        let mut senders = Vec::new();
+        for _ in 0..4 {
+            let (client_pk_a, _, _rx_a) = add_random_client(&server);
+            let (client_pk_b, _, rx_b) = add_random_client(&server);
+            // link clients to each other
+            server.handle_packet(&client_pk_a, Packet::RouteRequest(
+                RouteRequest { pk: client_pk_b }
+            )).wait().unwrap();
+            server.handle_packet(&client_pk_b, Packet::RouteRequest(
+                RouteRequest { pk: client_pk_a }
+            )).wait().unwrap();
+            receivers.push(rx_b);
+            senders.push(client_pk_a);
+        }
+
+        // each client_a sends Data to its client_b from its own thread
+        let threads = senders.into_iter().map(|client_pk_a| {
+            let server = server.clone();
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    server.handle_packet(&client_pk_a, Packet::Data(
+                        Data { connection_id: 16, data: vec![42; 100] }
+                    )).wait().unwrap();
+                }
+            })
+        }).collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        // drop all client's senders to finish their streams
+        drop(server);
+        for rx_b in receivers {
+            let data_count = rx_b.collect().wait().unwrap().into_iter()
+                .filter(|packet| *packet == Packet::Data(
+                    Data { connection_id: 16, data: vec![42; 100] }
+                ))
+                .count();
+            assert_eq!(data_count, 100);
+        }
+    }
+
+    /// A function that generates random keypair and addr, creates mpsc
+    ///  channel and inserts them as a mock Client into Server
+    fn add_random_client(server: &Server) -> (PublicKey, SocketAddr, mpsc::UnboundedReceiver<Packet>) {
         let (client_pk, _) = gen_keypair();
+        let client_addr = SocketAddr::new(IpAddr::V4(random_u32().into()), random_u32() as u16);
         let (tx, rx) = mpsc::unbounded();
-        server.insert(Client::new(tx, &client_pk));
-        (client_pk, rx)
+        server.insert(Client::new(tx, &client_pk, client_addr));
+        (client_pk, client_addr, rx)
     }
 
     #[test]
@@ -351,7 +736,7 @@ mod tests {
         let server = Server::new();
 
         // client 1 connects to the server
-        let (client_pk_1, rx_1) = add_random_client(&server);
+        let (client_pk_1, client_addr_1, rx_1) = add_random_client(&server);
 
         let (client_pk_2, _) = gen_keypair();
 
@@ -368,7 +753,7 @@ mod tests {
 
         // client 2 connects to the server
         let (tx_2, rx_2) = mpsc::unbounded();
-        server.insert(Client::new(tx_2, &client_pk_2));
+        server.insert(Client::new(tx_2, &client_pk_2, "1.2.3.4:12346".parse().unwrap()));
 
         // emulate send RouteRequest from client_1 again
         server.handle_packet(&client_pk_1, Packet::RouteRequest(
@@ -416,7 +801,7 @@ mod tests {
         ));
 
         // emulate client_1 disconnected
-        server.shutdown_client(&client_pk_1).wait().unwrap();
+        server.shutdown_client(&client_pk_1, client_addr_1).wait().unwrap();
         // the server should put DisconnectNotification into rx_2
         let (packet, _rx_2) = rx_2.into_future().wait().unwrap();
         assert_eq!(packet.unwrap(), Packet::DisconnectNotification(
@@ -426,8 +811,8 @@ mod tests {
     #[test]
     fn handle_route_request() {
         let server = Server::new();
-        let (client_pk_1, rx_1) = add_random_client(&server);
-        let (client_pk_2, _rx_2) = add_random_client(&server);
+        let (client_pk_1, _, rx_1) = add_random_client(&server);
+        let (client_pk_2, _, _rx_2) = add_random_client(&server);
 
         // emulate send RouteRequest from client_1
         server.handle_packet(&client_pk_1, Packet::RouteRequest(
@@ -443,7 +828,7 @@ mod tests {
     #[test]
     fn handle_route_request_to_itself() {
         let server = Server::new();
-        let (client_pk_1, rx_1) = add_random_client(&server);
+        let (client_pk_1, _, rx_1) = add_random_client(&server);
 
         // emulate send RouteRequest from client_1
         server.handle_packet(&client_pk_1, Packet::RouteRequest(
@@ -459,12 +844,12 @@ mod tests {
     #[test]
     fn handle_route_request_too_many_connections() {
         let server = Server::new();
-        let (client_pk_1, rx_1) = add_random_client(&server);
+        let (client_pk_1, _, rx_1) = add_random_client(&server);
         let mut rx_1 = rx_1;
 
         // send 240 RouteRequest
         for i in 0..240 {
-            let (other_client_pk, _other_rx) = add_random_client(&server);
+            let (other_client_pk, _, _other_rx) = add_random_client(&server);
             // emulate send RouteRequest from client_1
             server.handle_packet(&client_pk_1, Packet::RouteRequest(
                 RouteRequest { pk: other_client_pk }
@@ -478,7 +863,7 @@ mod tests {
             rx_1 = rx_1_nested;
         }
         // and send one more again
-        let (other_client_pk, _other_rx) = add_random_client(&server);
+        let (other_client_pk, _, _other_rx) = add_random_client(&server);
         // emulate send RouteRequest from client_1
         server.handle_packet(&client_pk_1, Packet::RouteRequest(
             RouteRequest { pk: other_client_pk }
@@ -493,7 +878,7 @@ mod tests {
     #[test]
     fn handle_connect_notification() {
         let server = Server::new();
-        let (client_pk_1, _rx_1) = add_random_client(&server);
+        let (client_pk_1, _, _rx_1) = add_random_client(&server);
 
         // emulate send ConnectNotification from client_1
         let handle_res = server.handle_packet(&client_pk_1, Packet::ConnectNotification(
@@ -504,8 +889,8 @@ mod tests {
     #[test]
     fn handle_disconnect_notification() {
         let server = Server::new();
-        let (client_pk_1, rx_1) = add_random_client(&server);
-        let (client_pk_2, rx_2) = add_random_client(&server);
+        let (client_pk_1, _, rx_1) = add_random_client(&server);
+        let (client_pk_2, _, rx_2) = add_random_client(&server);
 
         // emulate send RouteRequest from client_1
         server.handle_packet(&client_pk_1, Packet::RouteRequest(
@@ -555,8 +940,8 @@ mod tests {
     #[test]
     fn handle_disconnect_notification_other_not_linked() {
         let server = Server::new();
-        let (client_pk_1, _rx_1) = add_random_client(&server);
-        let (client_pk_2, _rx_2) = add_random_client(&server);
+        let (client_pk_1, _, _rx_1) = add_random_client(&server);
+        let (client_pk_2, _, _rx_2) = add_random_client(&server);
 
         // emulate send RouteRequest from client_1
         server.handle_packet(&client_pk_1, Packet::RouteRequest(
@@ -572,7 +957,7 @@ mod tests {
     #[test]
     fn handle_ping_request() {
         let server = Server::new();
-        let (client_pk_1, rx_1) = add_random_client(&server);
+        let (client_pk_1, _, rx_1) = add_random_client(&server);
 
         // emulate send PingRequest from client_1
         server.handle_packet(&client_pk_1, Packet::PingRequest(
@@ -586,10 +971,49 @@ mod tests {
         ));
     }
     #[test]
+    fn handle_pong_response() {
+        let server = Server::new();
+        let (client_pk_1, _, rx_1) = add_random_client(&server);
+
+        // the server sends PingRequest to client_1
+        let client_1 = server.get_client(&client_pk_1).unwrap();
+        let ping_request = lock(&client_1).send_ping_request();
+        ping_request.wait().unwrap();
+        let (packet, _rx_1) = rx_1.into_future().wait().unwrap();
+        let ping_id = match packet.unwrap() {
+            Packet::PingRequest(PingRequest { ping_id }) => ping_id,
+            packet => panic!("Unexpected packet {:?}", packet),
+        };
+
+        // emulate send PongResponse with wrong ping_id from client_1
+        let handle_res = server.handle_packet(&client_pk_1, Packet::PongResponse(
+            PongResponse { ping_id: ping_id.wrapping_add(1) }
+        )).wait();
+        assert!(handle_res.is_err());
+
+        // emulate send PongResponse from client_1
+        server.handle_packet(&client_pk_1, Packet::PongResponse(
+            PongResponse { ping_id: ping_id }
+        )).wait().unwrap();
+        assert_eq!(lock(&client_1).ping_id(), 0);
+    }
+    #[test]
+    fn send_pings_to_new_client() {
+        let server = Server::new();
+        let (client_pk_1, _, rx_1) = add_random_client(&server);
+
+        // a just connected client should be neither pinged nor disconnected
+        server.send_pings().wait().unwrap();
+        assert!(server.get_client(&client_pk_1).is_some());
+        drop(server);
+        let (packet, _rx_1) = rx_1.into_future().wait().unwrap();
+        assert!(packet.is_none());
+    }
+    #[test]
     fn handle_oob_send() {
         let server = Server::new();
-        let (client_pk_1, _rx_1) = add_random_client(&server);
-        let (client_pk_2, rx_2) = add_random_client(&server);
+        let (client_pk_1, _, _rx_1) = add_random_client(&server);
+        let (client_pk_2, _, rx_2) = add_random_client(&server);
 
         // emulate send OobSend from client_1
         server.handle_packet(&client_pk_1, Packet::OobSend(
@@ -605,8 +1029,8 @@ mod tests {
     #[test]
     fn shutdown_other_not_linked() {
         let server = Server::new();
-        let (client_pk_1, rx_1) = add_random_client(&server);
-        let (client_pk_2, _rx_2) = add_random_client(&server);
+        let (client_pk_1, client_addr_1, rx_1) = add_random_client(&server);
+        let (client_pk_2, _, _rx_2) = add_random_client(&server);
 
         // emulate send RouteRequest from client_1
         server.handle_packet(&client_pk_1, Packet::RouteRequest(
@@ -620,14 +1044,14 @@ mod tests {
         ));
 
         // emulate shutdown
-        let handle_res = server.shutdown_client(&client_pk_1).wait();
+        let handle_res = server.shutdown_client(&client_pk_1, client_addr_1).wait();
         assert!(handle_res.is_ok());
     }
     #[test]
     fn handle_data_other_not_linked() {
         let server = Server::new();
-        let (client_pk_1, rx_1) = add_random_client(&server);
-        let (client_pk_2, _rx_2) = add_random_client(&server);
+        let (client_pk_1, _, rx_1) = add_random_client(&server);
+        let (client_pk_2, _, _rx_2) = add_random_client(&server);
 
         // emulate send RouteRequest from client_1
         server.handle_packet(&client_pk_1, Packet::RouteRequest(
@@ -652,7 +1076,7 @@ mod tests {
     #[test]
     fn handle_route_response() {
         let server = Server::new();
-        let (client_pk_1, _rx_1) = add_random_client(&server);
+        let (client_pk_1, _, _rx_1) = add_random_client(&server);
 
         // emulate send RouteResponse from client_1
         let handle_res = server.handle_packet(&client_pk_1, Packet::RouteResponse(
@@ -663,7 +1087,7 @@ mod tests {
     #[test]
     fn handle_disconnect_notification_0() {
         let server = Server::new();
-        let (client_pk_1, _rx_1) = add_random_client(&server);
+        let (client_pk_1, _, _rx_1) = add_random_client(&server);
 
         // emulate send DisconnectNotification from client_1
         let handle_res = server.handle_packet(&client_pk_1, Packet::DisconnectNotification(
@@ -674,7 +1098,7 @@ mod tests {
     #[test]
     fn handle_disconnect_notification_not_linked() {
         let server = Server::new();
-        let (client_pk_1, _rx_1) = add_random_client(&server);
+        let (client_pk_1, _, _rx_1) = add_random_client(&server);
 
         // emulate send DisconnectNotification from client_1
         let handle_res = server.handle_packet(&client_pk_1, Packet::DisconnectNotification(
@@ -685,7 +1109,7 @@ mod tests {
     #[test]
     fn handle_ping_request_0() {
         let server = Server::new();
-        let (client_pk_1, _rx_1) = add_random_client(&server);
+        let (client_pk_1, _, _rx_1) = add_random_client(&server);
 
         // emulate send PingRequest from client_1
         let handle_res = server.handle_packet(&client_pk_1, Packet::PingRequest(
@@ -696,7 +1120,7 @@ mod tests {
     #[test]
     fn handle_pong_response_0() {
         let server = Server::new();
-        let (client_pk_1, _rx_1) = add_random_client(&server);
+        let (client_pk_1, _, _rx_1) = add_random_client(&server);
 
         // emulate send PongResponse from client_1
         let handle_res = server.handle_packet(&client_pk_1, Packet::PongResponse(
@@ -707,8 +1131,8 @@ mod tests {
     #[test]
     fn handle_oob_send_empty_data() {
         let server = Server::new();
-        let (client_pk_1, _rx_1) = add_random_client(&server);
-        let (client_pk_2, _rx_2) = add_random_client(&server);
+        let (client_pk_1, _, _rx_1) = add_random_client(&server);
+        let (client_pk_2, _, _rx_2) = add_random_client(&server);
 
         // emulate send OobSend from client_1
         let handle_res = server.handle_packet(&client_pk_1, Packet::OobSend(
@@ -717,9 +1141,245 @@ mod tests {
         assert!(handle_res.is_err());
     }
     #[test]
+    fn handle_onion_request() {
+        let (onion_tx, onion_rx) = mpsc::unbounded();
+        let mut server = Server::new();
+        server.set_udp_onion_sink(onion_tx);
+
+        let (client_pk_1, _) = gen_keypair();
+        let (tx_1, _rx_1) = mpsc::unbounded();
+        let client_addr_1 = "1.2.3.4:12345".parse().unwrap();
+        server.insert(Client::new(tx_1, &client_pk_1, client_addr_1));
+
+        let request = OnionDataRequest {
+            nonce: gen_nonce(),
+            ip_port: "5.6.7.8:33445".parse().unwrap(),
+            temporary_pk: gen_keypair().0,
+            payload: vec![42; 123]
+        };
+        // emulate send OnionDataRequest from client_1
+        server.handle_packet(&client_pk_1, Packet::OnionDataRequest(request.clone()))
+            .wait().unwrap();
+
+        // the server should pass it to UDP along with client's addr
+        let (onion_request, _onion_rx) = onion_rx.into_future().wait().unwrap();
+        assert_eq!(onion_request.unwrap(), (request, client_addr_1));
+    }
+    #[test]
+    fn handle_onion_request_without_sink() {
+        let server = Server::new();
+        let (client_pk_1, _, _rx_1) = add_random_client(&server);
+
+        let request = OnionDataRequest {
+            nonce: gen_nonce(),
+            ip_port: "5.6.7.8:33445".parse().unwrap(),
+            temporary_pk: gen_keypair().0,
+            payload: vec![42; 123]
+        };
+        // emulate send OnionDataRequest from client_1, it should be ignored
+        let handle_res = server.handle_packet(&client_pk_1, Packet::OnionDataRequest(request))
+            .wait();
+        assert!(handle_res.is_ok());
+    }
+    #[test]
+    fn handle_onion_request_wrong_payload_length() {
+        let (onion_tx, _onion_rx) = mpsc::unbounded();
+        let mut server = Server::new();
+        server.set_udp_onion_sink(onion_tx);
+        let (client_pk_1, _, _rx_1) = add_random_client(&server);
+
+        for &len in &[0, ONION_DATA_REQUEST_MAX_PAYLOAD_SIZE + 1] {
+            let request = OnionDataRequest {
+                nonce: gen_nonce(),
+                ip_port: "5.6.7.8:33445".parse().unwrap(),
+                temporary_pk: gen_keypair().0,
+                payload: vec![42; len]
+            };
+            let handle_res = server.handle_packet(&client_pk_1, Packet::OnionDataRequest(request))
+                .wait();
+            assert!(handle_res.is_err());
+        }
+
+        // the largest payload still fits in OnionRequest1
+        let request = OnionDataRequest {
+            nonce: gen_nonce(),
+            ip_port: "5.6.7.8:33445".parse().unwrap(),
+            temporary_pk: gen_keypair().0,
+            payload: vec![42; ONION_DATA_REQUEST_MAX_PAYLOAD_SIZE]
+        };
+        let handle_res = server.handle_packet(&client_pk_1, Packet::OnionDataRequest(request))
+            .wait();
+        assert!(handle_res.is_ok());
+    }
+    #[test]
+    fn handle_onion_response() {
+        let server = Server::new();
+        let (client_pk_1, _, _rx_1) = add_random_client(&server);
+
+        let payload = InnerOnionResponse::OnionDataResponse(onion::OnionDataResponse {
+            nonce: gen_nonce(),
+            temporary_pk: gen_keypair().0,
+            payload: vec![42; 123]
+        });
+        // emulate send OnionDataResponse from client_1
+        let handle_res = server.handle_packet(&client_pk_1, Packet::OnionDataResponse(
+            OnionDataResponse { payload: payload }
+        )).wait();
+        assert!(handle_res.is_err());
+    }
+    #[test]
+    fn handle_udp_onion_response() {
+        let server = Server::new();
+        let (client_pk_1, _) = gen_keypair();
+        let (tx_1, rx_1) = mpsc::unbounded();
+        let client_addr_1 = "1.2.3.4:12345".parse().unwrap();
+        server.insert(Client::new(tx_1, &client_pk_1, client_addr_1));
+
+        let (client_pk_2, _) = gen_keypair();
+        let (tx_2, _rx_2) = mpsc::unbounded();
+        server.insert(Client::new(tx_2, &client_pk_2, "1.2.3.4:12346".parse().unwrap()));
+
+        let payload = InnerOnionResponse::OnionDataResponse(onion::OnionDataResponse {
+            nonce: gen_nonce(),
+            temporary_pk: gen_keypair().0,
+            payload: vec![42; 123]
+        });
+        server.handle_udp_onion_response(client_addr_1, payload.clone()).wait().unwrap();
+
+        // the server should put OnionDataResponse into rx_1
+        let (packet, _rx_1) = rx_1.into_future().wait().unwrap();
+        assert_eq!(packet.unwrap(), Packet::OnionDataResponse(
+            OnionDataResponse { payload: payload.clone() }
+        ));
+
+        // there is no client with such addr
+        let handle_res = server.handle_udp_onion_response("1.2.3.4:12347".parse().unwrap(), payload)
+            .wait();
+        assert!(handle_res.is_err());
+    }
+    #[test]
+    fn handle_udp_onion_response_reconnected() {
+        let server = Server::new();
+        let (client_pk, _) = gen_keypair();
+        let (tx_1, _rx_1) = mpsc::unbounded();
+        let client_addr_1 = "1.2.3.4:12345".parse().unwrap();
+        server.insert(Client::new(tx_1, &client_pk, client_addr_1));
+
+        // the same client reconnects from another addr
+        let (tx_2, rx_2) = mpsc::unbounded();
+        let client_addr_2 = "1.2.3.4:12346".parse().unwrap();
+        server.insert(Client::new(tx_2, &client_pk, client_addr_2));
+
+        let payload = InnerOnionResponse::OnionDataResponse(onion::OnionDataResponse {
+            nonce: gen_nonce(),
+            temporary_pk: gen_keypair().0,
+            payload: vec![42; 123]
+        });
+        // the old connection is gone
+        let handle_res = server.handle_udp_onion_response(client_addr_1, payload.clone())
+            .wait();
+        assert!(handle_res.is_err());
+
+        server.handle_udp_onion_response(client_addr_2, payload.clone()).wait().unwrap();
+        let (packet, _rx_2) = rx_2.into_future().wait().unwrap();
+        assert_eq!(packet.unwrap(), Packet::OnionDataResponse(
+            OnionDataResponse { payload: payload.clone() }
+        ));
+
+        // another client with the same addr stays reachable after shutdown
+        let (client_pk_3, _) = gen_keypair();
+        let (tx_3, rx_3) = mpsc::unbounded();
+        server.insert(Client::new(tx_3, &client_pk_3, client_addr_2));
+        server.shutdown_client(&client_pk, client_addr_2).wait().unwrap();
+        server.handle_udp_onion_response(client_addr_2, payload.clone()).wait().unwrap();
+        let (packet, _rx_3) = rx_3.into_future().wait().unwrap();
+        assert_eq!(packet.unwrap(), Packet::OnionDataResponse(
+            OnionDataResponse { payload: payload.clone() }
+        ));
+
+        // the client is gone after shutdown
+        server.shutdown_client(&client_pk_3, client_addr_2).wait().unwrap();
+        let handle_res = server.handle_udp_onion_response(client_addr_2, payload)
+            .wait();
+        assert!(handle_res.is_err());
+    }
+    #[test]
+    fn handle_udp_onion_response_to_right_client() {
+        let server = Server::new();
+        let clients = (0..3).map(|_| add_random_client(&server)).collect::<Vec<_>>();
+
+        let payload = InnerOnionResponse::OnionDataResponse(onion::OnionDataResponse {
+            nonce: gen_nonce(),
+            temporary_pk: gen_keypair().0,
+            payload: vec![42; 123]
+        });
+        server.handle_udp_onion_response(clients[1].1, payload.clone()).wait().unwrap();
+
+        // drop all client's senders to finish their streams
+        drop(server);
+        let packets = clients.into_iter()
+            .map(|(_, _, rx)| rx.collect().wait().unwrap())
+            .collect::<Vec<_>>();
+        assert!(packets[0].is_empty());
+        assert_eq!(packets[1], vec![Packet::OnionDataResponse(
+            OnionDataResponse { payload: payload }
+        )]);
+        assert!(packets[2].is_empty());
+    }
+    #[test]
+    fn lookup_client_by_addr() {
+        let server = Server::new();
+        let (client_pk_1, client_addr_1, _rx_1) = add_random_client(&server);
+        let (client_pk_2, client_addr_2, _rx_2) = add_random_client(&server);
+        assert!(client_addr_1 != client_addr_2);
+
+        {
+            let addrs = server.addrs.read().unwrap();
+            assert_eq!(Some(&client_pk_1), addrs.get(&client_addr_1));
+            assert_eq!(Some(&client_pk_2), addrs.get(&client_addr_2));
+        }
+
+        server.shutdown_client(&client_pk_1, client_addr_1).wait().unwrap();
+        let addrs = server.addrs.read().unwrap();
+        assert_eq!(None, addrs.get(&client_addr_1));
+        assert_eq!(Some(&client_pk_2), addrs.get(&client_addr_2));
+    }
+    #[test]
+    fn shutdown_client_with_same_addr() {
+        let server = Server::new();
+        let (client_pk_1, client_addr, _rx_1) = add_random_client(&server);
+
+        // another client is connected from the same addr
+        let (client_pk_2, _) = gen_keypair();
+        let (tx_2, rx_2) = mpsc::unbounded();
+        server.insert(Client::new(tx_2, &client_pk_2, client_addr));
+
+        // removing the first client leaves the second one
+        server.shutdown_client(&client_pk_1, client_addr).wait().unwrap();
+        server.handle_packet(&client_pk_2, Packet::PingRequest(
+            PingRequest { ping_id: 42 }
+        )).wait().unwrap();
+
+        let payload = InnerOnionResponse::OnionDataResponse(onion::OnionDataResponse {
+            nonce: gen_nonce(),
+            temporary_pk: gen_keypair().0,
+            payload: vec![42; 123]
+        });
+        server.handle_udp_onion_response(client_addr, payload.clone()).wait().unwrap();
+
+        let (packet, rx_2) = rx_2.into_future().wait().unwrap();
+        assert_eq!(packet.unwrap(), Packet::PongResponse(
+            PongResponse { ping_id: 42 }
+        ));
+        let (packet, _rx_2) = rx_2.into_future().wait().unwrap();
+        assert_eq!(packet.unwrap(), Packet::OnionDataResponse(
+            OnionDataResponse { payload: payload }
+        ));
+    }
+    #[test]
     fn handle_data_0() {
         let server = Server::new();
-        let (client_pk_1, _rx_1) = add_random_client(&server);
+        let (client_pk_1, _, _rx_1) = add_random_client(&server);
 
         // emulate send Data from client_1
         let handle_res = server.handle_packet(&client_pk_1, Packet::Data(
@@ -730,7 +1390,7 @@ mod tests {
     #[test]
     fn handle_data_self_not_linked() {
         let server = Server::new();
-        let (client_pk_1, _rx_1) = add_random_client(&server);
+        let (client_pk_1, _, _rx_1) = add_random_client(&server);
 
         // emulate send Data from client_1
         let handle_res = server.handle_packet(&client_pk_1, Packet::Data(
@@ -741,8 +1401,8 @@ mod tests {
     #[test]
     fn handle_oob_send_to_loooong_data() {
         let server = Server::new();
-        let (client_pk_1, _rx_1) = add_random_client(&server);
-        let (client_pk_2, _rx_2) = add_random_client(&server);
+        let (client_pk_1, _, _rx_1) = add_random_client(&server);
+        let (client_pk_2, _, _rx_2) = add_random_client(&server);
 
         // emulate send OobSend from client_1
         let handle_res = server.handle_packet(&client_pk_1, Packet::OobSend(
@@ -753,8 +1413,8 @@ mod tests {
     #[test]
     fn handle_oob_recv() {
         let server = Server::new();
-        let (client_pk_1, _rx_1) = add_random_client(&server);
-        let (client_pk_2, _rx_2) = add_random_client(&server);
+        let (client_pk_1, _, _rx_1) = add_random_client(&server);
+        let (client_pk_2, _, _rx_2) = add_random_client(&server);
 
         // emulate send OobReceive from client_1
         let handle_res = server.handle_packet(&client_pk_1, Packet::OobReceive(
@@ -791,7 +1451,7 @@ mod tests {
     #[test]
     fn handle_disconnect_notification_other_not_connected() {
         let server = Server::new();
-        let (client_pk_1, _rx_1) = add_random_client(&server);
+        let (client_pk_1, _, _rx_1) = add_random_client(&server);
         let (client_pk_2, _) = gen_keypair();
 
         // emulate send RouteRequest from client_1
@@ -853,7 +1513,7 @@ mod tests {
     #[test]
     fn handle_data_other_not_connected() {
         let server = Server::new();
-        let (client_pk_1, rx_1) = add_random_client(&server);
+        let (client_pk_1, _, rx_1) = add_random_client(&server);
         let (client_pk_2, _) = gen_keypair();
 
         // emulate send RouteRequest from client_1
@@ -879,13 +1539,39 @@ mod tests {
         let (client_pk, _) = gen_keypair();
 
         // emulate shutdown
-        let handle_res = server.shutdown_client(&client_pk).wait();
+        let handle_res = server.shutdown_client(&client_pk, "1.2.3.4:12345".parse().unwrap()).wait();
         assert!(handle_res.is_err());
     }
     #[test]
+    fn shutdown_old_connection() {
+        let server = Server::new();
+        let (client_pk, _) = gen_keypair();
+        let (tx_1, _rx_1) = mpsc::unbounded();
+        let client_addr_1 = "1.2.3.4:12345".parse().unwrap();
+        server.insert(Client::new(tx_1, &client_pk, client_addr_1));
+
+        // the same client reconnects from another addr
+        let (tx_2, rx_2) = mpsc::unbounded();
+        let client_addr_2 = "1.2.3.4:12346".parse().unwrap();
+        server.insert(Client::new(tx_2, &client_pk, client_addr_2));
+
+        // closing of the old connection doesn't remove the new one
+        let handle_res = server.shutdown_client(&client_pk, client_addr_1).wait();
+        assert!(handle_res.is_err());
+        server.handle_packet(&client_pk, Packet::PingRequest(
+            PingRequest { ping_id: 42 }
+        )).wait().unwrap();
+        let (packet, _rx_2) = rx_2.into_future().wait().unwrap();
+        assert_eq!(packet.unwrap(), Packet::PongResponse(
+            PongResponse { ping_id: 42 }
+        ));
+
+        server.shutdown_client(&client_pk, client_addr_2).wait().unwrap();
+    }
+    #[test]
     fn shutdown_other_not_connected() {
         let server = Server::new();
-        let (client_pk_1, rx_1) = add_random_client(&server);
+        let (client_pk_1, client_addr_1, rx_1) = add_random_client(&server);
         let (client_pk_2, _) = gen_keypair();
 
         // emulate send RouteRequest from client_1
@@ -900,14 +1586,14 @@ mod tests {
         ));
 
         // emulate shutdown
-        let handle_res = server.shutdown_client(&client_pk_1).wait();
+        let handle_res = server.shutdown_client(&client_pk_1, client_addr_1).wait();
         assert!(handle_res.is_ok());
     }
     #[test]
     fn send_anything_to_dropped_client() {
         let server = Server::new();
-        let (client_pk_1, rx_1) = add_random_client(&server);
-        let (client_pk_2, _rx_2) = add_random_client(&server);
+        let (client_pk_1, _, rx_1) = add_random_client(&server);
+        let (client_pk_2, _, _rx_2) = add_random_client(&server);
 
         drop(rx_1);
 
@@ -917,4 +1603,95 @@ mod tests {
         )).wait();
         assert!(handle_res.is_err())
     }
+
+    /// Limits with small rates so that tests can exceed them
+    fn small_limits() -> Limits {
+        Limits {
+            max_connections_per_ip: 2,
+            route_requests: RateLimit { rate: 1, burst: 2 },
+            oob_sends: RateLimit { rate: 1, burst: 2 },
+            data_bytes: RateLimit { rate: 1, burst: 100 },
+        }
+    }
+    #[test]
+    fn connections_per_ip_limit() {
+        let mut server = Server::new();
+        server.set_limits(small_limits());
+        let ip_1 = "1.2.3.4".parse().unwrap();
+        let ip_2 = "1.2.3.5".parse().unwrap();
+
+        assert!(server.add_connection(ip_1));
+        assert!(server.add_connection(ip_1));
+        assert!(!server.add_connection(ip_1));
+        assert!(server.add_connection(ip_2));
+        assert_eq!(server.stats().connections, 3);
+        assert_eq!(server.stats().rejected_connections, 1);
+
+        server.remove_connection(ip_1);
+        assert!(server.add_connection(ip_1));
+        server.remove_connection(ip_1);
+        server.remove_connection(ip_1);
+        server.remove_connection(ip_2);
+        assert_eq!(server.stats().connections, 0);
+    }
+    #[test]
+    fn handle_route_request_flood() {
+        let mut server = Server::new();
+        server.set_limits(small_limits());
+        let (client_pk_1, _, _rx_1) = add_random_client(&server);
+        let (client_pk_2, _) = gen_keypair();
+
+        for _ in 0..2 {
+            server.handle_packet(&client_pk_1, Packet::RouteRequest(
+                RouteRequest { pk: client_pk_2 }
+            )).wait().unwrap();
+        }
+        let handle_res = server.handle_packet(&client_pk_1, Packet::RouteRequest(
+            RouteRequest { pk: client_pk_2 }
+        )).wait();
+        assert!(handle_res.is_err());
+        assert_eq!(server.stats().route_request_floods, 1);
+    }
+    #[test]
+    fn handle_oob_send_flood() {
+        let mut server = Server::new();
+        server.set_limits(small_limits());
+        let (client_pk_1, _, _rx_1) = add_random_client(&server);
+        let (client_pk_2, _, _rx_2) = add_random_client(&server);
+
+        for _ in 0..2 {
+            server.handle_packet(&client_pk_1, Packet::OobSend(
+                OobSend { destination_pk: client_pk_2, data: vec![13; 42] }
+            )).wait().unwrap();
+        }
+        let handle_res = server.handle_packet(&client_pk_1, Packet::OobSend(
+            OobSend { destination_pk: client_pk_2, data: vec![13; 42] }
+        )).wait();
+        assert!(handle_res.is_err());
+        assert_eq!(server.stats().oob_send_floods, 1);
+    }
+    #[test]
+    fn handle_data_flood() {
+        let mut server = Server::new();
+        server.set_limits(small_limits());
+        let (client_pk_1, _, _rx_1) = add_random_client(&server);
+        let (client_pk_2, _, _rx_2) = add_random_client(&server);
+
+        // link clients to each other
+        server.handle_packet(&client_pk_1, Packet::RouteRequest(
+            RouteRequest { pk: client_pk_2 }
+        )).wait().unwrap();
+        server.handle_packet(&client_pk_2, Packet::RouteRequest(
+            RouteRequest { pk: client_pk_1 }
+        )).wait().unwrap();
+
+        server.handle_packet(&client_pk_1, Packet::Data(
+            Data { connection_id: 16, data: vec![42; 60] }
+        )).wait().unwrap();
+        let handle_res = server.handle_packet(&client_pk_1, Packet::Data(
+            Data { connection_id: 16, data: vec![42; 60] }
+        )).wait();
+        assert!(handle_res.is_err());
+        assert_eq!(server.stats(), Stats { data_floods: 1, .. Stats::default() });
+    }
 }
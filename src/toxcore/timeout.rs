@@ -65,7 +65,7 @@ Possibly create a new `TimoutFuture` for each known node – future is
 created to trigger [`GetNodes`] requests.
 
 
-[`GetNodes`]: ../dht/struct.GetNodes.html
+[`GetNodes`]: ../dht_new/packet/struct.GetNodes.html
 [`NodeState`]: ./enum.NodeState.html
 [`NodeTimeout`]: ./struct.NodeTimeout.html
 [`PacketKind`]: ../packet_kind/enum.PacketKind.html
@@ -75,7 +75,7 @@ use tokio_proto::multiplex::RequestId;
 
 use std::collections::VecDeque;
 use std::ops::Deref;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use toxcore::crypto_core::*;
 
@@ -87,7 +87,7 @@ Number of seconds to reach ping timeout. DHT node has to respond to a
 Ping requests are used to check whether node is online, so that it could
 be added to own [`DhtNode`]'s `Kbucket` of close nodes.
 
-[`PingReq`]: ../dht/struct.PingReq.html
+[`PingReq`]: ../dht_new/packet/struct.PingRequest.html
 [`DhtNode`]: ../dht_node/struct.DhtNode.html
 */
 pub const PING_TIMEOUT: u64 = 5;
@@ -96,7 +96,7 @@ pub const PING_TIMEOUT: u64 = 5;
 Number of seconds between each time that a DHT node needs to be checked
 for responsiveness by sending to it a [`GetNodes`] request.
 
-[`GetNodes`]: ../dht/struct.GetNodes.html
+[`GetNodes`]: ../dht_new/packet/struct.GetNodes.html
 */
 // TODO: rename
 pub const RESPONSE_CHECK: u64 = 60;
@@ -118,6 +118,19 @@ See [`NodeState`].
 pub const UNRESPONSIVE_TIME: u64 = BAD_TIME + RESPONSE_CHECK;
 
 
+/**
+Number of seconds since the unix epoch.
+
+Used where time has to be embedded into packets, e.g. in ping IDs and
+cookies. Returns `0` if system time is set before the unix epoch.
+*/
+pub fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+
 /**
 Enum stating whether we consider responsiveness of given DHT node
 as `Good`, `Bad` or `Unresponsive`.
@@ -158,7 +171,7 @@ State of a `Good` node that goes offline:
     them all, hoping that network will become available again
 
 [`BAD_NODE_TIME`]: ./constant.BAD_NODE_TIME.html
-[`GetNodes`]: ../dht/struct.GetNodes.html
+[`GetNodes`]: ../dht_new/packet/struct.GetNodes.html
 [`RESPONSE_CHECK`]: ./constant.RESPONSE_CHECK.html
 [`UNRESPONSIVE_TIME`]: ./constant.UNRESPONSIVE_TIME.html
 */
@@ -221,6 +234,10 @@ impl NodeTimeout {
 /**
 Store & manage timeout data.
 
+Timeouts are stored per sent request, so that only responses to requests
+that were actually sent are accepted. Responses that don't match any
+request are counted as unsolicited.
+
 To create new `TimeoutQueue` use `Default` trait:
 
 ```
@@ -232,6 +249,8 @@ let _timeout_queue = TimeoutQueue::default();
 #[derive(Clone, Debug, Default, Eq, PartialEq)]
 pub struct TimeoutQueue {
     vec: VecDeque<NodeTimeout>,
+    /// Number of responses with unknown or expired ID.
+    unsolicited: u64,
 }
 
 impl TimeoutQueue {
@@ -274,6 +293,34 @@ impl TimeoutQueue {
         }
     }
 
+    /**
+    Check whether request with `id` was sent to node with `pk` less than
+    `secs` seconds ago. Matching timeout is removed so that every request
+    can be answered only once.
+
+    Returns `false` and counts the response as unsolicited if there is no
+    such request.
+    */
+    pub fn check(&mut self, pk: &PublicKey, id: RequestId, secs: u64) -> bool {
+        let pos = self.vec.iter().position(|nt|
+            nt.id() == id && nt.pk() == pk && !nt.is_timed_out(secs));
+        match pos {
+            Some(pos) => {
+                self.vec.remove(pos);
+                true
+            },
+            None => {
+                self.unsolicited += 1;
+                false
+            },
+        }
+    }
+
+    /// Number of responses that didn't match any request in the queue.
+    pub fn unsolicited(&self) -> u64 {
+        self.unsolicited
+    }
+
     /**
     Remove from `TimeoutQueue` and return all `PublicKey`s of nodes that
     timed out.
@@ -447,6 +494,33 @@ mod test {
         }
     }
 
+    // TimeoutQueue::check()
+
+    #[test]
+    fn timeout_queue_check_test() {
+        let mut tq = TimeoutQueue::default();
+        let (pk, _) = gen_keypair();
+        let (other_pk, _) = gen_keypair();
+        tq.add(&pk, 42);
+        tq.add(&pk, 43);
+
+        // wrong id and wrong node are unsolicited
+        assert!(!tq.check(&pk, 44, PING_TIMEOUT));
+        assert!(!tq.check(&other_pk, 42, PING_TIMEOUT));
+        assert_eq!(2, tq.unsolicited());
+
+        assert!(tq.check(&pk, 42, PING_TIMEOUT));
+        // other requests to the same node are still there
+        assert_eq!(1, tq.len());
+        // request can be answered only once
+        assert!(!tq.check(&pk, 42, PING_TIMEOUT));
+        assert_eq!(3, tq.unsolicited());
+
+        // expired request is unsolicited
+        assert!(!tq.check(&pk, 43, 0));
+        assert_eq!(4, tq.unsolicited());
+    }
+
     // TimeoutQueue::get_timed_out()
 
     quickcheck! {
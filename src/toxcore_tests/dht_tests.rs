@@ -446,6 +446,15 @@ fn packed_node_new_test_ip_type_TCP_IPv6() {
     assert_eq!(addr, info.socket_addr());
 }
 
+#[test]
+fn packed_node_new_test_ipv4_mapped() {
+    let pk = PublicKey([0; PUBLICKEYBYTES]);
+    let addr = SocketAddr::V6("[::ffff:1.2.3.4]:5".parse().unwrap());
+    let info = PackedNode::new(true, addr, &pk);
+    assert_eq!(IpType::U4, info.ip_type());
+    assert_eq!(SocketAddr::V4("1.2.3.4:5".parse().unwrap()), info.socket_addr());
+}
+
 
 // PackedNode::ip()
 
@@ -478,6 +487,20 @@ fn packed_node_parse_bytes_test_port() {
     quickcheck(with_pn as fn(PackedNode));
 }
 
+#[test]
+fn packed_node_parse_bytes_test_ipv4_mapped() {
+    let pk = PublicKey([1; PUBLICKEYBYTES]);
+    let mut bytes = vec![10];
+    bytes.extend_from_slice(&Ipv4Addr::new(1, 2, 3, 4).to_ipv6_mapped().octets());
+    bytes.extend_from_slice(&[0, 5]);
+    bytes.extend_from_slice(&pk.0);
+
+    let node = PackedNode::from_bytes(&bytes).unwrap();
+    assert_eq!(IpType::U4, node.ip_type());
+    assert_eq!(SocketAddr::V4("1.2.3.4:5".parse().unwrap()), node.socket_addr());
+    assert_eq!(&pk, node.pk());
+}
+
 
 // PackedNode::parse_bytes_multiple()
 
@@ -22,6 +22,7 @@
 
 use tokio_core::reactor::Core;
 
+use std::net::UdpSocket;
 use std::thread;
 use std::time::Duration;
 
@@ -86,3 +87,30 @@ fn bind_udp_test() {
         });
     }
 }
+
+#[test]
+fn bind_udp_dual_stack_test() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let socket = bind_udp("::".parse().unwrap(), PORT_MIN..PORT_MAX, &handle).unwrap();
+    let port = socket.local_addr().unwrap().port();
+
+    // IPv4 peer can reach IPv6 socket
+    let v4_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let v4_addr = v4_socket.local_addr().unwrap();
+    v4_socket.send_to(&[42], ("127.0.0.1", port)).unwrap();
+
+    let (_, buf, size, addr) = core.run(socket.recv_dgram(vec![0; 16])).unwrap();
+    assert_eq!(&[42], &buf[..size]);
+    assert_eq!(v4_addr, normalize_addr(addr));
+}
+
+// is_ipv4_mapped()
+
+#[test]
+fn is_ipv4_mapped_test() {
+    assert!(is_ipv4_mapped(&"::ffff:1.2.3.4".parse().unwrap()));
+    assert!(!is_ipv4_mapped(&"::1.2.3.4".parse().unwrap()));
+    assert!(!is_ipv4_mapped(&"::1".parse().unwrap()));
+    assert!(!is_ipv4_mapped(&"1:ffff::".parse().unwrap()));
+}
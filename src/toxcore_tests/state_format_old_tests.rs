@@ -25,7 +25,8 @@ use nom::IResult;
 use quickcheck::{Arbitrary, Gen, TestResult, quickcheck};
 
 use toxcore::binary_io::*;
-use toxcore::dht::*;
+use toxcore::dht_new::binary_io::ToBytes as NodeToBytes;
+use toxcore::dht_new::packed_node::PackedNode;
 use toxcore::crypto_core::*;
 use toxcore::toxid::*;
 use toxcore::state_format::old::*;
@@ -196,6 +197,13 @@ fn nospam_keys_parse_bytes_rest_test() {
 
 impl_arb_for_pn!(DhtState);
 
+/// Serialize `PackedNode` of DHT node
+fn packed_node_bytes(pn: &PackedNode) -> Vec<u8> {
+    let mut buf = [0; 51];
+    let (_, size) = NodeToBytes::to_bytes(pn, (&mut buf, 0)).unwrap();
+    buf[..size].to_vec()
+}
+
 // DhtState::from_bytes()
 
 #[test]
@@ -204,7 +212,7 @@ fn dht_state_from_bytes() {
         let pns_bytes: Vec<u8> = {
             let mut bytes = vec![];
             for pn in &pns {
-                bytes.extend_from_slice(&pn.to_bytes());
+                bytes.extend_from_slice(&packed_node_bytes(pn));
             }
             bytes
         };
@@ -242,7 +250,7 @@ fn dht_state_parse_bytes_rest_test() {
         let pns_bytes: Vec<u8> = {
             let mut bytes = vec![];
             for pn in &pns {
-                bytes.extend_from_slice(&pn.to_bytes());
+                bytes.extend_from_slice(&packed_node_bytes(pn));
             }
             bytes
         };